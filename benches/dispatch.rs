@@ -0,0 +1,105 @@
+//! Compare the dispatch speed of the `ByteCode` enum against the packed 32-bit `Instruction`.
+//!
+//! Both loops run the same program on a register file of integers, so the difference is the
+//! cost of matching on the enum versus decoding opcode and operands from the packed form.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use lua_interpreter::bytecode::{ByteCode, Instruction, OpCode};
+
+const ITERATIONS: usize = 200_000;
+
+fn program() -> Vec<ByteCode> {
+    vec![
+        ByteCode::LoadInteger(0, 1),
+        ByteCode::LoadInteger(1, 2),
+        ByteCode::Move(2, 0),
+        ByteCode::LoadConst(3, 0),
+        ByteCode::GetGlobal(4, 1),
+        ByteCode::LoadBool(5, true),
+        ByteCode::Call(2, 2, 1),
+        ByteCode::Move(6, 2),
+        ByteCode::LoadNil(7),
+        ByteCode::Call(6, 2, 1),
+        ByteCode::Add(7, 0, 1),
+    ]
+}
+
+fn run_enum(codes: &[ByteCode], consts: &[i64], regs: &mut [i64]) {
+    for code in codes {
+        match *code {
+            ByteCode::GetGlobal(dst, src) | ByteCode::LoadConst(dst, src) => {
+                regs[dst as usize] = consts[src as usize]
+            }
+            ByteCode::Call(func, ..) => {
+                regs[func as usize] = regs[func as usize].wrapping_add(regs[func as usize + 1])
+            }
+            ByteCode::LoadBool(dst, v) => regs[dst as usize] = v.into(),
+            ByteCode::LoadNil(dst) => regs[dst as usize] = 0,
+            ByteCode::LoadInteger(dst, v) => regs[dst as usize] = v.into(),
+            ByteCode::Move(dst, src) => regs[dst as usize] = regs[src as usize],
+            ByteCode::Add(dst, b, c) => {
+                regs[dst as usize] = regs[b as usize].wrapping_add(regs[c as usize])
+            }
+            _ => unreachable!("{code:?} is not used by the benchmark"),
+        }
+    }
+}
+
+fn run_packed(codes: &[Instruction], consts: &[i64], regs: &mut [i64]) {
+    for &code in codes {
+        let a = code.a() as usize;
+        match code.opcode() {
+            OpCode::GetGlobal | OpCode::LoadConst => regs[a] = consts[code.d() as usize],
+            OpCode::Call => regs[a] = regs[a].wrapping_add(regs[a + 1]),
+            OpCode::LoadBool => regs[a] = code.d().into(),
+            OpCode::Move => regs[a] = regs[code.d() as usize],
+            OpCode::LoadNil => regs[a] = 0,
+            OpCode::LoadInteger => regs[a] = code.sd().into(),
+            OpCode::Add => regs[a] = regs[code.b() as usize].wrapping_add(regs[code.c() as usize]),
+            op => unreachable!("{op:?} is not used by the benchmark"),
+        }
+    }
+}
+
+fn measure(name: &str, instructions: usize, mut f: impl FnMut()) -> Duration {
+    // warm up caches and branch predictors before timing
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let per_instruction = elapsed.as_nanos() as f64 / (ITERATIONS * instructions) as f64;
+    println!("{name:>8}: {elapsed:>12.3?} total, {per_instruction:.3} ns/instruction");
+    elapsed
+}
+
+fn main() {
+    let codes = program();
+    let packed: Vec<Instruction> = codes.iter().map(Instruction::from).collect();
+    let consts = [3, 4];
+    let mut regs = [0_i64; 8];
+
+    println!(
+        "enum size: {} bytes, packed size: {} bytes",
+        std::mem::size_of::<ByteCode>(),
+        std::mem::size_of::<Instruction>()
+    );
+    let enum_time = measure("enum", codes.len(), || {
+        run_enum(black_box(&codes), &consts, &mut regs)
+    });
+    let packed_time = measure("packed", packed.len(), || {
+        run_packed(black_box(&packed), &consts, &mut regs)
+    });
+    println!(
+        "packed/enum: {:.2}",
+        packed_time.as_secs_f64() / enum_time.as_secs_f64()
+    );
+    black_box(regs);
+}
@@ -0,0 +1,74 @@
+//! Compare the pauses of the incremental and the generational collector on a program keeping
+//! a large set of long lived objects while it creates short lived ones, like a server handling
+//! requests.
+//!
+//! Every request runs the same chunk, so the time it takes beyond the usual is the work the
+//! collector did while it ran. The incremental collector traverses every live object in each
+//! cycle, a step at a time, while minor collections of the generational one skip old objects
+//! but run without interruption: they do less work in total, in longer pauses. The last run
+//! does minor collections more often, each after the heap grew by 5% instead of 20%.
+//!
+//! Run with `cargo bench --bench gc_pause`.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use lua_interpreter::{load_str, ExeState};
+
+/// Coroutines kept alive for the whole run, chained through their stacks.
+const LONG_LIVED: usize = 50_000;
+const REQUESTS: usize = 50_000;
+
+/// Run the requests with the collector switched by `mode`, a call to collectgarbage.
+fn measure(name: &str, mode: &str) {
+    let mut output = io::sink();
+    let mut vm = ExeState::new(&mut output);
+    let setup = load_str(
+        "function hold(prev) coroutine.yield() end\n\
+         local co = coroutine.create(hold)\n\
+         coroutine.resume(co, chain)\n\
+         chain = co",
+        Some("=setup"),
+    )
+    .unwrap();
+    for _ in 0..LONG_LIVED {
+        vm.execute(&setup).unwrap();
+    }
+    // both start from a full collection, which brings the sizes of the threads up to date
+    vm.collect_garbage();
+    vm.execute(&load_str(mode, Some("=mode")).unwrap()).unwrap();
+
+    // every request leaves a few cycles for the collector
+    let request = load_str(
+        "function hold_self() local me = coroutine.running(); coroutine.yield() end\n\
+         coroutine.resume(coroutine.create(hold_self))\n\
+         coroutine.resume(coroutine.create(hold_self))\n\
+         coroutine.resume(coroutine.create(hold_self))\n\
+         coroutine.resume(coroutine.create(hold_self))",
+        Some("=request"),
+    )
+    .unwrap();
+    let mut pauses = Vec::with_capacity(REQUESTS);
+    for _ in 0..REQUESTS {
+        let start = Instant::now();
+        vm.execute(&request).unwrap();
+        pauses.push(start.elapsed());
+    }
+
+    pauses.sort();
+    let total: Duration = pauses.iter().sum();
+    let percentile = |p: usize| pauses[(pauses.len() - 1) * p / 100];
+    println!(
+        "{name:>12}: {total:>10.3?} total, median {:>9.3?}, p99 {:>9.3?}, max {:>9.3?}, heap {} KB",
+        percentile(50),
+        percentile(99),
+        pauses[pauses.len() - 1],
+        vm.heap_size() / 1024
+    );
+}
+
+fn main() {
+    measure("incremental", "collectgarbage(\"incremental\")");
+    measure("generational", "collectgarbage(\"generational\")");
+    measure("minor 5%", "collectgarbage(\"generational\", 5)");
+}
@@ -0,0 +1,88 @@
+//! Measure the cost of strings: copying them between registers and looking up globals by
+//! name, both in isolation, against the `String` values used before they were shared and
+//! interned, and in string heavy scripts.
+//!
+//! Run with `cargo bench --bench strings`.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::io;
+use std::time::{Duration, Instant};
+
+use lua_interpreter::{load_str, ExeState, LuaString, Value};
+
+const ITERATIONS: usize = 1_000_000;
+const RUNS: usize = 2_000;
+
+fn measure(name: &str, iterations: usize, mut f: impl FnMut()) -> Duration {
+    for _ in 0..iterations / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let per_iteration = elapsed.as_nanos() as f64 / iterations as f64;
+    println!("{name:>24}: {elapsed:>12.3?} total, {per_iteration:.2} ns/iteration");
+    elapsed
+}
+
+/// A chunk of `lines` statements copying the string `s` between locals and globals.
+fn script(s: &str, lines: usize) -> String {
+    let mut code = format!("local a = \"{s}\"\nlocal b = a\n");
+    for i in 0..lines {
+        code += match i % 4 {
+            0 => "b = a\n",
+            1 => "name = b\n",
+            2 => "a = name\n",
+            _ => "local c = print\n",
+        };
+    }
+    code
+}
+
+fn main() {
+    println!("Value size: {} bytes", std::mem::size_of::<Value>());
+
+    let text = "a string long enough to be copied";
+    let owned = String::from(text);
+    let shared = LuaString::from(text);
+    measure("String clone", ITERATIONS, || {
+        black_box(black_box(&owned).clone());
+    });
+    measure("LuaString clone", ITERATIONS, || {
+        black_box(black_box(&shared).clone());
+    });
+
+    let names = [
+        "print",
+        "error",
+        "pcall",
+        "coroutine.resume",
+        "collectgarbage",
+    ];
+    let by_string: HashMap<String, i32> = names.iter().map(|n| (n.to_string(), 0)).collect();
+    let keys: Vec<LuaString> = names.iter().map(|&n| LuaString::from(n)).collect();
+    let by_lua_string: HashMap<LuaString, i32> = keys.iter().map(|k| (k.clone(), 0)).collect();
+    measure("HashMap<String> get", ITERATIONS, || {
+        for name in &names {
+            black_box(by_string.get(black_box(*name)));
+        }
+    });
+    measure("HashMap<LuaString> get", ITERATIONS, || {
+        for key in &keys {
+            black_box(by_lua_string.get(black_box(key)));
+        }
+    });
+
+    let mut output = io::sink();
+    let mut vm = ExeState::new(&mut output);
+    for (name, s) in [
+        ("short strings", "moved"),
+        ("long strings", &*text.repeat(4)),
+    ] {
+        let proto = load_str(&script(s, 200), Some("=strings")).unwrap();
+        measure(name, RUNS, || vm.execute(&proto).unwrap());
+    }
+}
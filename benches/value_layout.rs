@@ -0,0 +1,174 @@
+//! Compare the 16-byte `Value`, whose strings are shared pointers, against the previous
+//! layout with a `String` inline, which made every value 24 bytes and copying a string
+//! allocate.
+//!
+//! The loop and call workloads run on a register file the way the VM does: loops copy and add
+//! registers, calls copy their arguments to the top of the stack and truncate it on return.
+//! The last three run Lua code through the VM, which only has the new layout: recursion, calls,
+//! and reads and writes of table fields and items.
+//!
+//! Run with `cargo bench --bench value_layout`.
+
+use std::hint::black_box;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use lua_interpreter::{load_str, ExeState, LuaString, NativeFunction, Value};
+
+const ITERATIONS: usize = 1_000_000;
+
+/// The previous layout of `Value`.
+#[allow(dead_code)]
+#[derive(Clone)]
+enum InlineValue {
+    Nil,
+    String(String),
+    Function(NativeFunction),
+    LuaFunction(Rc<()>),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+/// What the workloads need from a layout.
+trait Layout: Clone {
+    fn int(i: i64) -> Self;
+    fn string(s: &str) -> Self;
+    fn add(&self, other: &Self) -> Self;
+}
+
+impl Layout for Value {
+    fn int(i: i64) -> Self {
+        Value::Integer(i)
+    }
+
+    fn string(s: &str) -> Self {
+        Value::String(LuaString::from(s))
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => Value::Integer(a.wrapping_add(*b)),
+            _ => Value::Nil,
+        }
+    }
+}
+
+impl Layout for InlineValue {
+    fn int(i: i64) -> Self {
+        InlineValue::Integer(i)
+    }
+
+    fn string(s: &str) -> Self {
+        InlineValue::String(s.to_string())
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        match (self, other) {
+            (InlineValue::Integer(a), InlineValue::Integer(b)) => {
+                InlineValue::Integer(a.wrapping_add(*b))
+            }
+            _ => InlineValue::Nil,
+        }
+    }
+}
+
+fn registers<V: Layout>() -> Vec<V> {
+    (0..64)
+        .map(|i| match i % 4 {
+            0 => V::string("a string held in a register"),
+            _ => V::int(i),
+        })
+        .collect()
+}
+
+/// the body of a loop over the registers: moves, strings among them, and additions
+fn loop_body<V: Layout>(regs: &mut [V]) {
+    for i in (0..regs.len() - 4).step_by(4) {
+        regs[i + 3] = regs[i].clone();
+        regs[i + 1] = regs[i + 1].add(&regs[i + 2]);
+        regs[i] = regs[i + 4].clone();
+    }
+}
+
+/// calls passing three arguments, one a string, and returning
+fn calls<V: Layout>(stack: &mut Vec<V>) {
+    let top = stack.len();
+    for func in (0..top - 4).step_by(4) {
+        stack.extend_from_within(func..func + 4);
+        stack.truncate(top);
+    }
+}
+
+fn measure(name: &str, iterations: usize, mut f: impl FnMut()) -> Duration {
+    for _ in 0..iterations / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let per_iteration = elapsed.as_nanos() as f64 / iterations as f64;
+    println!("{name:>20}: {elapsed:>12.3?} total, {per_iteration:.2} ns/iteration");
+    elapsed
+}
+
+fn compare(workload: &str, f: fn(&mut Vec<Value>), g: fn(&mut Vec<InlineValue>)) {
+    let mut packed = registers::<Value>();
+    let mut inline = registers::<InlineValue>();
+    let new = measure(&format!("{workload}, 16 bytes"), ITERATIONS, || {
+        f(black_box(&mut packed))
+    });
+    let old = measure(&format!("{workload}, 24 bytes"), ITERATIONS, || {
+        g(black_box(&mut inline))
+    });
+    println!(
+        "{:>20}: {:.2}",
+        "ratio",
+        new.as_secs_f64() / old.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!(
+        "Value: {} bytes, previous layout: {} bytes",
+        std::mem::size_of::<Value>(),
+        std::mem::size_of::<InlineValue>()
+    );
+    compare("loop", |r| loop_body(r), |r| loop_body(r));
+    compare("calls", calls, calls);
+
+    let mut output = io::sink();
+    let mut vm = ExeState::new(&mut output);
+    let recursion = load_str(
+        "function count(n) return n > 0 and count(n - 1) + 1 or 0 end\n\
+         count(100)",
+        Some("=recursion"),
+    )
+    .unwrap();
+    measure("VM recursion", ITERATIONS / 100, || {
+        vm.execute(&recursion).unwrap()
+    });
+    let call = load_str(
+        "local function first(a, b, c) return a end\n\
+         local s = \"a string argument\"\n\
+         first(s, 1, 2) first(s, 1, 2) first(s, 1, 2) first(s, 1, 2)\n\
+         first(s, 1, 2) first(s, 1, 2) first(s, 1, 2) first(s, 1, 2)",
+        Some("=calls"),
+    )
+    .unwrap();
+    measure("VM calls", ITERATIONS / 10, || vm.execute(&call).unwrap());
+    let tables = load_str(
+        "local t = {1, 2, 3, name = \"a string value\"}\n\
+         t[1] = t[2] + t[3] t.x = t.name t[4] = t.x\n\
+         t.y = t[1] t[2] = t.y t.name = t[4]\n\
+         local s = t.x local n = t[1] + t[2] + t[3]",
+        Some("=tables"),
+    )
+    .unwrap();
+    measure("VM tables", ITERATIONS / 10, || {
+        vm.execute(&tables).unwrap()
+    });
+}
@@ -1,15 +1,19 @@
+use std::fmt;
+
 #[derive(Debug, PartialEq)]
 pub enum ByteCode {
     /// GetGlobal(dst, src):
     /// load global name from constants at src and load global into stack at dst
-    GetGlobal(u8, u8),
+    GetGlobal(u8, u16),
     /// LoadConst(dst, src):
     /// load value from constants into stack at dst
-    LoadConst(u8, u8),
-    /// Call(func_index, ???)
-    /// invokes the function at funx_index on the stack, for now it expects a single argument
-    /// at func_index+1 on the stack
-    Call(u8, u8),
+    LoadConst(u8, u16),
+    /// Call(func, nargs + 1, nresults + 1)
+    /// invokes the function in register func with the arguments in the registers after it. The
+    /// results replace the function and the registers after it. An argument count of 0 passes
+    /// everything up to the top set by the previous instruction, a result count of 0 keeps all
+    /// results and sets the top after them.
+    Call(u8, u8, u8),
     LoadBool(u8, bool),
     LoadNil(u8),
     /// LoadInteger(dst, value)
@@ -18,4 +22,466 @@ pub enum ByteCode {
     LoadInteger(u8, i16),
     /// Move(dst, src)
     Move(u8, u8),
+
+    // arithmetic and bitwise operators: Op(dst, lhs, rhs) with operands in registers, see
+    // `number::arith` for their semantics
+    Add(u8, u8, u8),
+    Sub(u8, u8, u8),
+    Mul(u8, u8, u8),
+    Mod(u8, u8, u8),
+    Pow(u8, u8, u8),
+    Div(u8, u8, u8),
+    Idiv(u8, u8, u8),
+    BitAnd(u8, u8, u8),
+    BitOr(u8, u8, u8),
+    BitXor(u8, u8, u8),
+    ShiftL(u8, u8, u8),
+    ShiftR(u8, u8, u8),
+    /// Neg(dst, src): unary minus
+    Neg(u8, u8),
+    /// BitNot(dst, src): unary `~`
+    BitNot(u8, u8),
+
+    // comparisons: Op(dst, lhs, rhs) store a boolean, see `compare` for their semantics.
+    // `>` and `>=` are `LesThan` and `LesEq` with swapped operands
+    Equal(u8, u8, u8),
+    NotEq(u8, u8, u8),
+    LesThan(u8, u8, u8),
+    LesEq(u8, u8, u8),
+    /// Not(dst, src)
+    Not(u8, u8),
+
+    // jumps: the offset counts from the instruction after the jump
+    /// Jump(offset)
+    Jump(i16),
+    /// TestAndJump(src, offset): jump if src is false or nil, the short circuit of `and`
+    TestAndJump(u8, i16),
+    /// TestOrJump(src, offset): jump if src is neither false nor nil, the short circuit of `or`
+    TestOrJump(u8, i16),
+
+    // functions
+    /// Closure(dst, proto): create the function of the nested prototype at index proto
+    Closure(u8, u16),
+    /// Return(first, n + 1): return the values from register first on, 0 returns everything up
+    /// to the top. Running past the last instruction returns nothing.
+    Return(u8, u8),
+    /// VarArgs(dst, n + 1): load the extra arguments into dst and the registers after it, 0
+    /// loads all of them and sets the top after them
+    VarArgs(u8, u8),
+    /// SetGlobal(src, name): store register src in the global whose name is the constant name
+    SetGlobal(u8, u16),
+    /// TailCall(func, nargs + 1): `return func(args)`, calls like `Call` with all results kept.
+    /// A Lua function takes over the frame of the caller, so its results are returned right
+    /// away, the `Return(func, 0)` after it handles the results of a native function.
+    TailCall(u8, u8),
+
+    /// Tbc(var): mark the local in register var as to-be-closed. Its `__close` metamethod is
+    /// called when the function returns, or an error unwinds it. Nil and false are not closed.
+    Tbc(u8),
+
+    // tables
+    /// NewTable(dst): create an empty table
+    NewTable(u8),
+    /// GetTable(dst, table, key): load the value of table at key, raw, there are no metamethods
+    GetTable(u8, u8, u8),
+    /// SetTable(table, key, src): store register src in table at key
+    SetTable(u8, u8, u8),
+    /// SetList(table, n + 1, batch): store the n values in the registers after table at the
+    /// indices from batch * FIELDS_PER_FLUSH + 1 on, 0 stores everything up to the top
+    SetList(u8, u8, u8),
+
+    /// Concat(dst, lhs, rhs): `lhs .. rhs` of two strings or numbers, numbers are converted
+    /// like `tostring` does
+    Concat(u8, u8, u8),
+}
+
+/// Number of list items of a table constructor stored by one `SetList`.
+pub const FIELDS_PER_FLUSH: usize = 50;
+
+impl ByteCode {
+    /// offset of a jump instruction, counted from the instruction after it
+    pub fn jump_offset(&self) -> Option<i16> {
+        match *self {
+            ByteCode::Jump(offset)
+            | ByteCode::TestAndJump(_, offset)
+            | ByteCode::TestOrJump(_, offset) => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// whether the instruction after this one can run next, false after unconditional jumps
+    /// and returns
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, ByteCode::Jump(_) | ByteCode::Return(..))
+    }
+}
+
+/// Opcodes of the packed [`Instruction`], one for each [`ByteCode`] variant.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    GetGlobal,
+    LoadConst,
+    Call,
+    LoadBool,
+    LoadNil,
+    LoadInteger,
+    Move,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    Idiv,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftL,
+    ShiftR,
+    Neg,
+    BitNot,
+    Equal,
+    NotEq,
+    LesThan,
+    LesEq,
+    Not,
+    Jump,
+    TestAndJump,
+    TestOrJump,
+    Closure,
+    Return,
+    VarArgs,
+    SetGlobal,
+    TailCall,
+    Tbc,
+    NewTable,
+    GetTable,
+    SetTable,
+    SetList,
+    Concat,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(op: u8) -> Result<Self, u8> {
+        let op = match op {
+            0 => OpCode::GetGlobal,
+            1 => OpCode::LoadConst,
+            2 => OpCode::Call,
+            3 => OpCode::LoadBool,
+            4 => OpCode::LoadNil,
+            5 => OpCode::LoadInteger,
+            6 => OpCode::Move,
+            7 => OpCode::Add,
+            8 => OpCode::Sub,
+            9 => OpCode::Mul,
+            10 => OpCode::Mod,
+            11 => OpCode::Pow,
+            12 => OpCode::Div,
+            13 => OpCode::Idiv,
+            14 => OpCode::BitAnd,
+            15 => OpCode::BitOr,
+            16 => OpCode::BitXor,
+            17 => OpCode::ShiftL,
+            18 => OpCode::ShiftR,
+            19 => OpCode::Neg,
+            20 => OpCode::BitNot,
+            21 => OpCode::Equal,
+            22 => OpCode::NotEq,
+            23 => OpCode::LesThan,
+            24 => OpCode::LesEq,
+            25 => OpCode::Not,
+            26 => OpCode::Jump,
+            27 => OpCode::TestAndJump,
+            28 => OpCode::TestOrJump,
+            29 => OpCode::Closure,
+            30 => OpCode::Return,
+            31 => OpCode::VarArgs,
+            32 => OpCode::SetGlobal,
+            33 => OpCode::TailCall,
+            34 => OpCode::Tbc,
+            35 => OpCode::NewTable,
+            36 => OpCode::GetTable,
+            37 => OpCode::SetTable,
+            38 => OpCode::SetList,
+            39 => OpCode::Concat,
+            _ => return Err(op),
+        };
+        Ok(op)
+    }
+}
+
+/// A bytecode instruction packed into 32 bits, with the 8 bit opcode in the lowest byte:
+///
+/// ```text
+/// +---+---+---+---+
+/// | B | C | A | OP|
+/// |   D   | A | OP|
+/// +---+---+---+---+
+/// ```
+///
+/// Every instruction built through [`Instruction::from`] or [`Instruction::try_from`] has a
+/// valid opcode, which lets the VM dispatch on it without further checks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Instruction(u32);
+
+const _: () = assert!(std::mem::size_of::<Instruction>() == 4);
+
+impl Instruction {
+    pub fn abc(op: OpCode, a: u8, b: u8, c: u8) -> Self {
+        Instruction(op as u32 | (a as u32) << 8 | (c as u32) << 16 | (b as u32) << 24)
+    }
+
+    pub fn ad(op: OpCode, a: u8, d: u16) -> Self {
+        Instruction(op as u32 | (a as u32) << 8 | (d as u32) << 16)
+    }
+
+    /// the raw 32 bits of the instruction
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn opcode(self) -> OpCode {
+        match OpCode::try_from(self.0 as u8) {
+            Ok(op) => op,
+            Err(op) => unreachable!("invalid opcode {op} in instruction"),
+        }
+    }
+
+    pub fn a(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn b(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    pub fn c(self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    /// redirect the result of the instruction to register `a`
+    pub fn set_a(&mut self, a: u8) {
+        self.0 = self.0 & !0xff00 | (a as u32) << 8;
+    }
+
+    pub fn d(self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+
+    /// D interpreted as a signed operand
+    pub fn sd(self) -> i16 {
+        self.d() as i16
+    }
+
+    /// change the offset of a jump
+    pub fn set_sd(&mut self, sd: i16) {
+        self.0 = self.0 & 0xffff | (sd as u16 as u32) << 16;
+    }
+
+    pub fn decode(self) -> ByteCode {
+        let (a, b, c, d) = (self.a(), self.b(), self.c(), self.d());
+        match self.opcode() {
+            OpCode::GetGlobal => ByteCode::GetGlobal(a, d),
+            OpCode::LoadConst => ByteCode::LoadConst(a, d),
+            OpCode::Call => ByteCode::Call(a, b, c),
+            OpCode::LoadBool => ByteCode::LoadBool(a, d != 0),
+            OpCode::LoadNil => ByteCode::LoadNil(a),
+            OpCode::LoadInteger => ByteCode::LoadInteger(a, self.sd()),
+            OpCode::Move => ByteCode::Move(a, d as u8),
+            OpCode::Add => ByteCode::Add(a, b, c),
+            OpCode::Sub => ByteCode::Sub(a, b, c),
+            OpCode::Mul => ByteCode::Mul(a, b, c),
+            OpCode::Mod => ByteCode::Mod(a, b, c),
+            OpCode::Pow => ByteCode::Pow(a, b, c),
+            OpCode::Div => ByteCode::Div(a, b, c),
+            OpCode::Idiv => ByteCode::Idiv(a, b, c),
+            OpCode::BitAnd => ByteCode::BitAnd(a, b, c),
+            OpCode::BitOr => ByteCode::BitOr(a, b, c),
+            OpCode::BitXor => ByteCode::BitXor(a, b, c),
+            OpCode::ShiftL => ByteCode::ShiftL(a, b, c),
+            OpCode::ShiftR => ByteCode::ShiftR(a, b, c),
+            OpCode::Neg => ByteCode::Neg(a, d as u8),
+            OpCode::BitNot => ByteCode::BitNot(a, d as u8),
+            OpCode::Equal => ByteCode::Equal(a, b, c),
+            OpCode::NotEq => ByteCode::NotEq(a, b, c),
+            OpCode::LesThan => ByteCode::LesThan(a, b, c),
+            OpCode::LesEq => ByteCode::LesEq(a, b, c),
+            OpCode::Not => ByteCode::Not(a, d as u8),
+            OpCode::Jump => ByteCode::Jump(self.sd()),
+            OpCode::TestAndJump => ByteCode::TestAndJump(a, self.sd()),
+            OpCode::TestOrJump => ByteCode::TestOrJump(a, self.sd()),
+            OpCode::Closure => ByteCode::Closure(a, d),
+            OpCode::Return => ByteCode::Return(a, d as u8),
+            OpCode::VarArgs => ByteCode::VarArgs(a, d as u8),
+            OpCode::SetGlobal => ByteCode::SetGlobal(a, d),
+            OpCode::TailCall => ByteCode::TailCall(a, d as u8),
+            OpCode::Tbc => ByteCode::Tbc(a),
+            OpCode::NewTable => ByteCode::NewTable(a),
+            OpCode::GetTable => ByteCode::GetTable(a, b, c),
+            OpCode::SetTable => ByteCode::SetTable(a, b, c),
+            OpCode::SetList => ByteCode::SetList(a, b, c),
+            OpCode::Concat => ByteCode::Concat(a, b, c),
+        }
+    }
+}
+
+impl From<&ByteCode> for Instruction {
+    fn from(code: &ByteCode) -> Self {
+        match *code {
+            ByteCode::GetGlobal(dst, src) => Instruction::ad(OpCode::GetGlobal, dst, src),
+            ByteCode::LoadConst(dst, src) => Instruction::ad(OpCode::LoadConst, dst, src),
+            ByteCode::Call(func, b, c) => Instruction::abc(OpCode::Call, func, b, c),
+            ByteCode::LoadBool(dst, v) => Instruction::ad(OpCode::LoadBool, dst, v.into()),
+            ByteCode::LoadNil(dst) => Instruction::ad(OpCode::LoadNil, dst, 0),
+            ByteCode::LoadInteger(dst, v) => Instruction::ad(OpCode::LoadInteger, dst, v as u16),
+            ByteCode::Move(dst, src) => Instruction::ad(OpCode::Move, dst, src.into()),
+            ByteCode::Add(dst, b, c) => Instruction::abc(OpCode::Add, dst, b, c),
+            ByteCode::Sub(dst, b, c) => Instruction::abc(OpCode::Sub, dst, b, c),
+            ByteCode::Mul(dst, b, c) => Instruction::abc(OpCode::Mul, dst, b, c),
+            ByteCode::Mod(dst, b, c) => Instruction::abc(OpCode::Mod, dst, b, c),
+            ByteCode::Pow(dst, b, c) => Instruction::abc(OpCode::Pow, dst, b, c),
+            ByteCode::Div(dst, b, c) => Instruction::abc(OpCode::Div, dst, b, c),
+            ByteCode::Idiv(dst, b, c) => Instruction::abc(OpCode::Idiv, dst, b, c),
+            ByteCode::BitAnd(dst, b, c) => Instruction::abc(OpCode::BitAnd, dst, b, c),
+            ByteCode::BitOr(dst, b, c) => Instruction::abc(OpCode::BitOr, dst, b, c),
+            ByteCode::BitXor(dst, b, c) => Instruction::abc(OpCode::BitXor, dst, b, c),
+            ByteCode::ShiftL(dst, b, c) => Instruction::abc(OpCode::ShiftL, dst, b, c),
+            ByteCode::ShiftR(dst, b, c) => Instruction::abc(OpCode::ShiftR, dst, b, c),
+            ByteCode::Neg(dst, src) => Instruction::ad(OpCode::Neg, dst, src.into()),
+            ByteCode::BitNot(dst, src) => Instruction::ad(OpCode::BitNot, dst, src.into()),
+            ByteCode::Equal(dst, b, c) => Instruction::abc(OpCode::Equal, dst, b, c),
+            ByteCode::NotEq(dst, b, c) => Instruction::abc(OpCode::NotEq, dst, b, c),
+            ByteCode::LesThan(dst, b, c) => Instruction::abc(OpCode::LesThan, dst, b, c),
+            ByteCode::LesEq(dst, b, c) => Instruction::abc(OpCode::LesEq, dst, b, c),
+            ByteCode::Not(dst, src) => Instruction::ad(OpCode::Not, dst, src.into()),
+            ByteCode::Jump(offset) => Instruction::ad(OpCode::Jump, 0, offset as u16),
+            ByteCode::TestAndJump(src, offset) => {
+                Instruction::ad(OpCode::TestAndJump, src, offset as u16)
+            }
+            ByteCode::TestOrJump(src, offset) => {
+                Instruction::ad(OpCode::TestOrJump, src, offset as u16)
+            }
+            ByteCode::Closure(dst, proto) => Instruction::ad(OpCode::Closure, dst, proto),
+            ByteCode::Return(first, n) => Instruction::ad(OpCode::Return, first, n.into()),
+            ByteCode::VarArgs(dst, n) => Instruction::ad(OpCode::VarArgs, dst, n.into()),
+            ByteCode::SetGlobal(src, name) => Instruction::ad(OpCode::SetGlobal, src, name),
+            ByteCode::TailCall(func, b) => Instruction::ad(OpCode::TailCall, func, b.into()),
+            ByteCode::Tbc(var) => Instruction::ad(OpCode::Tbc, var, 0),
+            ByteCode::NewTable(dst) => Instruction::ad(OpCode::NewTable, dst, 0),
+            ByteCode::GetTable(dst, t, k) => Instruction::abc(OpCode::GetTable, dst, t, k),
+            ByteCode::SetTable(t, k, src) => Instruction::abc(OpCode::SetTable, t, k, src),
+            ByteCode::SetList(t, n, batch) => Instruction::abc(OpCode::SetList, t, n, batch),
+            ByteCode::Concat(dst, b, c) => Instruction::abc(OpCode::Concat, dst, b, c),
+        }
+    }
+}
+
+impl From<ByteCode> for Instruction {
+    fn from(code: ByteCode) -> Self {
+        Instruction::from(&code)
+    }
+}
+
+impl TryFrom<u32> for Instruction {
+    type Error = u8;
+
+    /// check that the opcode of raw bits is valid, returns the invalid opcode otherwise
+    fn try_from(bits: u32) -> Result<Self, u8> {
+        OpCode::try_from(bits as u8)?;
+        Ok(Instruction(bits))
+    }
+}
+
+impl PartialEq<ByteCode> for Instruction {
+    fn eq(&self, other: &ByteCode) -> bool {
+        *self == Instruction::from(other)
+    }
+}
+
+impl fmt::Debug for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.decode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let codes = vec![
+            ByteCode::GetGlobal(1, 65535),
+            ByteCode::LoadConst(255, 3),
+            ByteCode::Call(0, 2, 1),
+            ByteCode::Call(3, 0, 0),
+            ByteCode::LoadBool(2, true),
+            ByteCode::LoadBool(2, false),
+            ByteCode::LoadNil(7),
+            ByteCode::LoadInteger(1, -32768),
+            ByteCode::LoadInteger(1, 32767),
+            ByteCode::Move(4, 2),
+            ByteCode::Add(0, 1, 2),
+            ByteCode::Idiv(255, 254, 253),
+            ByteCode::ShiftR(3, 3, 3),
+            ByteCode::Neg(1, 0),
+            ByteCode::BitNot(0, 255),
+            ByteCode::LesEq(1, 2, 3),
+            ByteCode::Not(0, 1),
+            ByteCode::Jump(-1),
+            ByteCode::TestAndJump(3, 32767),
+            ByteCode::TestOrJump(0, -32768),
+            ByteCode::Closure(1, 300),
+            ByteCode::Return(0, 1),
+            ByteCode::VarArgs(2, 0),
+            ByteCode::SetGlobal(3, 4),
+            ByteCode::TailCall(5, 0),
+            ByteCode::Tbc(8),
+            ByteCode::NewTable(6),
+            ByteCode::GetTable(1, 2, 3),
+            ByteCode::SetTable(4, 5, 6),
+            ByteCode::SetList(7, 0, 255),
+            ByteCode::Concat(9, 10, 11),
+        ];
+        for code in codes {
+            assert_eq!(Instruction::from(&code).decode(), code);
+        }
+    }
+
+    #[test]
+    fn operand_layout() {
+        let code = Instruction::abc(OpCode::Call, 1, 2, 3);
+        assert_eq!(code.bits(), 0x02_03_01_02);
+        assert_eq!((code.a(), code.b(), code.c()), (1, 2, 3));
+
+        let code = Instruction::from(ByteCode::LoadInteger(5, -2));
+        assert_eq!(code.bits(), 0xfffe_0505);
+        assert_eq!((code.a(), code.d(), code.sd()), (5, 0xfffe, -2));
+    }
+
+    #[test]
+    fn redirect_result() {
+        let mut code = Instruction::from(ByteCode::Sub(0, 1, 2));
+        code.set_a(7);
+        assert_eq!(code, ByteCode::Sub(7, 1, 2));
+    }
+
+    #[test]
+    fn patch_jump_offset() {
+        let mut code = Instruction::from(ByteCode::TestOrJump(4, 0));
+        code.set_sd(-3);
+        assert_eq!(code, ByteCode::TestOrJump(4, -3));
+    }
+
+    #[test]
+    fn reject_invalid_opcode() {
+        assert_eq!(Instruction::try_from(0x0000_01ff), Err(0xff));
+        assert_eq!(
+            Instruction::try_from(0x0001_0006).map(Instruction::decode),
+            Ok(ByteCode::Move(0, 1))
+        );
+    }
 }
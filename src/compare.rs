@@ -0,0 +1,163 @@
+//! Lua comparisons: `==` never fails and compares numbers by their mathematical value, the
+//! order operators `<` and `<=` only accept two numbers or two strings.
+//!
+//! Integers and floats are compared exactly. Converting the integer to a float would round
+//! large integers, so the float is split into its integral part, compared as an integer, and
+//! its fraction instead.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::value::Value;
+
+/// Failure of an order comparison between values that cannot be ordered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareError {
+    pub lhs: &'static str,
+    pub rhs: &'static str,
+}
+
+impl fmt::Display for CompareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.lhs == self.rhs {
+            write!(f, "attempt to compare two {} values", self.lhs)
+        } else {
+            write!(f, "attempt to compare {} with {}", self.lhs, self.rhs)
+        }
+    }
+}
+
+/// `a == b`, which is raw equality as there are no `__eq` metamethods
+pub fn equals(a: &Value, b: &Value) -> bool {
+    a == b
+}
+
+/// `a < b`
+pub fn less_than(a: &Value, b: &Value) -> Result<bool, CompareError> {
+    Ok(order(a, b)? == Some(Ordering::Less))
+}
+
+/// `a <= b`
+pub fn less_equal(a: &Value, b: &Value) -> Result<bool, CompareError> {
+    Ok(matches!(
+        order(a, b)?,
+        Some(Ordering::Less | Ordering::Equal)
+    ))
+}
+
+/// ordering of two numbers or two strings, `None` if a NaN is involved
+fn order(a: &Value, b: &Value) -> Result<Option<Ordering>, CompareError> {
+    let ordering = match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => Some(x.cmp(y)),
+        (Value::Float(x), Value::Float(y)) => x.partial_cmp(y),
+        (Value::Integer(i), Value::Float(f)) => int_float_cmp(*i, *f),
+        (Value::Float(f), Value::Integer(i)) => int_float_cmp(*i, *f).map(Ordering::reverse),
+        // strings compare bytewise
+        (Value::String(x), Value::String(y)) => Some(x.as_bytes().cmp(y.as_bytes())),
+        _ => {
+            return Err(CompareError {
+                lhs: a.type_name(),
+                rhs: b.type_name(),
+            })
+        }
+    };
+    Ok(ordering)
+}
+
+/// exact ordering of an integer and a float
+pub(crate) fn int_float_cmp(i: i64, f: f64) -> Option<Ordering> {
+    // 2^63, the first float above every integer
+    const TWO_POW_63: f64 = 9223372036854775808.0;
+    if f.is_nan() {
+        return None;
+    }
+    if f >= TWO_POW_63 {
+        return Some(Ordering::Less);
+    }
+    if f < -TWO_POW_63 {
+        return Some(Ordering::Greater);
+    }
+    // the integral part is in range and converts exactly
+    let trunc = f.trunc();
+    match i.cmp(&(trunc as i64)) {
+        Ordering::Equal => 0.0.partial_cmp(&(f - trunc)),
+        ordering => Some(ordering),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> Value {
+        Value::Integer(i)
+    }
+
+    fn float(f: f64) -> Value {
+        Value::Float(f)
+    }
+
+    fn str(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn integers_and_floats_compare_by_value() {
+        assert!(equals(&int(1), &float(1.0)));
+        assert!(equals(&float(-0.0), &int(0)));
+        assert!(!equals(&int(1), &float(1.5)));
+        assert!(!equals(&float(f64::NAN), &float(f64::NAN)));
+        assert!(!equals(&int(1), &str("1")));
+        assert_eq!(less_than(&int(1), &float(1.5)), Ok(true));
+        assert_eq!(less_than(&float(-1.5), &int(-1)), Ok(true));
+        assert_eq!(less_equal(&float(2.0), &int(2)), Ok(true));
+        assert_eq!(less_than(&int(2), &float(2.0)), Ok(false));
+    }
+
+    #[test]
+    fn large_integers_are_not_rounded() {
+        // 2^53 + 1 has no float representation, converting it would give 2^53
+        let i = (1 << 53) + 1;
+        let f = (1u64 << 53) as f64;
+        assert!(!equals(&int(i), &float(f)));
+        assert_eq!(less_than(&float(f), &int(i)), Ok(true));
+
+        assert_eq!(
+            less_than(&int(i64::MAX), &float(9223372036854775808.0)),
+            Ok(true)
+        );
+        assert_eq!(
+            less_equal(&float(-9223372036854775808.0), &int(i64::MIN)),
+            Ok(true)
+        );
+        assert_eq!(
+            less_than(&int(i64::MIN), &float(f64::NEG_INFINITY)),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn nan_is_unordered() {
+        let nan = float(f64::NAN);
+        assert_eq!(less_than(&nan, &int(1)), Ok(false));
+        assert_eq!(less_equal(&int(1), &nan), Ok(false));
+        assert_eq!(less_equal(&nan, &nan), Ok(false));
+    }
+
+    #[test]
+    fn strings_compare_bytewise() {
+        assert_eq!(less_than(&str("Z"), &str("a")), Ok(true));
+        assert_eq!(less_than(&str("ab"), &str("abc")), Ok(true));
+        assert_eq!(less_equal(&str("b"), &str("abc")), Ok(false));
+        assert!(equals(&str("x"), &str("x")));
+    }
+
+    #[test]
+    fn different_types_cannot_be_ordered() {
+        let err = less_than(&int(1), &str("2")).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to compare number with string");
+
+        let err = less_equal(&Value::Nil, &Value::Nil).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to compare two nil values");
+    }
+}
@@ -0,0 +1,564 @@
+//! Coroutines: Lua threads with a stack and call frames of their own, and the `coroutine`
+//! library.
+//!
+//! A coroutine runs on the same [`ExeState`] as the code resuming it, its stack and frames are
+//! swapped in for the time it runs. A yield is returned up the Rust stack like an error, so
+//! every Rust function between the yield and the resume must be able to continue later without
+//! its Rust stack frame, through the [`Continuation`](crate::vm::Continuation) stored in its
+//! call frame. `pcall` and `xpcall` have one, other calls into Lua from Rust cannot be yielded
+//! across.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::gc::Age;
+use crate::value::{NativeClosure, NativeFunction, Value};
+use crate::vm::{CallInfo, ExeState, MAX_NATIVE_NESTING};
+
+/// Functions of the `coroutine` library, by their names in it.
+pub(crate) const LIBRARY: [(&str, NativeFunction); 8] = [
+    ("close", lib_close),
+    ("create", lib_create),
+    ("isyieldable", lib_isyieldable),
+    ("resume", lib_resume),
+    ("running", lib_running),
+    ("status", lib_status),
+    ("wrap", lib_wrap),
+    ("yield", lib_yield),
+];
+
+/// A coroutine, or the main thread running the chunk.
+pub struct Thread {
+    /// registers of the active calls while the thread does not run, the function to run at
+    /// the bottom before it starts
+    pub(crate) stack: Vec<Value>,
+    /// active calls while the thread does not run
+    pub(crate) frames: Vec<CallInfo>,
+    /// stack indices of the to-be-closed variables while the thread does not run, kept after
+    /// it died of an error until it is closed
+    pub(crate) tbc: Vec<usize>,
+    status: Status,
+    /// error value the coroutine died of, until it is closed
+    pub(crate) error: Option<Value>,
+    /// see [`crate::gc`]
+    pub(crate) mark: Cell<u32>,
+    pub(crate) age: Cell<Age>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    /// not started yet, or stopped in a yield
+    Suspended,
+    Running,
+    /// resumed another coroutine and waits for it
+    Normal,
+    /// returned, failed or was closed
+    Dead,
+}
+
+impl Thread {
+    pub(crate) fn main() -> Self {
+        Thread {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            tbc: Vec::new(),
+            status: Status::Running,
+            error: None,
+            mark: Cell::new(0),
+            age: Cell::new(Age::New),
+        }
+    }
+
+    /// a coroutine that will call `body` when resumed first
+    fn new(body: Value) -> Self {
+        Thread {
+            stack: vec![body],
+            frames: Vec::new(),
+            tbc: Vec::new(),
+            status: Status::Suspended,
+            error: None,
+            mark: Cell::new(0),
+            age: Cell::new(Age::New),
+        }
+    }
+}
+
+impl ExeState<'_> {
+    /// Resume `co` with the values from stack index `args` on, which are passed to its body
+    /// when it starts, or returned by the yield it stopped in. They are replaced by the values
+    /// it yields or returns, or an error ending it is returned.
+    fn resume(&mut self, co: &Rc<RefCell<Thread>>, args: usize) -> Result<(), Value> {
+        match co.borrow().status {
+            Status::Suspended => {}
+            Status::Dead => return Err(Value::String("cannot resume dead coroutine".into())),
+            _ => {
+                let message = "cannot resume non-suspended coroutine";
+                return Err(Value::String(message.into()));
+            }
+        }
+        if self.native_nesting >= MAX_NATIVE_NESTING {
+            return Err(Value::String("C stack overflow".into()));
+        }
+        let values = self.stack.split_off(args);
+        let nargs = values.len();
+
+        // the stack of the resumer is kept in it while it waits
+        let resumer = std::mem::replace(&mut self.thread, co.clone());
+        self.swap_thread(&resumer);
+        resumer.borrow_mut().status = Status::Normal;
+        self.swap_thread(co);
+        co.borrow_mut().status = Status::Running;
+        let non_yieldable = std::mem::replace(&mut self.non_yieldable, 0);
+        self.native_nesting += 1;
+
+        self.stack.extend(values);
+        let result = self.run_coroutine(nargs);
+        let yielded = self.yielded.take();
+
+        self.native_nesting -= 1;
+        self.non_yieldable = non_yieldable;
+        self.swap_thread(co);
+        self.swap_thread(&resumer);
+        self.thread = resumer;
+        self.thread.borrow_mut().status = Status::Running;
+
+        let mut co = co.borrow_mut();
+        match (result, yielded) {
+            (Ok(()), _) => {
+                // the results of the body are all that is left
+                co.status = Status::Dead;
+                self.stack.append(&mut co.stack);
+                Ok(())
+            }
+            (Err(_), Some(n)) => {
+                co.status = Status::Suspended;
+                let first = co.stack.len() - n;
+                self.stack.extend(co.stack.drain(first..));
+                Ok(())
+            }
+            (Err(value), None) => {
+                co.status = Status::Dead;
+                // the variables still to be closed are closed with the coroutine
+                if co.tbc.is_empty() {
+                    co.stack.clear();
+                }
+                co.frames.clear();
+                co.error = Some(value.clone());
+                Err(value)
+            }
+        }
+    }
+
+    /// Exchange the stack and frames of the state with the ones kept in `co`: a thread that
+    /// stops running keeps them, one that starts running takes them back.
+    fn swap_thread(&mut self, co: &Rc<RefCell<Thread>>) {
+        // covers the error value a coroutine dies with too, stored after it stops running
+        self.heap.barrier(co);
+        let mut co = co.borrow_mut();
+        std::mem::swap(&mut self.stack, &mut co.stack);
+        std::mem::swap(&mut self.frames, &mut co.frames);
+        std::mem::swap(&mut self.tbc, &mut co.tbc);
+    }
+
+    /// Kill the suspended or dead coroutine `co`, calling the `__close` metamethods of its
+    /// to-be-closed variables with the error it died of, or nil. They run on the stack of the
+    /// running thread. Returns the error, replaced by the last one a metamethod raised.
+    fn close_thread(&mut self, co: &Rc<RefCell<Thread>>) -> Result<(), Value> {
+        let (pending, mut error) = {
+            let mut co = co.borrow_mut();
+            co.status = Status::Dead;
+            let tbc = std::mem::take(&mut co.tbc);
+            let pending: Vec<Value> = tbc.into_iter().map(|i| co.stack[i].clone()).collect();
+            co.stack.clear();
+            co.frames.clear();
+            (pending, co.error.take())
+        };
+        let level = self.stack.len();
+        for value in pending {
+            self.tbc.push(self.stack.len());
+            self.stack.push(value);
+        }
+        let depth = self.frames.len();
+        while let Err(value) = self.close_tbc(level, error.clone().unwrap_or(Value::Nil)) {
+            error = Some(value);
+            self.frames.truncate(depth);
+        }
+        self.stack.truncate(level);
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Run the swapped in coroutine, with `nargs` values passed to it at the top of the stack,
+    /// until its body returns, it yields or an error is not caught in it.
+    fn run_coroutine(&mut self, nargs: usize) -> Result<(), Value> {
+        let mut result = if self.frames.is_empty() {
+            self.precall(0, nargs, None).map(|_| ())
+        } else {
+            self.finish_yield(nargs)
+        };
+        loop {
+            result = match result {
+                Ok(()) if self.frames.is_empty() => return Ok(()),
+                Ok(()) => self.unroll(),
+                Err(_) if self.yielded.is_some() => return result,
+                // the Rust functions of all protected calls left in the frames are gone from
+                // the Rust stack, or they would have caught the error
+                Err(value) => match self.frames.iter().rposition(|ci| ci.protected.is_some()) {
+                    Some(index) => self.recover(index, value),
+                    None => return Err(value),
+                },
+            };
+        }
+    }
+
+    /// Finish the Rust function a coroutine yielded in, with the `nargs` values passed to the
+    /// resume at the top of the stack.
+    fn finish_yield(&mut self, nargs: usize) -> Result<(), Value> {
+        let n = match self.frames.last_mut().unwrap().k.take() {
+            Some(k) => k(self, Ok(()))? as usize,
+            None => nargs,
+        };
+        self.finish_call(self.stack.len() - n, n);
+        Ok(())
+    }
+
+    /// a new coroutine calling `body`, managed by the collector
+    fn new_thread(&mut self, body: Value) -> Value {
+        let co = Rc::new(RefCell::new(Thread::new(body)));
+        self.heap.add_thread(&co);
+        Value::Thread(co)
+    }
+
+    /// the coroutine passed as argument `arg` to the library function `name`
+    fn check_thread(&self, arg: usize, name: &str) -> Result<Rc<RefCell<Thread>>, Value> {
+        match self.args().get(arg - 1) {
+            Some(Value::Thread(co)) => Ok(co.clone()),
+            v => {
+                let got = v.map_or("no value", Value::type_name);
+                let message =
+                    format!("bad argument #{arg} to '{name}' (coroutine expected, got {got})");
+                Err(self.native_error(message))
+            }
+        }
+    }
+
+    /// the function passed as first argument to the library function `name`
+    fn check_function(&self, name: &str) -> Result<Value, Value> {
+        match self.args().first() {
+            Some(v @ (Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_))) => {
+                Ok(v.clone())
+            }
+            v => {
+                let got = v.map_or("no value", Value::type_name);
+                let message = format!("bad argument #1 to '{name}' (function expected, got {got})");
+                Err(self.native_error(message))
+            }
+        }
+    }
+}
+
+// "coroutine.create(f)": a new coroutine running f
+fn lib_create(state: &mut ExeState) -> Result<i32, Value> {
+    let body = state.check_function("create")?;
+    let co = state.new_thread(body);
+    state.stack.push(co);
+    state.check_gc();
+    Ok(1)
+}
+
+// "coroutine.resume(co, ...)": run co until it yields or returns, giving true and the values,
+// or false and the error value if it fails
+fn lib_resume(state: &mut ExeState) -> Result<i32, Value> {
+    let co = state.check_thread(1, "resume")?;
+    let base = state.frames.last().unwrap().base;
+    match state.resume(&co, base + 1) {
+        Ok(()) => state.stack[base] = Value::Boolean(true),
+        Err(value) => {
+            state.stack.truncate(base);
+            state.stack.extend([Value::Boolean(false), value]);
+        }
+    }
+    Ok((state.stack.len() - base) as i32)
+}
+
+// "coroutine.yield(...)": suspend the running coroutine, the values are the results of the
+// resume and the resume's arguments become the results of the yield
+fn lib_yield(state: &mut ExeState) -> Result<i32, Value> {
+    let n = state.args().len();
+    state.yield_values(n, None)
+}
+
+// "coroutine.status(co)": "running", "suspended", "normal" or "dead"
+fn lib_status(state: &mut ExeState) -> Result<i32, Value> {
+    let co = state.check_thread(1, "status")?;
+    let status = if Rc::ptr_eq(&co, &state.thread) {
+        "running"
+    } else {
+        match co.borrow().status {
+            Status::Suspended => "suspended",
+            Status::Running => "running",
+            Status::Normal => "normal",
+            Status::Dead => "dead",
+        }
+    };
+    let status = state.intern(status);
+    state.stack.push(Value::String(status));
+    Ok(1)
+}
+
+// "coroutine.running()": the running coroutine, and whether it is the main thread
+fn lib_running(state: &mut ExeState) -> Result<i32, Value> {
+    let is_main = Rc::ptr_eq(&state.thread, &state.main_thread);
+    state.stack.push(Value::Thread(state.thread.clone()));
+    state.stack.push(Value::Boolean(is_main));
+    Ok(2)
+}
+
+// "coroutine.isyieldable([co])": whether co, the running coroutine by default, can yield
+fn lib_isyieldable(state: &mut ExeState) -> Result<i32, Value> {
+    let yieldable = if state.args().is_empty() {
+        state.is_yieldable()
+    } else {
+        let co = state.check_thread(1, "isyieldable")?;
+        if Rc::ptr_eq(&co, &state.thread) {
+            state.is_yieldable()
+        } else {
+            !Rc::ptr_eq(&co, &state.main_thread)
+        }
+    };
+    state.stack.push(Value::Boolean(yieldable));
+    Ok(1)
+}
+
+// "coroutine.close(co)": kill a suspended or dead coroutine and close its pending to-be-closed
+// variables, giving true, or false and the error value it died of or a `__close` raised
+fn lib_close(state: &mut ExeState) -> Result<i32, Value> {
+    let co = state.check_thread(1, "close")?;
+    let status = if Rc::ptr_eq(&co, &state.thread) {
+        Status::Running
+    } else {
+        co.borrow().status
+    };
+    let result = match status {
+        Status::Suspended | Status::Dead => state.close_thread(&co),
+        Status::Running => return Err(state.native_error("cannot close a running coroutine")),
+        Status::Normal => return Err(state.native_error("cannot close a normal coroutine")),
+    };
+    match result {
+        Ok(()) => {
+            state.stack.push(Value::Boolean(true));
+            Ok(1)
+        }
+        Err(value) => {
+            state.stack.extend([Value::Boolean(false), value]);
+            Ok(2)
+        }
+    }
+}
+
+// "coroutine.wrap(f)": a function resuming a new coroutine running f, which returns the values
+// the coroutine yields or returns and raises the errors it fails with
+fn lib_wrap(state: &mut ExeState) -> Result<i32, Value> {
+    let body = state.check_function("wrap")?;
+    let co = state.new_thread(body);
+    let closure = Rc::new(NativeClosure::new(wrapped_resume, vec![co]));
+    state.heap.add_native_closure(&closure);
+    state.stack.push(Value::NativeClosure(closure));
+    state.check_gc();
+    Ok(1)
+}
+
+// the function returned by coroutine.wrap, with the coroutine as its upvalue
+fn wrapped_resume(state: &mut ExeState) -> Result<i32, Value> {
+    let base = state.frames.last().unwrap().base;
+    let Value::NativeClosure(closure) = &state.stack[base - 1] else {
+        unreachable!("wrapped coroutines are called through their closure")
+    };
+    let Value::Thread(co) = closure.upvalues.borrow()[0].clone() else {
+        unreachable!("the upvalue of a wrapped coroutine is the coroutine")
+    };
+    let result = state.resume(&co, base).or_else(|value| {
+        // a coroutine that died is closed before its error is passed on
+        if co.borrow().status == Status::Dead {
+            state.close_thread(&co)?;
+        }
+        Err(value)
+    });
+    match result {
+        Ok(()) => Ok((state.stack.len() - base) as i32),
+        // like reference Lua, a message gets the position of the call to the wrapper
+        Err(Value::String(message)) => Err(Value::String(
+            format!("{}{message}", state.position(1)).into(),
+        )),
+        Err(value) => Err(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parser::load;
+
+    /// output of running `code`, which must not fail
+    fn run(code: &str, setup: impl FnOnce(&mut ExeState)) -> String {
+        let proto = load(&mut Cursor::new(code), "=test").unwrap();
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        setup(&mut vm);
+        vm.execute(&proto).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn resume_and_yield_pass_values() {
+        let output = run(
+            "local co = coroutine.create(function(a, b)\n\
+               local c = coroutine.yield(a + b)\n\
+               print(\"got\", c)\n\
+               return coroutine.yield(c * 2)\n\
+             end)\n\
+             print(coroutine.status(co))\n\
+             print(coroutine.resume(co, 1, 2))\n\
+             print(coroutine.resume(co, 10))\n\
+             print(coroutine.resume(co, 3, 4))\n\
+             print(coroutine.status(co))\n\
+             print(coroutine.resume(co))",
+            |_| {},
+        );
+        assert_eq!(
+            output,
+            "suspended\n\
+             true\t3\n\
+             got\t10\n\
+             true\t20\n\
+             true\t3\t4\n\
+             dead\n\
+             false\tcannot resume dead coroutine\n"
+        );
+    }
+
+    #[test]
+    fn yield_across_pcall() {
+        let output = run(
+            "local co = coroutine.wrap(function(x)\n\
+               print(pcall(function(y) error(coroutine.yield(y + 1)) end, x))\n\
+               print(xpcall(function(y) return coroutine.yield(y) + 1 end, print, 5))\n\
+               print(xpcall(error, function(m) return coroutine.yield(m) end, \"e\"))\n\
+               return \"done\"\n\
+             end)\n\
+             print(co(1))\n\
+             print(co(\"boom\"))\n\
+             print(co(41))",
+            |_| {},
+        );
+        assert_eq!(
+            output,
+            "2\n\
+             false\ttest:2: boom\n\
+             5\n\
+             true\t42\n\
+             false\terror in error handling\n\
+             done\n"
+        );
+    }
+
+    #[test]
+    fn status_running_and_isyieldable() {
+        let output = run(
+            "function inner(outer)\n\
+               print(coroutine.status(outer), coroutine.isyieldable())\n\
+               print(coroutine.resume(outer))\n\
+               print(pcall(coroutine.close, outer))\n\
+             end\n\
+             local co = coroutine.create(function()\n\
+               local me, is_main = coroutine.running()\n\
+               print(coroutine.status(me), is_main)\n\
+               coroutine.resume(coroutine.create(inner), me)\n\
+             end)\n\
+             print(coroutine.resume(co))\n\
+             print(coroutine.running())\n\
+             print(coroutine.isyieldable(), coroutine.isyieldable(co))\n\
+             print(pcall(coroutine.yield, 1))",
+            |_| {},
+        );
+        // the address of the main thread changes from run to run
+        let (before, after) = output.split_once("thread: 0x").unwrap();
+        let after = after.trim_start_matches(|c: char| c.is_ascii_hexdigit());
+        assert_eq!(
+            format!("{before}thread: 0x...{after}"),
+            "running\tfalse\n\
+             normal\ttrue\n\
+             false\tcannot resume non-suspended coroutine\n\
+             false\tcannot close a normal coroutine\n\
+             true\n\
+             thread: 0x...\ttrue\n\
+             false\ttrue\n\
+             false\tattempt to yield from outside a coroutine\n"
+        );
+    }
+
+    #[test]
+    fn errors_end_coroutines() {
+        let output = run(
+            "local co = coroutine.create(function() local x = nil; x() end)\n\
+             print(coroutine.resume(co))\n\
+             print(coroutine.status(co))\n\
+             print(coroutine.close(co))\n\
+             print(coroutine.close(co))\n\
+             print(pcall(coroutine.wrap(error), \"oops\"))\n\
+             print(pcall(coroutine.wrap(error), 42))\n\
+             print(coroutine.close(coroutine.create(print)))\n\
+             print(pcall(coroutine.resume, 1))\n\
+             print(pcall(coroutine.create))",
+            |_| {},
+        );
+        assert_eq!(
+            output,
+            "false\ttest:1: attempt to call a nil value (local 'x')\n\
+             dead\n\
+             false\ttest:1: attempt to call a nil value (local 'x')\n\
+             true\n\
+             false\toops\n\
+             false\t42\n\
+             true\n\
+             false\tbad argument #1 to 'resume' (coroutine expected, got number)\n\
+             false\tbad argument #1 to 'create' (function expected, got no value)\n"
+        );
+    }
+
+    #[test]
+    fn resumes_nest_up_to_the_native_limit() {
+        let output = run(
+            "function deep() return coroutine.resume(coroutine.create(deep)) end\nprint(deep())",
+            |_| {},
+        );
+        // every resume but the one failing returns true before the results of the inner one
+        let expected = "true\t".repeat(MAX_NATIVE_NESTING - 1) + "false\tC stack overflow\n";
+        assert_eq!(output, expected);
+    }
+
+    // yields its arguments, then returns what it was resumed with and how many there were
+    fn yield_and_count(state: &mut ExeState) -> Result<i32, Value> {
+        let n = state.args().len();
+        state.yield_values(n, Some(count_resumed))
+    }
+
+    fn count_resumed(state: &mut ExeState, status: Result<(), Value>) -> Result<i32, Value> {
+        assert!(status.is_ok());
+        let n = state.args().len();
+        state.push(Value::Integer(n as i64));
+        Ok(n as i32 + 1)
+    }
+
+    #[test]
+    fn natives_yield_with_a_continuation() {
+        let output = run(
+            "local co = coroutine.wrap(function(a) print(\"resumed\", yield_and_count(a, a)) end)\n\
+             print(co(1))\n\
+             co(\"x\", \"y\")",
+            |vm| vm.register("yield_and_count", yield_and_count),
+        );
+        assert_eq!(output, "1\t1\nresumed\tx\ty\t2\n");
+    }
+}
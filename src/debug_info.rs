@@ -0,0 +1,272 @@
+//! Debug information of prototypes: where instructions come from and what their registers
+//! are called.
+//!
+//! Lines are stored the same way reference Lua 5.4 stores them, as a signed byte per
+//! instruction holding the difference to the line of the previous instruction. Differences
+//! that do not fit into a byte, and every `MAX_WITHOUT_ABS`th instruction, are stored as
+//! absolute lines instead, which keeps lookups cheap.
+
+use crate::bytecode::ByteCode;
+use crate::parser::ParseProto;
+use crate::value::Value;
+use std::rc::Rc;
+
+/// marks an instruction whose line is stored in `LineInfo::absolute`
+pub const ABS_LINE_INFO: i8 = i8::MIN;
+/// maximum number of instructions between two absolute lines
+const MAX_WITHOUT_ABS: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LineInfo {
+    /// line difference to the previous instruction, or `ABS_LINE_INFO`
+    pub deltas: Vec<i8>,
+    /// `(pc, line)` for every instruction whose delta is `ABS_LINE_INFO`, sorted by pc
+    pub absolute: Vec<(u32, u32)>,
+}
+
+impl LineInfo {
+    /// Encode the line of every instruction, `base` is the line the function is defined on.
+    pub fn encode(base: u32, lines: &[u32]) -> Self {
+        let mut info = LineInfo::default();
+        let mut previous = base as i64;
+        let mut without_abs = 0;
+        for (pc, &line) in lines.iter().enumerate() {
+            let delta = line as i64 - previous;
+            if delta.abs() >= -(ABS_LINE_INFO as i64) || without_abs >= MAX_WITHOUT_ABS {
+                info.absolute.push((pc as u32, line));
+                info.deltas.push(ABS_LINE_INFO);
+                without_abs = 1;
+            } else {
+                info.deltas.push(delta as i8);
+                without_abs += 1;
+            }
+            previous = line as i64;
+        }
+        info
+    }
+
+    /// line of the instruction at `pc`
+    pub fn line(&self, base: u32, pc: usize) -> Option<u32> {
+        if pc >= self.deltas.len() {
+            return None;
+        }
+        // start from the closest absolute line before pc, or from the beginning
+        let i = self
+            .absolute
+            .partition_point(|&(abs_pc, _)| abs_pc as usize <= pc);
+        let (mut line, start) = match i {
+            0 => (base as i64, 0),
+            i => {
+                let (abs_pc, abs_line) = self.absolute[i - 1];
+                (abs_line as i64, abs_pc as usize + 1)
+            }
+        };
+        for &delta in &self.deltas[start..=pc] {
+            line += delta as i64;
+        }
+        Some(line as u32)
+    }
+
+    /// the line of every instruction
+    pub fn decode(&self, base: u32) -> Vec<u32> {
+        (0..self.deltas.len())
+            .map(|pc| self.line(base, pc).unwrap())
+            .collect()
+    }
+}
+
+/// A local variable and the range of instructions where it is alive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVar {
+    pub name: String,
+    /// first instruction where the variable is alive
+    pub start_pc: u32,
+    /// first instruction where the variable is dead
+    pub end_pc: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    /// name of the chunk the prototype was compiled from, see [`crate::chunkid`]
+    pub source: String,
+    /// first and last line of the function definition, both 0 for main chunks
+    pub line_defined: u32,
+    pub last_line_defined: u32,
+    pub line_info: LineInfo,
+    /// local variables in the order they are declared
+    pub locals: Vec<LocalVar>,
+    pub upvalue_names: Vec<String>,
+}
+
+impl DebugInfo {
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        self.line_info.line(self.line_defined, pc)
+    }
+
+    /// name of the local variable held in register `reg` when the instruction at `pc` runs
+    pub fn local_name(&self, reg: u8, pc: usize) -> Option<&str> {
+        // locals alive at pc occupy the registers in declaration order
+        self.locals
+            .iter()
+            .filter(|var| var.start_pc as usize <= pc && pc < var.end_pc as usize)
+            .nth(reg as usize)
+            .map(|var| var.name.as_str())
+    }
+}
+
+impl ParseProto {
+    /// source line of the instruction at `pc`, if debug information is present
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        self.debug.as_ref()?.line(pc)
+    }
+
+    /// What the value in register `reg` is called when the instruction at `pc` runs, as the
+    /// kind of name ("local", "global", "field" or "constant") and the name. Like `getobjname` in
+    /// reference Lua, it follows the code to the instruction that loaded the register.
+    pub fn object_name(&self, pc: usize, reg: u8) -> Option<(&'static str, String)> {
+        if let Some(name) = self.debug.as_ref().and_then(|d| d.local_name(reg, pc)) {
+            return Some(("local", name.to_string()));
+        }
+        let set_pc = self.find_set_reg(pc, reg)?;
+        match self.byte_codes[set_pc].decode() {
+            // a copy of a lower register is named after it
+            ByteCode::Move(_, src) if src < reg => self.object_name(set_pc, src),
+            ByteCode::GetGlobal(_, k) => match &self.constants[k as usize] {
+                Value::String(name) => Some(("global", name.to_string())),
+                _ => None,
+            },
+            ByteCode::LoadConst(_, k) => match &self.constants[k as usize] {
+                Value::String(s) => Some(("constant", s.to_string())),
+                _ => None,
+            },
+            // only a constant key gives the field a name
+            ByteCode::GetTable(_, _, key) => match self.object_name(set_pc, key) {
+                Some(("constant", name)) => Some(("field", name)),
+                _ => Some(("field", "?".to_string())),
+            },
+            _ => None,
+        }
+    }
+
+    /// The last instruction before `last_pc` to write register `reg`, if it always runs
+    /// before `last_pc`, unlike one a jump can skip.
+    fn find_set_reg(&self, last_pc: usize, reg: u8) -> Option<usize> {
+        let mut set_pc = None;
+        // instructions before it may be jumped over
+        let mut jump_target = 0;
+        for (pc, code) in self.byte_codes[..last_pc].iter().enumerate() {
+            let change = match code.decode() {
+                ByteCode::Jump(offset)
+                | ByteCode::TestAndJump(_, offset)
+                | ByteCode::TestOrJump(_, offset) => {
+                    let dest = (pc as isize + 1 + offset as isize) as usize;
+                    if dest <= last_pc && dest > jump_target {
+                        jump_target = dest;
+                    }
+                    false
+                }
+                // calls leave their results from the function register on
+                ByteCode::Call(func, ..) | ByteCode::TailCall(func, _) => reg >= func,
+                ByteCode::VarArgs(dst, 0) => reg >= dst,
+                ByteCode::VarArgs(dst, n) => reg >= dst && reg - dst < n - 1,
+                ByteCode::Return(..)
+                | ByteCode::SetGlobal(..)
+                | ByteCode::SetTable(..)
+                | ByteCode::SetList(..)
+                | ByteCode::Tbc(..) => false,
+                // every other instruction stores into register A
+                _ => code.a() == reg,
+            };
+            if change {
+                set_pc = (pc >= jump_target).then_some(pc);
+            }
+        }
+        set_pc
+    }
+
+    /// Drop all debug information, like `luac -s` does.
+    pub fn strip(&mut self) {
+        self.debug = None;
+        for proto in self.protos.iter_mut() {
+            Rc::make_mut(proto).strip();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_differences_are_stored_as_deltas() {
+        let lines = [1, 1, 2, 5, 3];
+        let info = LineInfo::encode(0, &lines);
+
+        assert_eq!(info.deltas, vec![1, 0, 1, 3, -2]);
+        assert!(info.absolute.is_empty());
+        assert_eq!(info.decode(0), lines);
+        assert_eq!(info.line(0, 5), None);
+    }
+
+    #[test]
+    fn large_differences_are_stored_as_absolute_lines() {
+        let lines = [1, 1000, 1001, 2];
+        let info = LineInfo::encode(0, &lines);
+
+        assert_eq!(info.deltas, vec![1, ABS_LINE_INFO, 1, ABS_LINE_INFO]);
+        assert_eq!(info.absolute, vec![(1, 1000), (3, 2)]);
+        assert_eq!(info.decode(0), lines);
+    }
+
+    #[test]
+    fn long_functions_have_regular_absolute_lines() {
+        let lines: Vec<u32> = (0..1000).map(|pc| 10 + pc / 3).collect();
+        let info = LineInfo::encode(10, &lines);
+
+        assert_eq!(info.absolute.len(), 1000 / (MAX_WITHOUT_ABS + 1));
+        assert_eq!(info.decode(10), lines);
+    }
+
+    #[test]
+    fn local_names_by_register() {
+        let var = |name: &str, start_pc, end_pc| LocalVar {
+            name: name.to_string(),
+            start_pc,
+            end_pc,
+        };
+        let debug = DebugInfo {
+            locals: vec![var("a", 1, 10), var("b", 3, 10)],
+            ..Default::default()
+        };
+
+        assert_eq!(debug.local_name(0, 0), None);
+        assert_eq!(debug.local_name(0, 1), Some("a"));
+        assert_eq!(debug.local_name(1, 2), None);
+        assert_eq!(debug.local_name(1, 3), Some("b"));
+        assert_eq!(debug.local_name(0, 10), None);
+    }
+
+    #[test]
+    fn object_names_follow_the_loading_instruction() {
+        let code = "local a = 1\nlocal b = print\nprint(a, b)\nlocal c = a and x";
+        let proto = crate::parser::load(&mut std::io::Cursor::new(code), "=test").unwrap();
+        // 0 LoadInteger, 1 GetGlobal, 2 GetGlobal, 3 Move, 4 Move, 5 Call, 6 Move, 7 TestAndJump,
+        // 8 GetGlobal
+        let name = |pc, reg| proto.object_name(pc, reg);
+
+        assert_eq!(name(5, 0), Some(("local", "a".to_string())));
+        assert_eq!(name(5, 2), Some(("global", "print".to_string())));
+        assert_eq!(name(5, 3), Some(("local", "a".to_string())));
+        assert_eq!(name(5, 4), Some(("local", "b".to_string())));
+        // the global may have been skipped by the jump
+        assert_eq!(name(9, 2), None);
+
+        let mut stripped = proto.clone();
+        stripped.strip();
+        assert_eq!(
+            stripped.object_name(5, 2),
+            Some(("global", "print".to_string()))
+        );
+        assert_eq!(stripped.object_name(5, 3), None);
+    }
+}
@@ -0,0 +1,278 @@
+//! Human readable listings of compiled prototypes, modelled after `luac -l -l`.
+
+use std::fmt::{self, Write};
+
+use crate::bytecode::ByteCode;
+use crate::parser::{chunkid, ParseProto};
+use crate::value::Value;
+
+impl ParseProto {
+    /// List every instruction with its index, source line, opcode and operands. Constants
+    /// referenced by an instruction are resolved in a trailing comment. Debug information is
+    /// listed at the end unless the prototype was stripped. Nested functions follow, each
+    /// listed the same way.
+    pub fn disassemble(&self) -> String {
+        let mut listing = String::new();
+        self.write_listing(&mut listing, true)
+            .expect("writing to a String can not fail");
+        listing
+    }
+
+    fn write_listing(&self, out: &mut String, is_main: bool) -> fmt::Result {
+        let (source, line_defined, last_line_defined) = match &self.debug {
+            Some(debug) => (
+                chunkid(&debug.source),
+                debug.line_defined,
+                debug.last_line_defined,
+            ),
+            None => ("?".to_string(), 0, 0),
+        };
+        let (nupvalues, nlocals) = self
+            .debug
+            .as_ref()
+            .map_or((0, 0), |d| (d.upvalue_names.len(), d.locals.len()));
+        let kind = if is_main { "main" } else { "function" };
+        writeln!(
+            out,
+            "{kind} <{source}:{line_defined},{last_line_defined}> ({} instructions)",
+            self.byte_codes.len()
+        )?;
+        writeln!(
+            out,
+            "{}{} params, {} slots, {nupvalues} upvalues, {nlocals} locals, {} constants, {} functions",
+            self.num_params,
+            if self.is_vararg { "+" } else { "" },
+            self.max_stack_size,
+            self.constants.len(),
+            self.protos.len()
+        )?;
+        for (pc, code) in self.byte_codes.iter().enumerate() {
+            let code = code.decode();
+            let opname = opname(&code);
+            let (operands, comment) = match code {
+                ByteCode::GetGlobal(dst, k) => (format!("{dst} {k}"), self.constant(k)),
+                ByteCode::LoadConst(dst, k) => (format!("{dst} {k}"), self.constant(k)),
+                ByteCode::Call(func, nargs, nresults) => (
+                    format!("{func} {nargs} {nresults}"),
+                    Some(format!("{} in {} out", count(nargs), count(nresults))),
+                ),
+                ByteCode::TailCall(func, nargs) => (
+                    format!("{func} {nargs}"),
+                    Some(format!("{} in", count(nargs))),
+                ),
+                ByteCode::Return(first, n) => {
+                    (format!("{first} {n}"), Some(format!("{} out", count(n))))
+                }
+                ByteCode::VarArgs(dst, n) => {
+                    (format!("{dst} {n}"), Some(format!("{} out", count(n))))
+                }
+                ByteCode::Closure(dst, index) => (format!("{dst} {index}"), None),
+                ByteCode::SetGlobal(src, k) => (format!("{src} {k}"), self.constant(k)),
+                ByteCode::NewTable(dst) | ByteCode::Tbc(dst) => (format!("{dst}"), None),
+                ByteCode::GetTable(dst, t, k) => (format!("{dst} {t} {k}"), None),
+                ByteCode::SetTable(t, k, src) => (format!("{t} {k} {src}"), None),
+                ByteCode::SetList(t, n, batch) => {
+                    (format!("{t} {n} {batch}"), Some(format!("{} in", count(n))))
+                }
+                ByteCode::LoadBool(dst, v) => (format!("{dst} {}", v as u8), None),
+                ByteCode::LoadNil(dst) => (format!("{dst}"), None),
+                ByteCode::LoadInteger(dst, i) => (format!("{dst} {i}"), None),
+                ByteCode::Move(dst, src)
+                | ByteCode::Neg(dst, src)
+                | ByteCode::BitNot(dst, src)
+                | ByteCode::Not(dst, src) => (format!("{dst} {src}"), None),
+                ByteCode::Add(dst, b, c)
+                | ByteCode::Sub(dst, b, c)
+                | ByteCode::Mul(dst, b, c)
+                | ByteCode::Mod(dst, b, c)
+                | ByteCode::Pow(dst, b, c)
+                | ByteCode::Div(dst, b, c)
+                | ByteCode::Idiv(dst, b, c)
+                | ByteCode::BitAnd(dst, b, c)
+                | ByteCode::BitOr(dst, b, c)
+                | ByteCode::BitXor(dst, b, c)
+                | ByteCode::ShiftL(dst, b, c)
+                | ByteCode::ShiftR(dst, b, c)
+                | ByteCode::Equal(dst, b, c)
+                | ByteCode::NotEq(dst, b, c)
+                | ByteCode::LesThan(dst, b, c)
+                | ByteCode::LesEq(dst, b, c)
+                | ByteCode::Concat(dst, b, c) => (format!("{dst} {b} {c}"), None),
+                // targets are listed starting from 1, like the instructions
+                ByteCode::Jump(offset) => (format!("{offset}"), Some(jump_target(pc, offset))),
+                ByteCode::TestAndJump(src, offset) | ByteCode::TestOrJump(src, offset) => {
+                    (format!("{src} {offset}"), Some(jump_target(pc, offset)))
+                }
+            };
+            let line = self
+                .line(pc)
+                .map_or("-".to_string(), |line| line.to_string());
+            write!(out, "\t{}\t[{line}]\t{opname:<9}\t{operands}", pc + 1)?;
+            match comment {
+                Some(comment) => writeln!(out, "\t; {comment}")?,
+                None => writeln!(out)?,
+            }
+        }
+
+        writeln!(out, "constants ({}):", self.constants.len())?;
+        for (i, constant) in self.constants.iter().enumerate() {
+            let kind = match constant {
+                Value::Nil => "N",
+                Value::Boolean(_) => "B",
+                Value::Integer(_) => "I",
+                Value::Float(_) => "F",
+                Value::String(_) => "S",
+                Value::Function(_)
+                | Value::LuaFunction(_)
+                | Value::NativeClosure(_)
+                | Value::Thread(_)
+                | Value::Table(_) => "C",
+            };
+            writeln!(out, "\t{i}\t{kind}\t{}", format_constant(constant))?;
+        }
+
+        if let Some(debug) = &self.debug {
+            // pcs are listed starting from 1, like the instructions
+            writeln!(out, "locals ({}):", debug.locals.len())?;
+            for (i, var) in debug.locals.iter().enumerate() {
+                writeln!(
+                    out,
+                    "\t{i}\t{}\t{}\t{}",
+                    var.name,
+                    var.start_pc + 1,
+                    var.end_pc + 1
+                )?;
+            }
+            writeln!(out, "upvalues ({}):", debug.upvalue_names.len())?;
+            for (i, name) in debug.upvalue_names.iter().enumerate() {
+                writeln!(out, "\t{i}\t{name}")?;
+            }
+        }
+
+        for proto in self.protos.iter() {
+            writeln!(out)?;
+            proto.write_listing(out, false)?;
+        }
+        Ok(())
+    }
+
+    fn constant(&self, index: u16) -> Option<String> {
+        let constant = self.constants.get(index as usize);
+        Some(constant.map_or_else(|| "?".to_string(), format_constant))
+    }
+}
+
+fn opname(code: &ByteCode) -> String {
+    let name = format!("{code:?}");
+    let end = name.find('(').unwrap_or(name.len());
+    name[..end].to_uppercase()
+}
+
+/// a count of values stored plus one, 0 standing for all values up to the top
+fn count(n: u8) -> String {
+    match n {
+        0 => "all".to_string(),
+        n => (n - 1).to_string(),
+    }
+}
+
+fn jump_target(pc: usize, offset: i16) -> String {
+    format!("to {}", pc as isize + 2 + offset as isize)
+}
+
+/// format a constant the way it would be written in source, strings are quoted and escaped
+fn format_constant(v: &Value) -> String {
+    match v {
+        Value::String(s) => format!("{s:?}"),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::load;
+    use std::io::Cursor;
+
+    #[test]
+    fn list_instructions_with_resolved_constants() {
+        let code = "local a = 33000\nprint(a)\nprint \"hi\"\nprint(1.0)\nprint(true)";
+        let proto = load(&mut Cursor::new(code), "=test").unwrap();
+
+        assert_eq!(
+            proto.disassemble(),
+            "main <test:0,0> (13 instructions)\n\
+             0+ params, 3 slots, 1 upvalues, 1 locals, 4 constants, 0 functions\n\
+             \t1\t[1]\tLOADCONST\t0 0\t; 33000\n\
+             \t2\t[2]\tGETGLOBAL\t1 1\t; \"print\"\n\
+             \t3\t[2]\tMOVE     \t2 0\n\
+             \t4\t[2]\tCALL     \t1 2 1\t; 1 in 0 out\n\
+             \t5\t[3]\tGETGLOBAL\t1 1\t; \"print\"\n\
+             \t6\t[3]\tLOADCONST\t2 2\t; \"hi\"\n\
+             \t7\t[3]\tCALL     \t1 2 1\t; 1 in 0 out\n\
+             \t8\t[4]\tGETGLOBAL\t1 1\t; \"print\"\n\
+             \t9\t[4]\tLOADCONST\t2 3\t; 1.0\n\
+             \t10\t[4]\tCALL     \t1 2 1\t; 1 in 0 out\n\
+             \t11\t[5]\tGETGLOBAL\t1 1\t; \"print\"\n\
+             \t12\t[5]\tLOADBOOL \t2 1\n\
+             \t13\t[5]\tCALL     \t1 2 1\t; 1 in 0 out\n\
+             constants (4):\n\
+             \t0\tI\t33000\n\
+             \t1\tS\t\"print\"\n\
+             \t2\tS\t\"hi\"\n\
+             \t3\tF\t1.0\n\
+             locals (1):\n\
+             \t0\ta\t2\t14\n\
+             upvalues (1):\n\
+             \t0\t_ENV\n"
+        );
+    }
+
+    #[test]
+    fn list_jump_targets() {
+        let code = "local a = true\nlocal b = a and 1 or 2";
+        let proto = load(&mut Cursor::new(code), "=test").unwrap();
+        let listing = proto.disassemble();
+
+        assert!(
+            listing.contains("\tTESTANDJUMP\t1 1\t; to 5\n"),
+            "{listing}"
+        );
+        assert!(listing.contains("\tTESTORJUMP\t1 1\t; to 7\n"), "{listing}");
+    }
+
+    #[test]
+    fn list_nested_functions_after_main() {
+        let code = "local function f(a, ...)\n  return a, ...\nend";
+        let proto = load(&mut Cursor::new(code), "=test").unwrap();
+        let listing = proto.disassemble();
+
+        assert!(listing.contains("\t1\t[3]\tCLOSURE  \t0 0\n"), "{listing}");
+        assert!(
+            listing.contains(
+                "\n\nfunction <test:1,3> (3 instructions)\n\
+                 1+ params, 3 slots, 1 upvalues, 1 locals, 0 constants, 0 functions\n\
+                 \t1\t[2]\tMOVE     \t1 0\n\
+                 \t2\t[2]\tVARARGS  \t2 0\t; all out\n\
+                 \t3\t[2]\tRETURN   \t1 0\t; all out\n"
+            ),
+            "{listing}"
+        );
+    }
+
+    #[test]
+    fn stripped_prototypes_have_no_lines() {
+        let mut proto = load(&mut Cursor::new("print(1)"), "=test").unwrap();
+        proto.strip();
+
+        assert_eq!(
+            proto.disassemble(),
+            "main <?:0,0> (3 instructions)\n\
+             0+ params, 2 slots, 0 upvalues, 0 locals, 1 constants, 0 functions\n\
+             \t1\t[-]\tGETGLOBAL\t0 0\t; \"print\"\n\
+             \t2\t[-]\tLOADINTEGER\t1 1\n\
+             \t3\t[-]\tCALL     \t0 2 1\t; 1 in 0 out\n\
+             constants (1):\n\
+             \t0\tS\t\"print\"\n"
+        );
+    }
+}
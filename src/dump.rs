@@ -0,0 +1,609 @@
+//! Binary chunk format for precompiled [`ParseProto`]s.
+//!
+//! A chunk starts with a header that is checked before anything else is read:
+//!
+//! ```text
+//! magic        4 bytes  "\x1bLuR"
+//! version      1 byte
+//! sizes        3 bytes  size of Instruction, Integer and Float
+//! check int    8 bytes  CHECK_INTEGER, detects endianness and integer format
+//! check float  8 bytes  CHECK_FLOAT, detects float format
+//! ```
+//!
+//! followed by the prototype: stack size, parameters, constants, instructions, nested
+//! prototypes and, unless the prototype was stripped, its debug information. All numbers
+//! are stored in native byte order, like reference Lua does, so chunks are only portable
+//! between machines with the same layout. The loader never trusts its input: every length,
+//! tag and opcode is validated and a truncated or corrupted chunk is rejected with an error
+//! instead of a panic.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::bytecode::Instruction;
+use crate::debug_info::{DebugInfo, LineInfo, LocalVar};
+use crate::parser::ParseProto;
+use crate::value::Value;
+use crate::verify::VerifyError;
+
+/// First bytes of every binary chunk. Starting with ESC lets loaders tell chunks from source.
+pub const SIGNATURE: &[u8; 4] = b"\x1bLuR";
+pub const VERSION: u8 = 4;
+const CHECK_INTEGER: i64 = 0x5678;
+const CHECK_FLOAT: f64 = 370.5;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INTEGER: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+const TAG_STRING: u8 = 5;
+
+/// Functions nested deeper than this are rejected, so a corrupted chunk can not exhaust the
+/// stack of the loader. It is the same limit reference Lua has for nested calls in C.
+pub(crate) const MAX_NESTING: usize = 200;
+
+/// Reasons for rejecting a binary chunk.
+#[derive(Debug)]
+pub enum UndumpError {
+    Io(io::Error),
+    NotABinaryChunk,
+    VersionMismatch(u8),
+    FormatMismatch(&'static str),
+    Truncated,
+    Corrupted(String),
+    /// valid chunk using features this interpreter does not implement
+    Unsupported(String),
+    /// well-formed chunk with instructions that would fail at runtime
+    Invalid(VerifyError),
+}
+
+impl fmt::Display for UndumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UndumpError::Io(err) => write!(f, "cannot read chunk: {err}"),
+            UndumpError::NotABinaryChunk => write!(f, "bad binary format (not a binary chunk)"),
+            UndumpError::VersionMismatch(v) => {
+                write!(f, "version mismatch (chunk has {v}, expected {VERSION})")
+            }
+            UndumpError::FormatMismatch(what) => write!(f, "bad binary format ({what} mismatch)"),
+            UndumpError::Truncated => write!(f, "bad binary format (truncated chunk)"),
+            UndumpError::Corrupted(why) => write!(f, "bad binary format ({why})"),
+            UndumpError::Unsupported(what) => write!(f, "unsupported chunk ({what})"),
+            UndumpError::Invalid(err) => write!(f, "bad binary format ({err})"),
+        }
+    }
+}
+
+impl std::error::Error for UndumpError {}
+
+impl From<io::Error> for UndumpError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            UndumpError::Truncated
+        } else {
+            UndumpError::Io(err)
+        }
+    }
+}
+
+/// Serialize `proto` into the binary chunk format.
+pub fn dump(proto: &ParseProto, output: &mut impl Write) -> io::Result<()> {
+    output.write_all(SIGNATURE)?;
+    output.write_all(&[VERSION])?;
+    output.write_all(&[
+        std::mem::size_of::<Instruction>() as u8,
+        std::mem::size_of::<i64>() as u8,
+        std::mem::size_of::<f64>() as u8,
+    ])?;
+    output.write_all(&CHECK_INTEGER.to_ne_bytes())?;
+    output.write_all(&CHECK_FLOAT.to_ne_bytes())?;
+    dump_proto(proto, output)
+}
+
+fn dump_proto(proto: &ParseProto, output: &mut impl Write) -> io::Result<()> {
+    output.write_all(&[
+        proto.max_stack_size,
+        proto.num_params,
+        proto.is_vararg.into(),
+    ])?;
+    dump_len(proto.constants.len(), output)?;
+    for constant in proto.constants.iter() {
+        match constant {
+            Value::Nil => output.write_all(&[TAG_NIL])?,
+            Value::Boolean(false) => output.write_all(&[TAG_FALSE])?,
+            Value::Boolean(true) => output.write_all(&[TAG_TRUE])?,
+            Value::Integer(i) => {
+                output.write_all(&[TAG_INTEGER])?;
+                output.write_all(&i.to_ne_bytes())?;
+            }
+            Value::Float(f) => {
+                output.write_all(&[TAG_FLOAT])?;
+                output.write_all(&f.to_ne_bytes())?;
+            }
+            Value::String(s) => {
+                output.write_all(&[TAG_STRING])?;
+                dump_string(s, output)?;
+            }
+            v @ (Value::Function(_)
+            | Value::LuaFunction(_)
+            | Value::NativeClosure(_)
+            | Value::Thread(_)
+            | Value::Table(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot dump a {} constant", v.type_name()),
+                ))
+            }
+        }
+    }
+
+    dump_len(proto.byte_codes.len(), output)?;
+    for code in proto.byte_codes.iter() {
+        output.write_all(&code.bits().to_ne_bytes())?;
+    }
+
+    dump_len(proto.protos.len(), output)?;
+    for proto in proto.protos.iter() {
+        dump_proto(proto, output)?;
+    }
+
+    match &proto.debug {
+        None => output.write_all(&[0]),
+        Some(debug) => {
+            output.write_all(&[1])?;
+            dump_debug(debug, output)
+        }
+    }
+}
+
+fn dump_debug(debug: &DebugInfo, output: &mut impl Write) -> io::Result<()> {
+    dump_string(&debug.source, output)?;
+    output.write_all(&debug.line_defined.to_ne_bytes())?;
+    output.write_all(&debug.last_line_defined.to_ne_bytes())?;
+
+    let line_info = &debug.line_info;
+    dump_len(line_info.deltas.len(), output)?;
+    for &delta in line_info.deltas.iter() {
+        output.write_all(&delta.to_ne_bytes())?;
+    }
+    dump_len(line_info.absolute.len(), output)?;
+    for &(pc, line) in line_info.absolute.iter() {
+        output.write_all(&pc.to_ne_bytes())?;
+        output.write_all(&line.to_ne_bytes())?;
+    }
+
+    dump_len(debug.locals.len(), output)?;
+    for var in debug.locals.iter() {
+        dump_string(&var.name, output)?;
+        output.write_all(&var.start_pc.to_ne_bytes())?;
+        output.write_all(&var.end_pc.to_ne_bytes())?;
+    }
+
+    dump_len(debug.upvalue_names.len(), output)?;
+    for name in debug.upvalue_names.iter() {
+        dump_string(name, output)?;
+    }
+    Ok(())
+}
+
+fn dump_string(s: &str, output: &mut impl Write) -> io::Result<()> {
+    dump_len(s.len(), output)?;
+    output.write_all(s.as_bytes())
+}
+
+fn dump_len(len: usize, output: &mut impl Write) -> io::Result<()> {
+    let len = u32::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many items to dump"))?;
+    output.write_all(&len.to_ne_bytes())
+}
+
+/// Load a binary chunk written by [`dump`], validating it on the way. The loaded prototype is
+/// checked with [`ParseProto::verify`] before it is returned.
+pub fn undump(input: &mut impl Read) -> Result<ParseProto, UndumpError> {
+    let mut undumper = Undumper { input };
+    undumper.header()?;
+    let proto = undumper.proto(0)?;
+    // anything after the prototype means the chunk is not what it claims to be
+    if undumper.input.read(&mut [0])? != 0 {
+        return Err(UndumpError::Corrupted("trailing data".to_string()));
+    }
+    proto.verify().map_err(UndumpError::Invalid)?;
+    Ok(proto)
+}
+
+/// Validating reader for binary chunks, shared with the loader for reference Lua chunks.
+pub(crate) struct Undumper<'a, R: Read> {
+    pub(crate) input: &'a mut R,
+}
+
+impl<'a, R: Read> Undumper<'a, R> {
+    fn header(&mut self) -> Result<(), UndumpError> {
+        let mut signature = [0; 4];
+        self.input.read_exact(&mut signature)?;
+        if &signature != SIGNATURE {
+            return Err(UndumpError::NotABinaryChunk);
+        }
+        let version = self.byte()?;
+        if version != VERSION {
+            return Err(UndumpError::VersionMismatch(version));
+        }
+        let checks = [
+            (std::mem::size_of::<Instruction>(), "Instruction size"),
+            (std::mem::size_of::<i64>(), "Integer size"),
+            (std::mem::size_of::<f64>(), "Float size"),
+        ];
+        for (size, what) in checks {
+            if self.byte()? as usize != size {
+                return Err(UndumpError::FormatMismatch(what));
+            }
+        }
+        if self.integer()? != CHECK_INTEGER {
+            return Err(UndumpError::FormatMismatch("integer format"));
+        }
+        if self.float()? != CHECK_FLOAT {
+            return Err(UndumpError::FormatMismatch("float format"));
+        }
+        Ok(())
+    }
+
+    /// a prototype nested in `depth` others
+    fn proto(&mut self, depth: usize) -> Result<ParseProto, UndumpError> {
+        if depth > MAX_NESTING {
+            return Err(UndumpError::Corrupted(
+                "functions nested too deep".to_string(),
+            ));
+        }
+        let max_stack_size = self.byte()?;
+        let num_params = self.byte()?;
+        let is_vararg = match self.byte()? {
+            0 => false,
+            1 => true,
+            flag => {
+                return Err(UndumpError::Corrupted(format!(
+                    "invalid vararg flag {flag}"
+                )))
+            }
+        };
+        let n = self.len()?;
+        let mut constants = Vec::new();
+        for _ in 0..n {
+            let v = match self.byte()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Boolean(false),
+                TAG_TRUE => Value::Boolean(true),
+                TAG_INTEGER => Value::Integer(self.integer()?),
+                TAG_FLOAT => Value::Float(self.float()?),
+                TAG_STRING => Value::String(self.string()?.into()),
+                tag => {
+                    return Err(UndumpError::Corrupted(format!(
+                        "invalid constant tag {tag}"
+                    )))
+                }
+            };
+            constants.push(v);
+        }
+
+        let n = self.len()?;
+        let mut byte_codes = Vec::new();
+        for _ in 0..n {
+            let bits = self.u32()?;
+            let code = Instruction::try_from(bits)
+                .map_err(|op| UndumpError::Corrupted(format!("invalid opcode {op}")))?;
+            byte_codes.push(code);
+        }
+
+        let n = self.len()?;
+        let mut protos = Vec::new();
+        for _ in 0..n {
+            protos.push(Rc::new(self.proto(depth + 1)?));
+        }
+
+        let debug = match self.byte()? {
+            0 => None,
+            1 => Some(self.debug()?),
+            flag => {
+                return Err(UndumpError::Corrupted(format!(
+                    "invalid debug information flag {flag}"
+                )))
+            }
+        };
+
+        Ok(ParseProto {
+            constants,
+            byte_codes,
+            max_stack_size,
+            num_params,
+            is_vararg,
+            protos,
+            debug,
+        })
+    }
+
+    fn debug(&mut self) -> Result<DebugInfo, UndumpError> {
+        let source = self.string()?;
+        let line_defined = self.u32()?;
+        let last_line_defined = self.u32()?;
+
+        let n = self.len()?;
+        let mut deltas = Vec::new();
+        for _ in 0..n {
+            deltas.push(self.byte()? as i8);
+        }
+        let n = self.len()?;
+        let mut absolute = Vec::new();
+        for _ in 0..n {
+            absolute.push((self.u32()?, self.u32()?));
+        }
+
+        let n = self.len()?;
+        let mut locals = Vec::new();
+        for _ in 0..n {
+            locals.push(LocalVar {
+                name: self.string()?,
+                start_pc: self.u32()?,
+                end_pc: self.u32()?,
+            });
+        }
+
+        let n = self.len()?;
+        let mut upvalue_names = Vec::new();
+        for _ in 0..n {
+            upvalue_names.push(self.string()?);
+        }
+
+        Ok(DebugInfo {
+            source,
+            line_defined,
+            last_line_defined,
+            line_info: LineInfo { deltas, absolute },
+            locals,
+            upvalue_names,
+        })
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, UndumpError> {
+        let mut buf = [0; 1];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, UndumpError> {
+        let mut buf = [0; 4];
+        self.input.read_exact(&mut buf)?;
+        Ok(u32::from_ne_bytes(buf))
+    }
+
+    fn len(&mut self) -> Result<usize, UndumpError> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub(crate) fn integer(&mut self) -> Result<i64, UndumpError> {
+        let mut buf = [0; 8];
+        self.input.read_exact(&mut buf)?;
+        Ok(i64::from_ne_bytes(buf))
+    }
+
+    pub(crate) fn float(&mut self) -> Result<f64, UndumpError> {
+        let mut buf = [0; 8];
+        self.input.read_exact(&mut buf)?;
+        Ok(f64::from_ne_bytes(buf))
+    }
+
+    fn string(&mut self) -> Result<String, UndumpError> {
+        let len = self.len()?;
+        self.utf8(len)
+    }
+
+    /// read a string of `len` bytes, which must be valid UTF-8
+    pub(crate) fn utf8(&mut self, len: usize) -> Result<String, UndumpError> {
+        // read through `take` so that a corrupted length can not make us allocate a huge buffer
+        let mut buf = Vec::new();
+        (&mut *self.input).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(UndumpError::Truncated);
+        }
+        String::from_utf8(buf)
+            .map_err(|_| UndumpError::Corrupted("string is not valid UTF-8".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ByteCode;
+    use std::io::Cursor;
+
+    fn proto() -> ParseProto {
+        ParseProto {
+            constants: vec![
+                Value::Nil,
+                Value::Boolean(false),
+                Value::Boolean(true),
+                Value::Integer(-33000),
+                Value::Float(1.5),
+                Value::String("print".into()),
+            ],
+            byte_codes: vec![
+                ByteCode::GetGlobal(0, 5).into(),
+                ByteCode::LoadConst(1, 3).into(),
+                ByteCode::Call(0, 2, 1).into(),
+            ],
+            max_stack_size: 2,
+            ..Default::default()
+        }
+    }
+
+    fn chunk() -> Vec<u8> {
+        let mut chunk = Vec::new();
+        dump(&proto(), &mut chunk).unwrap();
+        chunk
+    }
+
+    fn load(chunk: &[u8]) -> Result<ParseProto, UndumpError> {
+        undump(&mut Cursor::new(chunk))
+    }
+
+    #[test]
+    fn round_trip_every_constant_kind() {
+        assert_eq!(load(&chunk()).unwrap(), proto());
+    }
+
+    #[test]
+    fn round_trip_debug_information() {
+        let mut proto = proto();
+        proto.debug = Some(DebugInfo {
+            source: "@test.lua".to_string(),
+            line_defined: 0,
+            last_line_defined: 0,
+            line_info: LineInfo::encode(0, &[1, 300, 2]),
+            locals: vec![LocalVar {
+                name: "a".to_string(),
+                start_pc: 1,
+                end_pc: 3,
+            }],
+            upvalue_names: vec!["_ENV".to_string()],
+        });
+        let mut chunk = Vec::new();
+        dump(&proto, &mut chunk).unwrap();
+
+        assert_eq!(load(&chunk).unwrap(), proto);
+        for len in 0..chunk.len() {
+            assert!(load(&chunk[..len]).is_err(), "truncated at {len}");
+        }
+    }
+
+    #[test]
+    fn round_trip_nested_functions() {
+        let code = "function f(a, ...) return function() return 1 end end";
+        let proto = crate::parser::load(&mut Cursor::new(code), "=test").unwrap();
+        let mut chunk = Vec::new();
+        dump(&proto, &mut chunk).unwrap();
+
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded, proto);
+        let f = &loaded.protos[0];
+        assert_eq!((f.num_params, f.is_vararg), (1, true));
+        assert_eq!(f.protos.len(), 1);
+    }
+
+    #[test]
+    fn reject_functions_nested_too_deep() {
+        let mut proto = ParseProto::default();
+        for _ in 0..=MAX_NESTING {
+            proto = ParseProto {
+                protos: vec![Rc::new(proto)],
+                ..Default::default()
+            };
+        }
+        let mut chunk = Vec::new();
+        dump(&proto, &mut chunk).unwrap();
+
+        assert_eq!(
+            load(&chunk).unwrap_err().to_string(),
+            "bad binary format (functions nested too deep)"
+        );
+    }
+
+    #[test]
+    fn reject_bad_header() {
+        let mut chunk = chunk();
+        chunk[0] = b'L';
+        assert!(matches!(load(&chunk), Err(UndumpError::NotABinaryChunk)));
+
+        let mut chunk = self::chunk();
+        chunk[4] = VERSION + 1;
+        assert!(matches!(load(&chunk), Err(UndumpError::VersionMismatch(_))));
+
+        let mut chunk = self::chunk();
+        chunk[6] = 4;
+        assert!(matches!(
+            load(&chunk),
+            Err(UndumpError::FormatMismatch("Integer size"))
+        ));
+
+        // a chunk written with the other byte order
+        let mut chunk = self::chunk();
+        chunk[8..16].reverse();
+        assert!(matches!(
+            load(&chunk),
+            Err(UndumpError::FormatMismatch("integer format"))
+        ));
+    }
+
+    #[test]
+    fn reject_truncated_chunk() {
+        let chunk = chunk();
+        for len in 0..chunk.len() {
+            assert!(load(&chunk[..len]).is_err(), "truncated at {len}");
+        }
+    }
+
+    #[test]
+    fn reject_corrupted_chunk() {
+        let header = 24;
+
+        let mut chunk = chunk();
+        chunk[header + 7] = 42;
+        assert_eq!(
+            load(&chunk).unwrap_err().to_string(),
+            "bad binary format (invalid constant tag 42)"
+        );
+
+        let mut chunk = self::chunk();
+        // the last instruction is followed by the number of nested prototypes and the debug
+        // information flag
+        let last = chunk.len() - 9;
+        chunk[last..last + 4].copy_from_slice(&0xffu32.to_ne_bytes());
+        assert_eq!(
+            load(&chunk).unwrap_err().to_string(),
+            "bad binary format (invalid opcode 255)"
+        );
+
+        let mut chunk = self::chunk();
+        chunk.push(0);
+        assert_eq!(
+            load(&chunk).unwrap_err().to_string(),
+            "bad binary format (trailing data)"
+        );
+
+        // huge string length must not be trusted
+        let mut chunk = Vec::new();
+        dump(
+            &ParseProto {
+                constants: vec![Value::String("x".into())],
+                ..Default::default()
+            },
+            &mut chunk,
+        )
+        .unwrap();
+        chunk[header + 8..header + 12].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(matches!(load(&chunk), Err(UndumpError::Truncated)));
+    }
+
+    #[test]
+    fn reject_chunk_failing_verification() {
+        let mut chunk = Vec::new();
+        let mut proto = proto();
+        proto.byte_codes.push(ByteCode::LoadConst(0, 6).into());
+        dump(&proto, &mut chunk).unwrap();
+
+        assert_eq!(
+            load(&chunk).unwrap_err().to_string(),
+            "bad binary format (invalid instruction 4: constant 6 out of range, there are 6 constants)"
+        );
+    }
+
+    #[test]
+    fn native_functions_can_not_be_dumped() {
+        fn native(_: &mut crate::vm::ExeState) -> Result<i32, Value> {
+            Ok(0)
+        }
+        let proto = ParseProto {
+            constants: vec![Value::Function(native)],
+            ..Default::default()
+        };
+        assert!(dump(&proto, &mut Vec::new()).is_err());
+    }
+}
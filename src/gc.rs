@@ -0,0 +1,1536 @@
+//! Tracing garbage collector for the objects reference counting cannot free: values that can
+//! refer to themselves, like a coroutine holding itself in a local, a wrapped coroutine whose
+//! stack holds its wrapper, or a table stored in itself.
+//!
+//! Objects are still shared through `Rc`, the heap keeps a weak reference to each of them. A
+//! cycle of the collector marks every object reachable from the roots, the stack, frames and
+//! globals of the state, the running and the main thread, then clears the objects left
+//! unmarked. That drops the references they hold, so reference counting frees them.
+//!
+//! Those are all the roots there are. There is no registry, and no open upvalues: the only
+//! upvalue of Lua functions is `_ENV`, which is the globals, and the upvalues of native
+//! closures are traced through the closures. A thread that resumed another keeps its stack in
+//! the thread it resumed while that runs. Values only held by locals of a Rust function are not
+//! roots. The collector may run a step at every allocation: creating a thread or closure,
+//! interning a string, loading a chunk or growing the stack. The step only runs once the new
+//! object is stored where the collector finds it.
+//!
+//! The collector is incremental like the one of reference Lua: objects are marked through a
+//! gray list a step at a time, as the program allocates. Stacks of threads and entries of
+//! tables change without telling the collector, so every thread and table marked is traversed
+//! again in the atomic step that ends marking, as are the roots. Other objects do not change
+//! once created, so the incremental collector needs no write barriers.
+//!
+//! In generational mode, switched to with `collectgarbage("generational")`, most collections
+//! are minor ones that only mark and sweep the young objects, those created since the previous
+//! two. Objects surviving two collections become old and are only traversed by major
+//! collections, which run when the heap grew enough since the last one. This relies on old
+//! objects not referring to young ones. Threads and tables are the only objects written to
+//! after they are created: the write barriers in [`Heap::barrier`] and [`Heap::table_barrier`]
+//! mark an old thread or table touched when it is changed, and the next two minor collections
+//! traverse it. Old objects referred to by young ones are simply not collected, like in
+//! reference Lua 5.4.
+//!
+//! Weak tables, ephemerons and finalizers follow the atomic step of Lua 5.4. A table whose
+//! metatable has a `__mode` does not mark what it refers to weakly, it is listed instead and
+//! its entries with unmarked keys or values are removed once marking is done. The value of a
+//! weak key is only marked once its key is: such tables are traversed again until no more
+//! values get marked. Values are removed from weak tables before finalizable tables are
+//! looked at, keys after those have been marked again. A table that had a `__gc` field in its
+//! metatable when it was set is held by the heap; once it is found unreachable it is marked,
+//! with everything it refers to, and its finalizer is called at the end of the collection,
+//! most recently set first. A finalizer storing its table somewhere resurrects it, but is not
+//! called again.
+//!
+//! The heap size the collector paces itself by, and `collectgarbage("count")` reports, is the
+//! memory allocated for these objects, the `Rc` allocation of each and the buffers it owns,
+//! plus the strings, the Lua functions and their prototypes, and the stack of the running
+//! thread. Strings cannot refer to other values, so reference counting frees them. Interned
+//! ones are dropped from the string table at the end of full cycles once nothing else refers to
+//! them. Long strings, Lua closures and prototypes cannot be part of a cycle either: they are
+//! only listed with their sizes, which are subtracted when a sweep finds them freed.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::rc::{Rc, Weak};
+
+use crate::bytecode::Instruction;
+use crate::coroutine::Thread;
+use crate::debug_info::LocalVar;
+use crate::parser::ParseProto;
+use crate::table::Table;
+use crate::value::{LuaClosure, NativeClosure, TableKey, Value};
+use crate::vm::{CallInfo, ExeState};
+
+/// Percentage the heap grows by after a cycle before the next one starts, unless changed with
+/// [`ExeState::set_gc_pause`].
+pub const DEFAULT_GC_PAUSE: usize = 200;
+
+/// Work done by a step for every byte allocated since the last one, as a percentage, unless
+/// changed with [`ExeState::set_gc_step_multiplier`].
+pub const DEFAULT_GC_STEP_MULTIPLIER: usize = 100;
+
+/// Bytes allocated between two steps of a cycle, as a power of 2.
+const DEFAULT_STEP_SIZE_LOG2: u32 = 13;
+
+/// Percentages of the generational mode: the heap grows by the minor multiplier before a minor
+/// collection, and by the major one since the last major collection before the next.
+const DEFAULT_MINOR_MULTIPLIER: usize = 20;
+const DEFAULT_MAJOR_MULTIPLIER: usize = 100;
+
+/// Work counted for sweeping an object, freeing it or not. It is cheaper than traversing one.
+const SWEEP_COST: usize = size_of::<HeapEntry>();
+
+/// Heap size below which no cycle is started.
+const MIN_THRESHOLD: usize = 16 * 1024;
+
+/// The objects the collector manages and the state of its cycle.
+pub(crate) struct Heap {
+    objects: Vec<HeapEntry>,
+    /// objects reference counting frees on its own, with their sizes, counted until a sweep
+    /// finds them freed
+    counted: Vec<(Counted, usize)>,
+    /// marked objects whose references are not marked yet
+    gray: Vec<Object>,
+    /// threads and tables traversed, to be traversed again in the atomic step
+    gray_again: Vec<Object>,
+    /// tables traversed whose values are weak, whose keys are, and whose keys and values are
+    weak_values: Vec<Rc<Table>>,
+    ephemerons: Vec<Rc<Table>>,
+    all_weak: Vec<Rc<Table>>,
+    /// tables whose metatable had a `__gc` field when it was set, in that order. The heap
+    /// keeps them alive, the collector finds out when nothing else does.
+    finalizable: Vec<Rc<Table>>,
+    /// tables found unreachable whose finalizers are still to be called, in that order
+    to_finalize: VecDeque<Rc<Table>>,
+    /// whether finalizers are being called, collections do not run meanwhile
+    finalizing: bool,
+    /// mark of the objects reached in the current cycle, objects with another one are white
+    epoch: u32,
+    phase: Phase,
+    /// estimated size of the objects in the heap
+    total_bytes: usize,
+    /// heap size at which the next step runs
+    threshold: usize,
+    pause: usize,
+    step_multiplier: usize,
+    /// bytes allocated between two steps
+    step_size: usize,
+    minor_multiplier: usize,
+    major_multiplier: usize,
+    mode: Mode,
+    /// whether steps are taken as the program allocates, `collectgarbage("stop")` clears it
+    running: bool,
+    /// collect fully at every allocation
+    stress: bool,
+    /// In generational mode, objects from this index of the object list on are young, the
+    /// ones from `first_old1` up to it became old in the last minor collection.
+    first_young: usize,
+    first_old1: usize,
+    /// old threads and tables written to since one of the last two minor collections
+    touched: Vec<Object>,
+    /// whether a minor collection is marking, which skips old objects
+    minor: bool,
+    /// heap size after the last major collection
+    major_base: usize,
+}
+
+/// Generation of an object, see the ages of reference Lua 5.4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Age {
+    /// created since the last collection
+    New,
+    /// survived one collection
+    Survival,
+    /// survived two collections, traversed by the next minor one as it may refer to survivors
+    Old1,
+    /// survived more collections, only traversed by major ones
+    Old,
+    /// old, written to since the last minor collection
+    Touched1,
+    /// old, written to before the last minor collection, traversed by the next one once more
+    Touched2,
+}
+
+impl Age {
+    fn is_old(self) -> bool {
+        !matches!(self, Age::New | Age::Survival)
+    }
+}
+
+/// How the collector works, switched with `collectgarbage`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Incremental,
+    Generational,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Incremental => "incremental",
+            Mode::Generational => "generational",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// between cycles
+    Pause,
+    /// marking objects from the gray list
+    Propagate,
+    /// clearing unmarked objects, from this index of the object list on
+    Sweep(usize),
+}
+
+struct HeapEntry {
+    object: WeakObject,
+    /// size counted when the object was last created, traversed or swept
+    size: usize,
+}
+
+/// An object that cannot be part of a cycle: a Lua closure only refers to its prototype, and
+/// a prototype to its constants and nested prototypes.
+enum Counted {
+    Proto(Weak<ParseProto>),
+    LuaClosure(Weak<LuaClosure>),
+}
+
+impl Counted {
+    fn is_alive(&self) -> bool {
+        match self {
+            Counted::Proto(p) => p.strong_count() > 0,
+            Counted::LuaClosure(c) => c.strong_count() > 0,
+        }
+    }
+}
+
+/// A strong reference to a collectable object.
+#[derive(Clone)]
+enum Object {
+    Thread(Rc<RefCell<Thread>>),
+    NativeClosure(Rc<NativeClosure>),
+    Table(Rc<Table>),
+}
+
+enum WeakObject {
+    Thread(Weak<RefCell<Thread>>),
+    NativeClosure(Weak<NativeClosure>),
+    Table(Weak<Table>),
+}
+
+impl WeakObject {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            WeakObject::Thread(t) => t.upgrade().map(Object::Thread),
+            WeakObject::NativeClosure(c) => c.upgrade().map(Object::NativeClosure),
+            WeakObject::Table(t) => t.upgrade().map(Object::Table),
+        }
+    }
+}
+
+impl Object {
+    /// the collectable object `value` is, if it is one
+    fn of(value: &Value) -> Option<Object> {
+        match value {
+            Value::Thread(t) => Some(Object::Thread(t.clone())),
+            Value::NativeClosure(c) => Some(Object::NativeClosure(c.clone())),
+            Value::Table(t) => Some(Object::Table(t.clone())),
+            _ => None,
+        }
+    }
+
+    /// the cycle that reached the object last
+    fn mark(&self) -> u32 {
+        match self {
+            Object::Thread(t) => t.borrow().mark.get(),
+            Object::NativeClosure(c) => c.mark.get(),
+            Object::Table(t) => t.mark.get(),
+        }
+    }
+
+    fn set_mark(&self, epoch: u32) {
+        match self {
+            Object::Thread(t) => t.borrow().mark.set(epoch),
+            Object::NativeClosure(c) => c.mark.set(epoch),
+            Object::Table(t) => t.mark.set(epoch),
+        }
+    }
+
+    fn age(&self) -> Age {
+        match self {
+            Object::Thread(t) => t.borrow().age.get(),
+            Object::NativeClosure(c) => c.age.get(),
+            Object::Table(t) => t.age.get(),
+        }
+    }
+
+    fn set_age(&self, age: Age) {
+        match self {
+            Object::Thread(t) => t.borrow().age.set(age),
+            Object::NativeClosure(c) => c.age.set(age),
+            Object::Table(t) => t.age.set(age),
+        }
+    }
+
+    /// bytes allocated for the object
+    fn size(&self) -> usize {
+        match self {
+            Object::Thread(t) => {
+                let t = t.borrow();
+                rc_size::<RefCell<Thread>>() + stack_size(&t.stack, &t.frames)
+            }
+            Object::NativeClosure(c) => {
+                rc_size::<NativeClosure>() + c.upvalues.borrow().capacity() * size_of::<Value>()
+            }
+            Object::Table(t) => {
+                let entries = t.entries.borrow().capacity();
+                rc_size::<Table>() + entries * size_of::<(TableKey, Value)>()
+            }
+        }
+    }
+}
+
+/// size of the allocation of an `Rc<T>`, which holds the reference counts too
+fn rc_size<T>() -> usize {
+    2 * size_of::<usize>() + size_of::<T>()
+}
+
+/// bytes allocated for a prototype, without its nested prototypes and the strings among its
+/// constants, which are counted on their own
+fn proto_size(proto: &ParseProto) -> usize {
+    let debug = proto.debug.as_ref().map_or(0, |debug| {
+        let line_info = &debug.line_info;
+        let locals: usize = debug.locals.iter().map(|var| var.name.capacity()).sum();
+        let names: usize = debug.upvalue_names.iter().map(String::capacity).sum();
+        debug.source.capacity()
+            + line_info.deltas.capacity()
+            + line_info.absolute.capacity() * size_of::<(u32, u32)>()
+            + debug.locals.capacity() * size_of::<LocalVar>()
+            + locals
+            + debug.upvalue_names.capacity() * size_of::<String>()
+            + names
+    });
+    rc_size::<ParseProto>()
+        + proto.constants.capacity() * size_of::<Value>()
+        + proto.byte_codes.capacity() * size_of::<Instruction>()
+        + proto.protos.capacity() * size_of::<Rc<ParseProto>>()
+        + debug
+}
+
+/// bytes allocated for the stack and frames of a thread
+fn stack_size(stack: &Vec<Value>, frames: &Vec<CallInfo>) -> usize {
+    let varargs: usize = frames.iter().map(|ci| ci.varargs.capacity()).sum();
+    (stack.capacity() + varargs) * size_of::<Value>() + frames.capacity() * size_of::<CallInfo>()
+}
+
+impl Heap {
+    pub(crate) fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            counted: Vec::new(),
+            gray: Vec::new(),
+            gray_again: Vec::new(),
+            weak_values: Vec::new(),
+            ephemerons: Vec::new(),
+            all_weak: Vec::new(),
+            finalizable: Vec::new(),
+            to_finalize: VecDeque::new(),
+            finalizing: false,
+            epoch: 0,
+            phase: Phase::Pause,
+            total_bytes: 0,
+            threshold: MIN_THRESHOLD,
+            pause: DEFAULT_GC_PAUSE,
+            step_multiplier: DEFAULT_GC_STEP_MULTIPLIER,
+            step_size: 1 << DEFAULT_STEP_SIZE_LOG2,
+            minor_multiplier: DEFAULT_MINOR_MULTIPLIER,
+            major_multiplier: DEFAULT_MAJOR_MULTIPLIER,
+            mode: Mode::Incremental,
+            running: true,
+            stress: false,
+            first_young: 0,
+            first_old1: 0,
+            touched: Vec::new(),
+            minor: false,
+            major_base: 0,
+        }
+    }
+
+    /// manage a new thread
+    pub(crate) fn add_thread(&mut self, thread: &Rc<RefCell<Thread>>) {
+        let object = Object::Thread(thread.clone());
+        self.add(object, WeakObject::Thread(Rc::downgrade(thread)));
+    }
+
+    /// manage a new closure
+    pub(crate) fn add_native_closure(&mut self, closure: &Rc<NativeClosure>) {
+        let object = Object::NativeClosure(closure.clone());
+        self.add(object, WeakObject::NativeClosure(Rc::downgrade(closure)));
+    }
+
+    /// count a new prototype in the heap size until it is freed
+    pub(crate) fn add_proto(&mut self, proto: &Rc<ParseProto>) {
+        let size = proto_size(proto);
+        self.counted
+            .push((Counted::Proto(Rc::downgrade(proto)), size));
+        self.total_bytes += size;
+    }
+
+    /// count a new Lua closure in the heap size until it is freed
+    pub(crate) fn add_lua_closure(&mut self, closure: &Rc<LuaClosure>) {
+        let size = rc_size::<LuaClosure>();
+        self.counted
+            .push((Counted::LuaClosure(Rc::downgrade(closure)), size));
+        self.total_bytes += size;
+    }
+
+    /// stop counting the prototypes and closures that have been freed
+    fn sweep_counted(&mut self) {
+        let mut freed = 0;
+        self.counted.retain(|(object, size)| {
+            let alive = object.is_alive();
+            if !alive {
+                freed += size;
+            }
+            alive
+        });
+        self.total_bytes -= freed;
+    }
+
+    /// manage a new table
+    pub(crate) fn add_table(&mut self, table: &Rc<Table>) {
+        let object = Object::Table(table.clone());
+        self.add(object, WeakObject::Table(Rc::downgrade(table)));
+    }
+
+    /// Call the `__gc` metamethod of `table` once it is found unreachable, unless that is
+    /// already going to happen.
+    pub(crate) fn add_finalizer(&mut self, table: &Rc<Table>) {
+        let pending = |t: &Rc<Table>| Rc::ptr_eq(t, table);
+        if !self.finalizable.iter().any(pending) && !self.to_finalize.iter().any(pending) {
+            self.finalizable.push(table.clone());
+        }
+    }
+
+    fn add(&mut self, object: Object, weak: WeakObject) {
+        let size = object.size();
+        // Created while marking, the object is only reachable through the roots or threads,
+        // which are marked again at the end. The sweep has to keep it.
+        let mark = match self.phase {
+            Phase::Sweep(_) => self.epoch,
+            _ => self.epoch.wrapping_sub(1),
+        };
+        object.set_mark(mark);
+        object.set_age(Age::New);
+        self.objects.push(HeapEntry { object: weak, size });
+        self.total_bytes += size;
+    }
+
+    /// number of objects managed, including ones freed since the last sweep
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// mark `value` if it is a collectable object not reached yet
+    fn mark_value(&mut self, value: &Value) {
+        let Some(object) = Object::of(value) else {
+            return;
+        };
+        if !self.is_reached(&object) {
+            object.set_mark(self.epoch);
+            self.gray.push(object);
+        }
+    }
+
+    /// Whether `object` has been marked by the current cycle, or is assumed to be alive by a
+    /// minor collection.
+    fn is_reached(&self, object: &Object) -> bool {
+        object.mark() == self.epoch || (self.minor && object.age().is_old())
+    }
+
+    /// Whether `value` is an object that nothing has reached so far, which is removed from
+    /// weak tables at the end of marking. Strings are values, they are never removed.
+    fn is_cleared(&self, value: &Value) -> bool {
+        Object::of(value).is_some_and(|object| !self.is_reached(&object))
+    }
+
+    fn mark_values<'v>(&mut self, values: impl IntoIterator<Item = &'v Value>) {
+        for value in values {
+            self.mark_value(value);
+        }
+    }
+
+    fn mark_frames(&mut self, frames: &[CallInfo]) {
+        for ci in frames {
+            self.mark_values(&ci.varargs);
+        }
+    }
+
+    /// Mark the references of a gray object. Returns the bytes traversed.
+    fn traverse(&mut self, object: Object) -> usize {
+        let size = object.size();
+        match object {
+            Object::Thread(thread) => {
+                {
+                    let t = thread.borrow();
+                    self.mark_values(&t.stack);
+                    self.mark_frames(&t.frames);
+                    if let Some(error) = &t.error {
+                        self.mark_value(error);
+                    }
+                }
+                self.gray_again.push(Object::Thread(thread));
+            }
+            Object::NativeClosure(closure) => self.mark_values(closure.upvalues.borrow().iter()),
+            Object::Table(table) => {
+                self.traverse_table(&table);
+                self.gray_again.push(Object::Table(table));
+            }
+        }
+        size
+    }
+
+    /// Mark the metatable of `table` and the keys and values that are not weak. A table with
+    /// weak references is listed to be cleared at the end of marking. Values of weak keys are
+    /// only marked once their key is, see [`Self::traverse_ephemeron`].
+    fn traverse_table(&mut self, table: &Rc<Table>) {
+        if let Some(metatable) = &*table.metatable.borrow() {
+            self.mark_value(&Value::Table(metatable.clone()));
+        }
+        let (weak_keys, weak_values) = match table.metafield("__mode") {
+            Value::String(mode) => (mode.contains('k'), mode.contains('v')),
+            _ => (false, false),
+        };
+        match (weak_keys, weak_values) {
+            (false, false) => {
+                for (key, value) in table.entries.borrow().iter() {
+                    self.mark_value(key.value());
+                    self.mark_value(value);
+                }
+            }
+            (false, true) => {
+                for key in table.entries.borrow().keys() {
+                    self.mark_value(key.value());
+                }
+                self.weak_values.push(table.clone());
+            }
+            (true, false) => {
+                self.traverse_ephemeron(table);
+                self.ephemerons.push(table.clone());
+            }
+            (true, true) => self.all_weak.push(table.clone()),
+        }
+    }
+
+    /// Mark the values of the table with weak keys whose keys have been reached. Returns
+    /// whether it marked any.
+    fn traverse_ephemeron(&mut self, table: &Table) -> bool {
+        let mut marked = false;
+        for (key, value) in table.entries.borrow().iter() {
+            if !self.is_cleared(key.value()) && self.is_cleared(value) {
+                self.mark_value(value);
+                marked = true;
+            }
+        }
+        marked
+    }
+
+    /// Traverse the gray objects, until the values of tables with weak keys reached by now
+    /// are marked too. Returns the bytes traversed.
+    fn propagate_all(&mut self) -> usize {
+        let mut work = 0;
+        loop {
+            while let Some(object) = self.gray.pop() {
+                work += self.traverse(object);
+            }
+            let mut marked = false;
+            for table in self.ephemerons.clone() {
+                marked |= self.traverse_ephemeron(&table);
+            }
+            if !marked {
+                return work;
+            }
+        }
+    }
+
+    /// End marking once everything reachable is marked: tables with a finalizer found
+    /// unreachable are kept for their finalizers, with everything they reach, and weak tables
+    /// are cleared, in the order of reference Lua 5.4. Weak values are cleared before those
+    /// tables come back to life, weak keys after. Returns the bytes traversed.
+    fn finish_marking(&mut self) -> usize {
+        let mut work = self.propagate_all();
+        let (weak_values, all_weak) = (self.weak_values.len(), self.all_weak.len());
+        self.clear_values(0, 0);
+        let finalizable = std::mem::take(&mut self.finalizable);
+        let (unreached, reached): (Vec<_>, Vec<_>) = finalizable
+            .into_iter()
+            .partition(|table| !self.is_reached(&Object::Table(table.clone())));
+        self.finalizable = reached;
+        // finalizers run in the reverse order of their metatables being set
+        self.to_finalize.extend(unreached.into_iter().rev());
+        for table in self.to_finalize.clone() {
+            self.mark_value(&Value::Table(table));
+        }
+        work += self.propagate_all();
+        for table in self.ephemerons.iter().chain(&self.all_weak) {
+            table
+                .entries
+                .borrow_mut()
+                .retain(|key, _| !self.is_cleared(key.value()));
+        }
+        // tables first reached through the tables to finalize
+        self.clear_values(weak_values, all_weak);
+        work
+    }
+
+    /// remove the entries with an unreached value from the tables with weak values listed from
+    /// these indices on
+    fn clear_values(&self, weak_values: usize, all_weak: usize) {
+        let tables = self.weak_values[weak_values..]
+            .iter()
+            .chain(&self.all_weak[all_weak..]);
+        for table in tables {
+            table
+                .entries
+                .borrow_mut()
+                .retain(|_, value| !self.is_cleared(value));
+        }
+    }
+
+    /// forget the weak tables listed by the last traversals
+    fn clear_weak_lists(&mut self) {
+        self.weak_values.clear();
+        self.ephemerons.clear();
+        self.all_weak.clear();
+    }
+
+    /// Clear the unmarked objects from index `index` of the object list on, until about
+    /// `budget` of work is done. Returns the index to continue at and the work done.
+    fn sweep(&mut self, mut index: usize, budget: usize) -> (usize, usize) {
+        let mut work = 0;
+        while index < self.objects.len() && work < budget {
+            work += SWEEP_COST;
+            match self.objects[index].object.upgrade() {
+                Some(object) if object.mark() == self.epoch => {
+                    // the stacks of threads grow and shrink, their size is brought up to date
+                    let size = object.size();
+                    let entry = &mut self.objects[index];
+                    self.total_bytes = self.total_bytes - entry.size + size;
+                    entry.size = size;
+                    index += 1;
+                }
+                object => {
+                    // freeing the object may free others, which are found dead later
+                    if let Some(object) = object {
+                        clear(object);
+                    }
+                    let entry = self.objects.swap_remove(index);
+                    self.total_bytes -= entry.size;
+                }
+            }
+        }
+        (index, work)
+    }
+}
+
+impl Heap {
+    /// The write barrier: to be called before the stack of `thread` changes, which may store
+    /// young objects in an old thread.
+    pub(crate) fn barrier(&mut self, thread: &Rc<RefCell<Thread>>) {
+        self.touch(Object::Thread(thread.clone()));
+    }
+
+    /// The write barrier of tables: to be called before `table` or its metatable changes.
+    pub(crate) fn table_barrier(&mut self, table: &Rc<Table>) {
+        self.touch(Object::Table(table.clone()));
+    }
+
+    fn touch(&mut self, object: Object) {
+        if self.mode != Mode::Generational {
+            return;
+        }
+        match object.age() {
+            Age::New | Age::Survival | Age::Touched1 => {}
+            Age::Touched2 => object.set_age(Age::Touched1),
+            Age::Old1 | Age::Old => {
+                object.set_age(Age::Touched1);
+                self.touched.push(object);
+            }
+        }
+    }
+
+    /// Clear the young objects not marked by a minor collection and age the others.
+    fn sweep_young(&mut self) {
+        // the objects that became old in the last collection are done being traversed
+        for entry in &self.objects[self.first_old1..self.first_young] {
+            if let Some(object) = entry.object.upgrade() {
+                if object.age() == Age::Old1 {
+                    object.set_age(Age::Old);
+                }
+            }
+        }
+        self.first_old1 = self.first_young;
+
+        let mut index = self.first_young;
+        while index < self.objects.len() {
+            match self.objects[index].object.upgrade() {
+                Some(object) if object.mark() == self.epoch => {
+                    let size = object.size();
+                    let entry = &mut self.objects[index];
+                    self.total_bytes = self.total_bytes - entry.size + size;
+                    entry.size = size;
+                    if object.age() == Age::New {
+                        object.set_age(Age::Survival);
+                    } else {
+                        // entries before `index` were swept already, the first young one too
+                        object.set_age(Age::Old1);
+                        self.objects.swap(index, self.first_young);
+                        self.first_young += 1;
+                    }
+                    index += 1;
+                }
+                object => {
+                    if let Some(object) = object {
+                        clear(object);
+                    }
+                    let entry = self.objects.swap_remove(index);
+                    self.total_bytes -= entry.size;
+                }
+            }
+        }
+
+        self.touched.retain(|object| {
+            match object.age() {
+                Age::Touched1 => object.set_age(Age::Touched2),
+                Age::Touched2 => object.set_age(Age::Old),
+                _ => {}
+            }
+            object.age() != Age::Old
+        });
+    }
+
+    /// Make every object old after a full collection, entering generational mode or ending a
+    /// major collection.
+    fn atomic_to_generational(&mut self) {
+        for entry in &self.objects {
+            if let Some(object) = entry.object.upgrade() {
+                object.set_age(Age::Old);
+            }
+        }
+        self.first_young = self.objects.len();
+        self.first_old1 = self.first_young;
+        self.touched.clear();
+    }
+}
+
+/// Drop the references of an unreachable object, so that reference counting frees it and the
+/// objects only it referred to.
+fn clear(object: Object) {
+    match object {
+        Object::Thread(thread) => {
+            let (stack, frames, error) = {
+                let mut t = thread.borrow_mut();
+                (
+                    std::mem::take(&mut t.stack),
+                    std::mem::take(&mut t.frames),
+                    t.error.take(),
+                )
+            };
+            // dropped once the thread is no longer borrowed, they may hold it
+            drop((stack, frames, error));
+        }
+        Object::NativeClosure(closure) => {
+            let upvalues = std::mem::take(&mut *closure.upvalues.borrow_mut());
+            drop(upvalues);
+        }
+        Object::Table(table) => {
+            // the entries are only dropped, nothing is looked up in them
+            #[allow(clippy::mutable_key_type)]
+            let entries = std::mem::take(&mut *table.entries.borrow_mut());
+            let metatable = table.metatable.borrow_mut().take();
+            drop((entries, metatable));
+        }
+    }
+}
+
+impl ExeState<'_> {
+    /// Run a complete garbage collection cycle, finishing the one in progress first, and call
+    /// the finalizers of the tables it found unreachable. In generational mode this is a major
+    /// collection. Does nothing when called from a finalizer.
+    pub fn collect_garbage(&mut self) {
+        if self.heap.finalizing {
+            return;
+        }
+        while self.heap.phase != Phase::Pause {
+            self.gc_step(usize::MAX);
+        }
+        self.gc_step(usize::MAX);
+        while self.heap.phase != Phase::Pause {
+            self.gc_step(usize::MAX);
+        }
+        if self.heap.mode == Mode::Generational {
+            self.heap.atomic_to_generational();
+            self.heap.major_base = self.heap_size();
+        }
+        self.call_finalizers();
+    }
+
+    /// Switch the collector to generational mode, after a full collection, or back to
+    /// incremental mode.
+    pub fn set_gc_generational(&mut self, generational: bool) {
+        match (self.heap.mode, generational) {
+            (Mode::Incremental, true) => {
+                self.heap.mode = Mode::Generational;
+                self.collect_garbage();
+                self.set_minor_threshold();
+            }
+            (Mode::Generational, false) => {
+                self.heap.mode = Mode::Incremental;
+                self.heap.touched.clear();
+                self.heap.threshold = self.heap_size().saturating_mul(self.heap.pause) / 100;
+            }
+            _ => {}
+        }
+    }
+
+    /// Bytes allocated for the objects managed by the collector, the strings, the Lua functions
+    /// and prototypes, and the stack of the running thread, as `collectgarbage("count")` reports
+    /// in kilobytes.
+    pub fn heap_size(&self) -> usize {
+        self.heap.total_bytes + self.strings.size() + stack_size(&self.stack, &self.frames)
+    }
+
+    /// Collect garbage at every allocation, to make errors in keeping objects alive show up
+    /// right away. Very slow.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.stress = stress;
+    }
+
+    /// Set how much the heap grows after a cycle before the next one starts, as a percentage
+    /// of its size: at 200 a cycle starts when the heap doubled.
+    pub fn set_gc_pause(&mut self, pause: usize) {
+        self.heap.pause = pause;
+    }
+
+    /// Set how much work a step does relative to the allocation that triggered it, as a
+    /// percentage. Larger values make cycles end sooner, in longer steps.
+    pub fn set_gc_step_multiplier(&mut self, step_multiplier: usize) {
+        self.heap.step_multiplier = step_multiplier;
+    }
+
+    /// Let the collector do some work if the program allocated enough since its last step.
+    /// Called after an allocation, when all values in use are reachable from the roots.
+    pub(crate) fn check_gc(&mut self) {
+        if self.heap.finalizing {
+            return;
+        }
+        if self.heap.stress {
+            match self.heap.mode {
+                Mode::Incremental => self.collect_garbage(),
+                Mode::Generational => self.young_collection(),
+            }
+        } else if self.heap.running && self.heap_size() >= self.heap.threshold {
+            match self.heap.mode {
+                Mode::Incremental => {
+                    self.incremental_step(self.heap.step_size);
+                }
+                Mode::Generational => {
+                    self.generational_step();
+                }
+            }
+        }
+        self.call_finalizers();
+    }
+
+    /// Do a minor collection, or a major one if the heap grew enough since the last. Returns
+    /// whether it was a major one.
+    fn generational_step(&mut self) -> bool {
+        let major_limit = self.heap.major_base / 100 * (100 + self.heap.major_multiplier);
+        let major = self.heap_size() > major_limit;
+        if major {
+            self.collect_garbage();
+        } else {
+            self.young_collection();
+        }
+        self.set_minor_threshold();
+        major
+    }
+
+    fn set_minor_threshold(&mut self) {
+        let size = self.heap_size();
+        let threshold = size + size / 100 * self.heap.minor_multiplier;
+        self.heap.threshold = threshold.max(MIN_THRESHOLD);
+    }
+
+    /// Mark and sweep the young objects without interruption. Old objects are assumed alive,
+    /// the ones that may refer to young objects are traversed.
+    fn young_collection(&mut self) {
+        let heap = &mut self.heap;
+        heap.clear_weak_lists();
+        heap.epoch = heap.epoch.wrapping_add(1);
+        heap.minor = true;
+        self.mark_roots();
+        let heap = &mut self.heap;
+        for index in heap.first_old1..heap.first_young {
+            if let Some(object) = heap.objects[index].object.upgrade() {
+                heap.traverse(object);
+            }
+        }
+        for object in heap.touched.clone() {
+            heap.traverse(object);
+        }
+        heap.finish_marking();
+        heap.gray_again.clear();
+        heap.clear_weak_lists();
+        heap.minor = false;
+        heap.sweep_young();
+        heap.sweep_counted();
+        self.strings.sweep_created();
+    }
+
+    /// Do the work owed for `allocated` bytes of allocation, and set when the next step is due.
+    /// Returns whether a cycle ended.
+    fn incremental_step(&mut self, allocated: usize) -> bool {
+        let budget = allocated.saturating_mul(self.heap.step_multiplier) / 100;
+        let budget = budget.max(1);
+        let mut work = 0;
+        let mut finished = false;
+        while work < budget && !finished {
+            work += self.gc_step(budget - work).max(1);
+            finished = self.heap.phase == Phase::Pause;
+        }
+        self.heap.threshold = if finished {
+            let estimate = self.heap_size().saturating_mul(self.heap.pause) / 100;
+            estimate.max(MIN_THRESHOLD)
+        } else {
+            self.heap_size() + self.heap.step_size
+        };
+        finished
+    }
+
+    /// Advance the cycle by about `budget` bytes of work, at most to the end of the current
+    /// phase, starting a cycle between them. Returns the work done.
+    fn gc_step(&mut self, budget: usize) -> usize {
+        match self.heap.phase {
+            Phase::Pause => {
+                self.heap.epoch = self.heap.epoch.wrapping_add(1);
+                self.mark_roots();
+                self.heap.phase = Phase::Propagate;
+                0
+            }
+            Phase::Propagate => {
+                let mut work = 0;
+                while work < budget {
+                    let Some(object) = self.heap.gray.pop() else {
+                        work += self.atomic();
+                        self.heap.phase = Phase::Sweep(0);
+                        break;
+                    };
+                    work += self.heap.traverse(object);
+                }
+                work
+            }
+            Phase::Sweep(index) => {
+                let (index, work) = self.heap.sweep(index, budget);
+                self.heap.phase = if index < self.heap.objects.len() {
+                    Phase::Sweep(index)
+                } else {
+                    self.heap.sweep_counted();
+                    self.strings.sweep();
+                    Phase::Pause
+                };
+                work
+            }
+        }
+    }
+
+    /// mark the values the program can reach directly, and the tables waiting for their
+    /// finalizers
+    fn mark_roots(&mut self) {
+        let heap = &mut self.heap;
+        heap.mark_values(&self.stack);
+        heap.mark_frames(&self.frames);
+        heap.mark_values(self.globals.values());
+        heap.mark_value(&Value::Thread(self.thread.clone()));
+        heap.mark_value(&Value::Thread(self.main_thread.clone()));
+        for table in heap.to_finalize.clone() {
+            heap.mark_value(&Value::Table(table));
+        }
+    }
+
+    /// End marking without interruption: the roots and the threads and tables traversed may
+    /// have changed since they were marked. Returns the bytes traversed.
+    fn atomic(&mut self) -> usize {
+        // the weak tables are all traversed again
+        self.heap.clear_weak_lists();
+        self.mark_roots();
+        let mut work = 0;
+        let objects = std::mem::take(&mut self.heap.gray_again);
+        for object in objects {
+            work += self.heap.traverse(object);
+        }
+        work += self.heap.finish_marking();
+        self.heap.gray_again.clear();
+        self.heap.clear_weak_lists();
+        work
+    }
+
+    /// Call the finalizers of the tables found unreachable, the `__gc` field of their
+    /// metatables, with the table as argument. Errors raised by finalizers are ignored, like
+    /// the warnings reference Lua turns them into, which are off by default. The tables live
+    /// on if a finalizer stored them somewhere, but their finalizers are not called again.
+    fn call_finalizers(&mut self) {
+        if self.heap.finalizing {
+            return;
+        }
+        self.heap.finalizing = true;
+        while let Some(table) = self.heap.to_finalize.pop_front() {
+            let finalizer = table.metafield("__gc");
+            if finalizer == Value::Nil {
+                continue;
+            }
+            let func = self.stack.len();
+            let depth = self.frames.len();
+            self.stack.push(finalizer);
+            self.stack.push(Value::Table(table));
+            if let Err(value) = self.call(func, 1, Some(0)) {
+                self.unwind(depth, func, None, value);
+            }
+        }
+        self.heap.finalizing = false;
+    }
+
+    /// the number passed as argument `arg` to collectgarbage, `default` if it is absent
+    fn check_gc_arg(&self, arg: usize, default: i64) -> Result<i64, Value> {
+        match self.args().get(arg - 1) {
+            None | Some(Value::Nil) => Ok(default),
+            Some(&Value::Integer(i)) => Ok(i),
+            Some(&Value::Float(f)) if f.fract() == 0.0 && f.abs() < 2f64.powi(63) => Ok(f as i64),
+            Some(v) => {
+                let message = format!(
+                    "bad argument #{arg} to 'collectgarbage' (number expected, got {})",
+                    v.type_name()
+                );
+                Err(self.native_error(message))
+            }
+        }
+    }
+}
+
+/// Set a parameter of the collector to `value` unless it is 0, which keeps the current one.
+fn set_param(param: &mut usize, value: i64) {
+    if value != 0 {
+        *param = value.max(0) as usize;
+    }
+}
+
+// "collectgarbage([opt [, arg...]])": control the collector, as in reference Lua 5.4
+//   "collect": run a full cycle, the default
+//   "count": the heap size in kilobytes, as a float
+//   "step" [size]: do the work owed for size kilobytes of allocation, a basic step for 0,
+//       returns true if a cycle ended. In generational mode, do a minor collection, or a
+//       major one if it is due, which returns true.
+//   "isrunning": whether the collector runs as the program allocates
+//   "stop", "restart": stop and restart the collector, it still runs when asked to
+//   "incremental" [pause, stepmul, stepsize], "generational" [minormul, majormul]: switch the
+//       mode of the collector and set its parameters, 0 keeps one unchanged. Returns the
+//       previous mode.
+pub(crate) fn lib_collectgarbage(state: &mut ExeState) -> Result<i32, Value> {
+    let option = match state.args().first() {
+        None | Some(Value::Nil) => "collect".into(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => {
+            let message = format!(
+                "bad argument #1 to 'collectgarbage' (string expected, got {})",
+                v.type_name()
+            );
+            return Err(state.native_error(message));
+        }
+    };
+    let result = match &*option {
+        "collect" => {
+            state.collect_garbage();
+            Value::Integer(0)
+        }
+        "count" => Value::Float(state.heap_size() as f64 / 1024.0),
+        "step" if state.heap.mode == Mode::Generational => {
+            let major = state.generational_step();
+            state.call_finalizers();
+            Value::Boolean(major)
+        }
+        "step" => {
+            let size = state.check_gc_arg(2, 0)?;
+            let allocated = if size > 0 {
+                (size as usize).saturating_mul(1024)
+            } else {
+                state.heap.step_size
+            };
+            // the step ends the cycle in progress, or a new one
+            let finished = state.incremental_step(allocated);
+            state.call_finalizers();
+            Value::Boolean(finished)
+        }
+        "isrunning" => Value::Boolean(state.heap.running),
+        "stop" => {
+            state.heap.running = false;
+            Value::Integer(0)
+        }
+        "restart" => {
+            state.heap.running = true;
+            Value::Integer(0)
+        }
+        "incremental" => {
+            let pause = state.check_gc_arg(2, 0)?;
+            let step_multiplier = state.check_gc_arg(3, 0)?;
+            let step_size = state.check_gc_arg(4, 0)?;
+            let heap = &mut state.heap;
+            set_param(&mut heap.pause, pause);
+            set_param(&mut heap.step_multiplier, step_multiplier);
+            if step_size != 0 {
+                heap.step_size = 1 << step_size.clamp(0, 40);
+            }
+            let previous = heap.mode;
+            state.set_gc_generational(false);
+            Value::String(state.intern(previous.name()))
+        }
+        "generational" => {
+            let minor_multiplier = state.check_gc_arg(2, 0)?;
+            let major_multiplier = state.check_gc_arg(3, 0)?;
+            let heap = &mut state.heap;
+            set_param(&mut heap.minor_multiplier, minor_multiplier);
+            set_param(&mut heap.major_multiplier, major_multiplier);
+            let previous = heap.mode;
+            state.set_gc_generational(true);
+            Value::String(state.intern(previous.name()))
+        }
+        _ => {
+            let message =
+                format!("bad argument #1 to 'collectgarbage' (invalid option '{option}')");
+            return Err(state.native_error(message));
+        }
+    };
+    state.stack.push(result);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parser::load;
+
+    fn run(vm: &mut ExeState, code: &str) {
+        let proto = load(&mut Cursor::new(code), "=test").unwrap();
+        vm.execute(&proto).unwrap();
+    }
+
+    #[test]
+    fn cycles_through_threads_are_collected() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(
+            &mut vm,
+            "function hold_self() local me = coroutine.running(); coroutine.yield() end\n\
+             local co = coroutine.create(hold_self)\n\
+             coroutine.resume(co)\n\
+             local wrapped = coroutine.wrap(function(f) coroutine.yield(f) end)\n\
+             wrapped(wrapped)\n\
+             kept = coroutine.create(hold_self)\n\
+             coroutine.resume(kept)",
+        );
+        // the main thread, the coroutine library table, and the three coroutines and the wrapper
+        assert_eq!(vm.heap.len(), 6);
+
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), 3);
+        run(
+            &mut vm,
+            "print(coroutine.status(kept))\nprint(coroutine.resume(kept))",
+        );
+        drop(vm);
+        assert_eq!(output, b"suspended\ntrue\n");
+    }
+
+    #[test]
+    fn incremental_cycles_keep_reachable_objects() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        vm.set_gc_pause(100);
+        run(
+            &mut vm,
+            "function count(n) n = coroutine.yield(n) n = coroutine.yield(n + 1) return n end\n\
+             co = coroutine.create(count)",
+        );
+        // the coroutine is resumed while cycles are in progress
+        for i in 0..3000 {
+            run(&mut vm, "coroutine.create(print)");
+            if i % 1000 == 999 {
+                run(&mut vm, &format!("print(coroutine.resume(co, {i}))"));
+            }
+        }
+        assert!(vm.heap.len() < 1000, "{} objects", vm.heap.len());
+        drop(vm);
+        assert_eq!(output, b"true\t999\ntrue\t2000\ntrue\t2999\n");
+    }
+
+    #[test]
+    fn stress_mode_collects_at_every_allocation() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        vm.set_gc_stress(true);
+        run(
+            &mut vm,
+            "local gen = coroutine.wrap(function(a)\n\
+               local inner = coroutine.wrap(function(b) coroutine.yield(b) return b + 1 end)\n\
+               coroutine.yield(inner(a))\n\
+               return inner()\n\
+             end)\n\
+             print(gen(1))\n\
+             print(gen())",
+        );
+        vm.set_gc_stress(false);
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), 2);
+        drop(vm);
+        assert_eq!(output, b"1\n2\n");
+    }
+
+    /// number of entries of the global table `name`
+    fn table_len(vm: &mut ExeState, name: &str) -> usize {
+        let key = vm.intern(name);
+        let Value::Table(t) = &vm.globals[&key] else {
+            panic!("{name} is not a table");
+        };
+        t.entries.borrow().len()
+    }
+
+    #[test]
+    fn weak_tables_drop_entries_only_they_refer_to() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        // `keys[key]` is an ephemeron: its value only keeps its own key alive
+        run(
+            &mut vm,
+            "kept = {}\n\
+             keys = setmetatable({}, {__mode = \"k\"})\n\
+             values = setmetatable({}, {__mode = \"v\"})\n\
+             both = setmetatable({}, {__mode = \"kv\"})\n\
+             function fill()\n\
+               local key = {}\n\
+               keys[key] = {key}\n\
+               keys[kept] = {}\n\
+               values[1], values[2], values[3] = {}, kept, \"strings are values\"\n\
+               both[{}], both[kept] = kept, kept\n\
+             end\n\
+             fill()",
+        );
+        assert_eq!(table_len(&mut vm, "keys"), 2);
+        assert_eq!(table_len(&mut vm, "values"), 3);
+        assert_eq!(table_len(&mut vm, "both"), 2);
+
+        vm.collect_garbage();
+        run(
+            &mut vm,
+            "print(keys[kept] ~= nil, values[1], values[2] == kept, values[3], both[kept] == kept)",
+        );
+        assert_eq!(table_len(&mut vm, "keys"), 1);
+        assert_eq!(table_len(&mut vm, "values"), 2);
+        assert_eq!(table_len(&mut vm, "both"), 1);
+        drop(vm);
+        assert_eq!(output, b"true\tnil\ttrue\tstrings are values\ttrue\n");
+    }
+
+    #[test]
+    fn weak_tables_are_cleared_by_minor_collections() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(
+            &mut vm,
+            "values = setmetatable({}, {__mode = \"v\"})\n\
+             collectgarbage(\"generational\")\n\
+             function fill() values[1], values[2] = {}, values end\n\
+             fill()",
+        );
+        vm.young_collection();
+        assert_eq!(table_len(&mut vm, "values"), 1);
+    }
+
+    #[test]
+    fn finalizers_run_in_reverse_order_of_marking() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        // a `__gc` field only set after setmetatable has no effect, errors are ignored
+        run(
+            &mut vm,
+            "function gc(t) print(\"finalized\", t.name) end\n\
+             function fail() error(\"ignored\") end\n\
+             function make()\n\
+               setmetatable({name = \"first\"}, {__gc = gc})\n\
+               setmetatable({name = \"failing\"}, {__gc = fail})\n\
+               setmetatable({name = \"second\"}, {__gc = gc})\n\
+               local mt = {}\n\
+               setmetatable({name = \"late\"}, mt)\n\
+               mt.__gc = gc\n\
+               kept = setmetatable({name = \"kept\"}, {__gc = gc})\n\
+             end\n\
+             make()",
+        );
+        vm.collect_garbage();
+        run(&mut vm, "print(\"collected\")");
+        vm.collect_garbage();
+        run(&mut vm, "kept = nil");
+        vm.collect_garbage();
+        drop(vm);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "finalized\tsecond\nfinalized\tfirst\ncollected\nfinalized\tkept\n"
+        );
+    }
+
+    #[test]
+    fn stress_mode_reclaims_cycles_without_explicit_collections() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        vm.set_gc_stress(true);
+        run(
+            &mut vm,
+            "co = coroutine.create(function() local me = coroutine.running() coroutine.yield() end)\n\
+             coroutine.resume(co)",
+        );
+        let key = vm.intern("co");
+        let Value::Thread(co) = &vm.globals[&key] else {
+            panic!("co is not a thread");
+        };
+        let co = Rc::downgrade(co);
+        // the stack of the suspended coroutine holds the coroutine
+        assert_eq!(co.strong_count(), 2);
+
+        // interning the result of tostring is an allocation
+        run(&mut vm, "co = nil\ntostring(1)");
+        assert!(co.upgrade().is_none());
+        assert_eq!(vm.heap.len(), 2);
+    }
+
+    #[test]
+    fn heap_size_counts_objects_until_they_are_collected() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        vm.collect_garbage();
+        let base = vm.heap.total_bytes;
+        run(&mut vm, "kept = coroutine.create(print)");
+        // the chunk itself is counted until it is swept
+        vm.collect_garbage();
+        let with_one = vm.heap.total_bytes;
+        assert!(with_one > base, "{with_one} <= {base}");
+
+        run(
+            &mut vm,
+            "function hold_self() local me = coroutine.running(); coroutine.yield() end\n\
+             coroutine.resume(coroutine.create(hold_self))\n\
+             hold_self = nil",
+        );
+        assert!(vm.heap.total_bytes > with_one);
+        vm.collect_garbage();
+        assert_eq!(vm.heap.total_bytes, with_one);
+        assert!(vm.heap_size() > with_one);
+    }
+
+    #[test]
+    fn heap_size_counts_long_strings_and_functions() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(
+            &mut vm,
+            "collectgarbage()\nbefore = collectgarbage(\"count\")",
+        );
+        // the constant is copied when the chunk runs, the copy is freed with the chunk
+        let long = "x".repeat(2000);
+        run(
+            &mut vm,
+            &format!(
+                "long = \"{long}\"\n\
+                 print(collectgarbage(\"count\") - before > 1.9)"
+            ),
+        );
+        run(
+            &mut vm,
+            "long = nil\n\
+             collectgarbage()\n\
+             print(collectgarbage(\"count\") - before < 1)",
+        );
+
+        run(&mut vm, "kept = false\nmake = false");
+        vm.collect_garbage();
+        let base = vm.heap_size();
+        run(
+            &mut vm,
+            "function make() return function() end end\n\
+             kept = make()",
+        );
+        let with_closures = vm.heap_size();
+        assert!(with_closures > base, "{with_closures} <= {base}");
+        run(&mut vm, "kept = false\nmake = false");
+        vm.collect_garbage();
+        assert_eq!(vm.heap_size(), base);
+        drop(vm);
+        assert_eq!(output, b"true\ntrue\n");
+    }
+
+    #[test]
+    fn collectgarbage_options() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(
+            &mut vm,
+            "print(collectgarbage())\n\
+             print(collectgarbage(\"count\") > 0)\n\
+             print(collectgarbage(\"isrunning\"))\n\
+             print(collectgarbage(\"stop\"))\n\
+             print(collectgarbage(\"isrunning\"))\n\
+             print(collectgarbage(\"restart\"))\n\
+             print(collectgarbage(\"isrunning\"))\n\
+             print(collectgarbage(\"step\", 1000))\n\
+             print(collectgarbage(\"generational\"))\n\
+             print(collectgarbage(\"incremental\", 100, 400))\n\
+             print(collectgarbage(\"incremental\"))\n\
+             print(pcall(collectgarbage, \"full\"))\n\
+             print(pcall(collectgarbage, \"step\", true))",
+        );
+        assert_eq!(vm.heap.pause, 100);
+        assert_eq!(vm.heap.step_multiplier, 400);
+        drop(vm);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "0\ntrue\ntrue\n0\nfalse\n0\ntrue\ntrue\n\
+             incremental\ngenerational\nincremental\n\
+             false\tbad argument #1 to 'collectgarbage' (invalid option 'full')\n\
+             false\tbad argument #2 to 'collectgarbage' (number expected, got boolean)\n"
+        );
+    }
+
+    #[test]
+    fn finalizers_can_resurrect_their_tables() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        // weak values are cleared before finalization, weak keys only after it
+        run(
+            &mut vm,
+            "keys = setmetatable({}, {__mode = \"k\"})\n\
+             values = setmetatable({}, {__mode = \"v\"})\n\
+             function revive(t) print(\"finalized\", t.name) saved = t end\n\
+             function make()\n\
+               local t = setmetatable({name = \"revived\"}, {__gc = revive})\n\
+               keys[t], values[1] = true, t\n\
+             end\n\
+             make()",
+        );
+        vm.collect_garbage();
+        run(
+            &mut vm,
+            "print(saved.name, values[1], keys[saved])\n\
+             saved = nil",
+        );
+        vm.collect_garbage();
+        run(&mut vm, "print(keys[saved])");
+        assert_eq!(table_len(&mut vm, "keys"), 0);
+        drop(vm);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "finalized\trevived\nrevived\tnil\ttrue\nnil\n"
+        );
+    }
+
+    #[test]
+    fn minor_collections_only_sweep_young_objects() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(
+            &mut vm,
+            "function hold_self() local me = coroutine.running(); coroutine.yield() end\n\
+             old = coroutine.create(hold_self)\n\
+             coroutine.resume(old)",
+        );
+        vm.set_gc_generational(true);
+        run(
+            &mut vm,
+            "old = nil\n\
+             coroutine.resume(coroutine.create(hold_self))\n\
+             young = coroutine.create(hold_self)\n\
+             coroutine.resume(young)",
+        );
+        // the main thread, the coroutine library table, the old coroutine and the two young ones
+        assert_eq!(vm.heap.len(), 5);
+        vm.young_collection();
+        assert_eq!(vm.heap.len(), 4);
+        run(&mut vm, "young = nil");
+        vm.young_collection();
+        assert_eq!(vm.heap.len(), 3);
+
+        vm.collect_garbage();
+        assert_eq!(vm.heap.len(), 2);
+    }
+
+    #[test]
+    fn barrier_keeps_young_objects_stored_in_old_threads() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(
+            &mut vm,
+            "function answer() return 42 end\n\
+             function keep(co) co = coroutine.yield() coroutine.yield() print(coroutine.resume(co)) end\n\
+             keeper = coroutine.create(keep)\n\
+             coroutine.resume(keeper)",
+        );
+        vm.set_gc_generational(true);
+        // only the old keeper refers to the new coroutine
+        run(
+            &mut vm,
+            "coroutine.resume(keeper, coroutine.create(answer))",
+        );
+        for _ in 0..4 {
+            run(&mut vm, "print(collectgarbage(\"step\"))");
+        }
+        run(&mut vm, "coroutine.resume(keeper)");
+        drop(vm);
+        assert_eq!(output, b"false\nfalse\nfalse\nfalse\ntrue\t42\n");
+    }
+
+    #[test]
+    fn generational_mode_keeps_reachable_objects() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(
+            &mut vm,
+            "collectgarbage(\"generational\", 10, 50)\n\
+             function count(n) n = coroutine.yield(n) n = coroutine.yield(n + 1) return n end\n\
+             co = coroutine.create(count)",
+        );
+        for i in 0..3000 {
+            run(&mut vm, "coroutine.create(print)");
+            if i % 1000 == 999 {
+                run(&mut vm, &format!("print(coroutine.resume(co, {i}))"));
+            }
+        }
+        assert!(vm.heap.len() < 1000, "{} objects", vm.heap.len());
+        drop(vm);
+        assert_eq!(output, b"true\t999\ntrue\t2000\ntrue\t2999\n");
+    }
+
+    #[test]
+    fn stopped_collector_still_collects_when_asked() {
+        let mut output = Vec::new();
+        let mut vm = ExeState::new(&mut output);
+        run(&mut vm, "collectgarbage(\"stop\")");
+        for _ in 0..1000 {
+            run(&mut vm, "coroutine.create(print)");
+        }
+        assert_eq!(vm.heap.len(), 1002);
+        run(&mut vm, "collectgarbage()");
+        assert_eq!(vm.heap.len(), 2);
+    }
+}
@@ -1,9 +1,10 @@
 use std::{
-    fmt::Debug,
+    fmt::{self, Debug},
     io::{Read, Seek, SeekFrom},
-    mem,
 };
 
+use crate::{number::str_to_number, value::Value};
+
 #[derive(Debug, PartialEq)]
 pub enum Token {
     // keywords
@@ -73,18 +74,122 @@ pub enum Token {
     // name of variables or table keys
     Name(String),
 
+    // malformed input
+    Error(LexError),
+
     // end
     Eos,
 }
 
+impl fmt::Display for Token {
+    /// format the token the way it appears in the source, used for `near` in error messages
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Add => "+",
+            Token::Sub => "-",
+            Token::Mul => "*",
+            Token::Div => "/",
+            Token::Mod => "%",
+            Token::Pow => "^",
+            Token::Len => "#",
+            Token::BitAnd => "&",
+            Token::BitXor => "~",
+            Token::BitOr => "|",
+            Token::ShiftL => "<<",
+            Token::ShiftR => ">>",
+            Token::Idiv => "//",
+            Token::Equal => "==",
+            Token::NotEq => "~=",
+            Token::LesEq => "<=",
+            Token::GreEq => ">=",
+            Token::Less => "<",
+            Token::Greater => ">",
+            Token::Assign => "=",
+            Token::ParL => "(",
+            Token::ParR => ")",
+            Token::CurlyL => "{",
+            Token::CurlyR => "}",
+            Token::SqurL => "[",
+            Token::SqurR => "]",
+            Token::DoubColon => "::",
+            Token::SemiColon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Integer(i) => return write!(f, "{i}"),
+            Token::Float(v) => return write!(f, "{v}"),
+            Token::String(s) => return write!(f, "\"{s}\""),
+            Token::Name(name) => name,
+            Token::Error(LexError::UnexpectedChar(c)) => return write!(f, "{c}"),
+            Token::Error(LexError::UnfinishedString(s)) => return write!(f, "\"{s}"),
+            Token::Error(LexError::MalformedNumber(s)) => s,
+            Token::Eos => "<eof>",
+        };
+        f.write_str(s)
+    }
+}
+
 pub trait SeekRead: Seek + Read + Debug {}
 impl<T> SeekRead for T where T: Seek + Read + Debug {}
 
+/// Malformed input found by the lexer. It is handed to the parser as a [`Token::Error`] so that
+/// it can be reported together with syntax errors.
+#[derive(Debug, PartialEq)]
+pub enum LexError {
+    UnexpectedChar(char),
+    UnfinishedString(String),
+    MalformedNumber(String),
+}
+
+/// Location of a token in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    /// line of the first character, starting at 1
+    pub line: usize,
+    /// column of the first character, starting at 1
+    pub column: usize,
+    /// byte offset of the first character
+    pub start: usize,
+    /// byte offset after the last character
+    pub end: usize,
+}
+
 #[derive(Debug)]
 pub struct Lexer<'a> {
     stream: &'a mut (dyn SeekRead + 'a),
     /// store next token for parsing purposes
-    ahead: Option<Token>,
+    ahead: Option<(Token, Span)>,
+    /// the token after `ahead`, only read by `peek_second`
+    after_ahead: Option<(Token, Span)>,
+    /// byte offset of the next character to read. Reading past the end of the stream still
+    /// advances it so that seeking back after hitting the end works as everywhere else.
+    pos: usize,
+    /// byte offsets where lines start, used to turn offsets into line and column numbers
+    line_starts: Vec<usize>,
 }
 
 impl<'a> Lexer<'a> {
@@ -92,37 +197,103 @@ impl<'a> Lexer<'a> {
         Self {
             stream,
             ahead: None,
+            after_ahead: None,
+            pos: 0,
+            line_starts: vec![0],
         }
     }
 
     fn seek(&mut self, n: i64) {
-        self.stream.seek(SeekFrom::Current(n)).unwrap();
+        self.pos = (self.pos as i64 + n) as usize;
+        self.stream.seek(SeekFrom::Start(self.pos as u64)).unwrap();
     }
 
     /// return the current ahead token and then parse the next one
     pub fn next(&mut self) -> Token {
-        // the initial value will be Token::Eos -> parse the next into self.ahead
-        if self.ahead == None {
-            self.do_next()
-        } else {
-            let token = self.do_next();
-            mem::replace(&mut self.ahead, Some(token)).unwrap()
+        match self.ahead.take() {
+            Some((token, _)) => {
+                self.ahead = self.after_ahead.take();
+                token
+            }
+            None => self.read_token().0,
+        }
+    }
+
+    /// look at the token after the next one, e.g. for the `=` of a field `name = exp`
+    pub fn peek_second(&mut self) -> &Token {
+        self.peek_with_span();
+        if self.after_ahead.is_none() {
+            let after = self.read_token();
+            self.after_ahead = Some(after);
+        }
+        &self.after_ahead.as_ref().unwrap().0
+    }
+
+    /// look at the next token without consuming it
+    pub fn peek(&mut self) -> &Token {
+        &self.peek_with_span().0
+    }
+
+    /// span of the token returned by `peek`
+    pub fn peek_span(&mut self) -> Span {
+        self.peek_with_span().1
+    }
+
+    fn peek_with_span(&mut self) -> &(Token, Span) {
+        if self.ahead.is_none() {
+            let ahead = self.read_token();
+            self.ahead = Some(ahead);
+        }
+        self.ahead.as_ref().unwrap()
+    }
+
+    fn read_token(&mut self) -> (Token, Span) {
+        self.skip_whitespace();
+        let start = self.pos;
+        let token = self.do_next();
+        let (line, column) = self.line_column(start);
+        let span = Span {
+            line,
+            column,
+            start,
+            end: self.pos,
+        };
+        (token, span)
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.read_char() {
+                ' ' | '\n' | '\t' | '\r' => (),
+                _ => {
+                    self.seek(-1);
+                    break;
+                }
+            }
         }
     }
 
+    fn line_column(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        (line, offset - self.line_starts[line - 1] + 1)
+    }
+
     fn do_next(&mut self) -> Token {
         let c = self.read_char();
         match c {
             '\0' => Token::Eos,
-            ' ' | '\n' | '\t' | '\r' => self.next(),
             '"' => {
                 let mut str = String::new();
                 loop {
                     let c = self.read_char();
-                    if c == '"' {
-                        break;
+                    match c {
+                        '"' => break,
+                        '\n' | '\0' => {
+                            self.seek(-1);
+                            return Token::Error(LexError::UnfinishedString(str));
+                        }
+                        _ => str.push(c),
                     }
-                    str.push(c);
                 }
                 Token::String(str)
             }
@@ -130,33 +301,11 @@ impl<'a> Lexer<'a> {
                 self.seek(-1);
                 self.read_word()
             }
-            '0'..='9' => {
-                let mut str = c.to_string();
-                let mut is_float = false;
-                loop {
-                    let c = self.read_char();
-                    match c {
-                        '0'..='9' => str.push(c),
-                        '.' => {
-                            is_float = true;
-                            str.push(c)
-                        }
-                        _ => {
-                            self.seek(-1);
-                            break;
-                        }
-                    }
-                }
-                if is_float {
-                    Token::Float(str.parse().unwrap())
-                } else {
-                    Token::Integer(str.parse().unwrap())
-                }
-            }
+            '0'..='9' => self.read_numeral(c),
             '+' => Token::Add,
             '-' => Token::Sub,
             '*' => Token::Mul,
-            '/' => Token::Div,
+            '/' => self.check_ahead('/', Token::Idiv, Token::Div),
             '%' => Token::Mod,
             '^' => Token::Pow,
             '#' => Token::Len,
@@ -213,20 +362,79 @@ impl<'a> Lexer<'a> {
                             }
                         }
                     }
+                    '0'..='9' => {
+                        self.seek(-1);
+                        self.read_numeral('.')
+                    }
                     _ => {
                         self.seek(-1);
                         Token::Dot
                     }
                 }
             }
-            c if self.match_pattern(c, "<<") => Token::ShiftL,
-            '<' => Token::Less,
-            c if self.match_pattern(c, ">>") => Token::ShiftR,
-            '>' => Token::Greater,
-            c if self.match_pattern(c, "//") => Token::Idiv,
-            c if self.match_pattern(c, "<=") => Token::LesEq,
-            c if self.match_pattern(c, ">=") => Token::GreEq,
-            _ => panic!("Unexpected char in lexer"),
+            '<' => match self.read_char() {
+                '<' => Token::ShiftL,
+                '=' => Token::LesEq,
+                _ => {
+                    self.seek(-1);
+                    Token::Less
+                }
+            },
+            '>' => match self.read_char() {
+                '>' => Token::ShiftR,
+                '=' => Token::GreEq,
+                _ => {
+                    self.seek(-1);
+                    Token::Greater
+                }
+            },
+            c => Token::Error(LexError::UnexpectedChar(c)),
+        }
+    }
+
+    /// Read a numeral starting with `first` the way reference Lua does: take everything that
+    /// could belong to a number, then convert it as a whole. Integers that do not fit are
+    /// converted to floats, hexadecimal ones wrap around.
+    fn read_numeral(&mut self, first: char) -> Token {
+        let mut str = first.to_string();
+        let mut exponent = ['e', 'E'];
+        if first == '0' {
+            let c = self.read_char();
+            if c == 'x' || c == 'X' {
+                str.push(c);
+                exponent = ['p', 'P'];
+            } else {
+                self.seek(-1);
+            }
+        }
+        loop {
+            let c = self.read_char();
+            if exponent.contains(&c) {
+                str.push(c);
+                let c = self.read_char();
+                if c == '+' || c == '-' {
+                    str.push(c);
+                } else {
+                    self.seek(-1);
+                }
+            } else if c.is_ascii_hexdigit() || c == '.' {
+                str.push(c);
+            } else {
+                self.seek(-1);
+                break;
+            }
+        }
+        // a numeral touching a letter is malformed
+        let c = self.read_char();
+        if c.is_ascii_alphabetic() || c == '_' {
+            str.push(c);
+        } else {
+            self.seek(-1);
+        }
+        match str_to_number(&str) {
+            Some(Value::Integer(i)) => Token::Integer(i),
+            Some(Value::Float(f)) => Token::Float(f),
+            _ => Token::Error(LexError::MalformedNumber(str)),
         }
     }
 
@@ -235,7 +443,7 @@ impl<'a> Lexer<'a> {
         loop {
             let c = self.read_char();
             match c {
-                'A'..='Z' | 'a'..='z' | '_' => word.push(c),
+                'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => word.push(c),
                 _ => {
                     self.seek(-1);
                     break;
@@ -269,32 +477,28 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn match_pattern(&mut self, start: char, pattern: &str) -> bool {
-        let mut n = 0;
-        let mut next_char = start;
-        for c in pattern.chars() {
-            if next_char != c {
-                self.seek(-n);
-                return false;
-            }
-            next_char = self.read_char();
-            n += 1;
+    /// `long` if the next character is `ahead`, otherwise `short` without consuming it
+    fn check_ahead(&mut self, ahead: char, long: Token, short: Token) -> Token {
+        if self.read_char() == ahead {
+            long
+        } else {
+            self.seek(-1);
+            short
         }
-        true
     }
 
     fn read_char(&mut self) -> char {
         let mut buf: [u8; 1] = [0];
+        self.pos += 1;
         if self.stream.read(&mut buf).unwrap() == 1 {
+            if buf[0] == b'\n' && *self.line_starts.last().unwrap() < self.pos {
+                self.line_starts.push(self.pos);
+            }
             buf[0] as char
         } else {
             '\0' // null-byte signifies end of file
         }
     }
-
-    pub fn peek(&mut self) -> Option<&Token> {
-        self.ahead.as_ref()
-    }
 }
 
 #[cfg(test)]
@@ -344,6 +548,41 @@ mod tests {
         assert_eq!(lexer.next(), Token::Float(0.5));
     }
 
+    #[test]
+    fn test_parse_numerals() {
+        let code =
+            "0xff 0xffffffffffffffff 1e2 .5 3. 0x1p4 9223372036854775808 2E-1 5..".to_string();
+        let mut cursor = Cursor::new(code);
+        let mut lexer = Lexer::new(&mut cursor);
+        assert_eq!(lexer.next(), Token::Integer(255));
+        assert_eq!(lexer.next(), Token::Integer(-1));
+        assert_eq!(lexer.next(), Token::Float(100.0));
+        assert_eq!(lexer.next(), Token::Float(0.5));
+        assert_eq!(lexer.next(), Token::Float(3.0));
+        assert_eq!(lexer.next(), Token::Float(16.0));
+        assert_eq!(lexer.next(), Token::Float(9223372036854775808.0));
+        assert_eq!(lexer.next(), Token::Float(0.2));
+        assert_eq!(
+            lexer.next(),
+            Token::Error(LexError::MalformedNumber("5..".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_numeral_touching_a_letter_is_malformed() {
+        let code = "3x 12abc".to_string();
+        let mut cursor = Cursor::new(code);
+        let mut lexer = Lexer::new(&mut cursor);
+        assert_eq!(
+            lexer.next(),
+            Token::Error(LexError::MalformedNumber("3x".to_string()))
+        );
+        assert_eq!(
+            lexer.next(),
+            Token::Error(LexError::MalformedNumber("12abc".to_string()))
+        );
+    }
+
     #[test]
     fn test_parse_dots() {
         let code = ". .. ...".to_string();
@@ -365,6 +604,23 @@ mod tests {
         assert_eq!(lexer.next(), Token::Greater);
     }
 
+    #[test]
+    fn test_parse_two_character_operators() {
+        let code = "a//b<=c>=d a<<1".to_string();
+        let mut cursor = Cursor::new(code);
+        let mut lexer = Lexer::new(&mut cursor);
+        assert_eq!(lexer.next(), Token::Name("a".to_string()));
+        assert_eq!(lexer.next(), Token::Idiv);
+        assert_eq!(lexer.next(), Token::Name("b".to_string()));
+        assert_eq!(lexer.next(), Token::LesEq);
+        assert_eq!(lexer.next(), Token::Name("c".to_string()));
+        assert_eq!(lexer.next(), Token::GreEq);
+        assert_eq!(lexer.next(), Token::Name("d".to_string()));
+        assert_eq!(lexer.next(), Token::Name("a".to_string()));
+        assert_eq!(lexer.next(), Token::ShiftL);
+        assert_eq!(lexer.next(), Token::Integer(1));
+    }
+
     #[test]
     fn test_parse_addition() {
         let code = "5+5".to_string();
@@ -396,4 +652,57 @@ mod tests {
         assert_eq!(lexer.next(), Token::Float(1.5));
         assert_eq!(lexer.next(), Token::ParR);
     }
+
+    #[test]
+    fn test_name_at_end_of_stream() {
+        let code = "print(a) a1".to_string();
+        let mut cursor = Cursor::new(code);
+        let mut lexer = Lexer::new(&mut cursor);
+        assert_eq!(lexer.next(), Token::Name("print".to_string()));
+        assert_eq!(lexer.next(), Token::ParL);
+        assert_eq!(lexer.next(), Token::Name("a".to_string()));
+        assert_eq!(lexer.next(), Token::ParR);
+        assert_eq!(lexer.next(), Token::Name("a1".to_string()));
+        assert_eq!(lexer.next(), Token::Eos);
+    }
+
+    #[test]
+    fn test_peek_and_spans() {
+        let code = "local a\n  = 1".to_string();
+        let mut cursor = Cursor::new(code);
+        let mut lexer = Lexer::new(&mut cursor);
+        assert_eq!(lexer.peek(), &Token::Local);
+        assert_eq!(lexer.next(), Token::Local);
+        assert_eq!(lexer.next(), Token::Name("a".to_string()));
+        assert_eq!(
+            lexer.peek_span(),
+            Span {
+                line: 2,
+                column: 3,
+                start: 10,
+                end: 11
+            }
+        );
+        assert_eq!(lexer.next(), Token::Assign);
+        assert_eq!(lexer.peek_span().line, 2);
+        assert_eq!(lexer.next(), Token::Integer(1));
+        assert_eq!(lexer.next(), Token::Eos);
+    }
+
+    #[test]
+    fn test_lexical_errors() {
+        let code = "\"abc\n1.2.3 @".to_string();
+        let mut cursor = Cursor::new(code);
+        let mut lexer = Lexer::new(&mut cursor);
+        assert_eq!(
+            lexer.next(),
+            Token::Error(LexError::UnfinishedString("abc".to_string()))
+        );
+        assert_eq!(
+            lexer.next(),
+            Token::Error(LexError::MalformedNumber("1.2.3".to_string()))
+        );
+        assert_eq!(lexer.next(), Token::Error(LexError::UnexpectedChar('@')));
+        assert_eq!(lexer.next(), Token::Eos);
+    }
 }
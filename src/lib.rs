@@ -1,13 +1,210 @@
-use std::{fs::File, io::Write};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Cursor, Read, Write},
+};
 
-mod bytecode;
+pub mod bytecode;
+mod compare;
+mod coroutine;
+mod debug_info;
+mod disasm;
+mod dump;
+mod gc;
 mod lexer;
+mod luac;
+mod number;
+mod optimize;
 mod parser;
+mod string;
+mod table;
+mod traceback;
 mod value;
+mod verify;
 mod vm;
 
-pub fn lua<'a>(input: &mut File, output: &'a mut (dyn Write + 'a)) {
-    let proto = parser::load(input);
+pub use coroutine::Thread;
+pub use debug_info::{DebugInfo, LineInfo, LocalVar};
+pub use dump::{dump, undump, UndumpError};
+pub use gc::{DEFAULT_GC_PAUSE, DEFAULT_GC_STEP_MULTIPLIER};
+pub use lexer::Span;
+pub use luac::{dump_luac, undump_luac};
+pub use parser::{chunkid, ParseError, ParseProto};
+pub use string::LuaString;
+pub use table::Table;
+pub use traceback::{FrameInfo, FrameKind, Traceback};
+pub use value::{LuaClosure, NativeClosure, NativeFunction, Value};
+pub use verify::VerifyError;
+pub use vm::{Continuation, ExeState, RuntimeError, DEFAULT_MAX_CALL_DEPTH};
 
-    vm::ExeState::new(output).execute(&proto);
+/// Chunk name used when a reader is loaded without an explicit name, like `load` in reference
+/// Lua does for chunks read through a function.
+const DEFAULT_READER_CHUNKNAME: &str = "=(load)";
+
+/// Options for compiling source code.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// run the peephole optimizer over the compiled bytecode, on by default
+    pub optimize: bool,
+    /// drop debug information, off by default
+    pub strip: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            optimize: true,
+            strip: false,
+        }
+    }
+}
+
+/// Failure to load a chunk from a reader.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Syntax(Vec<ParseError>),
+    Binary(UndumpError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "cannot read chunk: {err}"),
+            LoadError::Syntax(errors) => {
+                let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                f.write_str(&messages.join("\n"))
+            }
+            LoadError::Binary(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+impl From<UndumpError> for LoadError {
+    fn from(err: UndumpError) -> Self {
+        LoadError::Binary(err)
+    }
+}
+
+impl From<Vec<ParseError>> for LoadError {
+    fn from(errors: Vec<ParseError>) -> Self {
+        LoadError::Syntax(errors)
+    }
+}
+
+/// Failure of [`lua`]: the chunk did not compile, or it raised an error it did not catch.
+#[derive(Debug)]
+pub enum LuaError {
+    Syntax(Vec<ParseError>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaError::Syntax(errors) => {
+                let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                f.write_str(&messages.join("\n"))
+            }
+            LuaError::Runtime(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LuaError {}
+
+impl From<Vec<ParseError>> for LuaError {
+    fn from(errors: Vec<ParseError>) -> Self {
+        LuaError::Syntax(errors)
+    }
+}
+
+impl From<RuntimeError> for LuaError {
+    fn from(err: RuntimeError) -> Self {
+        LuaError::Runtime(err)
+    }
+}
+
+pub fn lua<'a>(input: &mut File, output: &'a mut (dyn Write + 'a)) -> Result<(), LuaError> {
+    let proto = compile(input, DEFAULT_READER_CHUNKNAME, &LoadOptions::default())?;
+
+    Ok(execute(&proto, output)?)
+}
+
+/// Compile Lua source held in a string.
+///
+/// Without a `chunkname` the source itself is used, so messages refer to it as
+/// `[string "..."]`.
+pub fn load_str(code: &str, chunkname: Option<&str>) -> Result<ParseProto, Vec<ParseError>> {
+    load_bytes(code.as_bytes(), Some(chunkname.unwrap_or(code)))
+}
+
+/// Compile Lua source held in a byte slice.
+pub fn load_bytes(code: &[u8], chunkname: Option<&str>) -> Result<ParseProto, Vec<ParseError>> {
+    let chunkname = match chunkname {
+        Some(name) => name.to_string(),
+        None => String::from_utf8_lossy(code).into_owned(),
+    };
+    compile(&mut Cursor::new(code), &chunkname, &LoadOptions::default())
+}
+
+/// Compile Lua source from any reader. The whole input is read before parsing starts.
+///
+/// Input starting with the binary chunk signature is loaded as a precompiled chunk written by
+/// [`dump`] instead, or by [`undump_luac`] if it was written by the reference Lua compiler.
+pub fn load_reader(
+    reader: &mut impl Read,
+    chunkname: Option<&str>,
+) -> Result<ParseProto, LoadError> {
+    load_with(reader, chunkname, &LoadOptions::default())
+}
+
+/// Like [`load_reader`], compiling source code according to `options`.
+pub fn load_with(
+    reader: &mut impl Read,
+    chunkname: Option<&str>,
+    options: &LoadOptions,
+) -> Result<ParseProto, LoadError> {
+    let mut code = Vec::new();
+    reader.read_to_end(&mut code)?;
+    if code.starts_with(luac::SIGNATURE) {
+        return Ok(undump_luac(&mut Cursor::new(code))?);
+    }
+    if code.first() == dump::SIGNATURE.first() {
+        return Ok(undump(&mut Cursor::new(code))?);
+    }
+    let chunkname = chunkname.unwrap_or(DEFAULT_READER_CHUNKNAME);
+    Ok(compile(&mut Cursor::new(code), chunkname, options)?)
+}
+
+fn compile(
+    stream: &mut impl lexer::SeekRead,
+    chunkname: &str,
+    options: &LoadOptions,
+) -> Result<ParseProto, Vec<ParseError>> {
+    let mut proto = parser::load(stream, chunkname)?;
+    if options.optimize {
+        proto.optimize();
+    }
+    if options.strip {
+        proto.strip();
+    }
+    Ok(proto)
+}
+
+/// Run a compiled chunk, writing everything it prints to `output`. An error the chunk does
+/// not catch ends it and is returned.
+pub fn execute<'a>(
+    proto: &ParseProto,
+    output: &'a mut (dyn Write + 'a),
+) -> Result<(), RuntimeError> {
+    vm::ExeState::new(output).execute(proto)
 }
@@ -0,0 +1,1792 @@
+//! Binary chunks in the format of the reference Lua 5.4 compiler `luac`.
+//!
+//! Loading maps the official opcodes onto [`ByteCode`], emitting maps them back, so chunks can
+//! be exchanged with the reference toolchain. Only the part of the instruction set that has an
+//! equivalent here is supported; anything else is rejected with an error naming the opcode.
+
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::bytecode::{ByteCode, Instruction, FIELDS_PER_FLUSH};
+use crate::debug_info::{DebugInfo, LineInfo, LocalVar};
+use crate::dump::{UndumpError, Undumper, MAX_NESTING};
+use crate::parser::{add_const, ParseProto};
+use crate::value::Value;
+
+pub const SIGNATURE: &[u8; 4] = b"\x1bLua";
+const VERSION: u8 = 0x54;
+const FORMAT: u8 = 0;
+const DATA: &[u8; 6] = b"\x19\x93\r\n\x1a\n";
+const CHECK_INTEGER: i64 = 0x5678;
+const CHECK_FLOAT: f64 = 370.5;
+
+// constant tags, a basic type in the low nibble and a variant in the high one
+const VNIL: u8 = 0;
+const VFALSE: u8 = 1;
+const VTRUE: u8 = 1 | 1 << 4;
+const VNUMINT: u8 = 3;
+const VNUMFLT: u8 = 3 | 1 << 4;
+const VSHRSTR: u8 = 4;
+const VLNGSTR: u8 = 4 | 1 << 4;
+/// longest string stored as short string by reference Lua (`LUAI_MAXSHORTLEN`)
+const MAX_SHORT_LEN: usize = 40;
+
+/// `sBx` is stored in excess-K notation
+const OFFSET_SBX: i32 = (1 << 16) - 1;
+/// as is `sC`, and `sB`
+const OFFSET_SC: i32 = (1 << 7) - 1;
+/// and `sJ`, which takes up everything but the opcode
+const OFFSET_SJ: i32 = (1 << 24) - 1;
+
+/// metamethod event of `__add`, the events of the other binary operators follow in the order of
+/// their opcodes
+const TM_ADD: u8 = 6;
+
+/// names of the Lua 5.4 opcodes, indexed by opcode
+const OPNAMES: [&str; 83] = [
+    "MOVE",
+    "LOADI",
+    "LOADF",
+    "LOADK",
+    "LOADKX",
+    "LOADFALSE",
+    "LFALSESKIP",
+    "LOADTRUE",
+    "LOADNIL",
+    "GETUPVAL",
+    "SETUPVAL",
+    "GETTABUP",
+    "GETTABLE",
+    "GETI",
+    "GETFIELD",
+    "SETTABUP",
+    "SETTABLE",
+    "SETI",
+    "SETFIELD",
+    "NEWTABLE",
+    "SELF",
+    "ADDI",
+    "ADDK",
+    "SUBK",
+    "MULK",
+    "MODK",
+    "POWK",
+    "DIVK",
+    "IDIVK",
+    "BANDK",
+    "BORK",
+    "BXORK",
+    "SHRI",
+    "SHLI",
+    "ADD",
+    "SUB",
+    "MUL",
+    "MOD",
+    "POW",
+    "DIV",
+    "IDIV",
+    "BAND",
+    "BOR",
+    "BXOR",
+    "SHL",
+    "SHR",
+    "MMBIN",
+    "MMBINI",
+    "MMBINK",
+    "UNM",
+    "BNOT",
+    "NOT",
+    "LEN",
+    "CONCAT",
+    "CLOSE",
+    "TBC",
+    "JMP",
+    "EQ",
+    "LT",
+    "LE",
+    "EQK",
+    "EQI",
+    "LTI",
+    "LEI",
+    "GTI",
+    "GEI",
+    "TEST",
+    "TESTSET",
+    "CALL",
+    "TAILCALL",
+    "RETURN",
+    "RETURN0",
+    "RETURN1",
+    "FORLOOP",
+    "FORPREP",
+    "TFORPREP",
+    "TFORCALL",
+    "TFORLOOP",
+    "SETLIST",
+    "CLOSURE",
+    "VARARG",
+    "VARARGPREP",
+    "EXTRAARG",
+];
+
+const OP_MOVE: u8 = 0;
+const OP_LOADI: u8 = 1;
+const OP_LOADF: u8 = 2;
+const OP_LOADK: u8 = 3;
+const OP_LOADFALSE: u8 = 5;
+const OP_LFALSESKIP: u8 = 6;
+const OP_LOADTRUE: u8 = 7;
+const OP_LOADNIL: u8 = 8;
+const OP_GETUPVAL: u8 = 9;
+const OP_GETTABUP: u8 = 11;
+const OP_GETTABLE: u8 = 12;
+const OP_GETI: u8 = 13;
+const OP_GETFIELD: u8 = 14;
+const OP_SETTABUP: u8 = 15;
+const OP_SETTABLE: u8 = 16;
+const OP_SETI: u8 = 17;
+const OP_SETFIELD: u8 = 18;
+const OP_NEWTABLE: u8 = 19;
+const OP_ADDI: u8 = 21;
+const OP_ADDK: u8 = 22;
+const OP_BXORK: u8 = 31;
+const OP_SHRI: u8 = 32;
+const OP_SHLI: u8 = 33;
+const OP_ADD: u8 = 34;
+const OP_SHR: u8 = 45;
+const OP_MMBIN: u8 = 46;
+const OP_MMBINK: u8 = 48;
+const OP_UNM: u8 = 49;
+const OP_BNOT: u8 = 50;
+const OP_NOT: u8 = 51;
+const OP_CONCAT: u8 = 53;
+const OP_TBC: u8 = 55;
+const OP_JMP: u8 = 56;
+const OP_EQ: u8 = 57;
+const OP_LT: u8 = 58;
+const OP_LE: u8 = 59;
+const OP_EQK: u8 = 60;
+const OP_EQI: u8 = 61;
+const OP_LTI: u8 = 62;
+const OP_LEI: u8 = 63;
+const OP_GTI: u8 = 64;
+const OP_GEI: u8 = 65;
+const OP_TEST: u8 = 66;
+const OP_TESTSET: u8 = 67;
+const OP_CALL: u8 = 68;
+const OP_TAILCALL: u8 = 69;
+const OP_RETURN: u8 = 70;
+const OP_RETURN0: u8 = 71;
+const OP_RETURN1: u8 = 72;
+const OP_SETLIST: u8 = 78;
+const OP_CLOSURE: u8 = 79;
+const OP_VARARG: u8 = 80;
+const OP_VARARGPREP: u8 = 81;
+const OP_EXTRAARG: u8 = 82;
+
+/// An instruction in the Lua 5.4 layout:
+///
+/// ```text
+/// |  C(8)  |  B(8)  |k|  A(8)  |  OP(7) |
+/// |       Bx(17)      |  A(8)  |  OP(7) |
+/// |            sJ(25)          |  OP(7) |
+/// ```
+#[derive(Clone, Copy)]
+struct LuaInstruction(u32);
+
+impl LuaInstruction {
+    fn abc(op: u8, a: u8, b: u8, c: u8) -> Self {
+        LuaInstruction(op as u32 | (a as u32) << 7 | (b as u32) << 16 | (c as u32) << 24)
+    }
+
+    fn abx(op: u8, a: u8, bx: u32) -> Self {
+        LuaInstruction(op as u32 | (a as u32) << 7 | bx << 15)
+    }
+
+    fn asbx(op: u8, a: u8, sbx: i32) -> Self {
+        LuaInstruction::abx(op, a, (sbx + OFFSET_SBX) as u32)
+    }
+
+    fn sj(op: u8, sj: i32) -> Self {
+        LuaInstruction(op as u32 | ((sj + OFFSET_SJ) as u32) << 7)
+    }
+
+    /// set the k flag, which selects the outcome of a test that executes the following jump
+    fn with_k(self) -> Self {
+        LuaInstruction(self.0 | 1 << 15)
+    }
+
+    fn op(self) -> u8 {
+        (self.0 & 0x7f) as u8
+    }
+
+    fn a(self) -> u8 {
+        (self.0 >> 7) as u8
+    }
+
+    fn k(self) -> bool {
+        self.0 >> 15 & 1 == 1
+    }
+
+    fn b(self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    fn c(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    fn bx(self) -> u32 {
+        self.0 >> 15
+    }
+
+    fn sbx(self) -> i32 {
+        self.bx() as i32 - OFFSET_SBX
+    }
+
+    fn sc(self) -> i32 {
+        self.c() as i32 - OFFSET_SC
+    }
+
+    fn sb(self) -> i32 {
+        self.b() as i32 - OFFSET_SC
+    }
+
+    fn get_sj(self) -> i32 {
+        (self.0 >> 7) as i32 - OFFSET_SJ
+    }
+
+    fn name(self) -> &'static str {
+        OPNAMES.get(self.op() as usize).unwrap_or(&"<invalid>")
+    }
+}
+
+/// Load a chunk produced by the reference Lua 5.4 compiler.
+pub fn undump_luac(input: &mut impl Read) -> Result<ParseProto, UndumpError> {
+    let mut undumper = Undumper { input };
+    undumper.luac_header()?;
+    let nupvalues = undumper.byte()?;
+    if nupvalues != 1 {
+        return Err(UndumpError::Unsupported(format!(
+            "main function with {nupvalues} upvalues"
+        )));
+    }
+    // the upvalue of the main function is in the stack of the loader
+    let proto = undumper.luac_function(0, None, Some((1, 0)))?;
+    if undumper.input.read(&mut [0])? != 0 {
+        return Err(UndumpError::Corrupted("trailing data".to_string()));
+    }
+    proto.verify().map_err(UndumpError::Invalid)?;
+    Ok(proto)
+}
+
+impl<'a, R: Read> Undumper<'a, R> {
+    fn luac_header(&mut self) -> Result<(), UndumpError> {
+        let mut signature = [0; 4];
+        self.input.read_exact(&mut signature)?;
+        if &signature != SIGNATURE {
+            return Err(UndumpError::NotABinaryChunk);
+        }
+        let version = self.byte()?;
+        if version != VERSION {
+            return Err(UndumpError::FormatMismatch("Lua version"));
+        }
+        if self.byte()? != FORMAT {
+            return Err(UndumpError::FormatMismatch("format"));
+        }
+        let mut data = [0; 6];
+        self.input.read_exact(&mut data)?;
+        if &data != DATA {
+            return Err(UndumpError::Corrupted("corrupted chunk".to_string()));
+        }
+        let checks = [
+            (std::mem::size_of::<u32>(), "Instruction size"),
+            (std::mem::size_of::<i64>(), "Integer size"),
+            (std::mem::size_of::<f64>(), "Float size"),
+        ];
+        for (size, what) in checks {
+            if self.byte()? as usize != size {
+                return Err(UndumpError::FormatMismatch(what));
+            }
+        }
+        if self.integer()? != CHECK_INTEGER {
+            return Err(UndumpError::FormatMismatch("integer format"));
+        }
+        if self.float()? != CHECK_FLOAT {
+            return Err(UndumpError::FormatMismatch("float format"));
+        }
+        Ok(())
+    }
+
+    /// sizes are stored most significant group first, 7 bits per byte, with the high bit set
+    /// on the last byte
+    fn size(&mut self) -> Result<usize, UndumpError> {
+        let mut x: usize = 0;
+        loop {
+            let b = self.byte()?;
+            if x >= usize::MAX >> 7 {
+                return Err(UndumpError::Corrupted("integer overflow".to_string()));
+            }
+            x = x << 7 | (b & 0x7f) as usize;
+            if b & 0x80 != 0 {
+                return Ok(x);
+            }
+        }
+    }
+
+    fn luac_string(&mut self) -> Result<Option<String>, UndumpError> {
+        match self.size()? {
+            0 => Ok(None),
+            size => self.utf8(size - 1).map(Some),
+        }
+    }
+
+    /// A function nested in `depth` others. Nested functions without a source of their own
+    /// share the one of the enclosing function, `parent_source`. The only upvalue supported is
+    /// `_ENV`, which has to be in the location `env`, `None` if it is not available at all.
+    fn luac_function(
+        &mut self,
+        depth: usize,
+        parent_source: Option<&str>,
+        env: Option<(u8, u8)>,
+    ) -> Result<ParseProto, UndumpError> {
+        if depth > MAX_NESTING {
+            return Err(UndumpError::Corrupted(
+                "functions nested too deep".to_string(),
+            ));
+        }
+        let source = self.luac_string()?;
+        let source = source.or_else(|| parent_source.map(str::to_string));
+        let line_defined = self.line()?;
+        let last_line_defined = self.line()?;
+        let num_params = self.byte()?;
+        let is_vararg = self.byte()? != 0;
+        let max_stack_size = self.byte()?;
+
+        let n = self.size()?;
+        let mut code = Vec::new();
+        for _ in 0..n {
+            code.push(LuaInstruction(self.u32()?));
+        }
+
+        let n = self.size()?;
+        let mut constants = Vec::new();
+        for _ in 0..n {
+            let v = match self.byte()? {
+                VNIL => Value::Nil,
+                VFALSE => Value::Boolean(false),
+                VTRUE => Value::Boolean(true),
+                VNUMINT => Value::Integer(self.integer()?),
+                VNUMFLT => Value::Float(self.float()?),
+                VSHRSTR | VLNGSTR => match self.luac_string()? {
+                    Some(s) => Value::String(s.into()),
+                    None => return Err(UndumpError::Corrupted("missing string".to_string())),
+                },
+                tag => {
+                    return Err(UndumpError::Corrupted(format!(
+                        "invalid constant tag {tag}"
+                    )))
+                }
+            };
+            constants.push(v);
+        }
+
+        // globals are reached through _ENV, the first upvalue of the main function which
+        // nested functions take from their enclosing one
+        let nupvalues = self.size()?;
+        for _ in 0..nupvalues {
+            let (instack, idx, _kind) = (self.byte()?, self.byte()?, self.byte()?);
+            if nupvalues > 1 || env != Some((instack, idx)) {
+                return Err(UndumpError::Unsupported(
+                    "upvalues other than _ENV".to_string(),
+                ));
+            }
+        }
+
+        let n = self.size()?;
+        let mut protos = Vec::new();
+        for _ in 0..n {
+            let env = (nupvalues == 1).then_some((0, 0));
+            let proto = self.luac_function(depth + 1, source.as_deref(), env)?;
+            protos.push(Rc::new(proto));
+        }
+
+        let n = self.size()?;
+        let mut deltas = Vec::new();
+        for _ in 0..n {
+            deltas.push(self.byte()? as i8);
+        }
+        let n = self.size()?;
+        let mut absolute = Vec::new();
+        for _ in 0..n {
+            absolute.push((self.line()?, self.line()?));
+        }
+        let n = self.size()?;
+        let mut locals = Vec::new();
+        for _ in 0..n {
+            let name = self.luac_string()?.unwrap_or_default();
+            locals.push(LocalVar {
+                name,
+                start_pc: self.line()?,
+                end_pc: self.line()?,
+            });
+        }
+        let n = self.size()?;
+        let mut upvalue_names = Vec::new();
+        for _ in 0..n {
+            upvalue_names.push(self.luac_string()?.unwrap_or_default());
+        }
+
+        let mut max_stack_size = max_stack_size;
+        let (byte_codes, origins) = translate(&code, &mut constants, &mut max_stack_size)?;
+
+        // stripped chunks have no line information
+        let debug = if deltas.is_empty() {
+            None
+        } else {
+            if deltas.len() != code.len() {
+                return Err(UndumpError::Corrupted(
+                    "line information does not match code".to_string(),
+                ));
+            }
+            let lines = LineInfo { deltas, absolute }.decode(line_defined);
+            let lines: Vec<u32> = origins.iter().map(|&pc| lines[pc]).collect();
+            // translated instructions before the one a Lua pc refers to
+            let map_pc = |pc: u32| origins.partition_point(|&origin| origin < pc as usize) as u32;
+            for var in locals.iter_mut() {
+                var.start_pc = map_pc(var.start_pc);
+                var.end_pc = map_pc(var.end_pc);
+            }
+            Some(DebugInfo {
+                source: source.unwrap_or_else(|| "=?".to_string()),
+                line_defined,
+                last_line_defined,
+                line_info: LineInfo::encode(line_defined, &lines),
+                locals,
+                upvalue_names,
+            })
+        };
+
+        Ok(ParseProto {
+            constants,
+            byte_codes,
+            max_stack_size,
+            num_params,
+            is_vararg,
+            protos,
+            debug,
+        })
+    }
+
+    fn line(&mut self) -> Result<u32, UndumpError> {
+        u32::try_from(self.size()?)
+            .map_err(|_| UndumpError::Corrupted("integer overflow".to_string()))
+    }
+}
+
+/// Map Lua 5.4 instructions onto ours, appending constants where ours need them. Along with
+/// the translated code goes the Lua pc every instruction was translated from.
+///
+/// Operators with an immediate or constant operand are split into loads into scratch
+/// registers above the frame, which grow `max_stack_size`, and the operator itself.
+///
+/// Lua tests skip the following jump unless their condition has the expected outcome. They
+/// become a comparison into the scratch register and one of our conditional jumps over the
+/// translation of that jump.
+fn translate(
+    code: &[LuaInstruction],
+    constants: &mut Vec<Value>,
+    max_stack_size: &mut u8,
+) -> Result<(Vec<Instruction>, Vec<usize>), UndumpError> {
+    let out_of_code =
+        |pc: usize| UndumpError::Corrupted(format!("jump out of code at instruction {}", pc + 1));
+    let unsupported = |pc: usize, code: LuaInstruction, why: &str| {
+        UndumpError::Unsupported(format!(
+            "opcode {} at instruction {}{why}",
+            code.name(),
+            pc + 1
+        ))
+    };
+    let constant = |index: u32, pc: usize, code: LuaInstruction| {
+        u16::try_from(index).map_err(|_| unsupported(pc, code, ": constant index too large"))
+    };
+
+    // the first of `count` scratch registers
+    let frame = *max_stack_size;
+    let mut scratch = |pc: usize, code: LuaInstruction, count: u8| {
+        let Some(end) = frame.checked_add(count) else {
+            return Err(unsupported(pc, code, ": no register left for the operand"));
+        };
+        *max_stack_size = (*max_stack_size).max(end);
+        Ok(frame)
+    };
+
+    let mut byte_codes: Vec<Instruction> = Vec::new();
+    let mut origins = Vec::new();
+    // where the translation of each Lua instruction starts
+    let mut starts = Vec::new();
+    // our jumps with the Lua pc they go to, resolved once all code is translated
+    let mut jumps = Vec::new();
+    for (pc, &c) in code.iter().enumerate() {
+        starts.push(byte_codes.len());
+        let a = c.a();
+        // skip the next Lua instruction if register `r` is `skip_if`
+        let mut skip = |byte_codes: &mut Vec<Instruction>, r: u8, skip_if: bool| {
+            jumps.push((byte_codes.len(), pc + 2));
+            let test = match skip_if {
+                true => ByteCode::TestOrJump(r, 0),
+                false => ByteCode::TestAndJump(r, 0),
+            };
+            byte_codes.push(test.into());
+        };
+        match c.op() {
+            // extra arguments are kept by every call, there is nothing to prepare here
+            OP_VARARGPREP if pc == 0 => (),
+            OP_MOVE => byte_codes.push(ByteCode::Move(a, c.b()).into()),
+            OP_LOADI => match i16::try_from(c.sbx()) {
+                Ok(i) => byte_codes.push(ByteCode::LoadInteger(a, i).into()),
+                Err(_) => {
+                    let k = add_const(constants, Value::Integer(c.sbx().into()));
+                    let k = constant(k as u32, pc, c)?;
+                    byte_codes.push(ByteCode::LoadConst(a, k).into());
+                }
+            },
+            OP_LOADF => {
+                let k = add_const(constants, Value::Float(c.sbx().into()));
+                let k = constant(k as u32, pc, c)?;
+                byte_codes.push(ByteCode::LoadConst(a, k).into());
+            }
+            OP_LOADK => {
+                let k = constant(c.bx(), pc, c)?;
+                byte_codes.push(ByteCode::LoadConst(a, k).into());
+            }
+            OP_LOADFALSE => byte_codes.push(ByteCode::LoadBool(a, false).into()),
+            OP_LFALSESKIP => {
+                byte_codes.push(ByteCode::LoadBool(a, false).into());
+                jumps.push((byte_codes.len(), pc + 2));
+                byte_codes.push(ByteCode::Jump(0).into());
+            }
+            OP_LOADTRUE => byte_codes.push(ByteCode::LoadBool(a, true).into()),
+            OP_LOADNIL => {
+                for dst in a..=a.saturating_add(c.b()) {
+                    byte_codes.push(ByteCode::LoadNil(dst).into());
+                }
+            }
+            // globals are fields of the _ENV upvalue
+            OP_GETTABUP if c.b() == 0 && !c.k() => match constants.get(c.c() as usize) {
+                Some(Value::String(_)) => {
+                    byte_codes.push(ByteCode::GetGlobal(a, c.c().into()).into())
+                }
+                _ => return Err(unsupported(pc, c, ": key is not a string constant")),
+            },
+            // _ENV copied to a register to be indexed with a long name, see `long_global`
+            OP_GETUPVAL if c.b() == 0 && long_global(code, constants, pc + 2).is_some() => (),
+            OP_GETTABLE | OP_SETTABLE if long_global(code, constants, pc).is_some() => {
+                let k = constant(long_global(code, constants, pc).unwrap(), pc, c)?;
+                if c.op() == OP_GETTABLE {
+                    byte_codes.push(ByteCode::GetGlobal(a, k).into());
+                } else if c.k() {
+                    let r = scratch(pc, c, 1)?;
+                    byte_codes.push(ByteCode::LoadConst(r, c.c().into()).into());
+                    byte_codes.push(ByteCode::SetGlobal(r, k).into());
+                } else {
+                    byte_codes.push(ByteCode::SetGlobal(c.c(), k).into());
+                }
+            }
+            // the sizes of the new table are only hints
+            OP_NEWTABLE => byte_codes.push(ByteCode::NewTable(a).into()),
+            OP_EXTRAARG if pc > 0 && matches!(code[pc - 1].op(), OP_NEWTABLE | OP_SETLIST) => (),
+            OP_GETTABLE => byte_codes.push(ByteCode::GetTable(a, c.b(), c.c()).into()),
+            OP_GETI | OP_GETFIELD => {
+                let r = scratch(pc, c, 1)?;
+                let key = match c.op() {
+                    OP_GETI => ByteCode::LoadInteger(r, c.c().into()),
+                    _ => ByteCode::LoadConst(r, c.c().into()),
+                };
+                byte_codes.push(key.into());
+                byte_codes.push(ByteCode::GetTable(a, c.b(), r).into());
+            }
+            OP_SETTABLE | OP_SETI | OP_SETFIELD => {
+                // an immediate or constant key and a constant value get a scratch register each
+                let count = u8::from(c.op() != OP_SETTABLE) + u8::from(c.k());
+                let first = match count {
+                    0 => 0,
+                    count => scratch(pc, c, count)?,
+                };
+                let key = match c.op() {
+                    OP_SETTABLE => c.b(),
+                    op => {
+                        let load = match op {
+                            OP_SETI => ByteCode::LoadInteger(first, c.b().into()),
+                            _ => ByteCode::LoadConst(first, c.b().into()),
+                        };
+                        byte_codes.push(load.into());
+                        first
+                    }
+                };
+                let src = if c.k() {
+                    let r = first + count - 1;
+                    byte_codes.push(ByteCode::LoadConst(r, c.c().into()).into());
+                    r
+                } else {
+                    c.c()
+                };
+                byte_codes.push(ByteCode::SetTable(a, key, src).into());
+            }
+            // C is the number of items stored before, with multiples of 256 in EXTRAARG if k
+            OP_SETLIST => {
+                let mut stored = c.c() as usize;
+                if c.k() {
+                    let extra = code.get(pc + 1).filter(|e| e.op() == OP_EXTRAARG);
+                    let extra = extra.ok_or_else(|| out_of_code(pc))?;
+                    stored += (extra.0 >> 7) as usize * (u8::MAX as usize + 1);
+                }
+                let batch = stored / FIELDS_PER_FLUSH;
+                if !stored.is_multiple_of(FIELDS_PER_FLUSH) || batch > u8::MAX as usize {
+                    return Err(unsupported(pc, c, ": items not stored in batches"));
+                }
+                let n = match c.b() {
+                    0 => 0,
+                    b => b
+                        .checked_add(1)
+                        .ok_or_else(|| unsupported(pc, c, ": too many items"))?,
+                };
+                byte_codes.push(ByteCode::SetList(a, n, batch as u8).into());
+            }
+            OP_ADD..=OP_SHR => {
+                byte_codes.push(arith_bytecode(c.op() - OP_ADD, a, c.b(), c.c()).into())
+            }
+            OP_ADDK..=OP_BXORK => {
+                let k = constant(c.c().into(), pc, c)?;
+                let r = scratch(pc, c, 1)?;
+                byte_codes.push(ByteCode::LoadConst(r, k).into());
+                byte_codes.push(arith_bytecode(c.op() - OP_ADDK, a, c.b(), r).into());
+            }
+            OP_ADDI | OP_SHRI | OP_SHLI => {
+                let r = scratch(pc, c, 1)?;
+                byte_codes.push(ByteCode::LoadInteger(r, c.sc() as i16).into());
+                let code = match c.op() {
+                    OP_ADDI => ByteCode::Add(a, c.b(), r),
+                    OP_SHRI => ByteCode::ShiftR(a, c.b(), r),
+                    // the immediate is the left operand
+                    _ => ByteCode::ShiftL(a, r, c.b()),
+                };
+                byte_codes.push(code.into());
+            }
+            // metamethod fallbacks of the preceding operator, ours raise errors themselves
+            OP_MMBIN..=OP_MMBINK => (),
+            OP_UNM => byte_codes.push(ByteCode::Neg(a, c.b()).into()),
+            OP_BNOT => byte_codes.push(ByteCode::BitNot(a, c.b()).into()),
+            OP_NOT => byte_codes.push(ByteCode::Not(a, c.b()).into()),
+            // the B registers from A on are concatenated from the right, each result replacing
+            // the left operand
+            OP_CONCAT => {
+                let last = a
+                    .checked_add(c.b())
+                    .and_then(|end| end.checked_sub(1))
+                    .filter(|&last| last > a)
+                    .ok_or_else(|| unsupported(pc, c, ": bad register range"))?;
+                for r in (a..last).rev() {
+                    byte_codes.push(ByteCode::Concat(r, r, r + 1).into());
+                }
+            }
+            OP_JMP => {
+                let target = pc as i64 + 1 + c.get_sj() as i64;
+                let target = usize::try_from(target).map_err(|_| out_of_code(pc))?;
+                jumps.push((byte_codes.len(), target));
+                byte_codes.push(ByteCode::Jump(0).into());
+            }
+            OP_EQ | OP_LT | OP_LE => {
+                let r = scratch(pc, c, 1)?;
+                let code = match c.op() {
+                    OP_EQ => ByteCode::Equal(r, a, c.b()),
+                    OP_LT => ByteCode::LesThan(r, a, c.b()),
+                    _ => ByteCode::LesEq(r, a, c.b()),
+                };
+                byte_codes.push(code.into());
+                skip(&mut byte_codes, r, !c.k());
+            }
+            OP_EQK => {
+                let k = constant(c.b().into(), pc, c)?;
+                let r = scratch(pc, c, 1)?;
+                byte_codes.push(ByteCode::LoadConst(r, k).into());
+                byte_codes.push(ByteCode::Equal(r, a, r).into());
+                skip(&mut byte_codes, r, !c.k());
+            }
+            OP_EQI..=OP_GEI => {
+                // C only tells metamethods whether the immediate was written as a float
+                let r = scratch(pc, c, 1)?;
+                byte_codes.push(ByteCode::LoadInteger(r, c.sb() as i16).into());
+                let code = match c.op() {
+                    OP_EQI => ByteCode::Equal(r, a, r),
+                    OP_LTI => ByteCode::LesThan(r, a, r),
+                    OP_LEI => ByteCode::LesEq(r, a, r),
+                    OP_GTI => ByteCode::LesThan(r, r, a),
+                    _ => ByteCode::LesEq(r, r, a),
+                };
+                byte_codes.push(code.into());
+                skip(&mut byte_codes, r, !c.k());
+            }
+            OP_TEST => skip(&mut byte_codes, a, !c.k()),
+            OP_TESTSET => {
+                skip(&mut byte_codes, c.b(), !c.k());
+                byte_codes.push(ByteCode::Move(a, c.b()).into());
+            }
+            // globals are set as fields of the _ENV upvalue
+            OP_SETTABUP if a == 0 => {
+                if !matches!(constants.get(c.b() as usize), Some(Value::String(_))) {
+                    return Err(unsupported(pc, c, ": key is not a string constant"));
+                }
+                let src = if c.k() {
+                    let r = scratch(pc, c, 1)?;
+                    byte_codes.push(ByteCode::LoadConst(r, c.c().into()).into());
+                    r
+                } else {
+                    c.c()
+                };
+                byte_codes.push(ByteCode::SetGlobal(src, c.b().into()).into());
+            }
+            OP_CALL => byte_codes.push(ByteCode::Call(a, c.b(), c.c()).into()),
+            // C and k have the same meaning as for returns
+            OP_TAILCALL => byte_codes.push(ByteCode::TailCall(a, c.b()).into()),
+            // our functions end implicitly
+            OP_RETURN | OP_RETURN0
+                if pc == code.len() - 1 && (c.op() == OP_RETURN0 || c.b() == 1) => {}
+            // C only tells vararg functions where their frame started, k whether upvalues have
+            // to be closed, neither is needed here
+            OP_RETURN => byte_codes.push(ByteCode::Return(a, c.b()).into()),
+            OP_RETURN0 => byte_codes.push(ByteCode::Return(a, 1).into()),
+            OP_RETURN1 => byte_codes.push(ByteCode::Return(a, 2).into()),
+            OP_CLOSURE => {
+                let index = u16::try_from(c.bx())
+                    .map_err(|_| unsupported(pc, c, ": function index too large"))?;
+                byte_codes.push(ByteCode::Closure(a, index).into());
+            }
+            OP_VARARG => byte_codes.push(ByteCode::VarArgs(a, c.c()).into()),
+            OP_TBC => byte_codes.push(ByteCode::Tbc(a).into()),
+            _ => return Err(unsupported(pc, c, "")),
+        }
+        origins.resize(byte_codes.len(), pc);
+    }
+
+    starts.push(byte_codes.len());
+    for (from, target) in jumps {
+        let Some(&to) = starts.get(target) else {
+            return Err(out_of_code(origins[from]));
+        };
+        let offset = i16::try_from(to as isize - from as isize - 1)
+            .map_err(|_| unsupported(origins[from], code[origins[from]], ": jump too long"))?;
+        byte_codes[from].set_sd(offset);
+    }
+    Ok((byte_codes, origins))
+}
+
+/// The constant naming the global that the GETTABLE or SETTABLE at `pc` reads or writes, if
+/// it indexes _ENV with a string. Only short strings can be the constant key of GETTABUP and
+/// SETTABUP, so reference Lua reaches globals with longer names by copying _ENV to a register
+/// with GETUPVAL and loading the name with LOADK right before.
+fn long_global(code: &[LuaInstruction], constants: &[Value], pc: usize) -> Option<u32> {
+    let c = *code.get(pc)?;
+    let (table, key) = match c.op() {
+        OP_GETTABLE => (c.b(), c.c()),
+        OP_SETTABLE => (c.a(), c.b()),
+        _ => return None,
+    };
+    let (env, name) = (code[pc.checked_sub(2)?], code[pc - 1]);
+    let is_env = env.op() == OP_GETUPVAL && env.a() == table && env.b() == 0;
+    let is_name = name.op() == OP_LOADK && name.a() == key;
+    match constants.get(name.bx() as usize) {
+        Some(Value::String(_)) if is_env && is_name => Some(name.bx()),
+        _ => None,
+    }
+}
+
+/// Write `proto` as a chunk that the reference Lua 5.4 interpreter can run. Prototypes without
+/// debug information are written like `luac -s` does.
+pub fn dump_luac(proto: &ParseProto, output: &mut impl Write) -> io::Result<()> {
+    output.write_all(SIGNATURE)?;
+    output.write_all(&[VERSION, FORMAT])?;
+    output.write_all(DATA)?;
+    output.write_all(&[4, 8, 8])?;
+    output.write_all(&CHECK_INTEGER.to_ne_bytes())?;
+    output.write_all(&CHECK_FLOAT.to_ne_bytes())?;
+    // one upvalue, _ENV
+    output.write_all(&[1])?;
+    dump_luac_function(proto, None, output)
+}
+
+/// Write a function nested in `parent`, `None` for the main function. Nested functions from
+/// the same source as their parent do not repeat it.
+fn dump_luac_function(
+    proto: &ParseProto,
+    parent: Option<&ParseProto>,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    // VARARGPREP belongs to the first line and the final RETURN to the last one
+    let lines = proto
+        .debug
+        .as_ref()
+        .map(|debug| debug.line_info.decode(debug.line_defined))
+        .unwrap_or_default();
+    let mut code = Vec::new();
+    let mut code_lines = Vec::new();
+    if proto.is_vararg {
+        code.push(LuaInstruction::abc(OP_VARARGPREP, proto.num_params, 0, 0));
+        code_lines.push(lines.first().copied().unwrap_or(1));
+    }
+    // vararg functions return with C = number of parameters + 1
+    let nparams1 = match proto.is_vararg {
+        true => proto.num_params + 1,
+        false => 0,
+    };
+    // returns have k set if they have to close variables
+    let close = proto
+        .byte_codes
+        .iter()
+        .any(|c| matches!(c.decode(), ByteCode::Tbc(_)));
+    // registers above the frame that globals with long names are reached through, and that
+    // the operands of concatenations are copied to
+    let scratch = proto.max_stack_size;
+    let mut max_stack = proto.max_stack_size;
+    // where the translation of each of our instructions starts
+    let mut starts = Vec::new();
+    for (pc, c) in proto.byte_codes.iter().enumerate() {
+        starts.push(code.len());
+        let c = c.decode();
+        if let Some(k) = long_global_name(&c, &proto.constants) {
+            max_stack = scratch.checked_add(2).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no register left to reach a global with a long name",
+                )
+            })?;
+            code.push(LuaInstruction::abc(OP_GETUPVAL, scratch, 0, 0));
+            code.push(LuaInstruction::abx(OP_LOADK, scratch + 1, k.into()));
+            code.push(match c {
+                ByteCode::GetGlobal(dst, _) => {
+                    LuaInstruction::abc(OP_GETTABLE, dst, scratch, scratch + 1)
+                }
+                ByteCode::SetGlobal(src, _) => {
+                    LuaInstruction::abc(OP_SETTABLE, scratch, scratch + 1, src)
+                }
+                code => unreachable!("{code:?} does not access a global"),
+            });
+        } else if let ByteCode::Concat(dst, b, c) = c {
+            // CONCAT takes its operands from consecutive registers, and stores into the first
+            max_stack = scratch.checked_add(2).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no register left to concatenate in",
+                )
+            })?;
+            code.push(LuaInstruction::abc(OP_MOVE, scratch, b, 0));
+            code.push(LuaInstruction::abc(OP_MOVE, scratch + 1, c, 0));
+            code.push(LuaInstruction::abc(OP_CONCAT, scratch, 2, 0));
+            code.push(LuaInstruction::abc(OP_MOVE, dst, scratch, 0));
+        } else {
+            luac_code(c, nparams1, close, &mut code);
+        }
+        code_lines.resize(code.len(), lines.get(pc).copied().unwrap_or(0));
+    }
+    starts.push(code.len());
+    // jumps are the last instruction of their translation, and go to the translation of
+    // their target
+    for (pc, c) in proto.byte_codes.iter().enumerate() {
+        if let Some(offset) = c.decode().jump_offset() {
+            let target = (pc as isize + 1 + offset as isize) as usize;
+            let from = starts[pc + 1] - 1;
+            code[from] = LuaInstruction::sj(OP_JMP, starts[target] as i32 - from as i32 - 1);
+        }
+    }
+    code.push(match (proto.is_vararg, close) {
+        (false, false) => LuaInstruction::abc(OP_RETURN0, 0, 1, 0),
+        (_, false) => LuaInstruction::abc(OP_RETURN, 0, 1, nparams1),
+        (_, true) => LuaInstruction::abc(OP_RETURN, 0, 1, nparams1).with_k(),
+    });
+    // the final RETURN belongs to the `end` of a function, or the last line of a chunk
+    let end_line = match &proto.debug {
+        Some(debug) if debug.last_line_defined != 0 => debug.last_line_defined,
+        _ => code_lines.last().copied().unwrap_or(0),
+    };
+    code_lines.push(end_line);
+    // reference Lua needs at least two registers
+    let max_stack = max_stack.max(2);
+
+    // source, linedefined, lastlinedefined, numparams, is_vararg, maxstacksize
+    match &proto.debug {
+        Some(debug) => {
+            let parent_source = parent.and_then(|p| p.debug.as_ref()).map(|d| &d.source);
+            if parent_source == Some(&debug.source) {
+                dump_size(0, output)?;
+            } else {
+                dump_luac_string(&debug.source, output)?;
+            }
+            dump_size(debug.line_defined as usize, output)?;
+            dump_size(debug.last_line_defined as usize, output)?;
+        }
+        None => {
+            for _ in 0..3 {
+                dump_size(0, output)?;
+            }
+        }
+    }
+    output.write_all(&[proto.num_params, proto.is_vararg.into(), max_stack])?;
+
+    dump_size(code.len(), output)?;
+    for c in code {
+        output.write_all(&c.0.to_ne_bytes())?;
+    }
+
+    dump_size(proto.constants.len(), output)?;
+    for constant in proto.constants.iter() {
+        match constant {
+            Value::Nil => output.write_all(&[VNIL])?,
+            Value::Boolean(false) => output.write_all(&[VFALSE])?,
+            Value::Boolean(true) => output.write_all(&[VTRUE])?,
+            Value::Integer(i) => {
+                output.write_all(&[VNUMINT])?;
+                output.write_all(&i.to_ne_bytes())?;
+            }
+            Value::Float(f) => {
+                output.write_all(&[VNUMFLT])?;
+                output.write_all(&f.to_ne_bytes())?;
+            }
+            Value::String(s) => {
+                let tag = if s.len() <= MAX_SHORT_LEN {
+                    VSHRSTR
+                } else {
+                    VLNGSTR
+                };
+                output.write_all(&[tag])?;
+                dump_luac_string(s, output)?;
+            }
+            v @ (Value::Function(_)
+            | Value::LuaFunction(_)
+            | Value::NativeClosure(_)
+            | Value::Thread(_)
+            | Value::Table(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot dump a {} constant", v.type_name()),
+                ))
+            }
+        }
+    }
+
+    // upvalue _ENV, a regular variable: in the stack of the loader for the main function,
+    // the first upvalue of the enclosing function otherwise
+    dump_size(1, output)?;
+    output.write_all(&[parent.is_none().into(), 0, 0])?;
+
+    dump_size(proto.protos.len(), output)?;
+    for nested in proto.protos.iter() {
+        dump_luac_function(nested, Some(proto), output)?;
+    }
+
+    let Some(debug) = &proto.debug else {
+        // stripped debug information: line info, absolute line info, locals, upvalue names
+        for _ in 0..4 {
+            dump_size(0, output)?;
+        }
+        return Ok(());
+    };
+    let line_info = LineInfo::encode(debug.line_defined, &code_lines);
+    dump_size(line_info.deltas.len(), output)?;
+    for &delta in line_info.deltas.iter() {
+        output.write_all(&delta.to_ne_bytes())?;
+    }
+    dump_size(line_info.absolute.len(), output)?;
+    for &(pc, line) in line_info.absolute.iter() {
+        dump_size(pc as usize, output)?;
+        dump_size(line as usize, output)?;
+    }
+
+    // locals alive at the end also cover the final RETURN
+    let end = proto.byte_codes.len();
+    let map_pc = |pc: u32| match pc as usize {
+        pc if pc == end => starts[pc] + 1,
+        pc => starts[pc],
+    };
+    dump_size(debug.locals.len(), output)?;
+    for var in debug.locals.iter() {
+        dump_luac_string(&var.name, output)?;
+        dump_size(map_pc(var.start_pc), output)?;
+        dump_size(map_pc(var.end_pc), output)?;
+    }
+
+    dump_size(debug.upvalue_names.len(), output)?;
+    for name in debug.upvalue_names.iter() {
+        dump_luac_string(name, output)?;
+    }
+    Ok(())
+}
+
+/// Append the Lua 5.4 instructions doing what `code` does. The targets of jumps are filled in
+/// by the caller.
+/// `nparams1` is the C operand of returns and `close` their k flag, see `dump_luac_function`.
+fn luac_code(code: ByteCode, nparams1: u8, close: bool, out: &mut Vec<LuaInstruction>) {
+    if let Some((i, dst, b, c)) = arith_operands(&code) {
+        out.push(LuaInstruction::abc(OP_ADD + i, dst, b, c));
+        // reached only if the operands are not numbers
+        out.push(LuaInstruction::abc(OP_MMBIN, b, c, TM_ADD + i));
+        return;
+    }
+    let lua = match code {
+        // larger indices are reached like long names, see `long_global_name`
+        ByteCode::GetGlobal(dst, k) => LuaInstruction::abc(OP_GETTABUP, dst, 0, k as u8),
+        ByteCode::LoadConst(dst, k) => LuaInstruction::abx(OP_LOADK, dst, k.into()),
+        ByteCode::Call(func, nargs, nresults) => {
+            LuaInstruction::abc(OP_CALL, func, nargs, nresults)
+        }
+        ByteCode::LoadBool(dst, true) => LuaInstruction::abc(OP_LOADTRUE, dst, 0, 0),
+        ByteCode::LoadBool(dst, false) => LuaInstruction::abc(OP_LOADFALSE, dst, 0, 0),
+        ByteCode::LoadNil(dst) => LuaInstruction::abc(OP_LOADNIL, dst, 0, 0),
+        ByteCode::LoadInteger(dst, i) => LuaInstruction::asbx(OP_LOADI, dst, i.into()),
+        ByteCode::Move(dst, src) => LuaInstruction::abc(OP_MOVE, dst, src, 0),
+        ByteCode::Neg(dst, src) => LuaInstruction::abc(OP_UNM, dst, src, 0),
+        ByteCode::BitNot(dst, src) => LuaInstruction::abc(OP_BNOT, dst, src, 0),
+        ByteCode::Not(dst, src) => LuaInstruction::abc(OP_NOT, dst, src, 0),
+        ByteCode::Equal(dst, b, c) => return luac_compare(OP_EQ, true, dst, b, c, out),
+        ByteCode::NotEq(dst, b, c) => return luac_compare(OP_EQ, false, dst, b, c, out),
+        ByteCode::LesThan(dst, b, c) => return luac_compare(OP_LT, true, dst, b, c, out),
+        ByteCode::LesEq(dst, b, c) => return luac_compare(OP_LE, true, dst, b, c, out),
+        ByteCode::Jump(_) => LuaInstruction::sj(OP_JMP, 0),
+        // the jump is taken if the test has the outcome in k, which is truthiness
+        ByteCode::TestAndJump(src, _) => {
+            out.push(LuaInstruction::abc(OP_TEST, src, 0, 0));
+            LuaInstruction::sj(OP_JMP, 0)
+        }
+        ByteCode::TestOrJump(src, _) => {
+            out.push(LuaInstruction::abc(OP_TEST, src, 0, 0).with_k());
+            LuaInstruction::sj(OP_JMP, 0)
+        }
+        ByteCode::TailCall(func, nargs) if close => {
+            LuaInstruction::abc(OP_TAILCALL, func, nargs, nparams1).with_k()
+        }
+        ByteCode::TailCall(func, nargs) => LuaInstruction::abc(OP_TAILCALL, func, nargs, nparams1),
+        ByteCode::Closure(dst, index) => LuaInstruction::abx(OP_CLOSURE, dst, index.into()),
+        ByteCode::Return(first, n) if close => {
+            LuaInstruction::abc(OP_RETURN, first, n, nparams1).with_k()
+        }
+        ByteCode::Return(first, n) => LuaInstruction::abc(OP_RETURN, first, n, nparams1),
+        ByteCode::VarArgs(dst, n) => LuaInstruction::abc(OP_VARARG, dst, 0, n),
+        ByteCode::SetGlobal(src, k) => LuaInstruction::abc(OP_SETTABUP, 0, k as u8, src),
+        ByteCode::Tbc(var) => LuaInstruction::abc(OP_TBC, var, 0, 0),
+        // without size hints
+        ByteCode::NewTable(dst) => {
+            out.push(LuaInstruction::abc(OP_NEWTABLE, dst, 0, 0));
+            LuaInstruction(OP_EXTRAARG.into())
+        }
+        ByteCode::GetTable(dst, table, key) => LuaInstruction::abc(OP_GETTABLE, dst, table, key),
+        ByteCode::SetTable(table, key, src) => LuaInstruction::abc(OP_SETTABLE, table, key, src),
+        ByteCode::SetList(table, n, batch) => {
+            let count = n.saturating_sub(1);
+            let stored = batch as u32 * FIELDS_PER_FLUSH as u32;
+            match u8::try_from(stored) {
+                Ok(stored) => LuaInstruction::abc(OP_SETLIST, table, count, stored),
+                Err(_) => {
+                    let c = (stored % 256) as u8;
+                    out.push(LuaInstruction::abc(OP_SETLIST, table, count, c).with_k());
+                    LuaInstruction(OP_EXTRAARG as u32 | (stored / 256) << 7)
+                }
+            }
+        }
+        code => unreachable!("{code:?} is a binary operator"),
+    };
+    out.push(lua);
+}
+
+/// The constant naming the global that `code` reads or writes, if it cannot be the constant
+/// key of GETTABUP and SETTABUP: the name is too long, or its index does not fit in a byte.
+/// See `long_global`.
+fn long_global_name(code: &ByteCode, constants: &[Value]) -> Option<u16> {
+    let k = match *code {
+        ByteCode::GetGlobal(_, k) | ByteCode::SetGlobal(_, k) => k,
+        _ => return None,
+    };
+    match &constants[k as usize] {
+        Value::String(name) if name.len() > MAX_SHORT_LEN || k > u8::MAX.into() => Some(k),
+        _ => None,
+    }
+}
+
+/// a comparison storing its outcome like reference Lua does: the jump is taken, and true is
+/// loaded, if the comparison is `expected`
+fn luac_compare(op: u8, expected: bool, dst: u8, b: u8, c: u8, out: &mut Vec<LuaInstruction>) {
+    let compare = LuaInstruction::abc(op, b, c, 0);
+    out.push(if expected { compare.with_k() } else { compare });
+    out.push(LuaInstruction::sj(OP_JMP, 1));
+    out.push(LuaInstruction::abc(OP_LFALSESKIP, dst, 0, 0));
+    out.push(LuaInstruction::abc(OP_LOADTRUE, dst, 0, 0));
+}
+
+/// position of a binary operator after ADD in the Lua 5.4 opcodes, and its operands
+fn arith_operands(code: &ByteCode) -> Option<(u8, u8, u8, u8)> {
+    let operands = match *code {
+        ByteCode::Add(dst, b, c) => (0, dst, b, c),
+        ByteCode::Sub(dst, b, c) => (1, dst, b, c),
+        ByteCode::Mul(dst, b, c) => (2, dst, b, c),
+        ByteCode::Mod(dst, b, c) => (3, dst, b, c),
+        ByteCode::Pow(dst, b, c) => (4, dst, b, c),
+        ByteCode::Div(dst, b, c) => (5, dst, b, c),
+        ByteCode::Idiv(dst, b, c) => (6, dst, b, c),
+        ByteCode::BitAnd(dst, b, c) => (7, dst, b, c),
+        ByteCode::BitOr(dst, b, c) => (8, dst, b, c),
+        ByteCode::BitXor(dst, b, c) => (9, dst, b, c),
+        ByteCode::ShiftL(dst, b, c) => (10, dst, b, c),
+        ByteCode::ShiftR(dst, b, c) => (11, dst, b, c),
+        _ => return None,
+    };
+    Some(operands)
+}
+
+/// the binary operator `i` positions after ADD in the Lua 5.4 opcodes, see `arith_operands`
+fn arith_bytecode(i: u8, dst: u8, b: u8, c: u8) -> ByteCode {
+    match i {
+        0 => ByteCode::Add(dst, b, c),
+        1 => ByteCode::Sub(dst, b, c),
+        2 => ByteCode::Mul(dst, b, c),
+        3 => ByteCode::Mod(dst, b, c),
+        4 => ByteCode::Pow(dst, b, c),
+        5 => ByteCode::Div(dst, b, c),
+        6 => ByteCode::Idiv(dst, b, c),
+        7 => ByteCode::BitAnd(dst, b, c),
+        8 => ByteCode::BitOr(dst, b, c),
+        9 => ByteCode::BitXor(dst, b, c),
+        10 => ByteCode::ShiftL(dst, b, c),
+        11 => ByteCode::ShiftR(dst, b, c),
+        _ => unreachable!("not a binary operator: {i}"),
+    }
+}
+
+fn dump_luac_string(s: &str, output: &mut impl Write) -> io::Result<()> {
+    dump_size(s.len() + 1, output)?;
+    output.write_all(s.as_bytes())
+}
+
+fn dump_size(mut x: usize, output: &mut impl Write) -> io::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        buf.push((x & 0x7f) as u8);
+        x >>= 7;
+        if x == 0 {
+            break;
+        }
+    }
+    buf.reverse();
+    *buf.last_mut().unwrap() |= 0x80;
+    output.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn header() -> Vec<u8> {
+        let mut chunk = b"\x1bLua\x54\x00\x19\x93\r\n\x1a\n\x04\x08\x08".to_vec();
+        chunk.extend(CHECK_INTEGER.to_ne_bytes());
+        chunk.extend(CHECK_FLOAT.to_ne_bytes());
+        chunk
+    }
+
+    fn function(code: &[u32], constants: &[u8], lineinfo: &[u8], max_stack: u8) -> Vec<u8> {
+        let mut chunk = header();
+        // one upvalue, source "@hello.lua", lines 0 to 0, no parameters, vararg
+        chunk.push(1);
+        chunk.push(0x80 | 11);
+        chunk.extend(b"@hello.lua");
+        chunk.extend([0x80, 0x80, 0, 1, max_stack]);
+        chunk.push(0x80 | code.len() as u8);
+        for c in code {
+            chunk.extend(c.to_ne_bytes());
+        }
+        chunk.extend(constants);
+        // upvalue _ENV, no nested functions
+        chunk.extend([0x81, 1, 0, 0, 0x80]);
+        chunk.push(0x80 | lineinfo.len() as u8);
+        chunk.extend(lineinfo);
+        // no absolute line info and locals, upvalue name _ENV
+        chunk.extend([0x80, 0x80, 0x81, 0x85]);
+        chunk.extend(b"_ENV");
+        chunk
+    }
+
+    /// what `luac hello.lua` writes for `print "hello world!"`
+    fn hello_world() -> Vec<u8> {
+        let mut constants = vec![0x82, VSHRSTR, 0x86];
+        constants.extend(b"print");
+        constants.extend([VSHRSTR, 0x8d]);
+        constants.extend(b"hello world!");
+        function(
+            &[
+                0x0000_0051, // VARARGPREP 0
+                0x0000_000b, // GETTABUP 0 0 0
+                0x0000_8083, // LOADK 1 1
+                0x0102_0044, // CALL 0 2 1
+                0x0101_0046, // RETURN 0 1 1
+            ],
+            &constants,
+            &[1, 0, 0, 0, 0],
+            2,
+        )
+    }
+
+    fn load(chunk: &[u8]) -> Result<ParseProto, UndumpError> {
+        undump_luac(&mut Cursor::new(chunk))
+    }
+
+    #[test]
+    fn load_luac_output() {
+        let proto = load(&hello_world()).unwrap();
+
+        assert_eq!(
+            proto.constants,
+            vec![
+                Value::String("print".into()),
+                Value::String("hello world!".into())
+            ]
+        );
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::GetGlobal(0, 0),
+                ByteCode::LoadConst(1, 1),
+                ByteCode::Call(0, 2, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn load_immediate_operands() {
+        let chunk = function(
+            &[
+                0x0000_0051,                                 // VARARGPREP 0
+                LuaInstruction::asbx(OP_LOADI, 0, -5).0,     // LOADI 0 -5
+                LuaInstruction::asbx(OP_LOADI, 1, 40000).0,  // LOADI 1 40000
+                LuaInstruction::asbx(OP_LOADF, 2, 2).0,      // LOADF 2 2
+                LuaInstruction::abc(OP_LOADNIL, 3, 1, 0).0,  // LOADNIL 3 1
+                LuaInstruction::abc(OP_LOADTRUE, 5, 0, 0).0, // LOADTRUE 5
+                LuaInstruction::abc(OP_MOVE, 6, 0, 0).0,     // MOVE 6 0
+                LuaInstruction::abc(OP_RETURN0, 0, 0, 0).0,  // RETURN0
+            ],
+            &[0x80],
+            &[],
+            7,
+        );
+        let proto = load(&chunk).unwrap();
+
+        assert_eq!(
+            proto.constants,
+            vec![Value::Integer(40000), Value::Float(2.0)]
+        );
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, -5),
+                ByteCode::LoadConst(1, 0),
+                ByteCode::LoadConst(2, 1),
+                ByteCode::LoadNil(3),
+                ByteCode::LoadNil(4),
+                ByteCode::LoadBool(5, true),
+                ByteCode::Move(6, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_unsupported_opcode() {
+        let chunk = function(
+            &[
+                0x0000_0051,
+                LuaInstruction::abx(4, 0, 0).0, // LOADKX 0
+                LuaInstruction::abc(OP_RETURN0, 0, 0, 0).0,
+            ],
+            &[0x80],
+            &[],
+            2,
+        );
+
+        assert_eq!(
+            load(&chunk).unwrap_err().to_string(),
+            "unsupported chunk (opcode LOADKX at instruction 2)"
+        );
+    }
+
+    #[test]
+    fn load_arithmetic() {
+        // local a = 5; local b = a + a; b = a + 1; b = 1 << a; b = a * 2.5; b = -a
+        let mut constants = vec![0x81, VNUMFLT];
+        constants.extend(2.5f64.to_ne_bytes());
+        let chunk = function(
+            &[
+                0x0000_0051,
+                LuaInstruction::asbx(OP_LOADI, 0, 5).0,
+                LuaInstruction::abc(OP_ADD, 1, 0, 0).0,
+                LuaInstruction::abc(OP_MMBIN, 0, 0, TM_ADD).0,
+                LuaInstruction::abc(OP_ADDI, 1, 0, 128).0,
+                LuaInstruction::abc(47, 0, 1, TM_ADD).0, // MMBINI
+                LuaInstruction::abc(OP_SHLI, 1, 0, 128).0,
+                LuaInstruction::abc(47, 0, 1, TM_ADD + 10).0,
+                LuaInstruction::abc(OP_ADDK + 2, 1, 0, 0).0, // MULK
+                LuaInstruction::abc(OP_MMBINK, 0, 0, TM_ADD + 2).0,
+                LuaInstruction::abc(OP_UNM, 1, 0, 0).0,
+                LuaInstruction::abc(OP_RETURN0, 0, 0, 0).0,
+            ],
+            &constants,
+            &[],
+            2,
+        );
+        let proto = load(&chunk).unwrap();
+
+        assert_eq!(proto.max_stack_size, 3);
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 5),
+                ByteCode::Add(1, 0, 0),
+                ByteCode::LoadInteger(2, 1),
+                ByteCode::Add(1, 0, 2),
+                ByteCode::LoadInteger(2, 1),
+                ByteCode::ShiftL(1, 2, 0),
+                ByteCode::LoadConst(2, 0),
+                ByteCode::Mul(1, 0, 2),
+                ByteCode::Neg(1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn load_concatenation_of_a_register_range() {
+        // local a, b, c = 1, 2, 3; local d = a .. b .. c
+        let chunk = function(
+            &[
+                0x0000_0051,
+                LuaInstruction::asbx(OP_LOADI, 0, 1).0,
+                LuaInstruction::asbx(OP_LOADI, 1, 2).0,
+                LuaInstruction::asbx(OP_LOADI, 2, 3).0,
+                LuaInstruction::abc(OP_MOVE, 3, 0, 0).0,
+                LuaInstruction::abc(OP_MOVE, 4, 1, 0).0,
+                LuaInstruction::abc(OP_MOVE, 5, 2, 0).0,
+                LuaInstruction::abc(OP_CONCAT, 3, 3, 0).0,
+                LuaInstruction::abc(OP_RETURN0, 0, 0, 0).0,
+            ],
+            &[0x80],
+            &[],
+            6,
+        );
+        let proto = load(&chunk).unwrap();
+
+        assert_eq!(
+            proto.byte_codes[6..],
+            [ByteCode::Concat(4, 4, 5), ByteCode::Concat(3, 3, 4)]
+        );
+    }
+
+    #[test]
+    fn emit_arithmetic_with_metamethod_fallback() {
+        let proto = ParseProto {
+            constants: vec![],
+            byte_codes: vec![
+                ByteCode::LoadInteger(0, 7).into(),
+                ByteCode::Idiv(1, 0, 0).into(),
+                ByteCode::BitNot(1, 1).into(),
+            ],
+            max_stack_size: 2,
+            is_vararg: true,
+            ..Default::default()
+        };
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+
+        let mut code = header();
+        code.extend([1, 0x80, 0x80, 0x80, 0, 1, 2, 0x86]);
+        for c in [
+            LuaInstruction::abc(OP_VARARGPREP, 0, 0, 0),
+            LuaInstruction::asbx(OP_LOADI, 0, 7),
+            LuaInstruction::abc(OP_ADD + 6, 1, 0, 0),
+            LuaInstruction::abc(OP_MMBIN, 0, 0, TM_ADD + 6),
+            LuaInstruction::abc(OP_BNOT, 1, 1, 0),
+            LuaInstruction::abc(OP_RETURN, 0, 1, 1),
+        ] {
+            code.extend(c.0.to_ne_bytes());
+        }
+        assert!(chunk.starts_with(&code));
+        assert_eq!(load(&chunk).unwrap(), proto);
+    }
+
+    #[test]
+    fn load_comparisons_and_tests() {
+        // local a = 5; local b = a >= 3; if not b then b = not a end
+        let chunk = function(
+            &[
+                0x0000_0051,
+                LuaInstruction::asbx(OP_LOADI, 0, 5).0,
+                LuaInstruction::abc(OP_GEI, 0, 3 + OFFSET_SC as u8, 0)
+                    .with_k()
+                    .0,
+                LuaInstruction::sj(OP_JMP, 1).0,
+                LuaInstruction::abc(OP_LFALSESKIP, 1, 0, 0).0,
+                LuaInstruction::abc(OP_LOADTRUE, 1, 0, 0).0,
+                LuaInstruction::abc(OP_TEST, 1, 0, 0).0,
+                LuaInstruction::sj(OP_JMP, 1).0,
+                LuaInstruction::abc(OP_NOT, 1, 0, 0).0,
+                LuaInstruction::abc(OP_RETURN0, 0, 0, 0).0,
+            ],
+            &[0x80],
+            &[],
+            2,
+        );
+        let proto = load(&chunk).unwrap();
+
+        assert_eq!(proto.max_stack_size, 3);
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 5),
+                ByteCode::LoadInteger(2, 3),
+                ByteCode::LesEq(2, 2, 0),
+                ByteCode::TestAndJump(2, 1),
+                ByteCode::Jump(2),
+                ByteCode::LoadBool(1, false),
+                ByteCode::Jump(1),
+                ByteCode::LoadBool(1, true),
+                ByteCode::TestOrJump(1, 1),
+                ByteCode::Jump(1),
+                ByteCode::Not(1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn load_table_accesses() {
+        // local t = {1, 2}; t.x = t[1]; t[2] = "x"
+        let mut constants = vec![0x81, VSHRSTR, 0x82];
+        constants.extend(b"x");
+        let chunk = function(
+            &[
+                0x0000_0051,
+                LuaInstruction::abc(OP_NEWTABLE, 0, 0, 2).0,
+                LuaInstruction(OP_EXTRAARG.into()).0,
+                LuaInstruction::asbx(OP_LOADI, 1, 1).0,
+                LuaInstruction::asbx(OP_LOADI, 2, 2).0,
+                LuaInstruction::abc(OP_SETLIST, 0, 2, 0).0,
+                LuaInstruction::abc(OP_GETI, 1, 0, 1).0,
+                LuaInstruction::abc(OP_SETFIELD, 0, 0, 1).0,
+                LuaInstruction::abc(OP_SETI, 0, 2, 0).with_k().0,
+                LuaInstruction::abc(OP_RETURN0, 0, 0, 0).0,
+            ],
+            &constants,
+            &[1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            3,
+        );
+        let proto = load(&chunk).unwrap();
+
+        assert_eq!(proto.max_stack_size, 5);
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::NewTable(0),
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::LoadInteger(2, 2),
+                ByteCode::SetList(0, 3, 0),
+                ByteCode::LoadInteger(3, 1),
+                ByteCode::GetTable(1, 0, 3),
+                ByteCode::LoadConst(3, 0),
+                ByteCode::SetTable(0, 3, 1),
+                ByteCode::LoadInteger(3, 2),
+                ByteCode::LoadConst(4, 0),
+                ByteCode::SetTable(0, 3, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_jump_out_of_code() {
+        let chunk = function(
+            &[
+                0x0000_0051,
+                LuaInstruction::sj(OP_JMP, 5).0,
+                LuaInstruction::abc(OP_RETURN0, 0, 0, 0).0,
+            ],
+            &[0x80],
+            &[],
+            2,
+        );
+
+        assert_eq!(
+            load(&chunk).unwrap_err().to_string(),
+            "bad binary format (jump out of code at instruction 2)"
+        );
+    }
+
+    #[test]
+    fn emit_comparisons_and_jumps() {
+        let proto = ParseProto {
+            constants: vec![],
+            byte_codes: vec![
+                ByteCode::LoadInteger(0, 1).into(),
+                ByteCode::NotEq(1, 0, 0).into(),
+                ByteCode::TestAndJump(1, 1).into(),
+                ByteCode::Not(1, 1).into(),
+                ByteCode::LoadNil(0).into(),
+            ],
+            max_stack_size: 2,
+            is_vararg: true,
+            ..Default::default()
+        };
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+
+        let mut code = header();
+        code.extend([1, 0x80, 0x80, 0x80, 0, 1, 2, 0x8b]);
+        for c in [
+            LuaInstruction::abc(OP_VARARGPREP, 0, 0, 0),
+            LuaInstruction::asbx(OP_LOADI, 0, 1),
+            LuaInstruction::abc(OP_EQ, 0, 0, 0),
+            LuaInstruction::sj(OP_JMP, 1),
+            LuaInstruction::abc(OP_LFALSESKIP, 1, 0, 0),
+            LuaInstruction::abc(OP_LOADTRUE, 1, 0, 0),
+            LuaInstruction::abc(OP_TEST, 1, 0, 0),
+            LuaInstruction::sj(OP_JMP, 1),
+            LuaInstruction::abc(OP_NOT, 1, 1, 0),
+            LuaInstruction::abc(OP_LOADNIL, 0, 0, 0),
+            LuaInstruction::abc(OP_RETURN, 0, 1, 1),
+        ] {
+            code.extend(c.0.to_ne_bytes());
+        }
+        assert!(chunk.starts_with(&code));
+    }
+
+    #[test]
+    fn emitted_logical_operators_behave_the_same() {
+        let source = "local a = 1\nprint(a ~= 1 or not a)\nprint(a >= 1 and a < 2)\n\
+                      print(nil or a > 2 or \"x\" == \"x\")";
+        let proto = crate::load_str(source, None).unwrap();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded.verify(), Ok(()));
+
+        let run = |proto: &ParseProto| {
+            let mut output = Vec::new();
+            crate::execute(proto, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run(&proto), "false\ntrue\ntrue\n");
+        assert_eq!(run(&loaded), run(&proto));
+    }
+
+    #[test]
+    fn emitted_functions_behave_the_same() {
+        let source = "function f(n, ...)\n  last = n\n  return n, ...\nend\n\
+                      local function g(a) return print(a, f(a)) end\n\
+                      print(f(1, 2, 3))\ng(5)\nprint((f(4)))";
+        let proto = crate::load_str(source, None).unwrap();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded.verify(), Ok(()));
+        assert_eq!(loaded.protos.len(), 2);
+        assert_eq!(loaded.protos[0].num_params, 1);
+        assert!(loaded.protos[0].is_vararg && !loaded.protos[1].is_vararg);
+        let tail_call = LuaInstruction::abc(OP_TAILCALL, 1, 0, 0).0;
+        assert!(chunk.windows(4).any(|w| w == tail_call.to_le_bytes()));
+
+        let run = |proto: &ParseProto| {
+            let mut output = Vec::new();
+            crate::execute(proto, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run(&proto), "1\t2\t3\n5\t5\n4\n");
+        assert_eq!(run(&loaded), run(&proto));
+    }
+
+    #[test]
+    fn emitted_tables_behave_the_same() {
+        // the last batch of items is stored after 300 others, more than C can hold
+        let items: Vec<String> = (1..=320).map(|i| i.to_string()).collect();
+        let source = format!(
+            "local t = {{{}, n = 320, [\"k\"] = true}}\n\
+             t.x, t[321] = t[1], t[300] + t[320]\n\
+             print(t[1], t[51], t[301], t[321], t.n, t.k, t.x)",
+            items.join(", ")
+        );
+        let proto = crate::load_str(&source, None).unwrap();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded.verify(), Ok(()));
+
+        let run = |proto: &ParseProto| {
+            let mut output = Vec::new();
+            crate::execute(proto, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run(&proto), "1\t51\t301\t620\t320\ttrue\t1\n");
+        assert_eq!(run(&loaded), run(&proto));
+    }
+
+    #[test]
+    fn emitted_to_be_closed_variables_behave_the_same() {
+        let source = "meta = {__close = function(v) print(\"closed\", v.name) end}\n\
+                      local function f()\n\
+                        local a <close> = setmetatable({name = \"a\"}, meta)\n\
+                        return g()\n\
+                      end\n\
+                      function g() print(\"g\") end\n\
+                      f()";
+        let proto = crate::load_str(source, None).unwrap();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded.verify(), Ok(()));
+        let tbc = LuaInstruction::abc(OP_TBC, 0, 0, 0).0;
+        assert!(chunk.windows(4).any(|w| w == tbc.to_le_bytes()));
+        // returns close the variable
+        let ret = LuaInstruction::abc(OP_RETURN, 1, 0, 0).with_k().0;
+        assert!(chunk.windows(4).any(|w| w == ret.to_le_bytes()));
+
+        let run = |proto: &ParseProto| {
+            let mut output = Vec::new();
+            crate::execute(proto, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run(&proto), "g\nclosed\ta\n");
+        assert_eq!(run(&loaded), run(&proto));
+    }
+
+    #[test]
+    fn emitted_concatenations_behave_the_same() {
+        let source = "local a, b = \"a\", 2\nprint(a .. b .. 0.5, 1 .. a)";
+        let proto = crate::load_str(source, None).unwrap();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+        // the operands are copied to the registers above the frame
+        let concat = LuaInstruction::abc(OP_CONCAT, proto.max_stack_size, 2, 0).0;
+        assert!(chunk.windows(4).any(|w| w == concat.to_le_bytes()));
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded.verify(), Ok(()));
+
+        let run = |proto: &ParseProto| {
+            let mut output = Vec::new();
+            crate::execute(proto, &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run(&proto), "a20.5\t1a\n");
+        assert_eq!(run(&loaded), run(&proto));
+    }
+
+    #[test]
+    fn globals_with_long_names_are_not_gettabup_keys() {
+        let name = "a_global_with_a_name_longer_than_forty_bytes";
+        let source = format!("{name} = 3\nprint({name})");
+        let proto = crate::load_str(&source, None).unwrap();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend(LuaInstruction::abc(OP_GETUPVAL, 2, 0, 0).0.to_le_bytes());
+        expected.extend(LuaInstruction::abx(OP_LOADK, 3, 0).0.to_le_bytes());
+        expected.extend(LuaInstruction::abc(OP_SETTABLE, 2, 3, 0).0.to_le_bytes());
+        assert!(chunk.windows(12).any(|w| w == expected), "{chunk:x?}");
+        let gettabup_long = LuaInstruction::abc(OP_GETTABUP, 1, 0, 0).0;
+        assert!(!chunk.windows(4).any(|w| w == gettabup_long.to_le_bytes()));
+
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded.verify(), Ok(()));
+        let mut output = Vec::new();
+        crate::execute(&loaded, &mut output).unwrap();
+        assert_eq!(output, b"3\n");
+    }
+
+    #[test]
+    fn globals_beyond_the_first_256_constants_are_not_gettabup_keys() {
+        let mut source: String = (0..300).map(|i| format!("x = \"s{i}\"\n")).collect();
+        source.push_str("late = 3\nprint(late)");
+        let proto = crate::load_str(&source, None).unwrap();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+
+        let loaded = load(&chunk).unwrap();
+        assert_eq!(loaded.verify(), Ok(()));
+        assert!(loaded
+            .byte_codes
+            .iter()
+            .any(|c| c.decode() == ByteCode::SetGlobal(0, 301)));
+        let mut output = Vec::new();
+        crate::execute(&loaded, &mut output).unwrap();
+        assert_eq!(output, b"3\n");
+    }
+
+    #[test]
+    fn reject_bad_header() {
+        let mut chunk = hello_world();
+        chunk[4] = 0x53;
+        assert!(matches!(
+            load(&chunk),
+            Err(UndumpError::FormatMismatch("Lua version"))
+        ));
+
+        let chunk = hello_world();
+        for len in 0..chunk.len() {
+            assert!(load(&chunk[..len]).is_err(), "truncated at {len}");
+        }
+    }
+
+    #[test]
+    fn load_debug_information() {
+        let proto = load(&hello_world()).unwrap();
+        let debug = proto.debug.as_ref().unwrap();
+
+        assert_eq!(debug.source, "@hello.lua");
+        assert_eq!(debug.line_info.decode(0), vec![1, 1, 1]);
+        assert_eq!(debug.upvalue_names, vec!["_ENV".to_string()]);
+
+        // luac without -s writes what it read
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+        assert_eq!(chunk, hello_world());
+    }
+
+    #[test]
+    fn emit_stripped_luac_chunk() {
+        let mut proto = load(&hello_world()).unwrap();
+        proto.strip();
+        let mut chunk = Vec::new();
+        dump_luac(&proto, &mut chunk).unwrap();
+
+        // what `luac -s hello.lua` writes for `print "hello world!"`
+        let mut expected = header();
+        expected.extend([1, 0x80, 0x80, 0x80, 0, 1, 2, 0x85]);
+        for c in [
+            0x0000_0051_u32,
+            0x0000_000b,
+            0x0000_8083,
+            0x0102_0044,
+            0x0101_0046,
+        ] {
+            expected.extend(c.to_ne_bytes());
+        }
+        expected.extend([0x82, VSHRSTR, 0x86]);
+        expected.extend(b"print");
+        expected.extend([VSHRSTR, 0x8d]);
+        expected.extend(b"hello world!");
+        expected.extend([0x81, 1, 0, 0, 0x80, 0x80, 0x80, 0x80, 0x80]);
+        assert_eq!(chunk, expected);
+
+        assert_eq!(load(&chunk).unwrap(), proto);
+    }
+
+    #[test]
+    fn sizes_are_big_endian_groups_of_seven_bits() {
+        let mut buf = Vec::new();
+        dump_size(300, &mut buf).unwrap();
+        assert_eq!(buf, [0x02, 0x80 | 0x2c]);
+
+        let mut undumper = Undumper {
+            input: &mut Cursor::new(buf),
+        };
+        assert_eq!(undumper.size().unwrap(), 300);
+    }
+}
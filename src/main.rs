@@ -1,21 +1,50 @@
 use std::env;
 use std::fs::File;
 use std::io::stdout;
+use std::process::exit;
 
-mod bytecode;
-mod lexer;
-mod parser;
-mod value;
-mod vm;
+use lua_interpreter::{execute, load_with, LoadOptions};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} script", args[0]);
+    let usage = || {
+        println!(
+            "Usage: {} [--list] [--no-optimize] [--strip] script",
+            args[0]
+        );
+    };
+    // `--list` prints the compiled bytecode instead of running the script,
+    // `--no-optimize` skips the peephole optimizer, `--strip` drops debug information
+    let mut list = false;
+    let mut options = LoadOptions::default();
+    let Some((script, flags)) = args[1..].split_last() else {
+        usage();
         return;
+    };
+    for flag in flags {
+        match flag.as_str() {
+            "--list" => list = true,
+            "--no-optimize" => options.optimize = false,
+            "--strip" => options.strip = true,
+            _ => {
+                usage();
+                return;
+            }
+        }
     }
-    let mut file = File::open(&args[1]).unwrap();
+    let mut file = File::open(script).unwrap();
 
-    let proto = parser::load(&mut file);
-    vm::ExeState::new(&mut stdout()).execute(&proto);
+    let proto = match load_with(&mut file, Some(&format!("@{script}")), &options) {
+        Ok(proto) => proto,
+        Err(err) => {
+            eprintln!("{}: {err}", args[0]);
+            exit(1);
+        }
+    };
+    if list {
+        print!("{}", proto.disassemble());
+    } else if let Err(err) = execute(&proto, &mut stdout()) {
+        eprintln!("{}: {err}\n{}", args[0], err.traceback);
+        exit(1);
+    }
 }
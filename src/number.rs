@@ -0,0 +1,660 @@
+//! Lua numbers: the arithmetic and bitwise operators with the exact semantics of Lua 5.4, and
+//! the conversions between strings, integers and floats they rely on.
+//!
+//! Integers wrap around on overflow, `/` and `^` always produce floats, `//` and `%` round
+//! towards minus infinity, and bitwise operators only accept floats with an exact integer
+//! value. Strings are converted to numbers for both, like the VM of reference Lua does for
+//! bitwise operators and the string metamethods do for arithmetic.
+
+use std::fmt;
+
+use crate::value::Value;
+
+/// Arithmetic and bitwise operators. `Unm` and `BNot` are unary, all others are binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    Idiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+}
+
+impl ArithOp {
+    pub fn is_bitwise(self) -> bool {
+        matches!(
+            self,
+            ArithOp::BAnd
+                | ArithOp::BOr
+                | ArithOp::BXor
+                | ArithOp::Shl
+                | ArithOp::Shr
+                | ArithOp::BNot
+        )
+    }
+}
+
+/// Failure of an arithmetic or bitwise operator. `operand` is 0 for the first operand and 1
+/// for the second, unary operators report 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArithError {
+    /// the operand is not a number, nor a string convertible to one for arithmetic
+    BadOperand {
+        operand: usize,
+        type_name: &'static str,
+        bitwise: bool,
+    },
+    /// a float operand of a bitwise operator has a fractional part or is out of range
+    NoIntegerRep { operand: usize },
+    /// integer `//` by zero
+    DivideByZero,
+    /// integer `%` by zero
+    ModuloByZero,
+}
+
+impl fmt::Display for ArithError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArithError::BadOperand {
+                type_name,
+                bitwise: false,
+                ..
+            } => write!(f, "attempt to perform arithmetic on a {type_name} value"),
+            ArithError::BadOperand {
+                type_name,
+                bitwise: true,
+                ..
+            } => write!(
+                f,
+                "attempt to perform bitwise operation on a {type_name} value"
+            ),
+            ArithError::NoIntegerRep { .. } => write!(f, "number has no integer representation"),
+            ArithError::DivideByZero => write!(f, "attempt to perform 'n//0'"),
+            ArithError::ModuloByZero => write!(f, "attempt to perform 'n%0'"),
+        }
+    }
+}
+
+impl std::error::Error for ArithError {}
+
+/// Apply `op` to `a` and `b`. Unary operators ignore `b`.
+pub fn arith(op: ArithOp, a: &Value, b: &Value) -> Result<Value, ArithError> {
+    let b = match op {
+        ArithOp::Unm | ArithOp::BNot => a,
+        _ => b,
+    };
+    if op.is_bitwise() {
+        return bitwise(op, a, b);
+    }
+
+    let bad = |operand: usize, v: &Value| ArithError::BadOperand {
+        operand,
+        type_name: v.type_name(),
+        bitwise: false,
+    };
+    let x = to_number(a).ok_or_else(|| bad(0, a))?;
+    let y = to_number(b).ok_or_else(|| bad(1, b))?;
+    match (x, y) {
+        (Value::Integer(x), Value::Integer(y)) if !matches!(op, ArithOp::Div | ArithOp::Pow) => {
+            int_arith(op, x, y).map(Value::Integer)
+        }
+        (x, y) => Ok(Value::Float(float_arith(op, to_float(&x), to_float(&y)))),
+    }
+}
+
+fn int_arith(op: ArithOp, x: i64, y: i64) -> Result<i64, ArithError> {
+    let v = match op {
+        ArithOp::Add => x.wrapping_add(y),
+        ArithOp::Sub => x.wrapping_sub(y),
+        ArithOp::Mul => x.wrapping_mul(y),
+        ArithOp::Idiv => {
+            if y == 0 {
+                return Err(ArithError::DivideByZero);
+            }
+            // wrapping_div only overflows for MIN / -1, which wraps to MIN like in Lua
+            let q = x.wrapping_div(y);
+            if x.wrapping_rem(y) != 0 && (x ^ y) < 0 {
+                q - 1
+            } else {
+                q
+            }
+        }
+        ArithOp::Mod => {
+            if y == 0 {
+                return Err(ArithError::ModuloByZero);
+            }
+            // wrapping_rem only overflows for MIN % -1, which is 0
+            let r = x.wrapping_rem(y);
+            if r != 0 && (r ^ y) < 0 {
+                r + y
+            } else {
+                r
+            }
+        }
+        ArithOp::Unm => x.wrapping_neg(),
+        _ => unreachable!("{op:?} is not an integer operation"),
+    };
+    Ok(v)
+}
+
+fn float_arith(op: ArithOp, x: f64, y: f64) -> f64 {
+    match op {
+        ArithOp::Add => x + y,
+        ArithOp::Sub => x - y,
+        ArithOp::Mul => x * y,
+        ArithOp::Div => x / y,
+        ArithOp::Pow => x.powf(y),
+        ArithOp::Idiv => (x / y).floor(),
+        ArithOp::Mod => {
+            let m = x % y;
+            // the result takes the sign of the divisor
+            if (m > 0.0 && y < 0.0) || (m < 0.0 && y > 0.0) {
+                m + y
+            } else {
+                m
+            }
+        }
+        ArithOp::Unm => -x,
+        _ => unreachable!("{op:?} is not a float operation"),
+    }
+}
+
+/// Operands are converted like `luaV_tointegerns` does, strings included. If that fails for
+/// one of them the error is the one of `luaT_trybinTM`: an operand that is not a number is
+/// blamed before one without an integer value.
+fn bitwise(op: ArithOp, a: &Value, b: &Value) -> Result<Value, ArithError> {
+    let integer = |v: &Value| to_number(v).as_ref().and_then(to_integer);
+    let (Some(x), Some(y)) = (integer(a), integer(b)) else {
+        let is_number = |v: &Value| matches!(v, Value::Integer(_) | Value::Float(_));
+        if is_number(a) && is_number(b) {
+            let operand = if to_integer(b).is_none() { 1 } else { 0 };
+            return Err(ArithError::NoIntegerRep { operand });
+        }
+        let (operand, v) = if is_number(a) { (1, b) } else { (0, a) };
+        return Err(ArithError::BadOperand {
+            operand,
+            type_name: v.type_name(),
+            bitwise: true,
+        });
+    };
+    let v = match op {
+        ArithOp::BAnd => x & y,
+        ArithOp::BOr => x | y,
+        ArithOp::BXor => x ^ y,
+        ArithOp::Shl => shift_left(x, y),
+        ArithOp::Shr => shift_left(x, y.wrapping_neg()),
+        ArithOp::BNot => !x,
+        _ => unreachable!("{op:?} is not a bitwise operation"),
+    };
+    Ok(Value::Integer(v))
+}
+
+/// Logical shift, to the right for negative `y`. Shifting by 64 bits or more gives 0.
+fn shift_left(x: i64, y: i64) -> i64 {
+    if y <= -64 || y >= 64 {
+        0
+    } else if y >= 0 {
+        ((x as u64) << y) as i64
+    } else {
+        ((x as u64) >> -y) as i64
+    }
+}
+
+/// The number `v` is or converts to, strings are converted with [`str_to_number`].
+pub fn to_number(v: &Value) -> Option<Value> {
+    match v {
+        Value::Integer(_) | Value::Float(_) => Some(v.clone()),
+        Value::String(s) => str_to_number(s),
+        _ => None,
+    }
+}
+
+fn to_float(v: &Value) -> f64 {
+    match *v {
+        Value::Integer(i) => i as f64,
+        Value::Float(f) => f,
+        _ => unreachable!("not a number: {v:?}"),
+    }
+}
+
+/// The integer value of a number, floats only convert if they have an exact integer value.
+pub fn to_integer(v: &Value) -> Option<i64> {
+    match *v {
+        Value::Integer(i) => Some(i),
+        Value::Float(f) => float_to_integer(f),
+        _ => None,
+    }
+}
+
+/// `f` as an integer if it has an integral value in the range of integers
+pub fn float_to_integer(f: f64) -> Option<i64> {
+    // -2^63 is exact as a float, 2^63 is the first float that does not fit
+    if f.floor() == f && (-9223372036854775808.0..9223372036854775808.0).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+/// Format a float the way `tostring` does in reference Lua: with `%.14g`, and `.0` appended
+/// when that looks like an integer, so the result reads back as a float.
+pub fn float_to_str(f: f64) -> String {
+    let mut s = format_g14(f);
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    s
+}
+
+/// C's `printf("%.14g", f)`.
+fn format_g14(f: f64) -> String {
+    const PRECISION: i32 = 14;
+    if f.is_nan() {
+        // printf shows the sign of NaNs, like the one 0/0 produces on x86
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    // the exponent once rounded to the precision, which decides the style
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, f);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-4..PRECISION).contains(&exponent) {
+        let fixed = format!("{:.*}", (PRECISION - 1 - exponent) as usize, f);
+        trim_fraction(&fixed).to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{sign}{:02}",
+            trim_fraction(mantissa),
+            exponent.unsigned_abs()
+        )
+    }
+}
+
+/// `%g` drops trailing zeros of the fraction, and the point if none are left
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Convert a string to a number following the rules of reference Lua: surrounding whitespace is
+/// allowed, integers are decimal or hexadecimal, decimal integers that overflow become floats,
+/// hexadecimal ones wrap around, and floats may use a hexadecimal mantissa with a binary
+/// exponent. `inf` and `nan` are not numbers.
+pub fn str_to_number(s: &str) -> Option<Value> {
+    let s = s.trim_matches(is_lua_space);
+    str_to_integer(s)
+        .map(Value::Integer)
+        .or_else(|| str_to_float(s).map(Value::Float))
+}
+
+/// whitespace as defined by C's `isspace`
+fn is_lua_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\x0b' | '\x0c' | '\r')
+}
+
+fn split_sign(s: &str) -> (bool, &str) {
+    match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    }
+}
+
+fn strip_hex_prefix(s: &str) -> Option<&str> {
+    s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))
+}
+
+fn str_to_integer(s: &str) -> Option<i64> {
+    let (neg, digits) = split_sign(s);
+    let value = if let Some(hex) = strip_hex_prefix(digits) {
+        if hex.is_empty() {
+            return None;
+        }
+        let mut a: u64 = 0;
+        for c in hex.chars() {
+            a = a.wrapping_mul(16).wrapping_add(c.to_digit(16)? as u64);
+        }
+        a
+    } else {
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // the magnitude of -2^63 is accepted, anything larger is a float
+        let a: u64 = digits.parse().ok()?;
+        if a > i64::MAX as u64 + neg as u64 {
+            return None;
+        }
+        a
+    };
+    let value = value as i64;
+    Some(if neg { value.wrapping_neg() } else { value })
+}
+
+fn str_to_float(s: &str) -> Option<f64> {
+    if s.contains(['n', 'N']) {
+        return None;
+    }
+    let (neg, rest) = split_sign(s);
+    let f = match strip_hex_prefix(rest) {
+        Some(hex) => hex_to_float(hex)?,
+        None => {
+            // Rust accepts a second sign that strtod would reject
+            if rest.starts_with(['+', '-']) {
+                return None;
+            }
+            rest.parse().ok()?
+        }
+    };
+    Some(if neg { -f } else { f })
+}
+
+/// `[mantissa][.fraction][p[sign]exponent]` with a hexadecimal mantissa and a decimal binary
+/// exponent, like C's `strtod` reads after the `0x`
+fn hex_to_float(s: &str) -> Option<f64> {
+    // digits beyond this are only counted, they can not change the result
+    const MAX_SIG_DIGITS: u32 = 30;
+    let (mantissa, exponent) = match s.find(['p', 'P']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let mut r = 0.0;
+    let mut exp: i64 = 0;
+    let mut sig_digits = 0;
+    let mut any_digit = false;
+    let mut seen_dot = false;
+    for c in mantissa.chars() {
+        if c == '.' {
+            if seen_dot {
+                return None;
+            }
+            seen_dot = true;
+            continue;
+        }
+        let d = c.to_digit(16)?;
+        any_digit = true;
+        if sig_digits == 0 && d == 0 {
+            // leading zeros are not significant
+            if seen_dot {
+                exp -= 4;
+            }
+        } else if sig_digits < MAX_SIG_DIGITS {
+            sig_digits += 1;
+            r = r * 16.0 + d as f64;
+            if seen_dot {
+                exp -= 4;
+            }
+        } else if !seen_dot {
+            exp += 4;
+        }
+    }
+    if !any_digit {
+        return None;
+    }
+    if let Some(exponent) = exponent {
+        let (neg, digits) = split_sign(exponent);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // saturate, the result is 0 or infinity long before this
+        let e: i64 = digits.parse().unwrap_or(i64::from(i32::MAX));
+        exp = exp.saturating_add(if neg { -e } else { e });
+    }
+    Some(ldexp(r, exp))
+}
+
+/// `x * 2^exp` without overflowing in intermediate steps
+fn ldexp(mut x: f64, mut exp: i64) -> f64 {
+    while exp > 1000 && x.is_finite() && x != 0.0 {
+        x *= 2f64.powi(1000);
+        exp -= 1000;
+    }
+    while exp < -1000 && x != 0.0 {
+        x *= 2f64.powi(-1000);
+        exp += 1000;
+    }
+    x * 2f64.powi(exp as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> Value {
+        Value::Integer(i)
+    }
+
+    fn float(f: f64) -> Value {
+        Value::Float(f)
+    }
+
+    fn str(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn integers_stay_integers_and_wrap_around() {
+        assert_eq!(arith(ArithOp::Add, &int(1), &int(2)), Ok(int(3)));
+        assert_eq!(
+            arith(ArithOp::Add, &int(i64::MAX), &int(1)),
+            Ok(int(i64::MIN))
+        );
+        assert_eq!(
+            arith(ArithOp::Mul, &int(i64::MIN), &int(-1)),
+            Ok(int(i64::MIN))
+        );
+        assert_eq!(
+            arith(ArithOp::Unm, &int(i64::MIN), &Value::Nil),
+            Ok(int(i64::MIN))
+        );
+        assert_eq!(arith(ArithOp::Add, &int(1), &float(2.0)), Ok(float(3.0)));
+    }
+
+    #[test]
+    fn division_and_power_always_give_floats() {
+        assert_eq!(arith(ArithOp::Div, &int(6), &int(3)), Ok(float(2.0)));
+        assert_eq!(arith(ArithOp::Pow, &int(2), &int(10)), Ok(float(1024.0)));
+        assert_eq!(
+            arith(ArithOp::Div, &int(1), &int(0)),
+            Ok(float(f64::INFINITY))
+        );
+        assert_eq!(
+            arith(ArithOp::Div, &int(-1), &int(0)),
+            Ok(float(f64::NEG_INFINITY))
+        );
+        let Ok(Value::Float(nan)) = arith(ArithOp::Div, &int(0), &int(0)) else {
+            panic!("0/0 is a float");
+        };
+        assert!(nan.is_nan());
+    }
+
+    #[test]
+    fn floor_division_and_modulo_round_down() {
+        assert_eq!(arith(ArithOp::Idiv, &int(7), &int(2)), Ok(int(3)));
+        assert_eq!(arith(ArithOp::Idiv, &int(-7), &int(2)), Ok(int(-4)));
+        assert_eq!(arith(ArithOp::Idiv, &int(7), &int(-2)), Ok(int(-4)));
+        assert_eq!(
+            arith(ArithOp::Idiv, &int(i64::MIN), &int(-1)),
+            Ok(int(i64::MIN))
+        );
+        assert_eq!(arith(ArithOp::Mod, &int(-7), &int(2)), Ok(int(1)));
+        assert_eq!(arith(ArithOp::Mod, &int(7), &int(-2)), Ok(int(-1)));
+        assert_eq!(arith(ArithOp::Mod, &int(i64::MIN), &int(-1)), Ok(int(0)));
+
+        assert_eq!(arith(ArithOp::Idiv, &float(-7.0), &int(2)), Ok(float(-4.0)));
+        assert_eq!(arith(ArithOp::Mod, &float(-7.5), &int(2)), Ok(float(0.5)));
+        assert_eq!(arith(ArithOp::Mod, &float(7.5), &int(-2)), Ok(float(-0.5)));
+        assert_eq!(
+            arith(ArithOp::Mod, &int(5), &float(f64::INFINITY)),
+            Ok(float(5.0))
+        );
+        assert_eq!(
+            arith(ArithOp::Mod, &int(-5), &float(f64::INFINITY)),
+            Ok(float(f64::INFINITY))
+        );
+        assert_eq!(
+            arith(ArithOp::Idiv, &int(1), &float(0.0)),
+            Ok(float(f64::INFINITY))
+        );
+    }
+
+    #[test]
+    fn integer_division_by_zero_is_an_error() {
+        let err = arith(ArithOp::Idiv, &int(1), &int(0)).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to perform 'n//0'");
+        let err = arith(ArithOp::Mod, &int(1), &int(0)).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to perform 'n%0'");
+    }
+
+    #[test]
+    fn bitwise_operators_need_integral_numbers() {
+        assert_eq!(
+            arith(ArithOp::BAnd, &int(0b1100), &int(0b1010)),
+            Ok(int(0b1000))
+        );
+        assert_eq!(arith(ArithOp::BOr, &float(8.0), &int(1)), Ok(int(9)));
+        assert_eq!(arith(ArithOp::BXor, &int(5), &int(3)), Ok(int(6)));
+        assert_eq!(arith(ArithOp::BNot, &int(0), &Value::Nil), Ok(int(-1)));
+
+        let err = arith(ArithOp::BAnd, &int(1), &float(1.5)).unwrap_err();
+        assert_eq!(err, ArithError::NoIntegerRep { operand: 1 });
+        assert_eq!(err.to_string(), "number has no integer representation");
+        assert_eq!(
+            arith(ArithOp::BOr, &float(1e100), &int(1)),
+            Err(ArithError::NoIntegerRep { operand: 0 })
+        );
+
+        assert_eq!(arith(ArithOp::BOr, &str("3"), &int(1)), Ok(int(3)));
+        assert_eq!(arith(ArithOp::Shl, &str("10"), &int(1)), Ok(int(20)));
+        assert_eq!(arith(ArithOp::BAnd, &int(7), &str(" 0x6 ")), Ok(int(6)));
+        assert_eq!(arith(ArithOp::BNot, &str("2.0"), &Value::Nil), Ok(int(-3)));
+
+        let err = arith(ArithOp::BAnd, &str("abc"), &int(1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "attempt to perform bitwise operation on a string value"
+        );
+        // a string without an integer value is blamed as a string, not as a number
+        assert_eq!(
+            arith(ArithOp::BOr, &int(1), &str("1.5")),
+            Err(ArithError::BadOperand {
+                operand: 1,
+                type_name: "string",
+                bitwise: true
+            })
+        );
+    }
+
+    #[test]
+    fn shifts_are_logical_and_saturate() {
+        assert_eq!(arith(ArithOp::Shl, &int(1), &int(4)), Ok(int(16)));
+        assert_eq!(arith(ArithOp::Shl, &int(1), &int(63)), Ok(int(i64::MIN)));
+        assert_eq!(arith(ArithOp::Shl, &int(1), &int(64)), Ok(int(0)));
+        assert_eq!(arith(ArithOp::Shr, &int(-1), &int(60)), Ok(int(15)));
+        assert_eq!(arith(ArithOp::Shr, &int(16), &int(-2)), Ok(int(64)));
+        assert_eq!(arith(ArithOp::Shl, &int(16), &int(i64::MIN)), Ok(int(0)));
+        assert_eq!(arith(ArithOp::Shr, &int(16), &int(i64::MIN)), Ok(int(0)));
+    }
+
+    #[test]
+    fn strings_are_converted_for_arithmetic() {
+        assert_eq!(arith(ArithOp::Add, &str("10"), &int(1)), Ok(int(11)));
+        assert_eq!(arith(ArithOp::Mul, &str(" 0x10 "), &str("2")), Ok(int(32)));
+        assert_eq!(arith(ArithOp::Add, &str("1.5"), &int(1)), Ok(float(2.5)));
+        assert_eq!(arith(ArithOp::Unm, &str("2"), &Value::Nil), Ok(int(-2)));
+
+        let err = arith(ArithOp::Add, &int(1), &str("abc")).unwrap_err();
+        assert_eq!(
+            err,
+            ArithError::BadOperand {
+                operand: 1,
+                type_name: "string",
+                bitwise: false
+            }
+        );
+        let err = arith(ArithOp::Sub, &Value::Nil, &int(1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "attempt to perform arithmetic on a nil value"
+        );
+    }
+
+    #[test]
+    fn floats_are_formatted_like_reference_lua() {
+        let cases = [
+            (1.5, "1.5"),
+            (3.0, "3.0"),
+            (-0.0, "-0.0"),
+            (0.1 + 0.2, "0.3"),
+            (1e15, "1e+15"),
+            (1e14, "1e+14"),
+            (123456789012345.0, "1.2345678901234e+14"),
+            (12345678901234.0, "12345678901234.0"),
+            (1e100, "1e+100"),
+            (2f64.powi(53), "9.007199254741e+15"),
+            (1e-5, "1e-05"),
+            (0.0001, "0.0001"),
+            (-1.25e-300, "-1.25e-300"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+            (f64::NAN, "nan"),
+            (-f64::NAN, "-nan"),
+            (f64::MAX, "1.7976931348623e+308"),
+            (5e-324, "4.9406564584125e-324"),
+        ];
+        for (f, expected) in cases {
+            assert_eq!(float_to_str(f), expected, "{f:e}");
+        }
+    }
+
+    #[test]
+    fn convert_strings_to_numbers() {
+        assert_eq!(str_to_number("42"), Some(int(42)));
+        assert_eq!(str_to_number("  -42\n"), Some(int(-42)));
+        assert_eq!(str_to_number("9223372036854775807"), Some(int(i64::MAX)));
+        assert_eq!(str_to_number("-9223372036854775808"), Some(int(i64::MIN)));
+        assert_eq!(
+            str_to_number("9223372036854775808"),
+            Some(float(9223372036854775808.0))
+        );
+        assert_eq!(str_to_number("0xff"), Some(int(255)));
+        assert_eq!(str_to_number("0xffffffffffffffff"), Some(int(-1)));
+        assert_eq!(str_to_number("1e2"), Some(float(100.0)));
+        assert_eq!(str_to_number(".5"), Some(float(0.5)));
+        assert_eq!(str_to_number("5."), Some(float(5.0)));
+        assert_eq!(str_to_number("0x1p4"), Some(float(16.0)));
+        assert_eq!(str_to_number("0x.8"), Some(float(0.5)));
+        assert_eq!(str_to_number("-0xA.8P-1"), Some(float(-5.25)));
+
+        for s in [
+            "", " ", "abc", "1e", "0x", "inf", "nan", "1 2", "--1", "+-1", "0x1p",
+        ] {
+            assert_eq!(str_to_number(s), None, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn floats_convert_to_integers_only_if_exact() {
+        assert_eq!(float_to_integer(3.0), Some(3));
+        assert_eq!(float_to_integer(-0.0), Some(0));
+        assert_eq!(float_to_integer(3.5), None);
+        assert_eq!(float_to_integer(-9223372036854775808.0), Some(i64::MIN));
+        assert_eq!(float_to_integer(9223372036854775808.0), None);
+        assert_eq!(float_to_integer(f64::NAN), None);
+        assert_eq!(float_to_integer(f64::INFINITY), None);
+    }
+}
@@ -0,0 +1,543 @@
+//! Peephole optimizations over the bytecode of a prototype.
+//!
+//! Apart from jump threading, which retargets jumps in place, passes only ever mark
+//! instructions for removal; [`ParseProto::optimize`] then compacts the code and repeats until
+//! no pass finds anything to do.
+
+use crate::bytecode::{ByteCode, Instruction};
+use crate::debug_info::LineInfo;
+use crate::parser::ParseProto;
+use std::rc::Rc;
+
+impl ParseProto {
+    /// Send jumps that land on another jump straight to where it goes, remove code that can
+    /// not be reached, moves without effect and loads whose result is overwritten before it is
+    /// read, in this function and all functions nested in it.
+    pub fn optimize(&mut self) {
+        for proto in self.protos.iter_mut() {
+            Rc::make_mut(proto).optimize();
+        }
+        loop {
+            let threaded = thread_jumps(&mut self.byte_codes);
+            let codes: Vec<ByteCode> = self.byte_codes.iter().map(|c| c.decode()).collect();
+            let mut keep = vec![true; codes.len()];
+            remove_unreachable(&codes, &mut keep);
+            remove_jumps_to_next(&codes, &mut keep);
+            remove_redundant_moves(&codes, &jump_targets(&codes), &mut keep);
+            remove_overwritten_loads(&codes, &mut keep);
+            if keep.iter().all(|&k| k) && !threaded {
+                break;
+            }
+            self.compact(&keep);
+        }
+    }
+
+    fn compact(&mut self, keep: &[bool]) {
+        // new pc of every old pc: the number of kept instructions before it
+        let mut new_pcs = vec![0];
+        for &k in keep {
+            new_pcs.push(new_pcs.last().unwrap() + k as u32);
+        }
+        // jumps to a removed instruction land on the next kept one
+        for (pc, code) in self.byte_codes.iter_mut().enumerate() {
+            if let Some(offset) = code.decode().jump_offset() {
+                let target = jump_target(pc, offset);
+                let offset = new_pcs[target] as isize - new_pcs[pc] as isize - 1;
+                code.set_sd(offset as i16);
+            }
+        }
+        if let Some(debug) = &mut self.debug {
+            let lines = debug.line_info.decode(debug.line_defined);
+            let lines: Vec<u32> = lines
+                .into_iter()
+                .zip(keep)
+                .filter_map(|(line, &k)| k.then_some(line))
+                .collect();
+            debug.line_info = LineInfo::encode(debug.line_defined, &lines);
+            for var in debug.locals.iter_mut() {
+                var.start_pc = new_pcs[var.start_pc as usize];
+                var.end_pc = new_pcs[var.end_pc as usize];
+            }
+        }
+        let mut keep = keep.iter();
+        self.byte_codes.retain(|_| *keep.next().unwrap());
+    }
+}
+
+/// registers read by an instruction
+fn reads(code: &ByteCode) -> Vec<u8> {
+    match *code {
+        // a count of 0 passes everything up to the top
+        ByteCode::Call(func, 0, _) | ByteCode::TailCall(func, 0) | ByteCode::Return(func, 0) => {
+            (func..=u8::MAX).collect()
+        }
+        ByteCode::Call(func, nargs, _) | ByteCode::TailCall(func, nargs) => {
+            (func..func.saturating_add(nargs)).collect()
+        }
+        ByteCode::Return(first, n) => (first..first.saturating_add(n - 1)).collect(),
+        ByteCode::SetList(table, 0, _) => (table..=u8::MAX).collect(),
+        ByteCode::SetList(table, n, _) => (table..table.saturating_add(n)).collect(),
+        ByteCode::SetTable(table, key, src) => vec![table, key, src],
+        ByteCode::GetTable(_, table, key) => vec![table, key],
+        ByteCode::Move(_, src)
+        | ByteCode::SetGlobal(src, _)
+        | ByteCode::Neg(_, src)
+        | ByteCode::BitNot(_, src)
+        | ByteCode::Not(_, src)
+        | ByteCode::TestAndJump(src, _)
+        | ByteCode::TestOrJump(src, _)
+        | ByteCode::Tbc(src) => vec![src],
+        ByteCode::Add(_, b, c)
+        | ByteCode::Sub(_, b, c)
+        | ByteCode::Mul(_, b, c)
+        | ByteCode::Mod(_, b, c)
+        | ByteCode::Pow(_, b, c)
+        | ByteCode::Div(_, b, c)
+        | ByteCode::Idiv(_, b, c)
+        | ByteCode::BitAnd(_, b, c)
+        | ByteCode::BitOr(_, b, c)
+        | ByteCode::BitXor(_, b, c)
+        | ByteCode::ShiftL(_, b, c)
+        | ByteCode::ShiftR(_, b, c)
+        | ByteCode::Equal(_, b, c)
+        | ByteCode::NotEq(_, b, c)
+        | ByteCode::LesThan(_, b, c)
+        | ByteCode::LesEq(_, b, c)
+        | ByteCode::Concat(_, b, c) => vec![b, c],
+        ByteCode::Jump(_)
+        | ByteCode::Closure(..)
+        | ByteCode::VarArgs(..)
+        | ByteCode::GetGlobal(..)
+        | ByteCode::NewTable(..)
+        | ByteCode::LoadConst(..)
+        | ByteCode::LoadBool(..)
+        | ByteCode::LoadNil(..)
+        | ByteCode::LoadInteger(..) => vec![],
+    }
+}
+
+/// register written by an instruction that has no other effect
+fn pure_write(code: &ByteCode) -> Option<u8> {
+    match *code {
+        ByteCode::GetGlobal(dst, _)
+        | ByteCode::LoadConst(dst, _)
+        | ByteCode::LoadBool(dst, _)
+        | ByteCode::LoadNil(dst)
+        | ByteCode::LoadInteger(dst, _)
+        | ByteCode::Move(dst, _)
+        | ByteCode::Equal(dst, ..)
+        | ByteCode::NotEq(dst, ..)
+        | ByteCode::Not(dst, _)
+        | ByteCode::Closure(dst, _)
+        | ByteCode::NewTable(dst) => Some(dst),
+        // operators can raise errors, which must not be optimized away
+        ByteCode::Call(..)
+        | ByteCode::TailCall(..)
+        | ByteCode::Add(..)
+        | ByteCode::Sub(..)
+        | ByteCode::Mul(..)
+        | ByteCode::Mod(..)
+        | ByteCode::Pow(..)
+        | ByteCode::Div(..)
+        | ByteCode::Idiv(..)
+        | ByteCode::BitAnd(..)
+        | ByteCode::BitOr(..)
+        | ByteCode::BitXor(..)
+        | ByteCode::ShiftL(..)
+        | ByteCode::ShiftR(..)
+        | ByteCode::Neg(..)
+        | ByteCode::BitNot(..)
+        | ByteCode::LesThan(..)
+        | ByteCode::LesEq(..)
+        | ByteCode::GetTable(..)
+        | ByteCode::Concat(..)
+        | ByteCode::Tbc(..) => None,
+        // writes more than one register
+        ByteCode::VarArgs(..) => None,
+        // jumps and stores write no register
+        ByteCode::Jump(_)
+        | ByteCode::TestAndJump(..)
+        | ByteCode::TestOrJump(..)
+        | ByteCode::Return(..)
+        | ByteCode::SetGlobal(..)
+        | ByteCode::SetTable(..)
+        | ByteCode::SetList(..) => None,
+    }
+}
+
+/// pc an instruction jumping by `offset` from `pc` goes to
+fn jump_target(pc: usize, offset: i16) -> usize {
+    (pc as isize + 1 + offset as isize) as usize
+}
+
+/// Retarget jumps landing on an unconditional jump to the end of the chain of jumps, returns
+/// whether any jump changed.
+fn thread_jumps(byte_codes: &mut [Instruction]) -> bool {
+    let codes: Vec<ByteCode> = byte_codes.iter().map(|c| c.decode()).collect();
+    let mut threaded = false;
+    for (pc, code) in codes.iter().enumerate() {
+        let Some(offset) = code.jump_offset() else {
+            continue;
+        };
+        let mut target = jump_target(pc, offset);
+        // chains can loop, jumping around in one forever is all the same
+        for _ in 0..codes.len() {
+            match codes.get(target) {
+                Some(next) if !next.falls_through() => match next.jump_offset() {
+                    Some(next) if jump_target(target, next) != target => {
+                        target = jump_target(target, next)
+                    }
+                    _ => break,
+                },
+                _ => break,
+            }
+        }
+        let Ok(offset) = i16::try_from(target as isize - pc as isize - 1) else {
+            continue;
+        };
+        if Some(offset) != code.jump_offset() {
+            byte_codes[pc].set_sd(offset);
+            threaded = true;
+        }
+    }
+    threaded
+}
+
+/// instructions that no path from the start of the function reaches, like those after a
+/// `return` or an unconditional jump
+fn remove_unreachable(codes: &[ByteCode], keep: &mut [bool]) {
+    let mut reached = vec![false; codes.len()];
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        if pc >= codes.len() || reached[pc] {
+            continue;
+        }
+        reached[pc] = true;
+        if let Some(offset) = codes[pc].jump_offset() {
+            pending.push(jump_target(pc, offset));
+        }
+        if codes[pc].falls_through() {
+            pending.push(pc + 1);
+        }
+    }
+    for (k, reached) in keep.iter_mut().zip(reached) {
+        *k &= reached;
+    }
+}
+
+/// unconditional jumps to the instruction right after them
+fn remove_jumps_to_next(codes: &[ByteCode], keep: &mut [bool]) {
+    for (pc, code) in codes.iter().enumerate() {
+        if code.jump_offset() == Some(0) && !code.falls_through() {
+            keep[pc] = false;
+        }
+    }
+}
+
+/// whether each instruction can be reached by a jump
+fn jump_targets(codes: &[ByteCode]) -> Vec<bool> {
+    let mut targets = vec![false; codes.len() + 1];
+    for (pc, code) in codes.iter().enumerate() {
+        if let Some(offset) = code.jump_offset() {
+            targets[jump_target(pc, offset)] = true;
+        }
+    }
+    targets
+}
+
+/// `Move(a, a)`, and `Move(a, b)` right after `Move(b, a)` unless a jump lands in between
+fn remove_redundant_moves(codes: &[ByteCode], targets: &[bool], keep: &mut [bool]) {
+    for (pc, code) in codes.iter().enumerate() {
+        match *code {
+            ByteCode::Move(dst, src) if dst == src => keep[pc] = false,
+            ByteCode::Move(dst, src)
+                if pc > 0
+                    && keep[pc - 1]
+                    && !targets[pc]
+                    && codes[pc - 1] == ByteCode::Move(src, dst) =>
+            {
+                keep[pc] = false
+            }
+            _ => (),
+        }
+    }
+}
+
+/// a pure write to a register that is written again before anything reads it, on the only
+/// path from one to the other
+fn remove_overwritten_loads(codes: &[ByteCode], keep: &mut [bool]) {
+    for (pc, code) in codes.iter().enumerate() {
+        let Some(dst) = pure_write(code) else {
+            continue;
+        };
+        for next in &codes[pc + 1..] {
+            // the register may be read wherever a jump goes
+            if reads(next).contains(&dst) || next.jump_offset().is_some() {
+                break;
+            }
+            if pure_write(next) == Some(dst) {
+                keep[pc] = false;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug_info::{DebugInfo, LocalVar};
+    use crate::value::Value;
+
+    fn optimize(codes: Vec<ByteCode>) -> ParseProto {
+        let mut proto = ParseProto {
+            constants: vec![Value::String("print".into())],
+            byte_codes: codes.into_iter().map(Into::into).collect(),
+            max_stack_size: 3,
+            ..Default::default()
+        };
+        proto.optimize();
+        proto
+    }
+
+    #[test]
+    fn remove_redundant_moves() {
+        let proto = optimize(vec![
+            ByteCode::LoadInteger(0, 1),
+            ByteCode::Move(0, 0),
+            ByteCode::Move(1, 0),
+            ByteCode::Move(0, 1),
+            ByteCode::GetGlobal(2, 0),
+            ByteCode::Call(1, 2, 1),
+        ]);
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::Move(1, 0),
+                ByteCode::GetGlobal(2, 0),
+                ByteCode::Call(1, 2, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_overwritten_loads() {
+        let proto = optimize(vec![
+            ByteCode::LoadNil(1),
+            ByteCode::LoadInteger(1, 1),
+            ByteCode::GetGlobal(0, 0),
+            ByteCode::Call(0, 2, 1),
+            ByteCode::LoadBool(1, true),
+        ]);
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::GetGlobal(0, 0),
+                ByteCode::Call(0, 2, 1),
+                ByteCode::LoadBool(1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_loads_that_are_read() {
+        let codes = vec![
+            ByteCode::LoadInteger(0, 1),
+            ByteCode::Move(1, 0),
+            ByteCode::LoadInteger(0, 2),
+            ByteCode::GetGlobal(0, 0),
+            ByteCode::Call(0, 2, 1),
+        ];
+        let proto = optimize(codes);
+
+        // the second load of register 0 is overwritten by GetGlobal before it is read
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::Move(1, 0),
+                ByteCode::GetGlobal(0, 0),
+                ByteCode::Call(0, 2, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps_follow_removed_instructions() {
+        let proto = optimize(vec![
+            ByteCode::LoadBool(0, true),
+            ByteCode::TestAndJump(0, 3),
+            ByteCode::LoadNil(1),
+            ByteCode::LoadInteger(1, 1),
+            ByteCode::Move(1, 1),
+            ByteCode::Move(2, 1),
+        ]);
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadBool(0, true),
+                ByteCode::TestAndJump(0, 1),
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::Move(2, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_instructions_around_jumps() {
+        let codes = vec![
+            ByteCode::LoadInteger(0, 1),
+            ByteCode::TestOrJump(0, 1),
+            ByteCode::Move(1, 0),
+            ByteCode::Move(0, 1),
+        ];
+        let proto = optimize(codes);
+
+        // register 0 is read after the jump, and the second move is its target
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::TestOrJump(0, 1),
+                ByteCode::Move(1, 0),
+                ByteCode::Move(0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn thread_jumps_to_jumps() {
+        let mut proto = ParseProto {
+            constants: vec![Value::String("print".into())],
+            byte_codes: [
+                ByteCode::LoadBool(0, true),
+                ByteCode::TestAndJump(0, 2),
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::Return(1, 2),
+                ByteCode::Jump(0),
+                ByteCode::Jump(1),
+                ByteCode::LoadInteger(1, 2),
+                ByteCode::GetGlobal(2, 0),
+                ByteCode::Call(2, 1, 1),
+            ]
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+            max_stack_size: 3,
+            ..Default::default()
+        };
+        assert!(thread_jumps(&mut proto.byte_codes));
+        assert_eq!(proto.byte_codes[1], ByteCode::TestAndJump(0, 5));
+
+        // the jumps of the chain are not reached anymore, and neither is what they skipped
+        proto.optimize();
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadBool(0, true),
+                ByteCode::TestAndJump(0, 2),
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::Return(1, 2),
+                ByteCode::GetGlobal(2, 0),
+                ByteCode::Call(2, 1, 1),
+            ]
+        );
+        assert_eq!(proto.verify(), Ok(()));
+    }
+
+    #[test]
+    fn keep_jumps_looping_forever() {
+        let proto = optimize(vec![
+            ByteCode::LoadBool(0, true),
+            ByteCode::TestOrJump(0, 0),
+            ByteCode::Jump(0),
+            ByteCode::Jump(-2),
+        ]);
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadBool(0, true),
+                ByteCode::TestOrJump(0, 0),
+                ByteCode::Jump(-1),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_code_after_return() {
+        let mut proto = ParseProto {
+            constants: vec![Value::String("print".into())],
+            byte_codes: [
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::Return(0, 2),
+                ByteCode::GetGlobal(1, 0),
+                ByteCode::Move(2, 0),
+                ByteCode::Call(1, 2, 1),
+            ]
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+            max_stack_size: 3,
+            debug: Some(DebugInfo {
+                line_info: LineInfo::encode(0, &[1, 2, 3, 3, 3]),
+                locals: vec![LocalVar {
+                    name: "a".to_string(),
+                    start_pc: 1,
+                    end_pc: 5,
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        proto.optimize();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![ByteCode::LoadInteger(0, 1), ByteCode::Return(0, 2)]
+        );
+        let debug = proto.debug.as_ref().unwrap();
+        assert_eq!(debug.line_info.decode(0), vec![1, 2]);
+        assert_eq!((debug.locals[0].start_pc, debug.locals[0].end_pc), (1, 2));
+        assert_eq!(proto.verify(), Ok(()));
+    }
+
+    #[test]
+    fn debug_information_follows_removed_instructions() {
+        let mut proto = ParseProto {
+            constants: vec![Value::String("print".into())],
+            byte_codes: [
+                ByteCode::LoadNil(0),
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::Move(0, 0),
+                ByteCode::GetGlobal(1, 0),
+                ByteCode::Call(1, 2, 1),
+            ]
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+            max_stack_size: 3,
+            debug: Some(DebugInfo {
+                line_info: LineInfo::encode(0, &[1, 2, 3, 4, 4]),
+                locals: vec![LocalVar {
+                    name: "a".to_string(),
+                    start_pc: 3,
+                    end_pc: 5,
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        proto.optimize();
+
+        let debug = proto.debug.as_ref().unwrap();
+        assert_eq!(debug.line_info.decode(0), vec![2, 4, 4]);
+        assert_eq!((debug.locals[0].start_pc, debug.locals[0].end_pc), (1, 3));
+        assert_eq!(proto.verify(), Ok(()));
+    }
+}
@@ -1,151 +1,1520 @@
-use crate::bytecode::ByteCode;
+use crate::bytecode::{ByteCode, Instruction, FIELDS_PER_FLUSH};
 use crate::{
-    lexer::{Lexer, Token},
+    debug_info::{DebugInfo, LineInfo, LocalVar},
+    lexer::{LexError, Lexer, SeekRead, Span, Token},
+    number::{arith, ArithOp},
+    string::LuaString,
     value::Value,
 };
-use std::fs::File;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct ParseProto {
     pub constants: Vec<Value>,
-    pub byte_codes: Vec<ByteCode>,
+    pub byte_codes: Vec<Instruction>,
+    /// number of stack slots the function needs, every register operand is below it
+    pub max_stack_size: u8,
+    /// number of fixed parameters, they are passed in the first registers
+    pub num_params: u8,
+    /// whether extra arguments are kept for `...`, always true for main chunks
+    pub is_vararg: bool,
+    /// functions defined inside this one, created by `Closure`
+    pub protos: Vec<Rc<ParseProto>>,
+    /// `None` if the prototype has been stripped
+    pub debug: Option<DebugInfo>,
 }
 
-pub fn load(stream: &mut File) -> ParseProto {
-    let mut constants = Vec::new();
-    let mut byte_codes = Vec::new();
-    let mut lex = Lexer::new(stream);
-    let mut locals: Vec<String> = Vec::new();
+/// Registers are addressed by a byte, so a function can use at most this many.
+const MAX_STACK_SIZE: usize = u8::MAX as usize;
 
+/// Constants and nested functions are addressed by the 16-bit D operand, so a function can
+/// have at most this many of each.
+const MAX_INDEX: usize = u16::MAX as usize + 1;
+
+/// Nesting depth of expressions and function bodies, `LUAI_MAXCCALLS` of reference Lua. The
+/// parser recurses for every level, so deeper chunks would overflow the Rust stack.
+const MAX_LEVELS: usize = 200;
+
+/// A syntax error, reported the way reference Lua does: `chunk:line: message`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// chunk description as returned by [`chunkid`]
+    pub chunk: String,
+    /// location of the offending token
+    pub span: Span,
+    /// what the parser was looking for, e.g. `'='` or `<name>`, if there was a single candidate
+    pub expected: Option<String>,
+    /// the offending token as it is shown in the message, e.g. `'x'` or `<eof>`
+    pub found: String,
+    /// message without position, e.g. `'=' expected near 'x'`
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.chunk, self.span.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Compile a chunk read from `stream`. `chunkname` follows the reference Lua convention: a
+/// leading `@` marks a file name, a leading `=` is used verbatim and anything else is treated as
+/// the source itself, see [`chunkid`].
+///
+/// Parsing continues after a syntax error at the next statement, so all errors of the chunk are
+/// returned at once.
+pub fn load(stream: &mut impl SeekRead, chunkname: &str) -> Result<ParseProto, Vec<ParseError>> {
+    let mut parser = Parser {
+        lex: Lexer::new(stream),
+        chunk: chunkid(chunkname),
+        source: chunkname.to_string(),
+        errors: Vec::new(),
+        consumed: 0,
+        line: 1,
+        levels: 0,
+        // the main chunk gets the command line arguments as `...`
+        fs: FuncState {
+            is_vararg: true,
+            ..Default::default()
+        },
+        enclosing: Vec::new(),
+    };
     loop {
-        match lex.next() {
-            // TODO: maybe parsing can be done on a higher level than tokens. Try to distinguish
-            // different cases, like assignments to local variables and function calls
-            Token::Local => match lex.next() {
-                Token::Name(name) => {
-                    // add name to local variables then parse the expression that follows the =
-                    // sign.
-                    if !(lex.next() == Token::Assign) {
-                        panic!("Expected assignment operator")
+        parser.block();
+        if *parser.lex.peek() == Token::Eos {
+            break;
+        }
+        // a block ended at a token like `end` that does not close anything
+        let err = parser.error_expected(&format!("'{}'", Token::Eos));
+        parser.errors.push(*err);
+        parser.next();
+    }
+    let proto = parser.close_function(0);
+
+    if parser.errors.is_empty() {
+        Ok(proto)
+    } else {
+        Err(parser.errors)
+    }
+}
+
+/// Errors are boxed while they are passed up to the statement level, they are large.
+type ParseResult<T> = Result<T, Box<ParseError>>;
+
+struct Parser<'a> {
+    lex: Lexer<'a>,
+    chunk: String,
+    /// chunk name as given to `load`, recorded in the debug information
+    source: String,
+    errors: Vec<ParseError>,
+    /// number of tokens consumed so far, used to make sure error recovery makes progress
+    consumed: usize,
+    /// line of the token consumed last
+    line: usize,
+    /// number of expressions and function bodies being parsed, see [`MAX_LEVELS`]
+    levels: usize,
+    /// the function being compiled
+    fs: FuncState,
+    /// functions the current one is nested in, the innermost last
+    enclosing: Vec<FuncState>,
+}
+
+/// Compilation state of a single function.
+#[derive(Default)]
+struct FuncState {
+    constants: Vec<Value>,
+    /// index of every constant, so that adding one does not search them all
+    constant_indices: HashMap<ConstantKey, usize>,
+    byte_codes: Vec<Instruction>,
+    /// names of the active locals, the local in register `i` is `locals[i]`
+    locals: Vec<String>,
+    /// registers of the locals declared `<const>` or `<close>`, which cannot be assigned to
+    readonly: Vec<usize>,
+    /// whether a to-be-closed local was declared, `return f(args)` is no tail call then since
+    /// the local is closed after the call
+    has_tbc: bool,
+    /// first free register, everything from `locals.len()` up to it holds temporaries
+    sp: usize,
+    max_stack_size: usize,
+    /// line of every instruction in `byte_codes`
+    lines: Vec<u32>,
+    /// debug information of every local declared so far
+    local_vars: Vec<LocalVar>,
+    protos: Vec<Rc<ParseProto>>,
+    num_params: usize,
+    is_vararg: bool,
+    /// line of the `function` keyword, 0 for the main chunk
+    line_defined: usize,
+    /// whether more constants than [`MAX_INDEX`] were added and reported
+    too_many_constants: bool,
+}
+
+impl<'a> Parser<'a> {
+    /// Statements up to the end of the enclosing block. Errors are recorded and parsing
+    /// continues at the next statement.
+    fn block(&mut self) {
+        while !block_follow(self.lex.peek()) {
+            // `return` has to be the last statement of a block
+            let last = *self.lex.peek() == Token::Return;
+            let consumed = self.consumed;
+            if let Err(err) = self.statement() {
+                let line = err.span.line;
+                self.errors.push(*err);
+                if self.consumed == consumed {
+                    self.next();
+                }
+                self.synchronize(line);
+            }
+            if last {
+                break;
+            }
+        }
+    }
+
+    /// Skip tokens until one that is likely to start a new statement: a keyword that always
+    /// starts a statement or ends a block, or a name or parenthesis at the beginning of a later
+    /// line.
+    fn synchronize(&mut self, error_line: usize) {
+        loop {
+            let line = self.lex.peek_span().line;
+            match self.lex.peek() {
+                Token::Eos
+                | Token::End
+                | Token::Else
+                | Token::Elseif
+                | Token::Until
+                | Token::Local
+                | Token::Function
+                | Token::If
+                | Token::While
+                | Token::For
+                | Token::Do
+                | Token::Repeat
+                | Token::Return
+                | Token::Break
+                | Token::Goto
+                | Token::DoubColon
+                | Token::SemiColon => break,
+                Token::Name(_) | Token::ParL if line > error_line => break,
+                _ => {
+                    self.next();
+                }
+            }
+        }
+    }
+
+    fn statement(&mut self) -> ParseResult<()> {
+        // temporaries of the previous statement are free again
+        self.fs.sp = self.fs.locals.len();
+        match self.lex.peek() {
+            Token::SemiColon => {
+                self.next();
+                Ok(())
+            }
+            Token::Local => {
+                self.next();
+                if *self.lex.peek() == Token::Function {
+                    self.next();
+                    self.local_function()
+                } else {
+                    self.local()
+                }
+            }
+            Token::Function => self.function_stat(),
+            Token::Return => self.return_stat(),
+            _ => self.exp_stat(),
+        }
+    }
+
+    /// `local attnamelist = explist`, each name can be followed by an attribute
+    fn local(&mut self) -> ParseResult<()> {
+        let first = self.fs.locals.len();
+        let mut names = Vec::new();
+        let mut close = None;
+        loop {
+            let reg = first + names.len();
+            names.push(self.name()?);
+            match self.attrib()? {
+                Some(Attrib::Const) => self.fs.readonly.push(reg),
+                Some(Attrib::Close) => {
+                    if close.is_some() {
+                        let message = "multiple to-be-closed variables in local list";
+                        return Err(self.error_semantic(message));
                     }
-                    // need to load expression onto the stack if the expression is a simple value, then just add that
-                    // to the stack, but if it is another name, then check local variables for the
-                    // variable, after that globals. Locals will never be saved to constants. When
-                    // looking up a name in the local variables the index of the name in the locals
-                    // list indicates the stack position where the value can be copied from.
-                    if let Token::Integer(i) = lex.next() {
-                        if let Ok(smallint) = i16::try_from(i) {
-                            byte_codes.push(ByteCode::LoadInteger(locals.len() as u8, smallint));
-                        } else {
-                            byte_codes.push(load_const(
-                                &mut constants,
-                                locals.len(),
-                                Value::Integer(i),
-                            ))
-                        }
-                    } else {
-                        panic!("Not implemented")
+                    self.fs.readonly.push(reg);
+                    close = Some(reg);
+                }
+                None => {}
+            }
+            if *self.lex.peek() != Token::Comma {
+                break;
+            }
+            self.next();
+        }
+        self.check_next(Token::Assign)?;
+        // the new locals live in the first free registers, right after the existing ones
+        let (nexps, last) = self.explist()?;
+        self.adjust_assign(names.len(), nexps, last)?;
+        for name in names {
+            self.add_local(name);
+        }
+        if let Some(reg) = close {
+            self.fs.has_tbc = true;
+            self.emit(ByteCode::Tbc(reg as u8));
+        }
+        Ok(())
+    }
+
+    /// `['<' Name '>']`, the attribute of a local
+    fn attrib(&mut self) -> ParseResult<Option<Attrib>> {
+        if *self.lex.peek() != Token::Less {
+            return Ok(None);
+        }
+        self.next();
+        let name = self.name()?;
+        self.check_next(Token::Greater)?;
+        match name.as_str() {
+            "const" => Ok(Some(Attrib::Const)),
+            "close" => Ok(Some(Attrib::Close)),
+            _ => Err(self.error_semantic(&format!("unknown attribute '{name}'"))),
+        }
+    }
+
+    /// `local function Name body`
+    fn local_function(&mut self) -> ParseResult<()> {
+        let line = self.line;
+        let name = self.name()?;
+        // the function can refer to itself
+        self.fs.locals.push(name.clone());
+        let reg = self.alloc_reg()?;
+        let closure = self.body(line)?;
+        self.exp_to(closure, reg)?;
+        // debug information only sees the variable once it holds the function
+        self.fs.local_vars.push(LocalVar {
+            name,
+            start_pc: self.fs.byte_codes.len() as u32,
+            end_pc: 0,
+        });
+        Ok(())
+    }
+
+    /// `function Name {'.' Name} body`
+    fn function_stat(&mut self) -> ParseResult<()> {
+        let line = self.lex.peek_span().line;
+        self.next();
+        let name = self.name()?;
+        let mut var = self.single_var(name)?;
+        while *self.lex.peek() == Token::Dot {
+            self.next();
+            var = self.field(var)?;
+        }
+        let closure = self.body(line)?;
+        self.store(var, closure)?;
+        // the definition belongs to the line of `function`
+        *self.fs.lines.last_mut().unwrap() = line as u32;
+        Ok(())
+    }
+
+    /// `return [explist] [;]`
+    fn return_stat(&mut self) -> ParseResult<()> {
+        self.next();
+        let first = self.fs.sp;
+        let code = if block_follow(self.lex.peek()) || *self.lex.peek() == Token::SemiColon {
+            ByteCode::Return(first as u8, 1)
+        } else {
+            let (nexps, last) = self.explist()?;
+            if last.is_multi() {
+                let tail_call = match last {
+                    ExpDesc::Call(pc) if nexps == 1 && !self.fs.has_tbc => Some(pc),
+                    _ => None,
+                };
+                self.set_returns(last, None)?;
+                // `return f(args)` reuses the frame of the returning function
+                if let Some(pc) = tail_call {
+                    let ByteCode::Call(func, nargs, _) = self.fs.byte_codes[pc].decode() else {
+                        unreachable!("calls are compiled into Call")
+                    };
+                    self.fs.byte_codes[pc] = ByteCode::TailCall(func, nargs).into();
+                }
+                ByteCode::Return(first as u8, 0)
+            } else if nexps == 1 {
+                // a single local is returned from its own register
+                let reg = self.exp_to_any_reg(last)?;
+                ByteCode::Return(reg as u8, 2)
+            } else {
+                self.exp_to_next_reg(last)?;
+                ByteCode::Return(first as u8, nexps as u8 + 1)
+            }
+        };
+        self.emit(code);
+        if *self.lex.peek() == Token::SemiColon {
+            self.next();
+        }
+        Ok(())
+    }
+
+    /// a function call, or an assignment `varlist = explist`
+    fn exp_stat(&mut self) -> ParseResult<()> {
+        let exp = self.suffixed_exp()?;
+        if let Token::Assign | Token::Comma = self.lex.peek() {
+            return self.assignment(exp);
+        }
+        match exp {
+            // the results of a call statement are dropped
+            ExpDesc::Call(_) => self.set_returns(exp, Some(0)),
+            _ => Err(self.error_near(None, "syntax error")),
+        }
+    }
+
+    /// `var {, var} = explist`, the first variable has been parsed already
+    fn assignment(&mut self, var: ExpDesc) -> ParseResult<()> {
+        let mut vars = vec![var];
+        loop {
+            match vars.last() {
+                Some(&ExpDesc::Local(reg)) => {
+                    if self.fs.readonly.contains(&reg) {
+                        let name = &self.fs.locals[reg];
+                        let message = format!("attempt to assign to const variable '{name}'");
+                        return Err(self.error_semantic(&message));
                     }
-                    locals.push(name)
+                    self.check_conflict(&mut vars, reg)?;
                 }
-                _ => panic!("Expected name after local keyword"),
-            },
-            Token::Name(name) => {
-                // `Name LiteralString` as function call
-                // Push function name to the constants
-                let src = add_const(&mut constants, Value::String(name));
-                // Push instructions to get function name from constants and push to stack at
-                // `locals.len()` which points to the first free stack position after local
-                // variables
-                byte_codes.push(ByteCode::GetGlobal(locals.len() as u8, src as u8));
-                match lex.next() {
-                    Token::ParL => {
-                        match lex.next() {
-                            Token::Name(var) => {
-                                // references a variable that should either be defined locally or
-                                // globally
-                                let i = locals.iter().rposition(|v| v == &var).unwrap();
-                                byte_codes.push(ByteCode::Move((locals.len() + 1) as u8, i as u8));
-                            }
-                            Token::Integer(i) => {
-                                if let Ok(smallint) = i16::try_from(i) {
-                                    byte_codes.push(ByteCode::LoadInteger(
-                                        (locals.len() + 1) as u8,
-                                        smallint,
-                                    ));
-                                } else {
-                                    byte_codes.push(load_const(
-                                        &mut constants,
-                                        locals.len() + 1,
-                                        Value::Integer(i),
-                                    ))
-                                }
-                            }
-                            Token::Float(f) => byte_codes.push(load_const(
-                                &mut constants,
-                                locals.len() + 1,
-                                Value::Float(f),
-                            )),
-                            Token::Nil => {
-                                byte_codes.push(ByteCode::LoadNil((locals.len() + 1) as u8));
-                            }
-                            Token::True => {
-                                byte_codes.push(ByteCode::LoadBool((locals.len() + 1) as u8, true))
-                            }
-                            Token::False => {
-                                byte_codes.push(ByteCode::LoadBool((locals.len() + 1) as u8, false))
-                            }
-                            _ => panic!("Expected something else"),
-                        }
-                        if let Token::ParR = lex.next() {
-                        } else {
-                            panic!("No closing paren")
-                        }
-                        byte_codes.push(ByteCode::Call(locals.len() as u8, 1));
+                Some(ExpDesc::Global(_) | ExpDesc::Index(..)) => {}
+                _ => return Err(self.error_near(None, "syntax error")),
+            }
+            if *self.lex.peek() != Token::Comma {
+                break;
+            }
+            self.next();
+            vars.push(self.suffixed_exp()?);
+        }
+        self.check_next(Token::Assign)?;
+        let first = self.fs.sp;
+        let (nexps, last) = self.explist()?;
+        if vars.len() == 1 && nexps == 1 {
+            // a single value is stored directly, without a temporary
+            return self.store(vars.pop().unwrap(), last);
+        }
+        // all values are evaluated before the first one is assigned
+        self.adjust_assign(vars.len(), nexps, last)?;
+        for (i, var) in vars.into_iter().enumerate().rev() {
+            self.store(var, ExpDesc::Register(first + i))?;
+        }
+        Ok(())
+    }
+
+    /// Values are stored from the last variable to the first one, so a local assigned in
+    /// `vars` must not be the table or key of an indexed variable before it: such tables and
+    /// keys are copied into a temporary first.
+    fn check_conflict(&mut self, vars: &mut [ExpDesc], local: usize) -> ParseResult<()> {
+        let (last, previous) = vars.split_last_mut().unwrap();
+        debug_assert_eq!(*last, ExpDesc::Local(local));
+        let mut copy = None;
+        for var in previous {
+            if let ExpDesc::Index(table, key) = var {
+                for reg in [table, key] {
+                    if *reg == local {
+                        *reg = match copy {
+                            Some(copy) => copy,
+                            None => *copy.insert(self.alloc_reg()?),
+                        };
                     }
-                    Token::String(s) => {
-                        let src = add_const(&mut constants, Value::String(s));
-                        byte_codes.push(ByteCode::LoadConst((locals.len() + 1) as u8, src as u8));
-                        byte_codes.push(ByteCode::Call(locals.len() as u8, 1));
+                }
+            }
+        }
+        if let Some(copy) = copy {
+            self.emit(ByteCode::Move(copy as u8, local as u8));
+        }
+        Ok(())
+    }
+
+    /// assign `exp` to the variable `var`
+    fn store(&mut self, var: ExpDesc, exp: ExpDesc) -> ParseResult<()> {
+        match var {
+            ExpDesc::Local(reg) => self.exp_to(exp, reg),
+            ExpDesc::Global(name) => {
+                let src = self.exp_to_any_reg(exp)?;
+                self.emit(ByteCode::SetGlobal(src as u8, name));
+                Ok(())
+            }
+            ExpDesc::Index(table, key) => {
+                let src = self.exp_to_any_reg(exp)?;
+                self.emit(ByteCode::SetTable(table as u8, key as u8, src as u8));
+                Ok(())
+            }
+            var => unreachable!("{var:?} is not a variable"),
+        }
+    }
+
+    /// `( parlist ) block end` of a function whose `function` keyword is on `line`. The
+    /// function is compiled into a nested prototype, the returned expression creates it.
+    fn body(&mut self, line: usize) -> ParseResult<ExpDesc> {
+        self.enter_level()?;
+        let result = self.nested_body(line);
+        self.levels -= 1;
+        result
+    }
+
+    fn nested_body(&mut self, line: usize) -> ParseResult<ExpDesc> {
+        self.open_function(line);
+        let result = self.function_body(line);
+        let proto = self.close_function(self.line);
+        result?;
+        let index = self.fs.protos.len();
+        if index >= MAX_INDEX {
+            return Err(self.error_limit(MAX_INDEX, "functions"));
+        }
+        self.fs.protos.push(Rc::new(proto));
+        Ok(ExpDesc::Reloc(
+            self.emit(ByteCode::Closure(0, index as u16)),
+        ))
+    }
+
+    fn function_body(&mut self, line: usize) -> ParseResult<()> {
+        self.check_next(Token::ParL)?;
+        if *self.lex.peek() != Token::ParR {
+            loop {
+                match self.lex.peek() {
+                    Token::Name(_) => {
+                        let name = self.name()?;
+                        self.add_local(name);
+                        self.fs.num_params += 1;
+                    }
+                    Token::Dots => {
+                        self.next();
+                        self.fs.is_vararg = true;
+                        break;
+                    }
+                    _ => return Err(self.error_expected("<name>")),
+                }
+                if *self.lex.peek() != Token::Comma {
+                    break;
+                }
+                self.next();
+            }
+        }
+        // parameters are passed in the first registers
+        self.fs.sp = self.fs.locals.len();
+        if self.fs.sp > 0 {
+            self.reserve(self.fs.sp - 1)?;
+        }
+        self.check_next(Token::ParR)?;
+        self.block();
+        self.check_match(Token::End, Token::Function, line)
+    }
+
+    /// start compiling a function nested in the current one
+    fn open_function(&mut self, line: usize) {
+        let fs = FuncState {
+            line_defined: line,
+            ..Default::default()
+        };
+        let enclosing = std::mem::replace(&mut self.fs, fs);
+        self.enclosing.push(enclosing);
+    }
+
+    /// finish the current function and continue with the one it is nested in
+    fn close_function(&mut self, last_line_defined: usize) -> ParseProto {
+        let enclosing = self.enclosing.pop().unwrap_or_default();
+        let mut fs = std::mem::replace(&mut self.fs, enclosing);
+        // all locals are alive until the end of the function
+        let end_pc = fs.byte_codes.len() as u32;
+        for var in fs.local_vars.iter_mut() {
+            var.end_pc = end_pc;
+        }
+        let line_defined = fs.line_defined as u32;
+        let debug = DebugInfo {
+            source: self.source.clone(),
+            line_defined,
+            last_line_defined: last_line_defined as u32,
+            line_info: LineInfo::encode(line_defined, &fs.lines),
+            locals: fs.local_vars,
+            // globals are reached through the first upvalue
+            upvalue_names: vec!["_ENV".to_string()],
+        };
+        ParseProto {
+            constants: fs.constants,
+            byte_codes: fs.byte_codes,
+            max_stack_size: fs.max_stack_size as u8,
+            num_params: fs.num_params as u8,
+            is_vararg: fs.is_vararg,
+            protos: fs.protos,
+            debug: Some(debug),
+        }
+    }
+
+    /// declare a local in the register after the existing ones, alive from the next instruction
+    fn add_local(&mut self, name: String) {
+        self.fs.local_vars.push(LocalVar {
+            name: name.clone(),
+            start_pc: self.fs.byte_codes.len() as u32,
+            end_pc: 0,
+        });
+        self.fs.locals.push(name);
+    }
+
+    /// Leave exactly `nvars` values in new registers for a list of `nexps` expressions whose
+    /// last one, `last`, has not been loaded yet. Values missing at the end are filled by a
+    /// call or `...`, or are nil otherwise.
+    fn adjust_assign(&mut self, nvars: usize, nexps: usize, last: ExpDesc) -> ParseResult<()> {
+        if last.is_multi() {
+            let missing = (nvars + 1).saturating_sub(nexps);
+            self.set_returns(last, Some(missing))
+        } else {
+            self.exp_to_next_reg(last)?;
+            for _ in nexps..nvars {
+                let reg = self.alloc_reg()?;
+                self.emit(ByteCode::LoadNil(reg as u8));
+            }
+            Ok(())
+        }
+    }
+
+    /// `exp {, exp}`: all but the last expression are loaded into new registers, the last one
+    /// is returned as it is, with the number of expressions
+    fn explist(&mut self) -> ParseResult<(usize, ExpDesc)> {
+        let mut nexps = 1;
+        let mut exp = self.exp()?;
+        while *self.lex.peek() == Token::Comma {
+            self.next();
+            self.exp_to_next_reg(exp)?;
+            exp = self.exp()?;
+            nexps += 1;
+        }
+        Ok((nexps, exp))
+    }
+
+    fn exp(&mut self) -> ParseResult<ExpDesc> {
+        self.subexp(0)
+    }
+
+    /// Parse an expression whose binary operators bind tighter than `limit`, by precedence
+    /// climbing like reference Lua does.
+    fn subexp(&mut self, limit: u8) -> ParseResult<ExpDesc> {
+        self.enter_level()?;
+        let result = self.nested_subexp(limit);
+        self.levels -= 1;
+        result
+    }
+
+    fn nested_subexp(&mut self, limit: u8) -> ParseResult<ExpDesc> {
+        let mut left = match unary_op(self.lex.peek()) {
+            Some(op) => {
+                let line = self.lex.peek_span().line;
+                self.next();
+                let operand = self.subexp(UNARY_PRIORITY)?;
+                self.unary(op, operand, line)?
+            }
+            None => self.simple_exp()?,
+        };
+        while let Some(op) = binary_op(self.lex.peek()) {
+            let (left_priority, right_priority) = priority(op);
+            if left_priority <= limit {
+                break;
+            }
+            let line = self.lex.peek_span().line;
+            self.next();
+            if let BinOp::And | BinOp::Or = op {
+                left = self.logical(op, left, right_priority, line)?;
+                continue;
+            }
+            // evaluate the left operand before the right one, unless it can be folded
+            if !left.is_numeral() && !matches!(left, ExpDesc::Local(_)) {
+                left = ExpDesc::Register(self.exp_to_any_reg(left)?);
+            }
+            let right = self.subexp(right_priority)?;
+            left = self.binary(op, left, right, line)?;
+        }
+        Ok(left)
+    }
+
+    fn simple_exp(&mut self) -> ParseResult<ExpDesc> {
+        let exp = match self.lex.peek() {
+            Token::Nil => ExpDesc::Nil,
+            Token::True => ExpDesc::Boolean(true),
+            Token::False => ExpDesc::Boolean(false),
+            Token::Integer(i) => ExpDesc::Integer(*i),
+            Token::Float(f) => ExpDesc::Float(*f),
+            Token::String(s) => ExpDesc::String(s.clone()),
+            Token::Dots => {
+                if !self.fs.is_vararg {
+                    return Err(self.error_near(None, "cannot use '...' outside a vararg function"));
+                }
+                self.next();
+                return Ok(ExpDesc::VarArgs(self.emit(ByteCode::VarArgs(0, 2))));
+            }
+            Token::Function => {
+                let line = self.lex.peek_span().line;
+                self.next();
+                return self.body(line);
+            }
+            Token::CurlyL => return self.table_constructor(),
+            _ => return self.suffixed_exp(),
+        };
+        self.next();
+        Ok(exp)
+    }
+
+    /// a variable or parenthesized expression, followed by any number of fields, indexes
+    /// and calls
+    fn suffixed_exp(&mut self) -> ParseResult<ExpDesc> {
+        // calls belong to the line where the called expression starts
+        let line = self.lex.peek_span().line;
+        let mut exp = self.primary_exp()?;
+        loop {
+            exp = match self.lex.peek() {
+                Token::Dot => {
+                    self.next();
+                    self.field(exp)?
+                }
+                Token::SqurL => {
+                    let open_line = self.lex.peek_span().line;
+                    self.next();
+                    let table = self.exp_to_any_reg(exp)?;
+                    let key = self.exp()?;
+                    let key = self.exp_to_any_reg(key)?;
+                    self.check_match(Token::SqurR, Token::SqurL, open_line)?;
+                    ExpDesc::Index(table, key)
+                }
+                Token::ParL | Token::String(_) | Token::CurlyL => self.call(exp, line)?,
+                _ => return Ok(exp),
+            };
+        }
+    }
+
+    /// `exp.Name`, the `.` has been consumed
+    fn field(&mut self, exp: ExpDesc) -> ParseResult<ExpDesc> {
+        let table = self.exp_to_any_reg(exp)?;
+        let name = self.name()?;
+        let key = self.exp_to_any_reg(ExpDesc::String(name))?;
+        Ok(ExpDesc::Index(table, key))
+    }
+
+    /// `{ [field {sep field} [sep]] }` where a field is `[exp] = exp`, `Name = exp` or `exp`.
+    /// List items are collected in the registers after the table and stored by `SetList`
+    /// every `FIELDS_PER_FLUSH` items, the last one keeps all values of a call or `...`.
+    fn table_constructor(&mut self) -> ParseResult<ExpDesc> {
+        let line = self.lex.peek_span().line;
+        self.next();
+        let table = self.alloc_reg()?;
+        self.emit(ByteCode::NewTable(table as u8));
+        // list items in registers, and the number of batches stored already
+        let mut pending = 0;
+        let mut batch = 0;
+        // the list item parsed last, it is only loaded once it is known not to be the last one
+        let mut last_item = None;
+        while *self.lex.peek() != Token::CurlyR {
+            if let Some(item) = last_item.take() {
+                self.exp_to_next_reg(item)?;
+                pending += 1;
+                if pending == FIELDS_PER_FLUSH {
+                    self.flush_list(table, pending, &mut batch)?;
+                    pending = 0;
+                }
+            }
+            let named = matches!(self.lex.peek(), Token::Name(_))
+                && *self.lex.peek_second() == Token::Assign;
+            match self.lex.peek() {
+                Token::Name(_) if named => {
+                    let name = self.name()?;
+                    self.next();
+                    self.record_field(table, ExpDesc::String(name))?;
+                }
+                Token::SqurL => {
+                    let open_line = self.lex.peek_span().line;
+                    self.next();
+                    let key = self.exp()?;
+                    self.check_match(Token::SqurR, Token::SqurL, open_line)?;
+                    self.check_next(Token::Assign)?;
+                    self.record_field(table, key)?;
+                }
+                _ => last_item = Some(self.exp()?),
+            }
+            match self.lex.peek() {
+                Token::Comma | Token::SemiColon => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+        self.check_match(Token::CurlyR, Token::CurlyL, line)?;
+        match last_item {
+            Some(item) if item.is_multi() => {
+                self.set_returns(item, None)?;
+                self.check_batch(batch)?;
+                self.emit(ByteCode::SetList(table as u8, 0, batch as u8));
+                self.fs.sp = table + 1;
+            }
+            item => {
+                if let Some(item) = item {
+                    self.exp_to_next_reg(item)?;
+                    pending += 1;
+                }
+                if pending > 0 {
+                    self.flush_list(table, pending, &mut batch)?;
+                }
+            }
+        }
+        Ok(ExpDesc::Register(table))
+    }
+
+    /// `key = exp` of a table constructor, the `=` has been consumed
+    fn record_field(&mut self, table: usize, key: ExpDesc) -> ParseResult<()> {
+        let key = self.exp_to_any_reg(key)?;
+        let value = self.exp()?;
+        let value = self.exp_to_any_reg(value)?;
+        self.emit(ByteCode::SetTable(table as u8, key as u8, value as u8));
+        self.free_regs(key, value);
+        Ok(())
+    }
+
+    /// store the `count` list items after `table` as the next batch
+    fn flush_list(&mut self, table: usize, count: usize, batch: &mut usize) -> ParseResult<()> {
+        self.check_batch(*batch)?;
+        self.emit(ByteCode::SetList(
+            table as u8,
+            count as u8 + 1,
+            *batch as u8,
+        ));
+        self.fs.sp = table + 1;
+        *batch += 1;
+        Ok(())
+    }
+
+    fn check_batch(&mut self, batch: usize) -> ParseResult<()> {
+        if batch > u8::MAX as usize {
+            let limit = (u8::MAX as usize + 1) * FIELDS_PER_FLUSH;
+            return Err(self.error_limit(limit, "items"));
+        }
+        Ok(())
+    }
+
+    fn primary_exp(&mut self) -> ParseResult<ExpDesc> {
+        match self.lex.peek() {
+            Token::Name(_) => {
+                let name = self.name()?;
+                self.single_var(name)
+            }
+            Token::ParL => {
+                let line = self.lex.peek_span().line;
+                self.next();
+                let exp = self.exp()?;
+                self.check_match(Token::ParR, Token::ParL, line)?;
+                // parentheses keep only the first value of a call or `...`
+                Ok(self.discharge(exp))
+            }
+            _ => Err(self.error_unexpected()),
+        }
+    }
+
+    /// `func ( [explist] )`, `func LiteralString` or `func TableConstructor`, the result count
+    /// is set later
+    fn call(&mut self, func: ExpDesc, line: usize) -> ParseResult<ExpDesc> {
+        let func = self.exp_to_next_reg(func)?;
+        let nargs = match self.lex.peek() {
+            Token::ParL => {
+                let open_line = self.lex.peek_span().line;
+                self.next();
+                let nargs = if *self.lex.peek() == Token::ParR {
+                    Some(0)
+                } else {
+                    let (nexps, last) = self.explist()?;
+                    if last.is_multi() {
+                        // pass everything up to the top
+                        self.set_returns(last, None)?;
+                        None
+                    } else {
+                        self.exp_to_next_reg(last)?;
+                        Some(nexps)
                     }
-                    _ => panic!("expected string"),
+                };
+                self.check_match(Token::ParR, Token::ParL, open_line)?;
+                nargs
+            }
+            _ => {
+                let arg = self.simple_exp()?;
+                self.exp_to_next_reg(arg)?;
+                Some(1)
+            }
+        };
+        // the arguments are gone after the call, the function register holds its result
+        self.fs.sp = func + 1;
+        let nargs = nargs.map_or(0, |n| n as u8 + 1);
+        let pc = self.emit_at(ByteCode::Call(func as u8, nargs, 2), line);
+        Ok(ExpDesc::Call(pc))
+    }
+
+    /// a variable: the innermost local with this name, or a global
+    fn single_var(&mut self, name: String) -> ParseResult<ExpDesc> {
+        if let Some(reg) = self.fs.locals.iter().rposition(|v| *v == name) {
+            return Ok(ExpDesc::Local(reg));
+        }
+        if self.enclosing.iter().any(|fs| fs.locals.contains(&name)) {
+            let message = format!("cannot access local '{name}' of an enclosing function");
+            return Err(self.error_near(None, &message));
+        }
+        Ok(ExpDesc::Global(self.add_const(Value::String(name.into()))?))
+    }
+
+    fn unary(&mut self, op: UnOp, operand: ExpDesc, line: usize) -> ParseResult<ExpDesc> {
+        let folded = match op {
+            UnOp::Arith(op) => fold(op, &operand, &operand),
+            UnOp::Not => operand.truthy().map(|truthy| ExpDesc::Boolean(!truthy)),
+        };
+        if let Some(folded) = folded {
+            return Ok(folded);
+        }
+        let src = self.exp_to_any_reg(operand)?;
+        self.free_reg(src);
+        let code = match op {
+            UnOp::Arith(ArithOp::Unm) => ByteCode::Neg(0, src as u8),
+            UnOp::Arith(ArithOp::BNot) => ByteCode::BitNot(0, src as u8),
+            UnOp::Not => ByteCode::Not(0, src as u8),
+            UnOp::Arith(op) => unreachable!("{op:?} is not a unary operator"),
+        };
+        Ok(ExpDesc::Reloc(self.emit_at(code, line)))
+    }
+
+    fn binary(
+        &mut self,
+        op: BinOp,
+        left: ExpDesc,
+        right: ExpDesc,
+        line: usize,
+    ) -> ParseResult<ExpDesc> {
+        if let BinOp::Arith(op) = op {
+            if let Some(folded) = fold(op, &left, &right) {
+                return Ok(folded);
+            }
+        }
+        let rhs = self.exp_to_any_reg(right)?;
+        let lhs = self.exp_to_any_reg(left)?;
+        self.free_regs(lhs, rhs);
+        let (lhs, rhs) = (lhs as u8, rhs as u8);
+        let code = match op {
+            BinOp::Arith(op) => arith_code(op, lhs, rhs),
+            BinOp::Eq => ByteCode::Equal(0, lhs, rhs),
+            BinOp::Ne => ByteCode::NotEq(0, lhs, rhs),
+            BinOp::Lt => ByteCode::LesThan(0, lhs, rhs),
+            BinOp::Le => ByteCode::LesEq(0, lhs, rhs),
+            // `a > b` is `b < a`, the operands have been evaluated in order already
+            BinOp::Gt => ByteCode::LesThan(0, rhs, lhs),
+            BinOp::Ge => ByteCode::LesEq(0, rhs, lhs),
+            BinOp::Concat => ByteCode::Concat(0, lhs, rhs),
+            BinOp::And | BinOp::Or => unreachable!("{op:?} is compiled with jumps"),
+        };
+        Ok(ExpDesc::Reloc(self.emit_at(code, line)))
+    }
+
+    /// `left and right` or `left or right`: the result is left in a register, and the right
+    /// operand is only evaluated if the test of the left one does not jump over it
+    fn logical(
+        &mut self,
+        op: BinOp,
+        left: ExpDesc,
+        right_priority: u8,
+        line: usize,
+    ) -> ParseResult<ExpDesc> {
+        let dst = match self.discharge(left) {
+            ExpDesc::Register(reg) => reg,
+            left => {
+                let reg = self.alloc_reg()?;
+                self.exp_to(left, reg)?;
+                reg
+            }
+        };
+        let test = match op {
+            BinOp::And => ByteCode::TestAndJump(dst as u8, 0),
+            _ => ByteCode::TestOrJump(dst as u8, 0),
+        };
+        let jump = self.emit_at(test, line);
+
+        let right = self.subexp(right_priority)?;
+        let right = self.discharge(right);
+        let temporary = match right {
+            ExpDesc::Register(reg) if reg != dst => Some(reg),
+            _ => None,
+        };
+        self.exp_to(right, dst)?;
+        if let Some(reg) = temporary {
+            self.free_reg(reg);
+        }
+        self.fix_jump(jump)?;
+        Ok(ExpDesc::Register(dst))
+    }
+
+    /// let the jump at `pc` continue after the last instruction emitted so far
+    fn fix_jump(&mut self, pc: usize) -> ParseResult<()> {
+        let offset = self.fs.byte_codes.len() - pc - 1;
+        let offset = i16::try_from(offset)
+            .map_err(|_| self.error_near(None, "control structure too long"))?;
+        self.fs.byte_codes[pc].set_sd(offset);
+        Ok(())
+    }
+
+    /// Let a call or `...` produce `nresults` values, or all of them for `None`, in the
+    /// registers from the first free one on. A call puts them where the function was.
+    fn set_returns(&mut self, exp: ExpDesc, nresults: Option<usize>) -> ParseResult<()> {
+        let (pc, first) = match exp {
+            ExpDesc::Call(pc) => (pc, self.fs.byte_codes[pc].a() as usize),
+            ExpDesc::VarArgs(pc) => (pc, self.fs.sp),
+            exp => unreachable!("{exp:?} has a single value"),
+        };
+        let count = match nresults {
+            Some(n) => {
+                if n > 0 {
+                    self.reserve(first + n - 1)?;
+                }
+                self.fs.sp = first + n;
+                n as u8 + 1
+            }
+            None => {
+                // the first value always has a register, which also counts for the frame size
+                self.reserve(first)?;
+                self.fs.sp = first;
+                0
+            }
+        };
+        let code = match self.fs.byte_codes[pc].decode() {
+            ByteCode::Call(func, nargs, _) => ByteCode::Call(func, nargs, count),
+            _ => ByteCode::VarArgs(first as u8, count),
+        };
+        self.fs.byte_codes[pc] = code.into();
+        Ok(())
+    }
+
+    /// Reduce a call or `...` to its first value. The result of a call is left in the
+    /// register of the function.
+    fn discharge(&mut self, exp: ExpDesc) -> ExpDesc {
+        match exp {
+            ExpDesc::Call(pc) => {
+                let ByteCode::Call(func, nargs, _) = self.fs.byte_codes[pc].decode() else {
+                    unreachable!("calls are compiled into Call")
+                };
+                self.fs.byte_codes[pc] = ByteCode::Call(func, nargs, 2).into();
+                ExpDesc::Register(func as usize)
+            }
+            // emitted for a single value, only the destination is missing
+            ExpDesc::VarArgs(pc) => ExpDesc::Reloc(pc),
+            ExpDesc::Index(table, key) => {
+                self.free_regs(table, key);
+                ExpDesc::Reloc(self.emit(ByteCode::GetTable(0, table as u8, key as u8)))
+            }
+            exp => exp,
+        }
+    }
+
+    /// emit the code that loads `exp` into register `dst`
+    fn exp_to(&mut self, exp: ExpDesc, dst: usize) -> ParseResult<()> {
+        self.reserve(dst)?;
+        let dst = dst as u8;
+        let code = match self.discharge(exp) {
+            ExpDesc::Nil => ByteCode::LoadNil(dst),
+            ExpDesc::Boolean(b) => ByteCode::LoadBool(dst, b),
+            ExpDesc::Integer(i) => match i16::try_from(i) {
+                Ok(i) => ByteCode::LoadInteger(dst, i),
+                Err(_) => ByteCode::LoadConst(dst, self.add_const(Value::Integer(i))?),
+            },
+            ExpDesc::Float(f) => ByteCode::LoadConst(dst, self.add_const(Value::Float(f))?),
+            ExpDesc::String(s) => {
+                ByteCode::LoadConst(dst, self.add_const(Value::String(s.into()))?)
+            }
+            ExpDesc::Local(reg) | ExpDesc::Register(reg) => {
+                if reg == dst as usize {
+                    return Ok(());
                 }
+                ByteCode::Move(dst, reg as u8)
+            }
+            ExpDesc::Global(name) => ByteCode::GetGlobal(dst, name),
+            ExpDesc::Reloc(pc) => {
+                self.fs.byte_codes[pc].set_a(dst);
+                return Ok(());
+            }
+            exp @ (ExpDesc::Call(_) | ExpDesc::VarArgs(_) | ExpDesc::Index(..)) => {
+                unreachable!("{exp:?} has been discharged")
+            }
+        };
+        self.emit(code);
+        Ok(())
+    }
+
+    /// Make sure `exp` is in a register and return it. Locals already are, anything else is
+    /// loaded into a new temporary register.
+    fn exp_to_any_reg(&mut self, exp: ExpDesc) -> ParseResult<usize> {
+        match self.discharge(exp) {
+            ExpDesc::Local(reg) | ExpDesc::Register(reg) => Ok(reg),
+            exp => {
+                let reg = self.alloc_reg()?;
+                self.exp_to(exp, reg)?;
+                Ok(reg)
+            }
+        }
+    }
+
+    /// load `exp` into the first free register and return it
+    fn exp_to_next_reg(&mut self, exp: ExpDesc) -> ParseResult<usize> {
+        let exp = self.discharge(exp);
+        if let ExpDesc::Register(reg) = exp {
+            self.free_reg(reg);
+        }
+        let reg = self.alloc_reg()?;
+        self.exp_to(exp, reg)?;
+        Ok(reg)
+    }
+
+    /// the first free register, it has to be freed in reverse order of allocation
+    fn alloc_reg(&mut self) -> ParseResult<usize> {
+        let reg = self.fs.sp;
+        self.reserve(reg)?;
+        self.fs.sp += 1;
+        Ok(reg)
+    }
+
+    /// release `reg` if it is a temporary
+    fn free_reg(&mut self, reg: usize) {
+        if reg >= self.fs.locals.len() {
+            self.fs.sp -= 1;
+            debug_assert_eq!(reg, self.fs.sp, "registers must be freed in reverse order");
+        }
+    }
+
+    fn free_regs(&mut self, r1: usize, r2: usize) {
+        self.free_reg(r1.max(r2));
+        self.free_reg(r1.min(r2));
+    }
+
+    fn add_const(&mut self, v: Value) -> ParseResult<u16> {
+        let constants = &mut self.fs.constants;
+        let k = *self
+            .fs
+            .constant_indices
+            .entry(ConstantKey::from(&v))
+            .or_insert_with(|| {
+                constants.push(v);
+                constants.len() - 1
+            });
+        match u16::try_from(k) {
+            Ok(k) => Ok(k),
+            // the function is not compiled anymore, the constants after the first one beyond
+            // the limit are not reported again
+            Err(_) if self.fs.too_many_constants => Ok(0),
+            Err(_) => {
+                self.fs.too_many_constants = true;
+                Err(self.error_limit(MAX_INDEX, "constants"))
+            }
+        }
+    }
+
+    /// make sure register `reg` fits into the stack frame of the function
+    fn reserve(&mut self, reg: usize) -> ParseResult<()> {
+        if reg >= MAX_STACK_SIZE {
+            return Err(self.error_near(None, "function or expression needs too many registers"));
+        }
+        self.fs.max_stack_size = self.fs.max_stack_size.max(reg + 1);
+        Ok(())
+    }
+
+    /// add an instruction belonging to the line of the token consumed last, return its pc
+    fn emit(&mut self, code: ByteCode) -> usize {
+        self.emit_at(code, self.line)
+    }
+
+    fn emit_at(&mut self, code: ByteCode, line: usize) -> usize {
+        self.fs.byte_codes.push(code.into());
+        self.fs.lines.push(line as u32);
+        self.fs.byte_codes.len() - 1
+    }
+
+    fn next(&mut self) -> Token {
+        self.consumed += 1;
+        self.line = self.lex.peek_span().line;
+        self.lex.next()
+    }
+
+    fn name(&mut self) -> ParseResult<String> {
+        if let Token::Name(_) = self.lex.peek() {
+            let Token::Name(name) = self.next() else {
+                unreachable!()
+            };
+            Ok(name)
+        } else {
+            Err(self.error_expected("<name>"))
+        }
+    }
+
+    /// consume the next token if it is `expected`, fail otherwise
+    fn check_next(&mut self, expected: Token) -> ParseResult<()> {
+        if *self.lex.peek() == expected {
+            self.next();
+            Ok(())
+        } else {
+            Err(self.error_expected(&format!("'{expected}'")))
+        }
+    }
+
+    /// like `check_next` for a token closing `open` which was found on line `line`
+    fn check_match(&mut self, expected: Token, open: Token, line: usize) -> ParseResult<()> {
+        if *self.lex.peek() == expected {
+            self.next();
+            Ok(())
+        } else if line == self.lex.peek_span().line {
+            Err(self.error_expected(&format!("'{expected}'")))
+        } else {
+            let what = format!("'{expected}'");
+            let message = format!("{what} expected (to close '{open}' at line {line})");
+            Err(self.error_near(Some(what), &message))
+        }
+    }
+
+    fn error_expected(&mut self, what: &str) -> Box<ParseError> {
+        self.error_near(Some(what.to_string()), &format!("{what} expected"))
+    }
+
+    fn error_unexpected(&mut self) -> Box<ParseError> {
+        self.error_near(Some("expression".to_string()), "unexpected symbol")
+    }
+
+    /// count one more level of nesting, which has to be left again whatever the result
+    fn enter_level(&mut self) -> ParseResult<()> {
+        if self.levels >= MAX_LEVELS {
+            return Err(self.error_near(None, "chunk has too many C levels"));
+        }
+        self.levels += 1;
+        Ok(())
+    }
+
+    /// more than `limit` of `what` in the current function, named like reference Lua does
+    fn error_limit(&mut self, limit: usize, what: &str) -> Box<ParseError> {
+        let location = match self.fs.line_defined {
+            0 => "main function".to_string(),
+            line => format!("function at line {line}"),
+        };
+        self.error_near(
+            None,
+            &format!("too many {what} (limit is {limit}) in {location}"),
+        )
+    }
+
+    /// an error that is not about the next token, reported without it like reference Lua does
+    fn error_semantic(&mut self, message: &str) -> Box<ParseError> {
+        let mut err = self.error_near(None, message);
+        err.message = message.to_string();
+        err
+    }
+
+    /// build an error at the next token, lexical errors take precedence over `message`
+    fn error_near(&mut self, expected: Option<String>, message: &str) -> Box<ParseError> {
+        let span = self.lex.peek_span();
+        let token = self.lex.peek();
+        let found = match token {
+            Token::Eos => token.to_string(),
+            _ => format!("'{token}'"),
+        };
+        let message = match token {
+            Token::Error(LexError::UnexpectedChar(_)) => "unexpected symbol",
+            Token::Error(LexError::UnfinishedString(_)) => "unfinished string",
+            Token::Error(LexError::MalformedNumber(_)) => "malformed number",
+            _ => message,
+        };
+        Box::new(ParseError {
+            chunk: self.chunk.clone(),
+            span,
+            expected,
+            message: format!("{message} near {found}"),
+            found,
+        })
+    }
+}
+
+/// whether `token` ends a block
+fn block_follow(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Eos | Token::End | Token::Else | Token::Elseif | Token::Until
+    )
+}
+
+/// Maximum length of a chunk description in messages, like `LUA_IDSIZE` in reference Lua.
+const ID_SIZE: usize = 60;
+
+/// Format a chunk name for use in messages, the same way `luaO_chunkid` does in reference Lua:
+/// `=name` is shown verbatim, `@file` shows the file name and everything else is shown as
+/// `[string "first line..."]`.
+pub fn chunkid(chunkname: &str) -> String {
+    if let Some(name) = chunkname.strip_prefix('=') {
+        truncate(name, ID_SIZE - 1).to_string()
+    } else if let Some(file) = chunkname.strip_prefix('@') {
+        if file.len() < ID_SIZE {
+            file.to_string()
+        } else {
+            // keep the end of long file names, that is the more interesting part
+            let mut start = file.len() - (ID_SIZE - 4);
+            while !file.is_char_boundary(start) {
+                start += 1;
             }
-            Token::Eos => break,
-            t => panic!("unexpected token: {t:?}"),
+            format!("...{}", &file[start..])
+        }
+    } else {
+        const PRE: &str = "[string \"";
+        const POS: &str = "\"]";
+        const DOTS: &str = "...";
+        let line = chunkname.lines().next().unwrap_or("");
+        let max = ID_SIZE - (PRE.len() + DOTS.len() + POS.len()) - 1;
+        if line.len() == chunkname.len() && line.len() <= max {
+            format!("{PRE}{line}{POS}")
+        } else {
+            format!("{PRE}{}{DOTS}{POS}", truncate(line, max))
         }
     }
+}
 
-    dbg!(&constants);
-    dbg!(&byte_codes);
-    ParseProto {
-        constants,
-        byte_codes,
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
     }
+    &s[..end]
 }
 
 /// adding constants is a separate function because it is a place to make performance optimizations
 /// in case of duplicate constants we can just reference the same value instead of adding it twice
-fn add_const(constants: &mut Vec<Value>, v: Value) -> usize {
-    constants.iter().position(|c| c == &v).unwrap_or_else(|| {
-        constants.push(v);
-        constants.len() - 1
-    })
+pub(crate) fn add_const(constants: &mut Vec<Value>, v: Value) -> usize {
+    constants
+        .iter()
+        .position(|c| same_constant(c, &v))
+        .unwrap_or_else(|| {
+            constants.push(v);
+            constants.len() - 1
+        })
 }
 
-fn load_const(constants: &mut Vec<Value>, dst: usize, v: Value) -> ByteCode {
-    ByteCode::LoadConst(dst as u8, add_const(constants, v) as u8)
+/// What tells constants apart, the same for two constants if one can be loaded for the other
+/// like [`same_constant`] decides.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Integer(i64),
+    /// the bits of the float, which tell `0.0` and `-0.0` apart
+    Float(u64),
+    String(LuaString),
+}
+
+impl From<&Value> for ConstantKey {
+    fn from(v: &Value) -> Self {
+        match v {
+            Value::Integer(i) => ConstantKey::Integer(*i),
+            Value::Float(f) => ConstantKey::Float(f.to_bits()),
+            Value::String(s) => ConstantKey::String(s.clone()),
+            v => unreachable!("{v:?} is not a constant"),
+        }
+    }
+}
+
+/// Whether a constant can be loaded for another. Raw equality is not enough: `1` and `1.0` are
+/// equal but load different values, and so would `0.0` and `-0.0`.
+fn same_constant(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Float(x), Value::Float(y)) => x.to_bits() == y.to_bits(),
+        (Value::Integer(_), Value::Float(_)) | (Value::Float(_), Value::Integer(_)) => false,
+        _ => a == b,
+    }
+}
+
+/// Attribute of a local variable.
+enum Attrib {
+    /// `<const>`: the variable cannot be assigned to
+    Const,
+    /// `<close>`: the variable cannot be assigned to, and its value is closed when it goes out
+    /// of scope
+    Close,
+}
+
+/// An expression as far as it has been compiled. Constants and variables are only loaded when
+/// it is known where the value is needed, which saves moves and allows constant folding.
+#[derive(Debug, Clone, PartialEq)]
+enum ExpDesc {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    /// local variable in this register
+    Local(usize),
+    /// temporary register holding the value
+    Register(usize),
+    /// global variable, with the constant index of its name
+    Global(u16),
+    /// table field, with the registers of the table and the key
+    Index(usize, usize),
+    /// result of the instruction at this pc, whose destination register is still to be set
+    Reloc(usize),
+    /// call at this pc, whose number of results is still to be set
+    Call(usize),
+    /// `...` at this pc, whose destination and number of values are still to be set
+    VarArgs(usize),
+}
+
+impl ExpDesc {
+    /// whether the expression can produce any number of values
+    fn is_multi(&self) -> bool {
+        matches!(self, ExpDesc::Call(_) | ExpDesc::VarArgs(_))
+    }
+
+    fn is_numeral(&self) -> bool {
+        matches!(self, ExpDesc::Integer(_) | ExpDesc::Float(_))
+    }
+
+    /// whether a constant is true in a condition, `None` if it is not known at compile time
+    fn truthy(&self) -> Option<bool> {
+        match self {
+            ExpDesc::Nil | ExpDesc::Boolean(false) => Some(false),
+            ExpDesc::Boolean(true)
+            | ExpDesc::Integer(_)
+            | ExpDesc::Float(_)
+            | ExpDesc::String(_) => Some(true),
+            _ => None,
+        }
+    }
+
+    fn to_number(&self) -> Option<Value> {
+        match *self {
+            ExpDesc::Integer(i) => Some(Value::Integer(i)),
+            ExpDesc::Float(f) => Some(Value::Float(f)),
+            _ => None,
+        }
+    }
+}
+
+/// priority of unary operators, only `^` binds tighter
+const UNARY_PRIORITY: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnOp {
+    Arith(ArithOp),
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Arith(ArithOp),
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Concat,
+    And,
+    Or,
+}
+
+fn unary_op(token: &Token) -> Option<UnOp> {
+    match token {
+        Token::Sub => Some(UnOp::Arith(ArithOp::Unm)),
+        Token::BitXor => Some(UnOp::Arith(ArithOp::BNot)),
+        Token::Not => Some(UnOp::Not),
+        _ => None,
+    }
+}
+
+fn binary_op(token: &Token) -> Option<BinOp> {
+    let op = match token {
+        Token::Add => ArithOp::Add,
+        Token::Sub => ArithOp::Sub,
+        Token::Mul => ArithOp::Mul,
+        Token::Mod => ArithOp::Mod,
+        Token::Pow => ArithOp::Pow,
+        Token::Div => ArithOp::Div,
+        Token::Idiv => ArithOp::Idiv,
+        Token::BitAnd => ArithOp::BAnd,
+        Token::BitOr => ArithOp::BOr,
+        Token::BitXor => ArithOp::BXor,
+        Token::ShiftL => ArithOp::Shl,
+        Token::ShiftR => ArithOp::Shr,
+        Token::Equal => return Some(BinOp::Eq),
+        Token::NotEq => return Some(BinOp::Ne),
+        Token::Less => return Some(BinOp::Lt),
+        Token::LesEq => return Some(BinOp::Le),
+        Token::Greater => return Some(BinOp::Gt),
+        Token::GreEq => return Some(BinOp::Ge),
+        Token::Concat => return Some(BinOp::Concat),
+        Token::And => return Some(BinOp::And),
+        Token::Or => return Some(BinOp::Or),
+        _ => return None,
+    };
+    Some(BinOp::Arith(op))
+}
+
+/// left and right priority of a binary operator, the same as in reference Lua
+fn priority(op: BinOp) -> (u8, u8) {
+    match op {
+        BinOp::Or => (1, 1),
+        BinOp::And => (2, 2),
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => (3, 3),
+        BinOp::Arith(ArithOp::BOr) => (4, 4),
+        BinOp::Arith(ArithOp::BXor) => (5, 5),
+        BinOp::Arith(ArithOp::BAnd) => (6, 6),
+        BinOp::Arith(ArithOp::Shl | ArithOp::Shr) => (7, 7),
+        // right associative
+        BinOp::Concat => (9, 8),
+        BinOp::Arith(ArithOp::Add | ArithOp::Sub) => (10, 10),
+        BinOp::Arith(ArithOp::Mul | ArithOp::Div | ArithOp::Idiv | ArithOp::Mod) => (11, 11),
+        // right associative
+        BinOp::Arith(ArithOp::Pow) => (14, 13),
+        BinOp::Arith(op @ (ArithOp::Unm | ArithOp::BNot)) => {
+            unreachable!("{op:?} is not a binary operator")
+        }
+    }
+}
+
+/// Evaluate `op` at compile time if both operands are numerals. Like reference Lua, operations
+/// that raise an error, divide by zero or produce NaN or a zero float are left to runtime.
+fn fold(op: ArithOp, left: &ExpDesc, right: &ExpDesc) -> Option<ExpDesc> {
+    let (a, b) = (left.to_number()?, right.to_number()?);
+    if matches!(op, ArithOp::Div | ArithOp::Idiv | ArithOp::Mod)
+        && matches!(b, Value::Integer(0) | Value::Float(0.0))
+    {
+        return None;
+    }
+    match arith(op, &a, &b).ok()? {
+        Value::Integer(i) => Some(ExpDesc::Integer(i)),
+        Value::Float(f) if f.is_nan() || f == 0.0 => None,
+        Value::Float(f) => Some(ExpDesc::Float(f)),
+        _ => unreachable!("arithmetic on numbers gives a number"),
+    }
+}
+
+/// the instruction computing `lhs op rhs`, the destination register is set later
+fn arith_code(op: ArithOp, lhs: u8, rhs: u8) -> ByteCode {
+    match op {
+        ArithOp::Add => ByteCode::Add(0, lhs, rhs),
+        ArithOp::Sub => ByteCode::Sub(0, lhs, rhs),
+        ArithOp::Mul => ByteCode::Mul(0, lhs, rhs),
+        ArithOp::Mod => ByteCode::Mod(0, lhs, rhs),
+        ArithOp::Pow => ByteCode::Pow(0, lhs, rhs),
+        ArithOp::Div => ByteCode::Div(0, lhs, rhs),
+        ArithOp::Idiv => ByteCode::Idiv(0, lhs, rhs),
+        ArithOp::BAnd => ByteCode::BitAnd(0, lhs, rhs),
+        ArithOp::BOr => ByteCode::BitOr(0, lhs, rhs),
+        ArithOp::BXor => ByteCode::BitXor(0, lhs, rhs),
+        ArithOp::Shl => ByteCode::ShiftL(0, lhs, rhs),
+        ArithOp::Shr => ByteCode::ShiftR(0, lhs, rhs),
+        ArithOp::Unm | ArithOp::BNot => unreachable!("{op:?} is not a binary operator"),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::fs::File;
     use std::io::{self, Seek, Write};
     use tempfile::tempfile;
 
     fn prepare_file(code: &str) -> File {
         let mut file = tempfile().unwrap();
-        file.write(code.as_bytes()).unwrap();
+        file.write_all(code.as_bytes()).unwrap();
         file.seek(io::SeekFrom::Start(0)).unwrap();
         file
     }
@@ -154,13 +1523,13 @@ mod test {
     fn parse_print_hello_world() {
         let mut file = prepare_file("print \"hello world!\"\n");
 
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         assert_eq!(
             proto.constants,
             vec![
-                Value::String("print".to_string()),
-                Value::String("hello world!".to_string())
+                Value::String("print".into()),
+                Value::String("hello world!".into())
             ]
         );
     }
@@ -169,11 +1538,11 @@ mod test {
     fn parse_print_large_integer() {
         let mut file = prepare_file("print(33000)");
 
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         assert_eq!(
             proto.constants,
-            vec![Value::String("print".to_string()), Value::Integer(33000)]
+            vec![Value::String("print".into()), Value::Integer(33000)]
         );
     }
 
@@ -181,14 +1550,14 @@ mod test {
     fn parse_print_small_integer() {
         let mut file = prepare_file("print(1)");
 
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         assert_eq!(
             proto.byte_codes,
             vec![
                 ByteCode::GetGlobal(0, 0),
                 ByteCode::LoadInteger(1, 1_i16),
-                ByteCode::Call(0, 1)
+                ByteCode::Call(0, 2, 1)
             ]
         );
     }
@@ -197,11 +1566,11 @@ mod test {
     fn parse_print_float() {
         let mut file = prepare_file("print(1.5)");
 
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         assert_eq!(
             proto.constants,
-            vec![Value::String("print".to_string()), Value::Float(1.5)]
+            vec![Value::String("print".into()), Value::Float(1.5)]
         );
     }
 
@@ -209,11 +1578,69 @@ mod test {
     fn multiple_constants_stored_only_once() {
         let mut file = prepare_file("print(1.5)\nprint(1.5)");
 
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         assert_eq!(
             proto.constants,
-            vec![Value::String("print".to_string()), Value::Float(1.5)]
+            vec![Value::String("print".into()), Value::Float(1.5)]
+        );
+    }
+
+    #[test]
+    fn equal_numbers_of_different_types_are_distinct_constants() {
+        let mut file = prepare_file("print(33000, 33000.0, 0.5, 33000.0, 33000)");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        let numbers: Vec<String> = proto.constants[1..].iter().map(Value::to_string).collect();
+        assert_eq!(numbers, ["33000", "33000.0", "0.5"]);
+    }
+
+    #[test]
+    fn table_constructors_store_fields_and_list_items() {
+        let mut file = prepare_file("local t = {1, x = 2, [3] = 4; f()}");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::NewTable(0),
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::LoadConst(2, 0),
+                ByteCode::LoadInteger(3, 2),
+                ByteCode::SetTable(0, 2, 3),
+                ByteCode::LoadInteger(2, 3),
+                ByteCode::LoadInteger(3, 4),
+                ByteCode::SetTable(0, 2, 3),
+                ByteCode::GetGlobal(2, 1),
+                ByteCode::Call(2, 1, 0),
+                // the item before the call is stored with all its results
+                ByteCode::SetList(0, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn locals_assigned_are_copied_before_they_are_indexed() {
+        let mut file = prepare_file("local a = {}\na.x, a = 1, 2\nreturn a.x");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::NewTable(0),
+                ByteCode::LoadConst(1, 0),
+                ByteCode::Move(2, 0),
+                ByteCode::LoadInteger(3, 1),
+                ByteCode::LoadInteger(4, 2),
+                ByteCode::Move(0, 4),
+                ByteCode::SetTable(2, 1, 3),
+                ByteCode::LoadConst(1, 0),
+                ByteCode::GetTable(1, 0, 1),
+                ByteCode::Return(1, 2),
+            ]
         );
     }
 
@@ -221,8 +1648,363 @@ mod test {
     fn assign_variable() {
         let mut file = prepare_file("local a = 1\nprint(a)");
 
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(proto.constants, vec![Value::String("print".into())])
+    }
+
+    #[test]
+    fn fold_constant_expressions() {
+        let mut file = prepare_file("local a = 1 + 2 * 3 ^ 2\nlocal b = -(7 // 2) << 1");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(proto.constants, vec![Value::Float(19.0)]);
+        assert_eq!(
+            proto.byte_codes,
+            vec![ByteCode::LoadConst(0, 0), ByteCode::LoadInteger(1, -6)]
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_not_folded() {
+        let mut file = prepare_file("local a = 7 // 0");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 0),
+                ByteCode::LoadInteger(1, 7),
+                ByteCode::Idiv(0, 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn operands_are_evaluated_into_free_registers() {
+        let mut file = prepare_file("local a = 1\nlocal b = a + a * 2\nlocal c = -b ^ 2");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::LoadInteger(1, 2),
+                ByteCode::Mul(1, 0, 1),
+                ByteCode::Add(1, 0, 1),
+                ByteCode::LoadInteger(2, 2),
+                ByteCode::Pow(2, 1, 2),
+                ByteCode::Neg(2, 2),
+            ]
+        );
+        assert_eq!(proto.max_stack_size, 3);
+    }
+
+    #[test]
+    fn concatenation_is_right_associative() {
+        let mut file = prepare_file("local a, b = \"a\", \"b\"\nlocal c = a .. b .. 1 + 2");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadConst(0, 0),
+                ByteCode::LoadConst(1, 1),
+                ByteCode::LoadInteger(2, 3),
+                ByteCode::Concat(2, 1, 2),
+                ByteCode::Concat(2, 0, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn logical_operators_jump_over_the_right_operand() {
+        let mut file = prepare_file("local a = 1\nlocal b = a > 0 and a or not a");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::LoadInteger(1, 0),
+                ByteCode::LesThan(1, 1, 0),
+                ByteCode::TestAndJump(1, 1),
+                ByteCode::Move(1, 0),
+                ByteCode::TestOrJump(1, 1),
+                ByteCode::Not(1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn function_definitions_and_calls() {
+        let mut file = prepare_file("function f(a, b, ...) return a, ... end\nlocal x, y = f(1)");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::Closure(0, 0),
+                ByteCode::SetGlobal(0, 0),
+                ByteCode::GetGlobal(0, 0),
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::Call(0, 2, 3),
+            ]
+        );
+        let f = &proto.protos[0];
+        assert_eq!((f.num_params, f.is_vararg), (2, true));
+        assert_eq!(
+            f.byte_codes,
+            vec![
+                ByteCode::Move(2, 0),
+                ByteCode::VarArgs(3, 0),
+                ByteCode::Return(2, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn return_call_is_a_tail_call() {
+        let mut file = prepare_file(
+            "function a() return (f()) end\nfunction b() return 1, f() end\nreturn f(1)",
+        );
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes[4..],
+            [
+                ByteCode::GetGlobal(0, 2),
+                ByteCode::LoadInteger(1, 1),
+                ByteCode::TailCall(0, 2),
+                ByteCode::Return(0, 0),
+            ]
+        );
+        let is_tail_call = |code: &Instruction| matches!(code.decode(), ByteCode::TailCall(..));
+        assert!(!proto.protos[0].byte_codes.iter().any(is_tail_call));
+        assert!(!proto.protos[1].byte_codes.iter().any(is_tail_call));
+    }
+
+    #[test]
+    fn to_be_closed_locals_are_marked_and_not_tail_called() {
+        let mut file = prepare_file("local a <const>, b <close> = 1, x\nreturn f()");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes[..],
+            [
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::GetGlobal(1, 0),
+                ByteCode::Tbc(1),
+                ByteCode::GetGlobal(2, 1),
+                ByteCode::Call(2, 1, 0),
+                ByteCode::Return(2, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn local_attributes_are_checked() {
+        let messages = |source| -> Vec<String> {
+            load_errors(source)
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        };
+        assert_eq!(
+            messages("local a <const> = 1\na = 2\nlocal b <close> = nil\nb, c = 1, 2"),
+            [
+                "test:2: attempt to assign to const variable 'a'",
+                "test:4: attempt to assign to const variable 'b'",
+            ]
+        );
+        assert_eq!(
+            messages("local a <static> = 1"),
+            ["test:1: unknown attribute 'static'"]
+        );
+        assert_eq!(
+            messages("local a <close>, b <close> = x, y"),
+            ["test:1: multiple to-be-closed variables in local list"]
+        );
+    }
+
+    #[test]
+    fn multiple_results_are_adjusted() {
+        let mut file = prepare_file("local a, b, c = 1, ...\nprint(...)\nreturn (...)");
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(
+            proto.byte_codes,
+            vec![
+                ByteCode::LoadInteger(0, 1),
+                ByteCode::VarArgs(1, 3),
+                ByteCode::GetGlobal(3, 0),
+                ByteCode::VarArgs(4, 0),
+                ByteCode::Call(3, 0, 1),
+                ByteCode::VarArgs(3, 2),
+                ByteCode::Return(3, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn chunkid_formats_like_reference_lua() {
+        assert_eq!(chunkid("=stdin"), "stdin");
+        assert_eq!(chunkid("@script.lua"), "script.lua");
+        assert_eq!(chunkid("print(1)"), "[string \"print(1)\"]");
+        assert_eq!(
+            chunkid("local a = 1\nprint(a)"),
+            "[string \"local a = 1...\"]"
+        );
+
+        let long_file = format!("@{}.lua", "x".repeat(100));
+        let id = chunkid(&long_file);
+        assert!(id.starts_with("...") && id.ends_with("x.lua"));
+        assert_eq!(id.len(), ID_SIZE - 1);
+    }
+
+    fn load_errors(code: &str) -> Vec<ParseError> {
+        let mut file = prepare_file(code);
+        load(&mut file, "=test").unwrap_err()
+    }
+
+    #[test]
+    fn error_has_span_and_expected_token() {
+        let errors = load_errors("local a 1");
+
+        assert_eq!(
+            errors,
+            vec![ParseError {
+                chunk: "test".to_string(),
+                span: Span {
+                    line: 1,
+                    column: 9,
+                    start: 8,
+                    end: 9
+                },
+                expected: Some("'='".to_string()),
+                found: "'1'".to_string(),
+                message: "'=' expected near '1'".to_string(),
+            }]
+        );
+        assert_eq!(errors[0].to_string(), "test:1: '=' expected near '1'");
+    }
+
+    #[test]
+    fn report_every_error_in_chunk() {
+        let errors = load_errors("local = 1\nprint(1)\nprint(1 2)\nprint x\nprint(1)\n");
+
+        let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "test:1: <name> expected near '='",
+                "test:3: ')' expected near '2'",
+                "test:4: syntax error near 'x'",
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_paren_mentions_opening_line() {
+        let errors = load_errors("print(1\n\nlocal a = 1");
+
+        assert_eq!(
+            errors[0].message,
+            "')' expected (to close '(' at line 1) near 'local'"
+        );
+        assert_eq!(errors[0].span.line, 3);
+    }
+
+    #[test]
+    fn error_at_end_of_stream() {
+        let errors = load_errors("print(");
+
+        assert_eq!(errors[0].message, "unexpected symbol near <eof>");
+        assert_eq!(errors[0].found, "<eof>");
+    }
 
-        assert_eq!(proto.constants, vec![Value::String("print".to_string())])
+    #[test]
+    fn lexical_errors_are_reported() {
+        let errors = load_errors("print(\"abc\nprint(1.2.3)\nprint(@)");
+
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "unfinished string near '\"abc'",
+                "malformed number near '1.2.3'",
+                "unexpected symbol near '@'",
+            ]
+        );
+    }
+
+    #[test]
+    fn varargs_only_in_vararg_functions() {
+        let errors = load_errors("function f() return ... end");
+
+        assert_eq!(
+            errors[0].message,
+            "cannot use '...' outside a vararg function near '...'"
+        );
+    }
+
+    #[test]
+    fn constant_indices_take_sixteen_bits() {
+        let code: String = (0..300).map(|i| format!("x = \"s{i}\"\n")).collect();
+        let mut file = prepare_file(&code);
+
+        let proto = load(&mut file, "=test").unwrap();
+
+        assert_eq!(proto.constants.len(), 301);
+        assert_eq!(
+            proto.byte_codes[598..],
+            [ByteCode::LoadConst(0, 300), ByteCode::SetGlobal(0, 0)]
+        );
+    }
+
+    #[test]
+    fn too_many_constants_are_reported_once() {
+        let code: String = (0..MAX_INDEX + 100)
+            .map(|i| format!("x = {i}.5\n"))
+            .collect();
+
+        let errors = load_errors(&code);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "too many constants (limit is 65536) in main function near 'x'"
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let depth = 100000;
+        let chunks = [
+            format!("print({}1{})", "(".repeat(depth), ")".repeat(depth)),
+            format!("x = {}1", "-".repeat(depth)),
+            format!("x = {}1", "2^".repeat(depth)),
+            format!("{}{}", "function f() ".repeat(depth), "end ".repeat(depth)),
+        ];
+        for chunk in chunks {
+            let errors = load_errors(&chunk);
+            assert!(
+                errors[0].message.starts_with("chunk has too many C levels"),
+                "{}",
+                errors[0]
+            );
+        }
+        // just below the limit is fine
+        let mut file = prepare_file(&format!("x = {}1", "-".repeat(MAX_LEVELS - 2)));
+        load(&mut file, "=test").unwrap();
     }
 }
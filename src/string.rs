@@ -0,0 +1,330 @@
+//! Lua strings: immutable, shared by reference counting, with their hash computed once when
+//! they are created.
+//!
+//! Like in reference Lua, short strings are interned in a table of the state, so a string
+//! built or loaded twice is the same object. Comparing two of them and looking them up as
+//! keys then comes down to comparing pointers: strings that are not the same object are told
+//! apart by their hashes, and only compared byte by byte when those are equal. Long strings
+//! are not interned, they are rarely compared and hashing them is what interning would cost.
+//! The table still keeps track of the ones the state creates, to count them in the heap size
+//! until they are freed.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+
+/// Strings up to this length are interned, `LUAI_MAXSHORTLEN` in reference Lua.
+pub(crate) const MAX_SHORT_LEN: usize = 40;
+
+/// Seed of the hash function, reference Lua randomizes it per state.
+const HASH_SEED: u32 = 0x2545_f491;
+
+/// A Lua string.
+#[derive(Clone)]
+pub struct LuaString(Rc<StringObject>);
+
+struct StringObject {
+    hash: u32,
+    s: Box<str>,
+}
+
+impl LuaString {
+    fn with_hash(s: Box<str>, hash: u32) -> Self {
+        LuaString(Rc::new(StringObject { hash, s }))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0.s
+    }
+
+    /// hash of the contents, the same for equal strings
+    pub fn hash_value(&self) -> u32 {
+        self.0.hash
+    }
+
+    /// whether `self` and `other` are the same object, which interned strings that are equal
+    /// always are
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// `luaS_hash` of reference Lua.
+fn hash_str(s: &str) -> u32 {
+    let mut h = HASH_SEED ^ s.len() as u32;
+    for &b in s.as_bytes().iter().rev() {
+        h ^= (h << 5).wrapping_add(h >> 2).wrapping_add(b.into());
+    }
+    h
+}
+
+/// A string that is not interned. Strings a state creates itself are interned by
+/// [`StringTable::intern`], these are compared by contents when they meet one.
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        LuaString::with_hash(s.into(), hash_str(s))
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> Self {
+        let hash = hash_str(&s);
+        LuaString::with_hash(s.into_boxed_str(), hash)
+    }
+}
+
+impl Deref for LuaString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other) || (self.0.hash == other.0.hash && self.0.s == other.0.s)
+    }
+}
+
+impl Eq for LuaString {}
+
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.0.hash);
+    }
+}
+
+impl fmt::Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Hasher for maps keyed by [`LuaString`], which spreads the hash already computed instead of
+/// hashing the contents again.
+#[derive(Default)]
+pub(crate) struct StringHasher(u64);
+
+impl Hasher for StringHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u32(b.into());
+        }
+    }
+
+    fn write_u32(&mut self, hash: u32) {
+        // the map picks buckets with the low bits and tags entries with the high ones
+        self.0 = (self.0 ^ u64::from(hash)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+pub(crate) type BuildStringHasher = BuildHasherDefault<StringHasher>;
+
+/// The short strings of a state, `stringtable` in reference Lua: a hash table with a list of
+/// strings in each bucket, doubled in size when it holds as many strings as buckets.
+pub(crate) struct StringTable {
+    buckets: Vec<Vec<LuaString>>,
+    count: usize,
+    /// bytes allocated for the strings
+    bytes: usize,
+    /// Strings the state created without interning them, long ones and error messages, with
+    /// their sizes, until a sweep finds them freed. Errors are raised without mutable access
+    /// to the state, hence the cells.
+    created: RefCell<Vec<(Weak<StringObject>, usize)>>,
+    created_bytes: Cell<usize>,
+}
+
+impl StringTable {
+    pub(crate) fn new() -> Self {
+        StringTable {
+            buckets: vec![Vec::new(); 128],
+            count: 0,
+            bytes: 0,
+            created: RefCell::new(Vec::new()),
+            created_bytes: Cell::new(0),
+        }
+    }
+
+    /// The string with contents `s`: the interned one if it is short, created if there is
+    /// none yet.
+    pub(crate) fn intern(&mut self, s: &str) -> LuaString {
+        if s.len() > MAX_SHORT_LEN {
+            return self.create(s);
+        }
+        let hash = hash_str(s);
+        let mask = self.buckets.len() - 1;
+        let bucket = &mut self.buckets[hash as usize & mask];
+        if let Some(found) = bucket.iter().find(|x| x.0.hash == hash && *x.0.s == *s) {
+            return found.clone();
+        }
+        let string = LuaString::with_hash(s.into(), hash);
+        bucket.push(string.clone());
+        self.count += 1;
+        self.bytes += string_size(s);
+        if self.count > self.buckets.len() {
+            self.resize(self.buckets.len() * 2);
+        }
+        string
+    }
+
+    /// `s` itself if it is interned already, the interned string with its contents if it is
+    /// short, and a copy counted like the strings the state creates if it is long
+    pub(crate) fn intern_string(&mut self, s: &LuaString) -> LuaString {
+        if s.len() > MAX_SHORT_LEN {
+            return self.create(s);
+        }
+        let bucket = &self.buckets[s.0.hash as usize & (self.buckets.len() - 1)];
+        match bucket.iter().find(|x| x.ptr_eq(s)) {
+            Some(found) => found.clone(),
+            None => self.intern(s),
+        }
+    }
+
+    /// a new string with contents `s` that is not interned, counted until it is freed
+    pub(crate) fn create(&self, s: &str) -> LuaString {
+        let string = LuaString::from(s);
+        let size = string_size(s);
+        self.created
+            .borrow_mut()
+            .push((Rc::downgrade(&string.0), size));
+        self.created_bytes.set(self.created_bytes.get() + size);
+        string
+    }
+
+    fn resize(&mut self, size: usize) {
+        let mut buckets = vec![Vec::new(); size];
+        for string in self.buckets.drain(..).flatten() {
+            buckets[string.0.hash as usize & (size - 1)].push(string);
+        }
+        self.buckets = buckets;
+    }
+
+    /// Stop counting the strings created without interning them that have been freed.
+    pub(crate) fn sweep_created(&mut self) {
+        let mut freed = 0;
+        self.created.get_mut().retain(|(string, size)| {
+            let alive = string.strong_count() > 0;
+            if !alive {
+                freed += size;
+            }
+            alive
+        });
+        *self.created_bytes.get_mut() -= freed;
+    }
+
+    /// Drop the strings only the table refers to, at the end of a collection like the dead
+    /// strings of reference Lua, and stop counting the other strings freed.
+    pub(crate) fn sweep(&mut self) {
+        self.sweep_created();
+        let mut freed = 0;
+        for bucket in &mut self.buckets {
+            bucket.retain(|string| {
+                let used = Rc::strong_count(&string.0) > 1;
+                if !used {
+                    freed += string_size(string);
+                }
+                used
+            });
+        }
+        self.bytes -= freed;
+        self.count = self.buckets.iter().map(Vec::len).sum();
+        if self.count < self.buckets.len() / 4 && self.buckets.len() > 128 {
+            self.resize(self.buckets.len() / 2);
+        }
+    }
+
+    /// bytes allocated for the strings counted and the table
+    pub(crate) fn size(&self) -> usize {
+        let created =
+            self.created.borrow().capacity() * std::mem::size_of::<(Weak<StringObject>, usize)>();
+        self.bytes
+            + self.created_bytes.get()
+            + created
+            + self.buckets.capacity() * std::mem::size_of::<Vec<LuaString>>()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+}
+
+/// bytes allocated for a string: the reference counts, the hash and the contents
+fn string_size(s: &str) -> usize {
+    std::mem::size_of::<usize>() * 2 + std::mem::size_of::<StringObject>() + s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_strings_are_interned() {
+        let mut strings = StringTable::new();
+        let a = strings.intern("print");
+        let b = strings.intern(&format!("pri{}", "nt"));
+        assert!(a.ptr_eq(&b));
+        assert_eq!(strings.len(), 1);
+
+        let long = "x".repeat(MAX_SHORT_LEN + 1);
+        assert!(!strings.intern(&long).ptr_eq(&strings.intern(&long)));
+        assert_eq!(strings.len(), 1);
+    }
+
+    #[test]
+    fn long_strings_are_counted_until_they_are_freed() {
+        let mut strings = StringTable::new();
+        let base = strings.size();
+        let long = strings.intern(&"x".repeat(100));
+        let with_long = strings.size();
+        assert!(with_long >= base + 100, "{with_long} < {base} + 100");
+
+        strings.sweep();
+        assert_eq!(strings.size(), with_long);
+        drop(long);
+        strings.sweep();
+        assert!(strings.size() < base + 100);
+    }
+
+    #[test]
+    fn strings_not_interned_compare_by_contents() {
+        let mut strings = StringTable::new();
+        let interned = strings.intern("yield");
+        let loaded = LuaString::from("yield");
+        assert!(!interned.ptr_eq(&loaded));
+        assert_eq!(interned, loaded);
+        assert_eq!(interned.hash_value(), loaded.hash_value());
+        assert_ne!(interned, LuaString::from("yields"));
+        assert!(strings.intern_string(&loaded).ptr_eq(&interned));
+    }
+
+    #[test]
+    fn table_grows_and_drops_unused_strings() {
+        let mut strings = StringTable::new();
+        let kept: Vec<LuaString> = (0..1000).map(|i| strings.intern(&i.to_string())).collect();
+        for i in 1000..2000 {
+            strings.intern(&i.to_string());
+        }
+        assert_eq!(strings.len(), 2000);
+        assert!(strings.buckets.len() >= 2000);
+
+        strings.sweep();
+        assert_eq!(strings.len(), 1000);
+        for (i, s) in kept.iter().enumerate() {
+            assert!(strings.intern(&i.to_string()).ptr_eq(s));
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Tables and their metatables, and the library functions setting and getting those.
+//!
+//! A table is a hash map from keys to values, without a separate array part. Metatables have
+//! no effect on indexing or operators yet. The collector is the only one to look into them:
+//! `__mode` makes the references of a table weak, and a table whose metatable has a `__gc`
+//! field when it is set is finalized, see [`crate::gc`].
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::gc::Age;
+use crate::value::{KeyError, TableKey, Value};
+use crate::vm::ExeState;
+
+/// A Lua table.
+pub struct Table {
+    pub(crate) entries: RefCell<HashMap<TableKey, Value>>,
+    pub(crate) metatable: RefCell<Option<Rc<Table>>>,
+    /// see [`crate::gc`]
+    pub(crate) mark: Cell<u32>,
+    pub(crate) age: Cell<Age>,
+}
+
+impl Table {
+    pub(crate) fn new() -> Self {
+        Table {
+            entries: RefCell::new(HashMap::new()),
+            metatable: RefCell::new(None),
+            mark: Cell::new(0),
+            age: Cell::new(Age::New),
+        }
+    }
+
+    /// the value stored at `key`, nil if there is none
+    pub(crate) fn get(&self, key: &Value) -> Value {
+        match TableKey::new(key.clone()) {
+            Ok(key) => self
+                .entries
+                .borrow()
+                .get(&key)
+                .cloned()
+                .unwrap_or(Value::Nil),
+            Err(_) => Value::Nil,
+        }
+    }
+
+    /// Store `value` at `key`. Storing nil removes the entry.
+    pub(crate) fn set(&self, key: Value, value: Value) -> Result<(), KeyError> {
+        let key = TableKey::new(key)?;
+        // the value replaced is dropped once the entries are no longer borrowed
+        let _old = match value {
+            Value::Nil => self.entries.borrow_mut().remove(&key),
+            value => self.entries.borrow_mut().insert(key, value),
+        };
+        Ok(())
+    }
+
+    /// the field `name` of the metatable, nil if there is no metatable
+    pub(crate) fn metafield(&self, name: &str) -> Value {
+        match &*self.metatable.borrow() {
+            Some(metatable) => metatable.get(&Value::String(name.into())),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl ExeState<'_> {
+    /// the table passed as argument `arg` to the library function `name`
+    fn check_table(&self, arg: usize, name: &str) -> Result<Rc<Table>, Value> {
+        match self.args().get(arg - 1) {
+            Some(Value::Table(t)) => Ok(t.clone()),
+            v => {
+                let got = v.map_or("no value", Value::type_name);
+                let message =
+                    format!("bad argument #{arg} to '{name}' (table expected, got {got})");
+                Err(self.native_error(message))
+            }
+        }
+    }
+}
+
+// "setmetatable(t, mt)": set the metatable of t, or remove it if mt is nil, and return t
+pub(crate) fn lib_setmetatable(state: &mut ExeState) -> Result<i32, Value> {
+    let table = state.check_table(1, "setmetatable")?;
+    let metatable = match state.args().get(1) {
+        Some(Value::Table(mt)) => Some(mt.clone()),
+        Some(Value::Nil) => None,
+        v => {
+            let got = v.map_or("no value", Value::type_name);
+            let message =
+                format!("bad argument #2 to 'setmetatable' (nil or table expected, got {got})");
+            return Err(state.native_error(message));
+        }
+    };
+    state.heap.table_barrier(&table);
+    *table.metatable.borrow_mut() = metatable;
+    // like in reference Lua, a `__gc` field added to the metatable later has no effect
+    if table.metafield("__gc") != Value::Nil {
+        state.heap.add_finalizer(&table);
+    }
+    state.stack.push(Value::Table(table));
+    Ok(1)
+}
+
+// "getmetatable(v)": the metatable of v, nil if it has none
+pub(crate) fn lib_getmetatable(state: &mut ExeState) -> Result<i32, Value> {
+    let metatable = match state.args().first() {
+        Some(Value::Table(t)) => t.metatable.borrow().clone().map(Value::Table),
+        Some(_) => None,
+        None => {
+            let message = "bad argument #1 to 'getmetatable' (value expected)";
+            return Err(state.native_error(message));
+        }
+    };
+    state.stack.push(metatable.unwrap_or(Value::Nil));
+    Ok(1)
+}
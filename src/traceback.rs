@@ -0,0 +1,144 @@
+//! Stack tracebacks: the calls active when an error was raised, innermost first, and their
+//! text form, which is the same as `luaL_traceback` in reference Lua produces.
+
+use std::fmt;
+
+/// Number of calls listed from the top of a long traceback before the rest is skipped.
+const LEVELS_TOP: usize = 10;
+/// Number of calls listed from the bottom of a long traceback.
+const LEVELS_BOTTOM: usize = 11;
+
+/// What kind of function a frame runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// the function of a chunk
+    Main,
+    /// a function defined in Lua
+    Lua,
+    /// a Rust function
+    Native,
+}
+
+/// An active call, as listed in a traceback.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameInfo {
+    pub kind: FrameKind,
+    /// chunk name formatted for messages, `[C]` for Rust functions
+    pub source: String,
+    /// line being executed, if the function has line information
+    pub current_line: Option<u32>,
+    /// line where the function was defined, 0 for main chunks and Rust functions
+    pub line_defined: u32,
+    /// how the calling code referred to the function, as the kind of name and the name, like
+    /// `("global", "foo")` or `("local", "f")`
+    pub name: Option<(&'static str, String)>,
+    /// whether the function was tail called, the calls in between are gone
+    pub tail_call: bool,
+}
+
+impl FrameInfo {
+    /// the function as described in tracebacks, like "function 'foo'" or "main chunk"
+    pub fn describe(&self) -> String {
+        match (&self.name, self.kind) {
+            (Some(("global", name)), _) => format!("function '{name}'"),
+            (Some((kind, name)), _) => format!("{kind} '{name}'"),
+            (None, FrameKind::Main) => "main chunk".to_string(),
+            (None, FrameKind::Lua) => format!("function <{}:{}>", self.source, self.line_defined),
+            (None, FrameKind::Native) => "?".to_string(),
+        }
+    }
+}
+
+/// The calls active at some point, innermost first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Traceback {
+    pub frames: Vec<FrameInfo>,
+}
+
+impl fmt::Display for Traceback {
+    /// The frames in the format of reference Lua, starting with "stack traceback:". The middle
+    /// of a long traceback is skipped.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("stack traceback:")?;
+        let total = self.frames.len();
+        let mut level = 0;
+        while level < total {
+            if level == LEVELS_TOP && total > LEVELS_TOP + LEVELS_BOTTOM {
+                let skipped = total - LEVELS_TOP - LEVELS_BOTTOM;
+                write!(f, "\n\t...\t(skipping {skipped} levels)")?;
+                level += skipped;
+            }
+            let frame = &self.frames[level];
+            match frame.current_line {
+                Some(line) => write!(f, "\n\t{}:{line}: in ", frame.source)?,
+                None => write!(f, "\n\t{}: in ", frame.source)?,
+            }
+            f.write_str(&frame.describe())?;
+            if frame.tail_call {
+                f.write_str("\n\t(...tail calls...)")?;
+            }
+            level += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: FrameKind, name: Option<(&'static str, &str)>, line: u32) -> FrameInfo {
+        FrameInfo {
+            kind,
+            source: if kind == FrameKind::Native {
+                "[C]"
+            } else {
+                "test"
+            }
+            .to_string(),
+            current_line: (kind != FrameKind::Native).then_some(line),
+            line_defined: if kind == FrameKind::Lua { 1 } else { 0 },
+            name: name.map(|(what, name)| (what, name.to_string())),
+            tail_call: false,
+        }
+    }
+
+    #[test]
+    fn format_like_reference_lua() {
+        let mut traceback = Traceback {
+            frames: vec![
+                frame(FrameKind::Native, Some(("global", "error")), 0),
+                frame(FrameKind::Lua, Some(("local", "f")), 2),
+                frame(FrameKind::Lua, None, 5),
+                frame(FrameKind::Native, None, 0),
+                frame(FrameKind::Main, None, 7),
+            ],
+        };
+        traceback.frames[2].tail_call = true;
+
+        assert_eq!(
+            traceback.to_string(),
+            "stack traceback:\n\
+             \t[C]: in function 'error'\n\
+             \ttest:2: in local 'f'\n\
+             \ttest:5: in function <test:1>\n\
+             \t(...tail calls...)\n\
+             \t[C]: in ?\n\
+             \ttest:7: in main chunk"
+        );
+    }
+
+    #[test]
+    fn skip_the_middle_of_long_tracebacks() {
+        let frames = (0..30)
+            .map(|line| frame(FrameKind::Lua, Some(("global", "f")), line))
+            .collect();
+        let text = Traceback { frames }.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 1 + LEVELS_TOP + 1 + LEVELS_BOTTOM);
+        assert_eq!(lines[10], "\ttest:9: in function 'f'");
+        assert_eq!(lines[11], "\t...\t(skipping 9 levels)");
+        assert_eq!(lines[12], "\ttest:19: in function 'f'");
+    }
+}
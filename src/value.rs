@@ -1,40 +1,307 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 
+use crate::compare::int_float_cmp;
+use crate::coroutine::Thread;
+use crate::gc::Age;
+use crate::number::{float_to_integer, float_to_str};
+use crate::parser::ParseProto;
+use crate::string::LuaString;
+use crate::table::Table;
 use crate::vm::ExeState;
 
+/// A Rust function callable from Lua. It returns how many of the values at the top of the
+/// stack are its results, or an error value.
+pub type NativeFunction = fn(&mut ExeState) -> Result<i32, Value>;
+
+/// A Lua value. Everything larger than a word lives on the heap behind a single pointer,
+/// shared by reference counting and traced by the collector when it can be part of a cycle,
+/// so a value is a tag and a word: 16 bytes, a quarter of a cache line per register.
 #[derive(Clone)]
 pub enum Value {
     Nil,
-    String(String),
-    Function(fn(&mut ExeState) -> i32),
+    String(LuaString),
+    /// a Rust function
+    Function(NativeFunction),
+    /// a function compiled from Lua source
+    LuaFunction(Rc<LuaClosure>),
+    /// a Rust function with values of its own
+    NativeClosure(Rc<NativeClosure>),
+    /// a coroutine
+    Thread(Rc<RefCell<Thread>>),
+    Table(Rc<Table>),
     Integer(i64),
     Float(f64),
     Boolean(bool),
 }
 
-impl fmt::Debug for Value {
+// a larger variant would make every register and copy bigger
+const _: () = assert!(std::mem::size_of::<Value>() == 16);
+
+impl Value {
+    /// name of the type of the value, as returned by `type()` in Lua
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::LuaFunction(_) | Value::NativeClosure(_) => "function",
+            Value::Integer(_) | Value::Float(_) => "number",
+            Value::Boolean(_) => "boolean",
+            Value::Thread(_) => "thread",
+            Value::Table(_) => "table",
+        }
+    }
+
+    /// only `nil` and `false` are false in conditions
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+}
+
+/// The text `tostring` gives for a value, the same as Lua 5.4 does: floats are formatted with
+/// `%.14g`, functions and threads show their type and address.
+impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             Value::Nil => write!(f, "nil"),
             Value::String(s) => write!(f, "{s}"),
-            Value::Function(_) => write!(f, "function"),
+            Value::Function(func) => write!(f, "function: {:p}", *func as *const ()),
+            Value::LuaFunction(closure) => write!(f, "function: {:p}", Rc::as_ptr(closure)),
+            Value::NativeClosure(closure) => write!(f, "function: {:p}", Rc::as_ptr(closure)),
             Value::Integer(i) => write!(f, "{i}"),
-            Value::Float(v) => write!(f, "{v:?}"),
+            Value::Float(v) => write!(f, "{}", float_to_str(*v)),
             Value::Boolean(b) => write!(f, "{b}"),
+            Value::Thread(thread) => write!(f, "thread: {:p}", Rc::as_ptr(thread)),
+            Value::Table(table) => write!(f, "table: {:p}", Rc::as_ptr(table)),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Value::String(s) => write!(f, "{s:?}"),
+            v => write!(f, "{v}"),
         }
     }
 }
 
+/// Raw equality of Lua, `rawequal`: numbers are equal if they have the same mathematical
+/// value, whether integers or floats, strings if they have the same contents, and other
+/// values only to themselves.
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Value::Boolean(a), Value::Boolean(b)) => a == b,
             (Value::Nil, Value::Nil) => true,
             (Value::String(a), Value::String(b)) => a == b,
-            (Value::Function(a), Value::Function(b)) => std::ptr::eq(a, b),
+            (Value::Function(a), Value::Function(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Value::LuaFunction(a), Value::LuaFunction(b)) => Rc::ptr_eq(a, b),
+            (Value::NativeClosure(a), Value::NativeClosure(b)) => Rc::ptr_eq(a, b),
+            (Value::Thread(a), Value::Thread(b)) => Rc::ptr_eq(a, b),
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
             (Value::Integer(a), Value::Integer(b)) => a == b,
             (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Integer(i), Value::Float(f)) | (Value::Float(f), Value::Integer(i)) => {
+                int_float_cmp(*i, *f) == Some(Ordering::Equal)
+            }
             _ => false,
         }
     }
 }
+
+/// Consistent with equality: a float with an integral value hashes like the integer, so `1`
+/// and `1.0` find the same table slot. Values of reference types hash by identity.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Nil => state.write_u8(0),
+            Value::Boolean(b) => {
+                state.write_u8(1);
+                b.hash(state);
+            }
+            Value::Integer(i) => {
+                state.write_u8(2);
+                i.hash(state);
+            }
+            Value::Float(f) => match float_to_integer(*f) {
+                Some(i) => {
+                    state.write_u8(2);
+                    i.hash(state);
+                }
+                None => {
+                    state.write_u8(3);
+                    f.to_bits().hash(state);
+                }
+            },
+            Value::String(s) => {
+                state.write_u8(4);
+                s.hash(state);
+            }
+            Value::Function(f) => {
+                state.write_u8(5);
+                (*f as *const ()).hash(state);
+            }
+            Value::LuaFunction(c) => {
+                state.write_u8(5);
+                Rc::as_ptr(c).hash(state);
+            }
+            Value::NativeClosure(c) => {
+                state.write_u8(5);
+                Rc::as_ptr(c).hash(state);
+            }
+            Value::Thread(t) => {
+                state.write_u8(6);
+                Rc::as_ptr(t).hash(state);
+            }
+            Value::Table(t) => {
+                state.write_u8(7);
+                Rc::as_ptr(t).hash(state);
+            }
+        }
+    }
+}
+
+/// A value that can index a table: neither nil nor NaN. Like in reference Lua, a float with
+/// an integral value is converted to the integer, so keys are equal exactly when the values
+/// are.
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub(crate) struct TableKey(Value);
+
+// NaN, the only value not equal to itself, cannot be a key
+impl Eq for TableKey {}
+
+/// Why a value cannot be a table key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyError {
+    Nil,
+    NaN,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Nil => write!(f, "table index is nil"),
+            KeyError::NaN => write!(f, "table index is NaN"),
+        }
+    }
+}
+
+impl TableKey {
+    pub(crate) fn new(value: Value) -> Result<Self, KeyError> {
+        match value {
+            Value::Nil => Err(KeyError::Nil),
+            Value::Float(f) if f.is_nan() => Err(KeyError::NaN),
+            Value::Float(f) => Ok(TableKey(float_to_integer(f).map_or(value, Value::Integer))),
+            value => Ok(TableKey(value)),
+        }
+    }
+
+    pub(crate) fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+/// A function compiled from Lua source, created each time its `function` expression is
+/// evaluated. Closures of the same prototype are distinct values. The only upvalue is `_ENV`,
+/// the globals, so a closure holds nothing but its prototype.
+pub struct LuaClosure {
+    pub proto: Rc<ParseProto>,
+}
+
+impl LuaClosure {
+    pub fn new(proto: Rc<ParseProto>) -> Self {
+        LuaClosure { proto }
+    }
+}
+
+/// A Rust function together with values it can reach while it runs, like a C closure in
+/// reference Lua.
+pub struct NativeClosure {
+    pub func: NativeFunction,
+    pub upvalues: RefCell<Vec<Value>>,
+    /// see [`crate::gc`]
+    pub(crate) mark: Cell<u32>,
+    pub(crate) age: Cell<Age>,
+}
+
+impl NativeClosure {
+    pub fn new(func: NativeFunction, upvalues: Vec<Value>) -> Self {
+        NativeClosure {
+            func,
+            upvalues: RefCell::new(upvalues),
+            mark: Cell::new(0),
+            age: Cell::new(Age::New),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn hash(v: &Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        v.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn key(v: Value) -> TableKey {
+        TableKey::new(v).unwrap()
+    }
+
+    #[test]
+    fn numbers_are_equal_by_value() {
+        assert_eq!(Value::Integer(1), Value::Float(1.0));
+        assert_eq!(Value::Float(-0.0), Value::Integer(0));
+        assert_ne!(Value::Integer(1), Value::Float(1.5));
+        assert_ne!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+        assert_ne!(Value::Integer(i64::MAX), Value::Float(i64::MAX as f64));
+        assert_ne!(Value::Integer(1), Value::String("1".into()));
+
+        assert_eq!(hash(&Value::Integer(1)), hash(&Value::Float(1.0)));
+        assert_eq!(hash(&Value::Integer(0)), hash(&Value::Float(-0.0)));
+    }
+
+    #[test]
+    fn reference_types_are_equal_by_identity() {
+        let closure = || Rc::new(NativeClosure::new(|_| Ok(0), Vec::new()));
+        let a = Value::NativeClosure(closure());
+        assert_eq!(a, a.clone());
+        assert_eq!(hash(&a), hash(&a.clone()));
+        assert_ne!(a, Value::NativeClosure(closure()));
+
+        let proto = Rc::new(ParseProto::default());
+        let f = Value::LuaFunction(Rc::new(LuaClosure::new(proto.clone())));
+        assert_eq!(f, f.clone());
+        assert_ne!(f, Value::LuaFunction(Rc::new(LuaClosure::new(proto))));
+
+        let print: NativeFunction = |_| Ok(0);
+        assert_eq!(Value::Function(print), Value::Function(print));
+        assert_eq!(Value::String("s".into()), Value::String("s".into()));
+    }
+
+    #[test]
+    // reference types hash by pointer, what they hold can change without moving their keys
+    #[allow(clippy::mutable_key_type)]
+    fn table_keys_normalize_floats() {
+        let mut table = HashMap::new();
+        table.insert(key(Value::Integer(1)), "one");
+        table.insert(key(Value::Float(1.0)), "float one");
+        table.insert(key(Value::Float(1.5)), "one and a half");
+        table.insert(key(Value::String("1".into())), "string");
+        assert_eq!(table.len(), 3);
+        assert_eq!(table[&key(Value::Integer(1))], "float one");
+        assert!(matches!(key(Value::Float(2.0)).value(), Value::Integer(2)));
+
+        assert_eq!(TableKey::new(Value::Nil), Err(KeyError::Nil));
+        let nan = TableKey::new(Value::Float(f64::NAN)).unwrap_err();
+        assert_eq!(nan.to_string(), "table index is NaN");
+    }
+}
@@ -0,0 +1,386 @@
+//! Static checks of prototypes that did not come from the parser.
+//!
+//! The VM trusts its input: operands index the stack and the constants without further
+//! checks. A prototype that passes [`ParseProto::verify`] can not make it read or write out of
+//! bounds.
+
+use std::fmt;
+
+use crate::bytecode::ByteCode;
+use crate::parser::ParseProto;
+use crate::value::Value;
+
+/// The first problem found in a prototype.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    /// index of the offending instruction
+    pub pc: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid instruction {}: {}", self.pc + 1, self.message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl ParseProto {
+    /// Check every operand of every instruction: registers must be below `max_stack_size`,
+    /// constant indices must exist and have the type the instruction expects, and the
+    /// arguments of a call must fit into the stack frame. Instructions using everything up to
+    /// the top must directly follow the one setting it. Debug information, if present, has to
+    /// describe exactly the instructions of the prototype. Nested prototypes are checked as
+    /// well, errors refer to the instructions of the nested prototype.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let codes: Vec<ByteCode> = self.byte_codes.iter().map(|c| c.decode()).collect();
+        let mut targets = vec![false; codes.len() + 1];
+        for (pc, code) in codes.iter().enumerate() {
+            if let Some(offset) = code.jump_offset() {
+                if let Ok(target) = usize::try_from(pc as isize + 1 + offset as isize) {
+                    if let Some(t) = targets.get_mut(target) {
+                        *t = true;
+                    }
+                }
+            }
+        }
+        for (pc, code) in codes.iter().enumerate() {
+            let check = Checker { proto: self, pc };
+            match *code {
+                ByteCode::GetGlobal(dst, k) => {
+                    check.register(dst)?;
+                    if !matches!(check.constant(k)?, Value::String(_)) {
+                        return Err(check.error(format!("global name {k} is not a string")));
+                    }
+                }
+                ByteCode::LoadConst(dst, k) => {
+                    check.register(dst)?;
+                    check.constant(k)?;
+                }
+                ByteCode::Call(func, nargs, nresults) => {
+                    check.register(func)?;
+                    match nargs {
+                        0 => check.top(&codes, &targets, func as usize + 1)?,
+                        n => check.range("arguments", func as usize + 1, n as usize - 1)?,
+                    }
+                    if nresults > 0 {
+                        check.range("results", func as usize, nresults as usize - 1)?;
+                    }
+                }
+                ByteCode::TailCall(func, nargs) => {
+                    check.register(func)?;
+                    match nargs {
+                        0 => check.top(&codes, &targets, func as usize + 1)?,
+                        n => check.range("arguments", func as usize + 1, n as usize - 1)?,
+                    }
+                }
+                ByteCode::Return(first, n) => match n {
+                    0 => {
+                        check.register(first)?;
+                        check.top(&codes, &targets, first as usize)?;
+                    }
+                    n => check.range("results", first as usize, n as usize - 1)?,
+                },
+                ByteCode::VarArgs(dst, n) => match n {
+                    0 => check.register(dst)?,
+                    n => check.range("results", dst as usize, n as usize - 1)?,
+                },
+                ByteCode::Closure(dst, index) => {
+                    check.register(dst)?;
+                    if index as usize >= self.protos.len() {
+                        return Err(check.error(format!(
+                            "function {index} out of range, there are {} functions",
+                            self.protos.len()
+                        )));
+                    }
+                }
+                ByteCode::SetGlobal(src, k) => {
+                    check.register(src)?;
+                    if !matches!(check.constant(k)?, Value::String(_)) {
+                        return Err(check.error(format!("global name {k} is not a string")));
+                    }
+                }
+                ByteCode::SetList(table, n, _) => {
+                    check.register(table)?;
+                    match n {
+                        0 => check.top(&codes, &targets, table as usize + 1)?,
+                        n => check.range("values", table as usize + 1, n as usize - 1)?,
+                    }
+                }
+                ByteCode::NewTable(dst)
+                | ByteCode::Tbc(dst)
+                | ByteCode::LoadBool(dst, _)
+                | ByteCode::LoadNil(dst)
+                | ByteCode::LoadInteger(dst, _) => check.register(dst)?,
+                ByteCode::Move(dst, src)
+                | ByteCode::Neg(dst, src)
+                | ByteCode::BitNot(dst, src)
+                | ByteCode::Not(dst, src) => {
+                    check.register(dst)?;
+                    check.register(src)?;
+                }
+                ByteCode::Add(dst, b, c)
+                | ByteCode::Sub(dst, b, c)
+                | ByteCode::Mul(dst, b, c)
+                | ByteCode::Mod(dst, b, c)
+                | ByteCode::Pow(dst, b, c)
+                | ByteCode::Div(dst, b, c)
+                | ByteCode::Idiv(dst, b, c)
+                | ByteCode::BitAnd(dst, b, c)
+                | ByteCode::BitOr(dst, b, c)
+                | ByteCode::BitXor(dst, b, c)
+                | ByteCode::ShiftL(dst, b, c)
+                | ByteCode::ShiftR(dst, b, c)
+                | ByteCode::Equal(dst, b, c)
+                | ByteCode::NotEq(dst, b, c)
+                | ByteCode::LesThan(dst, b, c)
+                | ByteCode::LesEq(dst, b, c)
+                | ByteCode::Concat(dst, b, c)
+                | ByteCode::GetTable(dst, b, c)
+                | ByteCode::SetTable(dst, b, c) => {
+                    check.register(dst)?;
+                    check.register(b)?;
+                    check.register(c)?;
+                }
+                ByteCode::Jump(offset) => check.jump(offset)?,
+                ByteCode::TestAndJump(src, offset) | ByteCode::TestOrJump(src, offset) => {
+                    check.register(src)?;
+                    check.jump(offset)?;
+                }
+            }
+        }
+        self.verify_debug()?;
+        for proto in self.protos.iter() {
+            proto.verify()?;
+        }
+        Ok(())
+    }
+
+    fn verify_debug(&self) -> Result<(), VerifyError> {
+        let Some(debug) = &self.debug else {
+            return Ok(());
+        };
+        let len = self.byte_codes.len();
+        let lines = debug.line_info.deltas.len();
+        if lines != len {
+            return Err(VerifyError {
+                pc: lines.min(len),
+                message: format!("line information for {lines} of {len} instructions"),
+            });
+        }
+        for var in debug.locals.iter() {
+            if var.start_pc > var.end_pc || var.end_pc as usize > len {
+                return Err(VerifyError {
+                    pc: var.start_pc as usize,
+                    message: format!(
+                        "local '{}' alive from {} to {} in {len} instructions",
+                        var.name, var.start_pc, var.end_pc
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Checker<'a> {
+    proto: &'a ParseProto,
+    pc: usize,
+}
+
+impl<'a> Checker<'a> {
+    fn error(&self, message: String) -> VerifyError {
+        VerifyError {
+            pc: self.pc,
+            message,
+        }
+    }
+
+    fn register(&self, reg: u8) -> Result<(), VerifyError> {
+        if reg < self.proto.max_stack_size {
+            Ok(())
+        } else {
+            Err(self.error(format!(
+                "register {reg} out of stack frame of {} slots",
+                self.proto.max_stack_size
+            )))
+        }
+    }
+
+    /// `count` registers from `first` on, all in the stack frame
+    fn range(&self, what: &str, first: usize, count: usize) -> Result<(), VerifyError> {
+        let last = first + count;
+        if count == 0 || last <= self.proto.max_stack_size as usize {
+            Ok(())
+        } else {
+            Err(self.error(format!(
+                "{what} {first}..={} out of stack frame of {} slots",
+                last - 1,
+                self.proto.max_stack_size
+            )))
+        }
+    }
+
+    /// The instruction uses values from register `first` up to the top. That is only known
+    /// right after a call or `...` with a variable number of results, not above `first`,
+    /// which is not reached by a jump.
+    fn top(&self, codes: &[ByteCode], targets: &[bool], first: usize) -> Result<(), VerifyError> {
+        let start = match self.pc.checked_sub(1).map(|pc| &codes[pc]) {
+            Some(&ByteCode::Call(func, _, 0) | &ByteCode::TailCall(func, _)) => Some(func),
+            Some(&ByteCode::VarArgs(dst, 0)) => Some(dst),
+            _ => None,
+        };
+        match start {
+            Some(start) if start as usize >= first && !targets[self.pc] => Ok(()),
+            _ => Err(self.error("top is not set by the previous instruction".to_string())),
+        }
+    }
+
+    /// a jump may land on any instruction, or right after the last one to end the chunk
+    fn jump(&self, offset: i16) -> Result<(), VerifyError> {
+        let target = self.pc as isize + 1 + offset as isize;
+        let len = self.proto.byte_codes.len();
+        if (0..=len as isize).contains(&target) {
+            Ok(())
+        } else {
+            Err(self.error(format!("jump to {} out of {len} instructions", target + 1)))
+        }
+    }
+
+    fn constant(&self, k: u16) -> Result<&'a Value, VerifyError> {
+        self.proto.constants.get(k as usize).ok_or_else(|| {
+            self.error(format!(
+                "constant {k} out of range, there are {} constants",
+                self.proto.constants.len()
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::load;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    fn proto(byte_codes: Vec<ByteCode>) -> ParseProto {
+        ParseProto {
+            constants: vec![Value::String("print".into()), Value::Integer(1)],
+            byte_codes: byte_codes.into_iter().map(Into::into).collect(),
+            max_stack_size: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parser_output_is_valid() {
+        let code = "local a = 1\nprint(a)\nprint(33000)\nprint \"hi\"";
+        let proto = load(&mut Cursor::new(code), "=test").unwrap();
+
+        assert_eq!(proto.verify(), Ok(()));
+    }
+
+    #[test]
+    fn reject_register_out_of_frame() {
+        let err = proto(vec![ByteCode::LoadNil(0), ByteCode::Move(1, 2)])
+            .verify()
+            .unwrap_err();
+
+        assert_eq!(err.pc, 1);
+        assert_eq!(
+            err.to_string(),
+            "invalid instruction 2: register 2 out of stack frame of 2 slots"
+        );
+    }
+
+    #[test]
+    fn reject_bad_constants() {
+        let err = proto(vec![ByteCode::LoadConst(0, 2)]).verify().unwrap_err();
+        assert_eq!(
+            err.message,
+            "constant 2 out of range, there are 2 constants"
+        );
+
+        let err = proto(vec![ByteCode::GetGlobal(0, 1)]).verify().unwrap_err();
+        assert_eq!(err.message, "global name 1 is not a string");
+    }
+
+    #[test]
+    fn reject_debug_information_not_matching_code() {
+        let code = "local a = 1\nprint(a)";
+        let mut proto = load(&mut Cursor::new(code), "=test").unwrap();
+        proto.byte_codes.push(ByteCode::LoadNil(0).into());
+        assert_eq!(
+            proto.verify().unwrap_err().to_string(),
+            "invalid instruction 5: line information for 4 of 5 instructions"
+        );
+
+        let mut proto = load(&mut Cursor::new(code), "=test").unwrap();
+        proto.debug.as_mut().unwrap().locals[0].end_pc = 10;
+        assert_eq!(
+            proto.verify().unwrap_err().message,
+            "local 'a' alive from 1 to 10 in 4 instructions"
+        );
+    }
+
+    #[test]
+    fn reject_jump_out_of_code() {
+        assert_eq!(proto(vec![ByteCode::Jump(-1)]).verify(), Ok(()));
+        assert_eq!(proto(vec![ByteCode::TestOrJump(0, 0)]).verify(), Ok(()));
+
+        let err = proto(vec![ByteCode::LoadNil(0), ByteCode::TestAndJump(0, 1)])
+            .verify()
+            .unwrap_err();
+        assert_eq!(err.pc, 1);
+        assert_eq!(err.message, "jump to 4 out of 2 instructions");
+
+        let err = proto(vec![ByteCode::Jump(-2)]).verify().unwrap_err();
+        assert_eq!(err.message, "jump to 0 out of 1 instructions");
+    }
+
+    #[test]
+    fn reject_call_arguments_out_of_frame() {
+        assert_eq!(proto(vec![ByteCode::Call(0, 2, 1)]).verify(), Ok(()));
+
+        let err = proto(vec![ByteCode::Call(1, 2, 1)]).verify().unwrap_err();
+        assert_eq!(err.message, "arguments 2..=2 out of stack frame of 2 slots");
+    }
+
+    #[test]
+    fn reject_variable_count_without_top() {
+        let codes = vec![ByteCode::VarArgs(1, 0), ByteCode::Call(0, 0, 1)];
+        assert_eq!(proto(codes).verify(), Ok(()));
+
+        let err = proto(vec![ByteCode::Return(0, 0)]).verify().unwrap_err();
+        assert_eq!(err.message, "top is not set by the previous instruction");
+
+        let codes = vec![ByteCode::VarArgs(0, 0), ByteCode::Call(1, 0, 1)];
+        let err = proto(codes).verify().unwrap_err();
+        assert_eq!(err.pc, 1);
+        assert_eq!(err.message, "top is not set by the previous instruction");
+    }
+
+    #[test]
+    fn reject_missing_function() {
+        let err = proto(vec![ByteCode::Closure(0, 0)]).verify().unwrap_err();
+        assert_eq!(
+            err.message,
+            "function 0 out of range, there are 0 functions"
+        );
+    }
+
+    #[test]
+    fn nested_functions_are_verified() {
+        let code = "local function f(...) return ... end\nprint(f(1, 2))";
+        let mut proto = load(&mut Cursor::new(code), "=test").unwrap();
+        assert_eq!(proto.verify(), Ok(()));
+
+        Rc::make_mut(&mut proto.protos[0]).max_stack_size = 0;
+        assert_eq!(
+            proto.verify().unwrap_err().message,
+            "register 0 out of stack frame of 0 slots"
+        );
+    }
+}
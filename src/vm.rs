@@ -1,82 +1,1146 @@
-use crate::bytecode::ByteCode;
-use crate::parser::ParseProto;
-use crate::value::Value;
+use crate::bytecode::{ByteCode, Instruction, OpCode, FIELDS_PER_FLUSH};
+use crate::compare::{equals, less_equal, less_than, CompareError};
+use crate::coroutine::{self, Thread};
+use crate::gc::{self, Heap};
+use crate::number::{arith, ArithError, ArithOp};
+use crate::parser::{chunkid, ParseProto};
+use crate::string::{BuildStringHasher, LuaString, StringTable};
+use crate::table::{self, Table};
+use crate::traceback::{FrameInfo, FrameKind, Traceback};
+use crate::value::{KeyError, LuaClosure, NativeFunction, Value};
+use crate::verify::VerifyError;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::io::Write;
+use std::rc::Rc;
+
+/// Number of nested calls allowed unless changed with [`ExeState::set_max_call_depth`].
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 200_000;
+
+/// Number of calls into Lua that may be nested on the Rust stack, through `pcall` and the
+/// like. Unlike calls between Lua functions each of them takes Rust stack space.
+pub(crate) const MAX_NATIVE_NESTING: usize = 200;
+
+/// Rest of a Rust function whose call into Lua yielded, which took the Rust function off the
+/// Rust stack. It runs once the coroutine is resumed and the call returns, with how the call
+/// ended: `Ok` with its results on the stack, or the error value a protected call caught. It
+/// returns like the Rust function would have.
+pub type Continuation = fn(&mut ExeState, Result<(), Value>) -> Result<i32, Value>;
+
+/// An error raised by running code and not caught, holding the Lua error value and the calls
+/// that were active when it was raised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub value: Value,
+    pub traceback: Traceback,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Value::String(_) | Value::Integer(_) | Value::Float(_) => write!(f, "{}", self.value),
+            v => write!(f, "(error object is a {} value)", v.type_name()),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// A chunk that could not be run, with the message of the error and no active calls.
+impl From<VerifyError> for RuntimeError {
+    fn from(err: VerifyError) -> Self {
+        RuntimeError {
+            value: Value::String(err.to_string().into()),
+            traceback: Traceback::default(),
+        }
+    }
+}
+
+/// Failure of an instruction, made into an error value once the failing pc is saved, which
+/// naming the operands needs.
+enum OpError {
+    /// an operator failed, on operands at these stack indices
+    Arith(ArithError, [usize; 2]),
+    Compare(CompareError),
+    /// the value at this stack index is not a table
+    Index(usize),
+    Key(KeyError),
+    /// the value at this stack index is neither a string nor a number
+    Concat(usize),
+    /// the local in this register got a value without a `__close` metamethod
+    NotClosable(u8),
+}
 
 pub struct ExeState<'a> {
-    globals: HashMap<String, Value>,
-    stack: Vec<Value>,
+    pub(crate) globals: HashMap<LuaString, Value, BuildStringHasher>,
+    /// the short strings of the state, see [`crate::string`]
+    pub(crate) strings: StringTable,
+    /// registers of all active calls of the running thread, each frame addresses its own from
+    /// its base on
+    pub(crate) stack: Vec<Value>,
     output: &'a mut (dyn Write + 'a),
-    func_index: usize,
+    /// active calls of the running thread, the innermost last
+    pub(crate) frames: Vec<CallInfo>,
+    /// stack indices of the to-be-closed variables of the running thread, the innermost last
+    pub(crate) tbc: Vec<usize>,
+    max_call_depth: usize,
+    /// calls into Lua currently running on the Rust stack
+    pub(crate) native_nesting: usize,
+    /// the running coroutine, whose stack and frames are swapped into this state while it runs
+    pub(crate) thread: Rc<RefCell<Thread>>,
+    pub(crate) main_thread: Rc<RefCell<Thread>>,
+    /// calls into Lua on the Rust stack since the running coroutine was resumed that cannot be
+    /// yielded across, as they have no continuation
+    pub(crate) non_yieldable: usize,
+    /// number of values at the top of the stack being yielded, while the yield makes its way
+    /// up the Rust stack to the resume
+    pub(crate) yielded: Option<usize>,
+    pub(crate) heap: Heap,
+}
+
+/// An active call.
+pub(crate) struct CallInfo {
+    /// function running, `None` for Rust functions
+    proto: Option<Rc<ParseProto>>,
+    /// stack index of register 0, the called function is right below it
+    pub(crate) base: usize,
+    /// instruction to continue with once the call this function made returns, or the one
+    /// after the instruction that raised an error
+    pc: usize,
+    /// number of results the caller expects, `None` for all of them
+    nresults: Option<usize>,
+    /// arguments beyond the fixed parameters, the values of `...`
+    pub(crate) varargs: Vec<Value>,
+    /// whether the function was tail called, the frames of its callers up to the last regular
+    /// call are gone
+    tail_call: bool,
+    /// for a Rust function, what finishes it if its call into Lua yields
+    pub(crate) k: Option<Continuation>,
+    /// for a Rust function, the protected call it is making in a coroutine, which catches the
+    /// errors raised in it once a yield took the Rust function off the Rust stack
+    pub(crate) protected: Option<ProtectedCall>,
+}
+
+/// A protected call made by a Rust function.
+#[derive(Clone, Copy)]
+pub(crate) struct ProtectedCall {
+    /// stack index of the called function, where the stack is cut back to on an error
+    func: usize,
+    /// stack index of the message handler
+    handler: Option<usize>,
 }
 
 impl<'a> ExeState<'a> {
     pub fn new(output: &'a mut (dyn Write + 'a)) -> Self {
-        let mut globals = HashMap::new();
-        globals.insert(String::from("print"), Value::Function(lib_print));
+        let mut strings = StringTable::new();
+        let mut globals = HashMap::default();
+        let mut register = |name: &str, f: NativeFunction| {
+            globals.insert(strings.intern(name), Value::Function(f));
+        };
+        register("print", lib_print);
+        register("tostring", lib_tostring);
+        register("error", lib_error);
+        register("pcall", lib_pcall);
+        register("xpcall", lib_xpcall);
+        register("collectgarbage", gc::lib_collectgarbage);
+        register("setmetatable", table::lib_setmetatable);
+        register("getmetatable", table::lib_getmetatable);
 
+        let main_thread = Rc::new(RefCell::new(Thread::main()));
+        let mut heap = Heap::new();
+        heap.add_thread(&main_thread);
+        let library = Rc::new(Table::new());
+        for (name, f) in coroutine::LIBRARY {
+            let name = Value::String(strings.intern(name));
+            library.set(name, Value::Function(f)).unwrap();
+        }
+        heap.add_table(&library);
+        globals.insert(strings.intern("coroutine"), Value::Table(library));
         ExeState {
             globals,
+            strings,
             stack: Vec::new(),
             output,
-            func_index: 0,
+            frames: Vec::new(),
+            tbc: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            native_nesting: 0,
+            thread: main_thread.clone(),
+            main_thread,
+            non_yieldable: 0,
+            yielded: None,
+            heap,
         }
     }
 
-    fn set_stack(&mut self, dst: u8, c: Value) {
-        let dst: usize = dst.into();
-        match dst.cmp(&self.stack.len()) {
-            std::cmp::Ordering::Less => self.stack[dst] = c,
-            std::cmp::Ordering::Equal => self.stack.push(c),
-            std::cmp::Ordering::Greater => panic!("failed to set_stack"),
+    /// Make the Rust function `f` the global `name`, like the standard functions are.
+    pub fn register(&mut self, name: &str, f: NativeFunction) {
+        let name = self.intern(name);
+        self.globals.insert(name, Value::Function(f));
+    }
+
+    /// Limit the number of nested calls. A call beyond it fails with "stack overflow".
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Run a compiled chunk. An error it does not catch ends it and is returned.
+    ///
+    /// The chunk is checked with [`ParseProto::verify`] first, as it may have been built by
+    /// hand. One that fails is not run, the [`VerifyError`] is returned instead.
+    pub fn execute(&mut self, proto: &ParseProto) -> Result<(), RuntimeError> {
+        proto.verify()?;
+        let func = self.stack.len();
+        let proto = self.intern_constants(proto);
+        let main = Rc::new(LuaClosure::new(proto));
+        self.heap.add_lua_closure(&main);
+        self.stack.push(Value::LuaFunction(main));
+        // for the constants interned and the function created
+        self.check_gc();
+        let depth = self.frames.len();
+        self.call(func, 0, Some(0)).map_err(|value| {
+            let traceback = self.traceback();
+            let value = self.unwind(depth, func, None, value);
+            RuntimeError { value, traceback }
+        })
+    }
+
+    /// A copy of `proto` whose string constants, and those of the functions defined in it, are
+    /// the interned strings of the state. Global names are then found by pointer. The copies
+    /// are counted in the heap size.
+    fn intern_constants(&mut self, proto: &ParseProto) -> Rc<ParseProto> {
+        let constants = proto
+            .constants
+            .iter()
+            .map(|k| match k {
+                Value::String(s) => Value::String(self.strings.intern_string(s)),
+                k => k.clone(),
+            })
+            .collect();
+        let protos = proto
+            .protos
+            .iter()
+            .map(|p| self.intern_constants(p))
+            .collect();
+        let proto = Rc::new(ParseProto {
+            constants,
+            protos,
+            ..proto.clone()
+        });
+        self.heap.add_proto(&proto);
+        proto
+    }
+
+    /// The string with contents `s`, interned if it is short. It is an allocation, so the
+    /// collector may run a step: values in use must be reachable from the roots.
+    pub(crate) fn intern(&mut self, s: &str) -> LuaString {
+        let s = self.strings.intern(s);
+        self.check_gc();
+        s
+    }
+
+    /// The active calls, innermost first. While an error is on its way to the call catching
+    /// it, those are the calls it was raised in.
+    pub fn traceback(&self) -> Traceback {
+        let frames = (0..self.frames.len())
+            .rev()
+            .map(|level| self.frame_info(level))
+            .collect();
+        Traceback { frames }
+    }
+
+    /// what a traceback shows of the frame at index `index`
+    fn frame_info(&self, index: usize) -> FrameInfo {
+        let ci = &self.frames[index];
+        // like reference Lua, a function held by a global is named after it. Otherwise the
+        // calling instruction tells how the caller referred to the function, unless the
+        // caller is gone after a tail call or is a Rust function.
+        let global = self.global_name(&self.stack[ci.base - 1]);
+        let name = match index.checked_sub(1).map(|i| &self.frames[i]) {
+            _ if global.is_some() => global.map(|name| ("global", name)),
+            Some(CallInfo {
+                proto: Some(caller),
+                pc,
+                ..
+            }) if !ci.tail_call => match caller.byte_codes[pc - 1].decode() {
+                ByteCode::Call(func, ..) | ByteCode::TailCall(func, _) => {
+                    caller.object_name(pc - 1, func)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(proto) = &ci.proto else {
+            return FrameInfo {
+                kind: FrameKind::Native,
+                source: "[C]".to_string(),
+                current_line: None,
+                line_defined: 0,
+                name,
+                tail_call: ci.tail_call,
+            };
+        };
+        let (source, line_defined) = match &proto.debug {
+            Some(debug) => (chunkid(&debug.source), Some(debug.line_defined)),
+            None => ("?".to_string(), None),
+        };
+        // without debug information only the function at the bottom is known to be a chunk
+        let kind = match line_defined.unwrap_or(index as u32) {
+            0 => FrameKind::Main,
+            _ => FrameKind::Lua,
+        };
+        FrameInfo {
+            kind,
+            source,
+            current_line: ci.pc.checked_sub(1).and_then(|pc| proto.line(pc)),
+            line_defined: line_defined.unwrap_or(0),
+            name,
+            tail_call: ci.tail_call,
         }
     }
 
-    fn get_stack(&self, src: u8) -> Value {
-        self.stack.get(src as usize).unwrap().clone()
+    /// Call the value at stack index `func` with the `nargs` values after it. The results
+    /// replace the function and its arguments. The called code cannot yield.
+    pub(crate) fn call(
+        &mut self,
+        func: usize,
+        nargs: usize,
+        nresults: Option<usize>,
+    ) -> Result<(), Value> {
+        self.non_yieldable += 1;
+        let result = self.call_yieldable(func, nargs, nresults);
+        self.non_yieldable -= 1;
+        result
     }
 
-    pub fn execute(&mut self, proto: &ParseProto) {
-        for code in proto.byte_codes.iter() {
-            dbg!(&self.stack);
-            match *code {
-                ByteCode::GetGlobal(dst, name) => {
-                    let name = &proto.constants[name as usize];
-                    if let Value::String(key) = name {
-                        let v = self.globals.get(key).unwrap_or(&Value::Nil).clone();
-                        self.set_stack(dst, v);
+    /// Like [`Self::call`], but the called code may yield, which is returned like an error with
+    /// [`Self::yielded`] set. The caller has to be finished by a continuation then.
+    fn call_yieldable(
+        &mut self,
+        func: usize,
+        nargs: usize,
+        nresults: Option<usize>,
+    ) -> Result<(), Value> {
+        if self.native_nesting >= MAX_NATIVE_NESTING {
+            return Err(self.runtime_error("C stack overflow"));
+        }
+        self.native_nesting += 1;
+        let result = match self.precall(func, nargs, nresults) {
+            Ok(true) => self.run(),
+            Ok(false) => Ok(()),
+            Err(err) => Err(err),
+        };
+        self.native_nesting -= 1;
+        result
+    }
+
+    /// Like [`Self::call`], but an error is replaced by what the message handler at stack index
+    /// `handler` returns for it, the calls it was raised in are unwound, leaving the stack as
+    /// it was before the function, and it is returned as the inner result.
+    ///
+    /// In a coroutine the called code may yield, which is returned as the outer error for the
+    /// running Rust function to return. It is finished by `k` once the call returns, which gets
+    /// the error instead if one is raised after the yield.
+    fn pcallk(
+        &mut self,
+        func: usize,
+        nargs: usize,
+        nresults: Option<usize>,
+        handler: Option<usize>,
+        k: Continuation,
+    ) -> Result<Result<(), Value>, Value> {
+        let depth = self.frames.len();
+        let result = if self.is_yieldable() {
+            let ci = self.frames.last_mut().unwrap();
+            ci.k = Some(k);
+            ci.protected = Some(ProtectedCall { func, handler });
+            match self.call_yieldable(func, nargs, nresults) {
+                Err(value) if self.yielded.is_some() => return Err(value),
+                result => {
+                    let ci = &mut self.frames[depth - 1];
+                    ci.k = None;
+                    ci.protected = None;
+                    result
+                }
+            }
+        } else {
+            self.call(func, nargs, nresults)
+        };
+        Ok(result.map_err(|value| {
+            let value = self.handle_error(handler, value);
+            self.unwind(depth, func, handler, value)
+        }))
+    }
+
+    /// Catch an error raised in a coroutine after a yield took the Rust function making the
+    /// protected call of the frame at `index` off the Rust stack, and finish that function.
+    pub(crate) fn recover(&mut self, index: usize, value: Value) -> Result<(), Value> {
+        let ProtectedCall { func, handler } = self.frames[index].protected.take().unwrap();
+        let value = self.handle_error(handler, value);
+        let value = self.unwind(index + 1, func, handler, value);
+        let k = self.frames[index]
+            .k
+            .take()
+            .expect("protected calls have a continuation");
+        let n = k(self, Err(value))? as usize;
+        self.finish_call(self.stack.len() - n, n);
+        Ok(())
+    }
+
+    /// Continue the innermost call of a resumed coroutine: run a Lua function until it returns,
+    /// or finish a Rust function whose call into Lua has returned.
+    pub(crate) fn unroll(&mut self) -> Result<(), Value> {
+        let ci = self.frames.last_mut().unwrap();
+        if ci.proto.is_some() {
+            return self.run();
+        }
+        ci.protected = None;
+        let k =
+            ci.k.take()
+                .expect("Rust functions below a yield have a continuation");
+        let n = k(self, Ok(()))? as usize;
+        self.finish_call(self.stack.len() - n, n);
+        Ok(())
+    }
+
+    /// The error value `value` as replaced by the message handler at stack index `handler`, if
+    /// there is one. It runs before the calls the error was raised in are unwound.
+    fn handle_error(&mut self, handler: Option<usize>, value: Value) -> Value {
+        let Some(handler) = handler else {
+            return value;
+        };
+        let func = self.stack.len();
+        self.stack.push(self.stack[handler].clone());
+        self.stack.push(value);
+        match self.call(func, 1, Some(1)) {
+            Ok(()) => self.stack.pop().unwrap(),
+            Err(_) => Value::String(self.strings.create("error in error handling")),
+        }
+    }
+
+    /// Whether the running Rust function can yield: it runs in a coroutine, and only calls
+    /// that can be finished by a continuation are between it and the resume.
+    pub(crate) fn is_yieldable(&self) -> bool {
+        !Rc::ptr_eq(&self.thread, &self.main_thread) && self.non_yieldable == 0
+    }
+
+    /// Yield the `n` values at the top of the stack from the running coroutine, to be returned
+    /// by the running Rust function as its result. When the coroutine is resumed, `k` finishes
+    /// the Rust function, or without one the values passed to the resume are its results.
+    pub fn yield_values(&mut self, n: usize, k: Option<Continuation>) -> Result<i32, Value> {
+        if Rc::ptr_eq(&self.thread, &self.main_thread) {
+            return Err(self.runtime_error("attempt to yield from outside a coroutine"));
+        }
+        if self.non_yieldable > 0 {
+            return Err(self.runtime_error("attempt to yield across a C-call boundary"));
+        }
+        self.frames.last_mut().unwrap().k = k;
+        self.yielded = Some(n);
+        // the yield makes its way up the Rust stack like an error does
+        Err(Value::Nil)
+    }
+
+    /// Drop the frames above `depth` and the stack from `top` on, which the error `value` left
+    /// behind, closing the to-be-closed variables in that part of the stack. Returns the error,
+    /// which is replaced by one a `__close` metamethod raises, after the message handler at
+    /// stack index `handler` got it.
+    pub(crate) fn unwind(
+        &mut self,
+        depth: usize,
+        top: usize,
+        handler: Option<usize>,
+        value: Value,
+    ) -> Value {
+        self.frames.truncate(depth);
+        let mut value = value;
+        while let Err(err) = self.close_tbc(top, value.clone()) {
+            value = self.handle_error(handler, err);
+            self.frames.truncate(depth);
+        }
+        self.stack.truncate(top);
+        value
+    }
+
+    /// Call the `__close` metamethods of the to-be-closed variables from stack index `level` on,
+    /// the innermost first, with the variable and `err`, the error that ends their scope or nil.
+    /// Variables are no longer to be closed once their metamethod is called, whether it fails
+    /// or not.
+    pub(crate) fn close_tbc(&mut self, level: usize, err: Value) -> Result<(), Value> {
+        while let Some(&index) = self.tbc.last().filter(|&&index| index >= level) {
+            self.tbc.pop();
+            let value = self.stack[index].clone();
+            let close = close_metamethod(&value);
+            if close == Value::Nil {
+                return Err(self.runtime_error("attempt to call a nil value (metamethod 'close')"));
+            }
+            let func = self.stack.len();
+            self.stack.extend([close, value, err.clone()]);
+            self.call(func, 2, Some(0))?;
+        }
+        Ok(())
+    }
+
+    /// Start a call. A Rust function runs to completion right away, a Lua function gets a new
+    /// frame for [`Self::run`] to execute. Returns whether a frame was left to run.
+    pub(crate) fn precall(
+        &mut self,
+        func: usize,
+        nargs: usize,
+        nresults: Option<usize>,
+    ) -> Result<bool, Value> {
+        if self.frames.len() >= self.max_call_depth {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        // the caller's registers above the arguments are dead during the call
+        self.stack.truncate(func + 1 + nargs);
+        let base = func + 1;
+        let native = match &self.stack[func] {
+            &Value::Function(f) => Some(f),
+            Value::NativeClosure(c) => Some(c.func),
+            _ => None,
+        };
+        if let Some(f) = native {
+            self.frames.push(CallInfo {
+                proto: None,
+                base,
+                pc: 0,
+                nresults,
+                varargs: Vec::new(),
+                tail_call: false,
+                k: None,
+                protected: None,
+            });
+            let n = f(self)? as usize;
+            self.finish_call(self.stack.len() - n, n);
+            return Ok(false);
+        }
+        match &self.stack[func] {
+            Value::LuaFunction(closure) => {
+                let proto = closure.proto.clone();
+                let nparams = proto.num_params as usize;
+                let varargs = if proto.is_vararg && nargs > nparams {
+                    self.stack.split_off(base + nparams)
+                } else {
+                    Vec::new()
+                };
+                // missing parameters and all other registers start as nil
+                self.stack.truncate(base + nparams.min(nargs));
+                let capacity = self.stack.capacity();
+                self.stack
+                    .resize(base + proto.max_stack_size as usize, Value::Nil);
+                let grown = self.stack.capacity() != capacity;
+                self.frames.push(CallInfo {
+                    proto: Some(proto),
+                    base,
+                    pc: 0,
+                    nresults,
+                    varargs,
+                    tail_call: false,
+                    k: None,
+                    protected: None,
+                });
+                if grown {
+                    self.check_gc();
+                }
+                Ok(true)
+            }
+            v => {
+                let message = format!(
+                    "attempt to call a {} value{}",
+                    v.type_name(),
+                    self.var_info(func)
+                );
+                Err(self.runtime_error(message))
+            }
+        }
+    }
+
+    /// Return from the innermost call with the `n` values from stack index `first` on. They
+    /// are moved to where the called function was and adjusted to the count the caller expects.
+    pub(crate) fn finish_call(&mut self, first: usize, n: usize) {
+        let ci = self.frames.pop().unwrap();
+        let func = ci.base - 1;
+        for i in 0..n {
+            self.stack.swap(func + i, first + i);
+        }
+        self.stack.truncate(func + n);
+        if let Some(wanted) = ci.nresults {
+            self.stack.resize(func + wanted, Value::Nil);
+        }
+    }
+
+    /// Function, base and saved pc of the innermost frame, which runs Lua code.
+    fn lua_frame(&self) -> (Rc<ParseProto>, usize, usize) {
+        let ci = self.frames.last().unwrap();
+        let proto = ci.proto.clone().expect("frame of a Lua function");
+        (proto, ci.base, ci.pc)
+    }
+
+    /// Make all registers of the frame at `base` addressable again after a call shrank the
+    /// stack to the end of its results.
+    fn restore_frame(&mut self, base: usize, proto: &ParseProto) {
+        let end = base + proto.max_stack_size as usize;
+        if self.stack.len() < end {
+            self.stack.resize(end, Value::Nil);
+        }
+    }
+
+    /// Name of a global holding `func`, the first in alphabetical order if there are several.
+    /// A function of the coroutine library is named after its field, like `coroutine.wrap`.
+    fn global_name(&self, func: &Value) -> Option<String> {
+        let global = self
+            .globals
+            .iter()
+            .filter(|(_, v)| *v == func)
+            .map(|(name, _)| name.as_str())
+            .min()
+            .map(str::to_string);
+        global.or_else(|| {
+            coroutine::LIBRARY
+                .iter()
+                .find(|&&(_, f)| Value::Function(f) == *func)
+                .map(|(name, _)| format!("coroutine.{name}"))
+        })
+    }
+
+    /// Error value for `message`, raised by the innermost frame. Raised by a Lua function it
+    /// is prefixed with its position, "?:-1:" without debug information.
+    fn runtime_error(&self, message: impl fmt::Display) -> Value {
+        let position = match self.frames.last() {
+            Some(CallInfo {
+                proto: Some(proto),
+                pc,
+                ..
+            }) => {
+                let source = proto
+                    .debug
+                    .as_ref()
+                    .map_or("?".to_string(), |d| chunkid(&d.source));
+                let line = proto.line(pc - 1).map_or(-1, i64::from);
+                format!("{source}:{line}: ")
+            }
+            _ => String::new(),
+        };
+        Value::String(self.strings.create(&format!("{position}{message}")))
+    }
+
+    /// Error value for `message`, raised by the running Rust function. Like `luaL_error` in
+    /// reference Lua it is prefixed with the position of the caller.
+    pub(crate) fn native_error(&self, message: impl fmt::Display) -> Value {
+        Value::String(
+            self.strings
+                .create(&format!("{}{message}", self.position(1))),
+        )
+    }
+
+    /// " (global 'x')" naming the value at stack index `index`, like `varinfo` in reference Lua.
+    /// Only registers of a running Lua function have names, found from the instruction that
+    /// loaded them, otherwise it is empty.
+    fn var_info(&self, index: usize) -> String {
+        let Some(CallInfo {
+            proto: Some(proto),
+            base,
+            pc,
+            ..
+        }) = self.frames.last()
+        else {
+            return String::new();
+        };
+        let name = index
+            .checked_sub(*base)
+            .and_then(|reg| u8::try_from(reg).ok())
+            .and_then(|reg| proto.object_name(pc - 1, reg));
+        match name {
+            Some((kind, name)) => format!(" ({kind} '{name}')"),
+            None => String::new(),
+        }
+    }
+
+    /// "chunk:line: " of the function `level` frames below the innermost one, like `luaL_where`
+    /// in reference Lua. Empty if there is no such function, it is a Rust function or has no
+    /// line information.
+    pub(crate) fn position(&self, level: usize) -> String {
+        let Some(ci) = self
+            .frames
+            .len()
+            .checked_sub(level + 1)
+            .map(|i| &self.frames[i])
+        else {
+            return String::new();
+        };
+        let Some(proto) = &ci.proto else {
+            return String::new();
+        };
+        match (&proto.debug, proto.line(ci.pc - 1)) {
+            (Some(debug), Some(line)) => format!("{}:{line}: ", chunkid(&debug.source)),
+            _ => String::new(),
+        }
+    }
+
+    /// Execute the Lua function of the innermost frame until it returns. Calls to other Lua
+    /// functions push a frame and continue in this loop instead of recursing.
+    ///
+    /// An error leaves all frames in place, with the pc of the failing one saved, for the
+    /// caller catching it to unwind.
+    fn run(&mut self) -> Result<(), Value> {
+        let depth = self.frames.len();
+        let (mut proto, mut base, mut pc) = self.lua_frame();
+        // end of the values left by the last call or `...` with a variable count. A frame
+        // continued after a yield finds the results of the call it made there.
+        let mut top = self.stack.len();
+        self.restore_frame(base, &proto);
+        loop {
+            // running off the end of the code returns no values
+            let code = match proto.byte_codes.get(pc) {
+                Some(&code) => code,
+                None => Instruction::from(ByteCode::Return(0, 1)),
+            };
+            pc += 1;
+            // every register operand is below max_stack_size, see ParseProto::verify
+            let a = base + code.a() as usize;
+            let result = match code.opcode() {
+                OpCode::GetGlobal => {
+                    let Value::String(key) = &proto.constants[code.d() as usize] else {
+                        unreachable!("global names are strings, see ParseProto::verify");
+                    };
+                    self.stack[a] = self.globals.get(key).unwrap_or(&Value::Nil).clone();
+                    Ok(())
+                }
+                OpCode::SetGlobal => {
+                    let Value::String(key) = &proto.constants[code.d() as usize] else {
+                        unreachable!("global names are strings, see ParseProto::verify");
+                    };
+                    self.globals.insert(key.clone(), self.stack[a].clone());
+                    Ok(())
+                }
+                OpCode::LoadConst => {
+                    self.stack[a] = proto.constants[code.d() as usize].clone();
+                    Ok(())
+                }
+                OpCode::Call => {
+                    let nargs = match code.b() {
+                        0 => top - a - 1,
+                        b => b as usize - 1,
+                    };
+                    let nresults = match code.c() {
+                        0 => None,
+                        c => Some(c as usize - 1),
+                    };
+                    self.frames.last_mut().unwrap().pc = pc;
+                    match self.precall(a, nargs, nresults)? {
+                        true => (proto, base, pc) = self.lua_frame(),
+                        false => {
+                            top = self.stack.len();
+                            self.restore_frame(base, &proto);
+                        }
+                    }
+                    Ok(())
+                }
+                OpCode::TailCall => {
+                    let nargs = match code.d() {
+                        0 => top - a - 1,
+                        d => d as usize - 1,
+                    };
+                    self.frames.last_mut().unwrap().pc = pc;
+                    if let Value::LuaFunction(_) = self.stack[a] {
+                        // the parser compiles no tail calls in the scope of to-be-closed
+                        // variables, but loaded code may have them
+                        if self.tbc.last().is_some_and(|&index| index >= base) {
+                            self.close_tbc(base, Value::Nil)?;
+                        }
+                        // the callee takes the place of the returning function
+                        let func = base - 1;
+                        for i in 0..=nargs {
+                            self.stack.swap(func + i, a + i);
+                        }
+                        let nresults = self.frames.pop().unwrap().nresults;
+                        self.precall(func, nargs, nresults)?;
+                        self.frames.last_mut().unwrap().tail_call = true;
+                        (proto, base, pc) = self.lua_frame();
                     } else {
-                        panic!("invalid global key: {name:?}");
+                        // native functions do not grow the stack of frames, the `Return` after
+                        // the call hands on their results
+                        self.precall(a, nargs, None)?;
+                        top = self.stack.len();
+                        self.restore_frame(base, &proto);
+                    }
+                    Ok(())
+                }
+                OpCode::Return => {
+                    let n = match code.d() {
+                        0 => top - a,
+                        n => n as usize - 1,
+                    };
+                    if self.tbc.last().is_some_and(|&index| index >= base) {
+                        self.frames.last_mut().unwrap().pc = pc;
+                        self.close_tbc(base, Value::Nil)?;
+                    }
+                    self.finish_call(a, n);
+                    if self.frames.len() < depth {
+                        return Ok(());
+                    }
+                    top = self.stack.len();
+                    (proto, base, pc) = self.lua_frame();
+                    self.restore_frame(base, &proto);
+                    Ok(())
+                }
+                OpCode::Closure => {
+                    let closure = Rc::new(LuaClosure::new(proto.protos[code.d() as usize].clone()));
+                    self.heap.add_lua_closure(&closure);
+                    self.stack[a] = Value::LuaFunction(closure);
+                    // finalizers may run, which look at the frames
+                    self.frames.last_mut().unwrap().pc = pc;
+                    self.check_gc();
+                    Ok(())
+                }
+                OpCode::VarArgs => {
+                    let varargs = &self.frames.last().unwrap().varargs;
+                    let n = match code.d() {
+                        0 => varargs.len(),
+                        n => n as usize - 1,
+                    };
+                    let values: Vec<Value> = (0..n)
+                        .map(|i| varargs.get(i).cloned().unwrap_or(Value::Nil))
+                        .collect();
+                    if self.stack.len() < a + n {
+                        self.stack.resize(a + n, Value::Nil);
+                    }
+                    for (i, v) in values.into_iter().enumerate() {
+                        self.stack[a + i] = v;
                     }
+                    top = a + n;
+                    Ok(())
+                }
+                OpCode::LoadNil => {
+                    self.stack[a] = Value::Nil;
+                    Ok(())
+                }
+                OpCode::LoadBool => {
+                    self.stack[a] = Value::Boolean(code.d() != 0);
+                    Ok(())
+                }
+                OpCode::LoadInteger => {
+                    self.stack[a] = Value::Integer(code.sd().into());
+                    Ok(())
+                }
+                OpCode::Move => {
+                    self.stack[a] = self.stack[base + code.d() as usize].clone();
+                    Ok(())
+                }
+                OpCode::Add => self.binary(ArithOp::Add, code, base),
+                OpCode::Sub => self.binary(ArithOp::Sub, code, base),
+                OpCode::Mul => self.binary(ArithOp::Mul, code, base),
+                OpCode::Mod => self.binary(ArithOp::Mod, code, base),
+                OpCode::Pow => self.binary(ArithOp::Pow, code, base),
+                OpCode::Div => self.binary(ArithOp::Div, code, base),
+                OpCode::Idiv => self.binary(ArithOp::Idiv, code, base),
+                OpCode::BitAnd => self.binary(ArithOp::BAnd, code, base),
+                OpCode::BitOr => self.binary(ArithOp::BOr, code, base),
+                OpCode::BitXor => self.binary(ArithOp::BXor, code, base),
+                OpCode::ShiftL => self.binary(ArithOp::Shl, code, base),
+                OpCode::ShiftR => self.binary(ArithOp::Shr, code, base),
+                OpCode::Neg => self.unary(ArithOp::Unm, code, base),
+                OpCode::BitNot => self.unary(ArithOp::BNot, code, base),
+                OpCode::Equal => self.compare(code, base, |a, b| Ok(equals(a, b))),
+                OpCode::NotEq => self.compare(code, base, |a, b| Ok(!equals(a, b))),
+                OpCode::LesThan => self.compare(code, base, less_than),
+                OpCode::LesEq => self.compare(code, base, less_equal),
+                OpCode::Not => {
+                    let v = !self.stack[base + code.d() as usize].truthy();
+                    self.stack[a] = Value::Boolean(v);
+                    Ok(())
+                }
+                // jump targets are within the code or right after it, see ParseProto::verify
+                OpCode::Jump => {
+                    pc = jump(pc, code);
+                    Ok(())
+                }
+                OpCode::TestAndJump => {
+                    if !self.stack[a].truthy() {
+                        pc = jump(pc, code);
+                    }
+                    Ok(())
+                }
+                OpCode::TestOrJump => {
+                    if self.stack[a].truthy() {
+                        pc = jump(pc, code);
+                    }
+                    Ok(())
+                }
+                OpCode::NewTable => {
+                    let table = Rc::new(Table::new());
+                    self.heap.add_table(&table);
+                    self.stack[a] = Value::Table(table);
+                    // finalizers may run, which look at the frames
+                    self.frames.last_mut().unwrap().pc = pc;
+                    self.check_gc();
+                    Ok(())
                 }
-                ByteCode::LoadConst(dst, c) => {
-                    let v = proto.constants[c as usize].clone();
-                    self.set_stack(dst, v);
+                OpCode::GetTable => {
+                    let t = base + code.b() as usize;
+                    match &self.stack[t] {
+                        Value::Table(table) => {
+                            self.stack[a] = table.get(&self.stack[base + code.c() as usize]);
+                            Ok(())
+                        }
+                        _ => Err(OpError::Index(t)),
+                    }
                 }
-                ByteCode::Call(func, _) => {
-                    self.func_index = func as usize;
-                    let func = &self.stack[self.func_index as usize];
-                    if let Value::Function(f) = func {
-                        f(self);
+                OpCode::SetTable => match &self.stack[a] {
+                    Value::Table(table) => {
+                        let table = table.clone();
+                        self.heap.table_barrier(&table);
+                        let key = self.stack[base + code.b() as usize].clone();
+                        let value = self.stack[base + code.c() as usize].clone();
+                        table.set(key, value).map_err(OpError::Key)
+                    }
+                    _ => Err(OpError::Index(a)),
+                },
+                OpCode::SetList => match &self.stack[a] {
+                    Value::Table(table) => {
+                        let table = table.clone();
+                        self.heap.table_barrier(&table);
+                        let n = match code.b() {
+                            0 => top - a - 1,
+                            b => b as usize - 1,
+                        };
+                        let first = code.c() as usize * FIELDS_PER_FLUSH;
+                        for i in 1..=n {
+                            let index = Value::Integer((first + i) as i64);
+                            let stored = table.set(index, self.stack[a + i].clone());
+                            stored.expect("integers are valid keys");
+                        }
+                        Ok(())
+                    }
+                    _ => Err(OpError::Index(a)),
+                },
+                OpCode::Concat => self.concat(code, base).map(|()| {
+                    // finalizers may run, which look at the frames
+                    self.frames.last_mut().unwrap().pc = pc;
+                    self.check_gc();
+                }),
+                OpCode::Tbc => {
+                    let value = &self.stack[a];
+                    if !value.truthy() {
+                        Ok(())
+                    } else if close_metamethod(value) == Value::Nil {
+                        Err(OpError::NotClosable(code.a()))
                     } else {
-                        panic!("invalid function: {func:?}");
+                        self.tbc.push(a);
+                        Ok(())
                     }
                 }
-                ByteCode::LoadNil(dst) => self.set_stack(dst, Value::Nil),
-                ByteCode::LoadBool(dst, v) => self.set_stack(dst, Value::Boolean(v)),
-                ByteCode::LoadInteger(dst, v) => self.set_stack(dst, Value::Integer(v.into())),
-                ByteCode::Move(dst, src) => self.set_stack(dst, self.get_stack(src)),
+            };
+            if let Err(err) = result {
+                self.frames.last_mut().unwrap().pc = pc;
+                let message = match err {
+                    OpError::Arith(ArithError::NoIntegerRep { operand }, operands) => format!(
+                        "number{} has no integer representation",
+                        self.var_info(operands[operand])
+                    ),
+                    OpError::Arith(err @ ArithError::BadOperand { operand, .. }, operands) => {
+                        format!("{err}{}", self.var_info(operands[operand]))
+                    }
+                    OpError::Arith(err, _) => err.to_string(),
+                    OpError::Compare(err) => err.to_string(),
+                    OpError::Index(index) => format!(
+                        "attempt to index a {} value{}",
+                        self.stack[index].type_name(),
+                        self.var_info(index)
+                    ),
+                    OpError::Key(err) => err.to_string(),
+                    OpError::Concat(index) => format!(
+                        "attempt to concatenate a {} value{}",
+                        self.stack[index].type_name(),
+                        self.var_info(index)
+                    ),
+                    OpError::NotClosable(reg) => {
+                        let name = proto.debug.as_ref().and_then(|d| d.local_name(reg, pc - 1));
+                        format!(
+                            "variable '{}' got a non-closable value",
+                            name.unwrap_or("?")
+                        )
+                    }
+                };
+                return Err(self.runtime_error(message));
             }
         }
     }
+
+    /// `dst = lhs cmp rhs` with all three in registers A, B and C of the frame at `base`
+    fn compare(
+        &mut self,
+        code: Instruction,
+        base: usize,
+        cmp: impl Fn(&Value, &Value) -> Result<bool, CompareError>,
+    ) -> Result<(), OpError> {
+        let lhs = &self.stack[base + code.b() as usize];
+        let rhs = &self.stack[base + code.c() as usize];
+        let v = cmp(lhs, rhs).map_err(OpError::Compare)?;
+        self.stack[base + code.a() as usize] = Value::Boolean(v);
+        Ok(())
+    }
+
+    /// `dst = lhs op rhs` with all three in registers A, B and C of the frame at `base`
+    fn binary(&mut self, op: ArithOp, code: Instruction, base: usize) -> Result<(), OpError> {
+        let operands = [base + code.b() as usize, base + code.c() as usize];
+        let v = arith(op, &self.stack[operands[0]], &self.stack[operands[1]])
+            .map_err(|err| OpError::Arith(err, operands))?;
+        self.stack[base + code.a() as usize] = v;
+        Ok(())
+    }
+
+    /// `dst = lhs .. rhs` with all three in registers A, B and C of the frame at `base`
+    fn concat(&mut self, code: Instruction, base: usize) -> Result<(), OpError> {
+        let mut s = String::new();
+        for src in [base + code.b() as usize, base + code.c() as usize] {
+            match &self.stack[src] {
+                Value::String(v) => s.push_str(v),
+                v @ (Value::Integer(_) | Value::Float(_)) => s.push_str(&v.to_string()),
+                _ => return Err(OpError::Concat(src)),
+            }
+        }
+        self.stack[base + code.a() as usize] = Value::String(self.strings.intern(&s));
+        Ok(())
+    }
+
+    /// `dst = op src` with the operand in register D of the frame at `base`
+    fn unary(&mut self, op: ArithOp, code: Instruction, base: usize) -> Result<(), OpError> {
+        let src = base + code.d() as usize;
+        let v = arith(op, &self.stack[src], &self.stack[src])
+            .map_err(|err| OpError::Arith(err, [src, src]))?;
+        self.stack[base + code.a() as usize] = v;
+        Ok(())
+    }
+
+    /// arguments of the running Rust function, and the values it pushed since
+    pub fn args(&self) -> &[Value] {
+        &self.stack[self.frames.last().unwrap().base..]
+    }
+
+    /// Push `value` on the stack of the running Rust function. It returns the number of
+    /// values at the top of the stack that are its results.
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+}
+
+/// pc after the jump `code`, with `pc` pointing to the instruction after it
+fn jump(pc: usize, code: Instruction) -> usize {
+    (pc as isize + code.sd() as isize) as usize
+}
+
+/// the `__close` metamethod of `value`, nil if it has none
+fn close_metamethod(value: &Value) -> Value {
+    match value {
+        Value::Table(table) => table.metafield("__close"),
+        _ => Value::Nil,
+    }
 }
 
 // "print" function in Lua's std-lib.
-// It supports only 1 argument and assumes the argument is at func_index + 1 on stack.
-fn lib_print(state: &mut ExeState) -> i32 {
-    writeln!(state.output, "{:?}", state.stack[state.func_index + 1]).unwrap();
-    0
+// It prints all arguments separated by tabs, they start at the base of its frame.
+fn lib_print(state: &mut ExeState) -> Result<i32, Value> {
+    let args: Vec<String> = state.args().iter().map(Value::to_string).collect();
+    writeln!(state.output, "{}", args.join("\t")).unwrap();
+    Ok(0)
+}
+
+// "tostring(v)": the text print shows for v
+fn lib_tostring(state: &mut ExeState) -> Result<i32, Value> {
+    let Some(v) = state.args().first() else {
+        return Err(state.native_error("bad argument #1 to 'tostring' (value expected)"));
+    };
+    let s = match v {
+        Value::String(_) => v.clone(),
+        v => {
+            let text = v.to_string();
+            Value::String(state.intern(&text))
+        }
+    };
+    state.stack.push(s);
+    Ok(1)
+}
+
+// "error(message [, level])": raise message, a string message is prefixed with the position
+// of the function `level` calls up, 1 by default for the function calling error.
+fn lib_error(state: &mut ExeState) -> Result<i32, Value> {
+    let args = state.args();
+    let value = args.first().cloned().unwrap_or(Value::Nil);
+    let level = match args.get(1) {
+        None | Some(Value::Nil) => 1,
+        Some(&Value::Integer(i)) => i,
+        Some(&Value::Float(f)) if f.fract() == 0.0 && f.abs() < 2f64.powi(63) => f as i64,
+        Some(v) => {
+            let message = format!(
+                "bad argument #2 to 'error' (number expected, got {})",
+                v.type_name()
+            );
+            return Err(state.native_error(message));
+        }
+    };
+    match value {
+        Value::String(message) if level > 0 => {
+            let position = state.position(level as usize);
+            Err(Value::String(
+                state.strings.create(&format!("{position}{message}")),
+            ))
+        }
+        value => Err(value),
+    }
+}
+
+// "pcall(f, ...)": call f in protected mode, returns true and its results, or false and the
+// error value
+fn lib_pcall(state: &mut ExeState) -> Result<i32, Value> {
+    let func = state.frames.last().unwrap().base;
+    let nargs = state.stack.len() - func;
+    if nargs == 0 {
+        return Err(state.native_error("bad argument #1 to 'pcall' (value expected)"));
+    }
+    let status = state.pcallk(func, nargs - 1, None, None, finish_pcall)?;
+    finish_pcall(state, status)
+}
+
+// the results of pcall once the call returned or failed
+fn finish_pcall(state: &mut ExeState, status: Result<(), Value>) -> Result<i32, Value> {
+    let func = state.frames.last().unwrap().base;
+    match status {
+        Ok(()) => state.stack.insert(func, Value::Boolean(true)),
+        Err(value) => state.stack.extend([Value::Boolean(false), value]),
+    }
+    Ok((state.stack.len() - func) as i32)
+}
+
+// "xpcall(f, msgh, ...)": like pcall, but an error value is replaced by what the message
+// handler returns for it. The handler runs before the calls that failed are unwound.
+fn lib_xpcall(state: &mut ExeState) -> Result<i32, Value> {
+    let base = state.frames.last().unwrap().base;
+    let nargs = state.stack.len() - base;
+    if nargs < 2 {
+        return Err(state.native_error("bad argument #2 to 'xpcall' (value expected)"));
+    }
+    // call f in place of the handler, which is kept below it
+    state.stack.swap(base, base + 1);
+    let status = state.pcallk(base + 1, nargs - 2, None, Some(base), finish_xpcall)?;
+    finish_xpcall(state, status)
+}
+
+// the results of xpcall once the call returned or failed
+fn finish_xpcall(state: &mut ExeState, status: Result<(), Value>) -> Result<i32, Value> {
+    let base = state.frames.last().unwrap().base;
+    match status {
+        Ok(()) => {
+            state.stack[base] = Value::Boolean(true);
+            Ok((state.stack.len() - base) as i32)
+        }
+        Err(value) => {
+            state.stack.truncate(base);
+            state.stack.extend([Value::Boolean(false), value]);
+            Ok(2)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -90,11 +1154,13 @@ mod test {
 
     use crate::parser::load;
 
-    use super::ExeState;
+    use super::{ExeState, MAX_NATIVE_NESTING};
+    use crate::traceback::FrameKind;
+    use crate::value::Value;
 
     fn prepare_file(code: &str) -> File {
         let mut file = tempfile().unwrap();
-        file.write(code.as_bytes()).unwrap();
+        file.write_all(code.as_bytes()).unwrap();
         file.seek(io::SeekFrom::Start(0)).unwrap();
         file
     }
@@ -110,10 +1176,10 @@ mod test {
     fn test_hello_world() {
         let mut file = prepare_file("print \"hello world!\"\n");
         let mut output = tempfile().unwrap();
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         let mut vm = ExeState::new(&mut output);
-        vm.execute(&proto);
+        vm.execute(&proto).unwrap();
 
         compare_output(&mut output, "hello world!\n");
     }
@@ -122,10 +1188,10 @@ mod test {
     fn test_print_small_integer() {
         let mut file = prepare_file("print(1)");
         let mut output = tempfile().unwrap();
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         let mut vm = ExeState::new(&mut output);
-        vm.execute(&proto);
+        vm.execute(&proto).unwrap();
 
         compare_output(&mut output, "1\n");
     }
@@ -134,10 +1200,10 @@ mod test {
     fn parse_print_large_integer() {
         let mut file = prepare_file("print(33000)");
         let mut output = tempfile().unwrap();
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         let mut vm = ExeState::new(&mut output);
-        vm.execute(&proto);
+        vm.execute(&proto).unwrap();
 
         compare_output(&mut output, "33000\n");
     }
@@ -146,10 +1212,10 @@ mod test {
     fn parse_print_float() {
         let mut file = prepare_file("print(1.5)");
         let mut output = tempfile().unwrap();
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
 
         let mut vm = ExeState::new(&mut output);
-        vm.execute(&proto);
+        vm.execute(&proto).unwrap();
 
         compare_output(&mut output, "1.5\n");
     }
@@ -158,11 +1224,474 @@ mod test {
     fn assign_local_variable_then_print() {
         let mut file = prepare_file("local a = 1\nprint(a)");
         let mut output = tempfile().unwrap();
-        let proto = load(&mut file);
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(&mut output, "1\n");
+    }
+
+    #[test]
+    fn arithmetic_on_locals() {
+        let mut file =
+            prepare_file("local a = 7\nlocal b = 2\nprint(a // b)\nprint(a / b)\nprint(-a % b)\nprint(a ~ b << 1)");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(&mut output, "3\n3.5\n1\n3\n");
+    }
+
+    #[test]
+    fn comparisons_and_logical_operators() {
+        let mut file = prepare_file(
+            "local a = 1\nlocal b = 2.5\nprint(a < b)\nprint(a >= b)\nprint(a == 1.0)\n\
+             print(a ~= a)\nprint(\"a\" < \"b\")\nprint(nil and a)\nprint(a and b)\n\
+             print(false or nil)\nprint(nil or a)\nprint(not a)\nprint(not nil)\n\
+             print(a > 2 or b > 2 and \"yes\")",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(
+            &mut output,
+            "true\nfalse\ntrue\nfalse\ntrue\nnil\n2.5\nnil\n1\nfalse\ntrue\nyes\n",
+        );
+    }
+
+    #[test]
+    fn short_circuit_skips_the_right_operand() {
+        let mut file = prepare_file("local a = nil\nprint(a and a < 1)\nprint(1 or a < 1)");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(&mut output, "nil\n1\n");
+    }
+
+    #[test]
+    fn recursive_lua_function() {
+        let mut file = prepare_file(
+            "function f(n) return n > 0 and f(n - 1) + 1 or 0 end\n\
+             print(f(10), f(100000))",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(&mut output, "10\t100000\n");
+    }
+
+    #[test]
+    fn varargs_and_multiple_results() {
+        let mut file = prepare_file(
+            "local function f(...) return ... end\n\
+             local function g(a, b, ...) print(a, b, ...) return a end\n\
+             local x, y, z = f(1, 2)\n\
+             print(x, y, z)\n\
+             print(f(1, f(2, 3)))\n\
+             print((f(1, 2)))\n\
+             g(1)\n\
+             g(1, 2, 3, 4)\n\
+             x, y = y, x\n\
+             print(x, y, g(5))",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(
+            &mut output,
+            "1\t2\tnil\n1\t2\t3\n1\n1\tnil\n1\t2\t3\t4\n5\tnil\n2\t1\t5\n",
+        );
+    }
+
+    #[test]
+    fn call_depth_is_limited() {
+        let mut file = prepare_file("function f() return 1 + f() end\nf()");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.set_max_call_depth(100);
+        let err = vm.execute(&proto).unwrap_err();
+
+        assert_eq!(err.to_string(), "test:1: stack overflow");
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_stack_space() {
+        let mut file = prepare_file(
+            "function done(n) return \"done\", n end\n\
+             function step(n) return (n > 0 and step or done)(n - 1) end\n\
+             local function show(...) return print(...) end\n\
+             show(step(1000000))\n\
+             print((show(1, 2)))",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.set_max_call_depth(10);
+        vm.execute(&proto).unwrap();
+
+        compare_output(&mut output, "done\t-1\n1\t2\nnil\n");
+    }
+
+    #[test]
+    fn errors_are_caught_by_pcall() {
+        let mut file = prepare_file(
+            "function fail() error(\"bad\") end\n\
+             function fail_caller() error(\"deep\", 2) end\n\
+             function call_it()\n  fail_caller()\nend\n\
+             print(pcall(fail))\n\
+             print(pcall(call_it))\n\
+             print(pcall(error, \"msg\"))\n\
+             print(pcall(error, \"msg\", 0))\n\
+             print(pcall(error, 42))\n\
+             print(pcall(error))\n\
+             print(pcall(function(a, b) return a < b end, 1, nil))\n\
+             print(pcall(function(...) return ... end, 1, 2))\n\
+             print(pcall(pcall, error, \"nested\"))\n\
+             print(pcall(pcall))\n\
+             print(pcall(error, \"level\", \"x\"))",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(
+            &mut output,
+            "false\ttest:1: bad\n\
+             false\ttest:4: deep\n\
+             false\tmsg\n\
+             false\tmsg\n\
+             false\t42\n\
+             false\tnil\n\
+             false\ttest:12: attempt to compare number with nil\n\
+             true\t1\t2\n\
+             true\tfalse\tnested\n\
+             false\tbad argument #1 to 'pcall' (value expected)\n\
+             false\tbad argument #2 to 'error' (number expected, got string)\n",
+        );
+    }
+
+    #[test]
+    fn xpcall_handles_errors_before_unwinding() {
+        let mut file = prepare_file(
+            "function fail() error(\"bad\") end\n\
+             print(xpcall(fail, function(m) return \"handled\", 2 end))\n\
+             print(xpcall(function(a) return a end, print, 5))\n\
+             print(xpcall(fail, fail))\n\
+             print(xpcall(error, function(m) return m end, 3))",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let mut vm = ExeState::new(&mut output);
+        vm.execute(&proto).unwrap();
+
+        compare_output(
+            &mut output,
+            "false\thandled\ntrue\t5\nfalse\terror in error handling\nfalse\t3\n",
+        );
+    }
+
+    #[test]
+    fn stack_overflow_is_caught() {
+        let mut file = prepare_file(
+            "function f() return 1 + f() end\nprint(pcall(f))\nprint(pcall(f))\n\
+             function g() return pcall(g) end\nprint(g())",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
 
         let mut vm = ExeState::new(&mut output);
-        vm.execute(&proto);
+        vm.set_max_call_depth(1000);
+        vm.execute(&proto).unwrap();
+
+        // every pcall but the one failing returns true before the results of the inner one
+        let nested = "true\t".repeat(MAX_NATIVE_NESTING - 1);
+        compare_output(
+            &mut output,
+            &format!(
+                "false\ttest:1: stack overflow\nfalse\ttest:1: stack overflow\n\
+                 {nested}false\tC stack overflow\n"
+            ),
+        );
+    }
 
+    #[test]
+    fn uncaught_error_values() {
+        let mut output = tempfile().unwrap();
+        let mut vm = ExeState::new(&mut output);
+        for (code, message) in [
+            ("error(\"boom\")", "test:1: boom"),
+            ("error(\"boom\", 0)", "boom"),
+            ("error(1.5)", "1.5"),
+            ("error()", "(error object is a nil value)"),
+            ("error(true)", "(error object is a boolean value)"),
+        ] {
+            let proto = load(&mut prepare_file(code), "=test").unwrap();
+            assert_eq!(vm.execute(&proto).unwrap_err().to_string(), message);
+        }
+        // the state can still be used after an error
+        let proto = load(&mut prepare_file("print(1)"), "=test").unwrap();
+        vm.execute(&proto).unwrap();
         compare_output(&mut output, "1\n");
     }
+
+    #[test]
+    fn uncaught_errors_carry_a_traceback() {
+        let mut file = prepare_file(
+            "function inner()\n  error(\"boom\")\nend\n\
+             function outer()\n  inner()\nend\n\
+             function tail()\n  return outer()\nend\n\
+             local function g()\n  tail()\nend\n\
+             g()",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let err = ExeState::new(&mut output).execute(&proto).unwrap_err();
+
+        assert_eq!(err.to_string(), "test:2: boom");
+        assert_eq!(
+            err.traceback.to_string(),
+            "stack traceback:\n\
+             \t[C]: in function 'error'\n\
+             \ttest:2: in function 'inner'\n\
+             \ttest:5: in function 'outer'\n\
+             \t(...tail calls...)\n\
+             \ttest:11: in local 'g'\n\
+             \ttest:13: in main chunk"
+        );
+        let frames = &err.traceback.frames;
+        assert_eq!(frames.len(), 5);
+        assert_eq!(
+            (frames[0].kind, frames[0].current_line),
+            (FrameKind::Native, None)
+        );
+        assert_eq!(frames[3].kind, FrameKind::Lua);
+        assert_eq!(frames[3].name, Some(("local", "g".to_string())));
+        assert_eq!(frames[3].line_defined, 10);
+        assert!(frames[2].tail_call);
+        assert_eq!(frames[4].kind, FrameKind::Main);
+    }
+
+    #[test]
+    fn traceback_of_stripped_code() {
+        let mut file = prepare_file("local f = function() error(\"x\", 0) end\nf()");
+        let mut output = tempfile().unwrap();
+        let mut proto = load(&mut file, "=test").unwrap();
+        proto.strip();
+
+        let err = ExeState::new(&mut output).execute(&proto).unwrap_err();
+
+        assert_eq!(
+            err.traceback.to_string(),
+            "stack traceback:\n\t[C]: in function 'error'\n\t?: in function <?:0>\n\t?: in main chunk"
+        );
+    }
+
+    #[test]
+    fn errors_name_the_offending_variable() {
+        let mut output = tempfile().unwrap();
+        let mut vm = ExeState::new(&mut output);
+        for (code, message) in [
+            (
+                "prnt(\"hi\")",
+                "test:1: attempt to call a nil value (global 'prnt')",
+            ),
+            (
+                "local f = nil\nf()",
+                "test:2: attempt to call a nil value (local 'f')",
+            ),
+            (
+                "local f = 1\nlocal g = f\nreturn g(1)",
+                "test:3: attempt to call a number value (local 'g')",
+            ),
+            (
+                "local x = nil\nlocal y = 1 + x",
+                "test:2: attempt to perform arithmetic on a nil value (local 'x')",
+            ),
+            (
+                "local y = -count",
+                "test:1: attempt to perform arithmetic on a nil value (global 'count')",
+            ),
+            (
+                "local y = 1 + \"one\"",
+                "test:1: attempt to perform arithmetic on a string value (constant 'one')",
+            ),
+            (
+                "local y = 1.5 | mask",
+                "test:1: attempt to perform bitwise operation on a nil value (global 'mask')",
+            ),
+            (
+                "local y = 2 ^ 0.5 & 1",
+                "test:1: number has no integer representation",
+            ),
+            // the value is not named if it may come from either side of a jump
+            (
+                "local y = (a or b) + 1",
+                "test:1: attempt to perform arithmetic on a nil value",
+            ),
+        ] {
+            let proto = load(&mut prepare_file(code), "=test").unwrap();
+            assert_eq!(
+                vm.execute(&proto).unwrap_err().to_string(),
+                message,
+                "{code}"
+            );
+        }
+    }
+
+    #[test]
+    fn call_nil() {
+        let mut file = prepare_file("f()");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let err = ExeState::new(&mut output).execute(&proto).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "test:1: attempt to call a nil value (global 'f')"
+        );
+    }
+
+    #[test]
+    fn to_be_closed_values_need_a_close_metamethod() {
+        let mut file = prepare_file("local a <close> = false\nlocal b <close> = 42");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let err = ExeState::new(&mut output).execute(&proto).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "test:2: variable 'b' got a non-closable value"
+        );
+    }
+
+    #[test]
+    fn compare_different_types() {
+        let mut file = prepare_file("local a = nil\nprint(1 < a)");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let err = ExeState::new(&mut output).execute(&proto).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "test:2: attempt to compare number with nil"
+        );
+    }
+
+    #[test]
+    fn integer_division_by_zero() {
+        let mut file = prepare_file("local a = 0\nprint(1 // a)");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let err = ExeState::new(&mut output).execute(&proto).unwrap_err();
+
+        assert_eq!(err.to_string(), "test:2: attempt to perform 'n//0'");
+    }
+
+    #[test]
+    fn bitwise_operation_on_fraction() {
+        let mut file = prepare_file("local a = 1.5\nprint(a | 1)");
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        let err = ExeState::new(&mut output).execute(&proto).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "test:2: number (local 'a') has no integer representation"
+        );
+    }
+
+    #[test]
+    fn strings_of_different_chunks_are_interned() {
+        let mut output = tempfile().unwrap();
+        let mut vm = ExeState::new(&mut output);
+        let first = load(&mut prepare_file("greeting = \"hello\""), "=first").unwrap();
+        let second = load(
+            &mut prepare_file("local s = \"hello\"\nmine = s"),
+            "=second",
+        )
+        .unwrap();
+        vm.execute(&first).unwrap();
+        vm.execute(&second).unwrap();
+
+        let global = |vm: &mut ExeState, name| {
+            let key = vm.intern(name);
+            match &vm.globals[&key] {
+                Value::String(s) => s.clone(),
+                v => panic!("{v:?} is not a string"),
+            }
+        };
+        let greeting = global(&mut vm, "greeting");
+        assert!(greeting.ptr_eq(&global(&mut vm, "mine")));
+        assert!(greeting.ptr_eq(&vm.intern("hello")));
+    }
+
+    #[test]
+    fn values_print_like_reference_lua() {
+        let mut file = prepare_file(
+            "print(1e100, 0.1 + 0.2, 1e15, -0.0, 2^53, 1 / 0, -1 / 0, 10 / 2)\n\
+             print(tostring(12), tostring(nil), tostring(true), tostring(\"s\"))\n\
+             print(print, tostring(print) == tostring(print))\n\
+             local function f() end\n\
+             print(f, coroutine.create(f))\n\
+             print(pcall(tostring))",
+        );
+        let mut output = tempfile().unwrap();
+        let proto = load(&mut file, "=test").unwrap();
+
+        ExeState::new(&mut output).execute(&proto).unwrap();
+
+        let mut buffer = String::new();
+        output.seek(io::SeekFrom::Start(0)).unwrap();
+        output.read_to_string(&mut buffer).unwrap();
+        let lines: Vec<&str> = buffer.lines().collect();
+        assert_eq!(
+            lines[0],
+            "1e+100\t0.3\t1e+15\t-0.0\t9.007199254741e+15\tinf\t-inf\t5.0"
+        );
+        assert_eq!(lines[1], "12\tnil\ttrue\ts");
+        let is_address = |s: &str| {
+            s.strip_prefix("0x")
+                .is_some_and(|hex| !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+        };
+        let (print, same) = lines[2].split_once('\t').unwrap();
+        assert!(
+            is_address(print.strip_prefix("function: ").unwrap()),
+            "{print}"
+        );
+        assert_eq!(same, "true");
+        let (f, co) = lines[3].split_once('\t').unwrap();
+        assert!(is_address(f.strip_prefix("function: ").unwrap()), "{f}");
+        assert!(is_address(co.strip_prefix("thread: ").unwrap()), "{co}");
+        assert_eq!(
+            lines[4],
+            "false\tbad argument #1 to 'tostring' (value expected)"
+        );
+    }
 }
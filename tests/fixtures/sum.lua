@@ -0,0 +1,4 @@
+local function add(a, b)
+  return a + b
+end
+print("sum", add(1, 2.5))
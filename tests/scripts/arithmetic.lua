@@ -0,0 +1,22 @@
+local a = 7
+local b = 2
+print(a + b)
+print(a - b)
+print(a * b)
+print(a / b)
+print(a // b)
+print(a % b)
+print(a ^ b)
+print(-a // b)
+print(-a % b)
+print(7.5 // 2)
+print(a & 3 | 8 ~ b)
+print(~a)
+print(a << 62)
+print(a >> 1)
+print(1 << 64)
+print(2 ^ 3 ^ 2)
+print(-2 ^ 2)
+print(0x10 + 1e2)
+print(0xA.8p1)
+print("3" | 1, "10" << 1)
@@ -0,0 +1,55 @@
+meta = {__close = function(value, err) print("close", value.name, err) end}
+
+function closer(name)
+  return setmetatable({name = name}, meta)
+end
+
+function tail()
+  print("tail")
+  return "result"
+end
+
+local function scoped()
+  local a <close> = closer("a")
+  local b <close>, c = closer("b"), closer("not closed")
+  local d <close> = nil
+  print("body")
+  return tail()
+end
+print(scoped())
+
+function failing()
+  local a <close> = closer("a")
+  error("failed")
+end
+print(pcall(failing))
+
+bad = setmetatable({}, {__close = function() error("close failed", 0) end})
+function failing_close()
+  local a <close> = closer("a")
+  local b <close> = bad
+  return "not returned"
+end
+print(pcall(failing_close))
+print(pcall(function() local x <close> = 42 end))
+
+co = coroutine.create(function()
+  local a <close> = closer("suspended")
+  coroutine.yield("yielded")
+end)
+print(coroutine.resume(co))
+print(coroutine.close(co))
+print(coroutine.status(co))
+
+co = coroutine.create(function()
+  local a <close> = closer("dead")
+  error("died", 0)
+end)
+print(coroutine.resume(co))
+print(coroutine.close(co))
+
+wrapped = coroutine.wrap(function()
+  local a <close> = closer("wrapped")
+  error("wrapped failed", 0)
+end)
+print(pcall(wrapped))
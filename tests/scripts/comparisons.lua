@@ -0,0 +1,14 @@
+local a = 1
+local b = 2.5
+print(a < b)
+print(a <= 1.0)
+print(a == 1.0)
+print(b > a)
+print(a ~= b)
+print("abc" < "abd")
+print(9007199254740993 < 9007199254740992.0)
+print(nil and a)
+print(false or b)
+print(a and b or "none")
+print(not nil)
+print(not 0)
@@ -0,0 +1,11 @@
+print(1 .. "")
+print(0.1 .. "")
+print(2^63 .. "")
+print(-0.0 .. "", 1e15 .. "", 1e100 .. "")
+local a, b = "a", 2
+print(a .. b .. 3.5 .. "c")
+print("x" .. 1 + 2 .. "y")
+s = "s"
+print(pcall(function() return s .. nil end))
+print(pcall(function() local t = {} return t .. s end))
+print(pcall(function() return s .. undefined end))
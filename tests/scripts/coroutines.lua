@@ -0,0 +1,27 @@
+function producer(first, count)
+  local last = first + count - 1
+  print("producing", first, last)
+  coroutine.yield(first)
+  coroutine.yield(first + 1)
+  return "exhausted"
+end
+
+local co = coroutine.create(producer)
+print(coroutine.resume(co, 10, 2))
+print(coroutine.status(co))
+print(coroutine.resume(co))
+print(coroutine.resume(co))
+print(coroutine.status(co))
+print(coroutine.resume(co))
+
+local safe = coroutine.wrap(function(x)
+  local ok, err = pcall(function(y) error(coroutine.yield(y * 2)) end, x)
+  print("caught", ok, err)
+  return "done"
+end)
+print(safe(21))
+print(safe("interrupted"))
+
+local failing = coroutine.create(function() return 1 + nil end)
+print(coroutine.resume(failing))
+print(coroutine.close(failing))
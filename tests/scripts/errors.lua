@@ -0,0 +1,18 @@
+function fail(value)
+  error(value)
+end
+
+function fail_caller(message)
+  error(message, 2)
+end
+
+local function divide(a, b)
+  return a // b
+end
+
+print(pcall(fail, "failed"))
+print(pcall(fail, 42))
+print(pcall(fail_caller, "caller failed"))
+print(pcall(divide, 1, 0))
+print(pcall(divide, 7, 2))
+print(xpcall(fail, function(message) return "handled", message end, "again"))
@@ -0,0 +1,23 @@
+function count(n)
+  return n > 0 and count(n - 1) + 1 or 0
+end
+
+local function pack(...)
+  return ...
+end
+
+local function first(a, b)
+  return a
+end
+
+print(count(10))
+print(pack(1, "two", 3.0))
+print(first(pack(4, 5)))
+print((pack(6, 7)), pack())
+local x, y, z = pack(8, 9)
+print(x, y, z)
+x, y = y, x
+print(x, y)
+local function make() return function() end end
+local f = make()
+print(f == f, make() == make())
@@ -0,0 +1,5 @@
+local a = 1
+local b = 100000
+print(a)
+print(b)
+print(print)
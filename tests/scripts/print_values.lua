@@ -0,0 +1,9 @@
+print(1)
+print(33000)
+print(1.5)
+print(true)
+print(false)
+print(nil)
+print(1e100, 0.1 + 0.2, 1e15, -0.0, 2^53)
+print(1 / 0, -1 / 0, 10 / 2, 3 // 1.0)
+print(tostring(12), tostring(nil), tostring("s"))
@@ -0,0 +1,24 @@
+local point = {x = 1, y = 2, ["z"] = 3}
+print(point.x, point.y, point.z, point.w)
+
+local list = {"a", "b", "c"; n = 3}
+list[4], list.n = "d", list.n + 1
+print(list[1], list[4], list[4.0], list.n)
+
+local nested = {inner = {value = 42}}
+nested.inner.value = nested.inner.value + 1
+print(nested.inner.value)
+
+shapes = {}
+function shapes.square(side) return side * side end
+print(shapes.square(3))
+
+local function values(...) return ... end
+local packed = {values(1, 2, 3)}
+local first = {values(1, 2, 3), 4}
+print(packed[3], first[1], first[2], first[3])
+
+print(getmetatable(point), getmetatable(setmetatable(point, {})) ~= nil)
+print(pcall(setmetatable, point, 1))
+print(pcall(function() local t = nil; return t.x end))
+print(pcall(function() shapes[nil] = 1 end))
@@ -0,0 +1,99 @@
+use lua_interpreter::{dump, execute, load_reader, undump, undump_luac, ParseProto};
+use std::{
+    fs::{self, File},
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+fn run(proto: &ParseProto) -> String {
+    let mut output = Vec::new();
+    execute(proto, &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+/// Replace the addresses printed for functions and threads, which change from run to run.
+fn mask_addresses(output: &str) -> String {
+    let mut masked = String::new();
+    let mut rest = output;
+    while let Some(at) = rest.find("0x") {
+        masked.push_str(&rest[..at + 2]);
+        rest = rest[at + 2..].trim_start_matches(|c: char| c.is_ascii_hexdigit());
+        masked.push_str("...");
+    }
+    masked.push_str(rest);
+    masked
+}
+
+fn compare_output(script: &Path, output: &str) {
+    let expected = fs::read_to_string(script.with_extension("out")).unwrap();
+    assert_eq!(mask_addresses(output), expected, "{}", script.display());
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/scripts");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lua"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty());
+    scripts
+}
+
+#[test]
+fn round_trip_every_test_script() {
+    for script in scripts() {
+        let mut file = File::open(&script).unwrap();
+        let name = script.file_name().unwrap().to_str().unwrap();
+        let proto = load_reader(&mut file, Some(&format!("@{name}"))).unwrap();
+
+        let mut chunk = Vec::new();
+        dump(&proto, &mut chunk).unwrap();
+        let loaded = undump(&mut Cursor::new(&chunk)).unwrap();
+
+        assert_eq!(loaded, proto, "{}", script.display());
+        compare_output(&script, &run(&proto));
+        compare_output(&script, &run(&loaded));
+    }
+}
+
+#[test]
+fn load_reader_accepts_binary_chunks() {
+    let proto = load_reader(&mut Cursor::new("print(\"precompiled\")"), None).unwrap();
+    let mut chunk = Vec::new();
+    dump(&proto, &mut chunk).unwrap();
+
+    let loaded = load_reader(&mut Cursor::new(chunk), None).unwrap();
+
+    assert_eq!(run(&loaded), "precompiled\n");
+}
+
+/// `tests/fixtures/sum.luac` is what `luac -o sum.luac sum.lua` of Lua 5.4 writes,
+/// `sum_stripped.luac` what `luac -s -o sum_stripped.luac sum.lua` does.
+#[test]
+fn load_chunks_of_reference_luac() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let mut file = File::open(format!("{dir}/sum.lua")).unwrap();
+    let source = load_reader(&mut file, Some("@sum.lua")).unwrap();
+
+    let mut file = File::open(format!("{dir}/sum.luac")).unwrap();
+    let proto = undump_luac(&mut file).unwrap();
+    assert_eq!(run(&proto), "sum\t3.5\n");
+    assert_eq!(run(&proto), run(&source));
+    let debug = proto.debug.as_ref().unwrap();
+    assert_eq!(debug.source, "@sum.lua");
+    assert_eq!(debug.local_name(0, proto.byte_codes.len() - 1), Some("add"));
+    assert_eq!(proto.line(0), Some(3));
+    assert_eq!(proto.line(proto.byte_codes.len() - 1), Some(4));
+    let add = &proto.protos[0];
+    assert_eq!((add.num_params, add.is_vararg), (2, false));
+    assert_eq!(add.debug.as_ref().unwrap().local_name(0, 0), Some("a"));
+    assert_eq!(add.line(0), Some(2));
+
+    let mut file = File::open(format!("{dir}/sum_stripped.luac")).unwrap();
+    let stripped = undump_luac(&mut file).unwrap();
+    assert_eq!(stripped.debug, None);
+    assert_eq!(stripped.byte_codes, proto.byte_codes);
+    assert_eq!(run(&stripped), "sum\t3.5\n");
+}
@@ -1,4 +1,4 @@
-use lua_interpreter::lua;
+use lua_interpreter::{lua, LuaError, Value};
 use std::{
     fs::File,
     io::{self, Read, Seek, Write},
@@ -8,7 +8,7 @@ use tempfile::tempfile;
 
 fn prepare_file(code: &str) -> File {
     let mut file = tempfile().unwrap();
-    file.write(code.as_bytes()).unwrap();
+    file.write_all(code.as_bytes()).unwrap();
     file.seek(io::SeekFrom::Start(0)).unwrap();
     file
 }
@@ -25,7 +25,7 @@ fn test_hello_world() {
     let mut file = prepare_file("print \"hello world!\"\n");
     let mut output = tempfile().unwrap();
 
-    lua(&mut file, &mut output);
+    lua(&mut file, &mut output).unwrap();
 
     compare_output(&mut output, "hello world!\n");
 }
@@ -35,7 +35,7 @@ fn test_print_integer() {
     let mut file = prepare_file("print(1)\n");
     let mut output = tempfile().unwrap();
 
-    lua(&mut file, &mut output);
+    lua(&mut file, &mut output).unwrap();
 
     compare_output(&mut output, "1\n");
 }
@@ -45,7 +45,7 @@ fn test_print_bool() {
     let mut file = prepare_file("print(true)\n");
     let mut output = tempfile().unwrap();
 
-    lua(&mut file, &mut output);
+    lua(&mut file, &mut output).unwrap();
 
     compare_output(&mut output, "true\n");
 }
@@ -55,7 +55,7 @@ fn test_print_nil() {
     let mut file = prepare_file("print(nil)\n");
     let mut output = tempfile().unwrap();
 
-    lua(&mut file, &mut output);
+    lua(&mut file, &mut output).unwrap();
 
     compare_output(&mut output, "nil\n");
 }
@@ -65,7 +65,35 @@ fn test_print_local_variable() {
     let mut file = prepare_file("local a = 1\nprint(a)");
     let mut output = tempfile().unwrap();
 
-    lua(&mut file, &mut output);
+    lua(&mut file, &mut output).unwrap();
 
     compare_output(&mut output, "1\n");
 }
+
+#[test]
+fn uncaught_error_is_returned() {
+    let mut file = prepare_file("print(1)\nerror(\"boom\")\nprint(2)\n");
+    let mut output = tempfile().unwrap();
+
+    let err = lua(&mut file, &mut output).unwrap_err();
+
+    let LuaError::Runtime(err) = err else {
+        panic!("{err:?} is not a runtime error");
+    };
+    assert_eq!(err.value, Value::String("(load):2: boom".into()));
+    assert_eq!(
+        err.traceback.to_string(),
+        "stack traceback:\n\t[C]: in function 'error'\n\t(load):2: in main chunk"
+    );
+    compare_output(&mut output, "1\n");
+}
+
+#[test]
+fn syntax_error_is_returned() {
+    let mut file = prepare_file("print(1\n");
+    let mut output = tempfile().unwrap();
+
+    let err = lua(&mut file, &mut output).unwrap_err();
+
+    assert!(matches!(err, LuaError::Syntax(_)), "{err:?}");
+}
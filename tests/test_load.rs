@@ -0,0 +1,123 @@
+use lua_interpreter::{
+    bytecode::ByteCode, execute, load_bytes, load_reader, load_str, load_with, LoadOptions,
+    ParseProto, Value,
+};
+use std::io::Cursor;
+
+fn run(proto: &lua_interpreter::ParseProto) -> String {
+    let mut output = Vec::new();
+    execute(proto, &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn load_from_str() {
+    let proto = load_str("print \"hello world!\"\n", None).unwrap();
+
+    assert_eq!(run(&proto), "hello world!\n");
+}
+
+#[test]
+fn load_from_bytes() {
+    let proto = load_bytes(b"local a = 1\nprint(a)", Some("=bytes")).unwrap();
+
+    assert_eq!(run(&proto), "1\n");
+}
+
+#[test]
+fn load_from_reader() {
+    let mut reader = Cursor::new("print(1.5)");
+    let proto = load_reader(&mut reader, Some("@script.lua")).unwrap();
+
+    assert_eq!(run(&proto), "1.5\n");
+}
+
+#[test]
+fn chunkname_in_error_message() {
+    let errors = load_str("1", Some("@script.lua")).unwrap_err();
+
+    assert_eq!(
+        errors[0].to_string(),
+        "script.lua:1: unexpected symbol near '1'"
+    );
+}
+
+#[test]
+fn default_chunkname_is_source() {
+    let errors = load_str("print(1) 2", None).unwrap_err();
+
+    assert_eq!(
+        errors[0].to_string(),
+        "[string \"print(1) 2\"]:1: unexpected symbol near '2'"
+    );
+}
+
+#[test]
+fn load_from_reader_reports_syntax_errors() {
+    let mut reader = Cursor::new("local = 1");
+    let err = load_reader(&mut reader, Some("=stdin")).unwrap_err();
+
+    assert_eq!(err.to_string(), "stdin:1: <name> expected near '='");
+}
+
+#[test]
+fn optimizer_can_be_switched_off() {
+    let code = "local a = 1\nlocal b = a\nprint(b)";
+    let options = LoadOptions {
+        optimize: false,
+        ..Default::default()
+    };
+    let plain = load_with(&mut Cursor::new(code), None, &options).unwrap();
+    let optimized = load_reader(&mut Cursor::new(code), None).unwrap();
+
+    assert_eq!(run(&plain), run(&optimized));
+}
+
+#[test]
+fn debug_information_records_lines_and_locals() {
+    let code = "local a = 1\n\nprint(a)";
+    let proto = load_reader(&mut Cursor::new(code), Some("@script.lua")).unwrap();
+    let debug = proto.debug.as_ref().unwrap();
+
+    assert_eq!(debug.source, "@script.lua");
+    let lines: Vec<_> = (0..proto.byte_codes.len())
+        .map(|pc| proto.line(pc))
+        .collect();
+    assert_eq!(lines, vec![Some(1), Some(3), Some(3), Some(3)]);
+    assert_eq!(debug.local_name(0, 1), Some("a"));
+
+    let options = LoadOptions {
+        strip: true,
+        ..Default::default()
+    };
+    let stripped = load_with(&mut Cursor::new(code), None, &options).unwrap();
+    assert_eq!(stripped.debug, None);
+    assert_eq!(stripped.line(0), None);
+    assert_eq!(run(&stripped), run(&proto));
+}
+
+#[test]
+fn hand_built_prototypes_are_verified_before_running() {
+    // loads a register beyond the frame, then prints a constant that does not exist
+    let proto = ParseProto {
+        constants: vec![Value::String("print".into())],
+        byte_codes: vec![
+            ByteCode::GetGlobal(0, 0).into(),
+            ByteCode::LoadConst(5, 7).into(),
+            ByteCode::Call(0, 2, 1).into(),
+        ],
+        max_stack_size: 2,
+        is_vararg: true,
+        ..Default::default()
+    };
+    let mut output = Vec::new();
+
+    let err = execute(&proto, &mut output).unwrap_err();
+
+    assert_eq!(
+        err.value,
+        Value::String("invalid instruction 2: register 5 out of stack frame of 2 slots".into())
+    );
+    assert!(err.traceback.frames.is_empty());
+    assert!(output.is_empty());
+}
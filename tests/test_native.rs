@@ -0,0 +1,70 @@
+use lua_interpreter::{load_str, ExeState, Value};
+
+// yields its arguments doubled, then returns the sum of its arguments and what it was resumed
+// with, which took the place of the yielded values
+fn double_then_sum(state: &mut ExeState) -> Result<i32, Value> {
+    let doubled: Vec<Value> = state
+        .args()
+        .iter()
+        .map(|arg| match arg {
+            Value::Integer(i) => Value::Integer(i * 2),
+            _ => Value::Nil,
+        })
+        .collect();
+    let n = doubled.len();
+    for value in doubled {
+        state.push(value);
+    }
+    state.yield_values(n, Some(sum_resumed))
+}
+
+fn sum_resumed(state: &mut ExeState, status: Result<(), Value>) -> Result<i32, Value> {
+    assert!(status.is_ok());
+    let sum = state
+        .args()
+        .iter()
+        .map(|arg| match arg {
+            Value::Integer(i) => *i,
+            _ => 0,
+        })
+        .sum();
+    state.push(Value::Integer(sum));
+    Ok(1)
+}
+
+#[test]
+fn test_native_yields_and_continues() {
+    let proto = load_str(
+        "local co = coroutine.create(function(a, b) return \"sum\", double_then_sum(a, b) end)\n\
+         print(coroutine.resume(co, 1, 2))\n\
+         print(coroutine.resume(co, 3, 4, 5))\n\
+         print(coroutine.status(co))",
+        Some("=test"),
+    )
+    .unwrap();
+    let mut output = Vec::new();
+    let mut vm = ExeState::new(&mut output);
+    vm.register("double_then_sum", double_then_sum);
+    vm.execute(&proto).unwrap();
+    drop(vm);
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "true\t2\t4\ntrue\tsum\t15\ndead\n"
+    );
+}
+
+#[test]
+fn test_native_cannot_yield_outside_a_coroutine() {
+    let proto = load_str("print(pcall(double_then_sum, 1))", Some("=test")).unwrap();
+    let mut output = Vec::new();
+    let mut vm = ExeState::new(&mut output);
+    vm.register("double_then_sum", double_then_sum);
+    vm.execute(&proto).unwrap();
+    drop(vm);
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "false\tattempt to yield from outside a coroutine\n"
+    );
+}